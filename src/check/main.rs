@@ -0,0 +1,225 @@
+// Offline check of snapshot, RDB and AOF files, e.g. after a crash or a full
+// disk. Reports the offset of the first corruption and can truncate an AOF back
+// to its last complete command. Encrypted files are checked with `--key-file`.
+
+use std::{
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+};
+
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use ds_cache::persistence::{
+    Corruption, aof,
+    crypto::{EncryptionOptions, Keyring, Opened},
+    manifest::{AofFileType, Manifest},
+    rdb, snapshot,
+};
+
+#[derive(Debug, Parser)]
+#[command(about = "Check ds-cache snapshot, RDB and AOF files")]
+struct Args {
+    /// A snapshot, RDB or AOF file, or an AOF directory
+    path: PathBuf,
+
+    /// Truncate a corrupt AOF back to its last complete command
+    #[arg(long)]
+    fix: bool,
+
+    /// The encryption-key-file the files were sealed with
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match check(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn check(args: &Args) -> Result<bool> {
+    let encryption = EncryptionOptions {
+        keyring: match &args.key_file {
+            Some(path) => Some(Arc::new(Keyring::load(path)?)),
+            None => None,
+        },
+        allow_plaintext: true,
+    };
+    if args.path.is_dir() {
+        check_aof_dir(&args.path, &encryption, args.fix)
+    } else if args.path.extension().is_some_and(|ext| ext == "manifest") {
+        let dir = args.path.parent().unwrap_or(Path::new("."));
+        check_aof_dir(dir, &encryption, args.fix)
+    } else {
+        check_file(&args.path, &encryption, args.fix, false)
+    }
+}
+
+// Check every file the manifest in `dir` lists. Only the last one may be
+// truncated, the files after it build on everything before, and only the last
+// one may lack its final encrypted record.
+fn check_aof_dir(dir: &Path, encryption: &EncryptionOptions, fix: bool) -> Result<bool> {
+    let mut prefixes = Vec::new();
+    for entry in
+        fs::read_dir(dir).map_err(|e| anyhow!("Failed to read {}: {}", dir.display(), e))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "manifest")
+            && let Some(stem) = path.file_stem()
+        {
+            prefixes.push(stem.to_string_lossy().into_owned());
+        }
+    }
+    let [prefix] = prefixes.as_slice() else {
+        return Err(anyhow!(
+            "Expected one manifest in {}, found {}",
+            dir.display(),
+            prefixes.len()
+        ));
+    };
+    let manifest = Manifest::load(dir, prefix, encryption)?
+        .ok_or_else(|| anyhow!("No manifest in {}", dir.display()))?;
+
+    let files: Vec<_> = manifest.base.iter().chain(&manifest.incrs).collect();
+    let mut ok = true;
+    for (i, info) in files.iter().enumerate() {
+        let path = dir.join(&info.name);
+        if !path.exists() {
+            println!("{}: listed in the manifest but missing", path.display());
+            ok = false;
+            continue;
+        }
+        let is_last = i + 1 == files.len();
+        let file_ok = match info.file_type {
+            AofFileType::Base => check_file(&path, encryption, fix && is_last, true)?,
+            AofFileType::Incr => check_aof(&path, encryption, fix && is_last, !is_last)?,
+        };
+        if !file_ok && fix && !is_last {
+            println!("{}: only the last file can be truncated", path.display());
+        }
+        ok &= file_ok;
+    }
+    Ok(ok)
+}
+
+// Check a file of any format, told apart by its header. Snapshots, and AOF files
+// when `closed`, must end with their final encrypted record.
+fn check_file(
+    path: &Path,
+    encryption: &EncryptionOptions,
+    fix: bool,
+    closed: bool,
+) -> Result<bool> {
+    let Some((file, len)) = open(path, encryption)? else {
+        return Ok(false);
+    };
+    let data = &file.data;
+    let mut keys = 0;
+    let res = if snapshot::is_snapshot(data) {
+        snapshot::decode(data, |_| keys += 1).map(|()| format!("snapshot with {} keys", keys))
+    } else if rdb::is_rdb(data) {
+        rdb::decode(data, |_, _| keys += 1).map(|()| format!("RDB file with {} keys", keys))
+    } else {
+        return check_aof_data(path, file, len, fix, closed);
+    };
+    let res = res.and_then(|summary| finished(&file).map(|()| summary));
+    Ok(report(path, res))
+}
+
+fn check_aof(path: &Path, encryption: &EncryptionOptions, fix: bool, closed: bool) -> Result<bool> {
+    match open(path, encryption)? {
+        Some((file, len)) => check_aof_data(path, file, len, fix, closed),
+        None => Ok(false),
+    }
+}
+
+// A sealed file cut short at a record boundary lacks its final record
+fn finished(file: &Opened) -> Result<(), Corruption> {
+    match file.sealed && !file.finished {
+        true => Err(Corruption::new(
+            file.valid_len,
+            "Final encrypted record missing",
+        )),
+        false => Ok(()),
+    }
+}
+
+// The decrypted file and its size on disk, None once a file that can't be
+// decrypted was reported
+fn open(path: &Path, encryption: &EncryptionOptions) -> Result<Option<(Opened, usize)>> {
+    let data = fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    let len = data.len();
+    match encryption.open_records(data) {
+        Ok(file) => Ok(Some((file, len))),
+        Err(e) => {
+            println!("{}: {}", path.display(), e);
+            Ok(None)
+        }
+    }
+}
+
+// Check the commands of an AOF file and offer to cut it back to the last
+// complete one. A sealed file can only be cut back to its last complete record,
+// and a `closed` one that lost its final record can't be fixed.
+fn check_aof_data(path: &Path, file: Opened, len: usize, fix: bool, closed: bool) -> Result<bool> {
+    let mut res = aof::check(&file.data).map(|commands| format!("AOF with {} commands", commands));
+    let offset = match (&res, file.sealed) {
+        (Ok(_), true) if file.truncated => {
+            res = Err(Corruption::new(file.valid_len, "Partial encrypted record"));
+            Some(file.valid_len)
+        }
+        (Ok(_), true) if closed => {
+            res = res.and_then(|summary| finished(&file).map(|()| summary));
+            None
+        }
+        (Err(corruption), false) => Some(corruption.offset),
+        _ => None,
+    };
+    if report(path, res) {
+        return Ok(true);
+    }
+    let Some(offset) = offset else {
+        return Ok(false);
+    };
+
+    if !fix {
+        println!(
+            "{}: run with --fix to truncate it to {} bytes",
+            path.display(),
+            offset
+        );
+        return Ok(false);
+    }
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(offset as u64)?;
+    println!(
+        "{}: truncated to {} bytes, {} bytes dropped",
+        path.display(),
+        offset,
+        len - offset
+    );
+    Ok(true)
+}
+
+fn report(path: &Path, res: Result<String, Corruption>) -> bool {
+    match res {
+        Ok(summary) => {
+            println!("{}: OK, {}", path.display(), summary);
+            true
+        }
+        Err(corruption) => {
+            println!("{}: {}", path.display(), corruption);
+            false
+        }
+    }
+}
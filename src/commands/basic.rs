@@ -1,10 +1,16 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use redis_protocol::resp2::types::BytesFrame;
 use std::{sync::Arc, time::Duration};
 use tokio::sync::RwLock;
-use tracing::info;
 
-use crate::{commands::BasicCommand, protocol::encode::encode_error, storage::CacheStore};
+use crate::{
+    commands::{BasicCommand, RestoreOptions},
+    persistence::{rdb, snapshot::SnapshotValue},
+    protocol::encode::encode_error,
+    storage::{CacheStore, entry::Entry},
+    utils::{from_hex, to_hex, unix_time_ms},
+};
+use tracing::{debug, trace};
 
 pub struct BasicCmdHandler {
     pub store: Arc<RwLock<CacheStore>>,
@@ -16,22 +22,36 @@ impl BasicCmdHandler {
     }
 
     pub async fn handle_cmd(&mut self, cmd: BasicCommand) -> Result<BytesFrame> {
-        info!("[BasicCmdHandler] handle_cmd cmd: {:?}", cmd);
+        trace!("[BasicCmdHandler] handle_cmd cmd: {:?}", cmd);
 
         match cmd {
             BasicCommand::Ping { message } => self.handle_ping(message).await,
             BasicCommand::Echo { message } => self.handle_echo(message).await,
             BasicCommand::Del { keys } => self.handle_del(keys).await,
+            BasicCommand::Unlink { keys } => self.handle_unlink(keys).await,
+            BasicCommand::FlushDb { lazy } | BasicCommand::FlushAll { lazy } => {
+                self.handle_flush(lazy).await
+            }
             BasicCommand::Exists { keys } => self.handle_exists(keys).await,
             BasicCommand::Expire { key, seconds } => self.handle_expire(key, seconds).await,
+            BasicCommand::PExpireAt { key, timestamp_ms } => {
+                self.handle_pexpireat(key, timestamp_ms).await
+            }
             BasicCommand::TTL { key } => self.handle_ttl(key).await,
             BasicCommand::Keys { pattern } => self.handle_keys(pattern).await,
             BasicCommand::Type { key } => self.handle_type(key).await,
+            BasicCommand::Dump { key } => self.handle_dump(key).await,
+            BasicCommand::Restore {
+                key,
+                ttl_ms,
+                payload,
+                options,
+            } => self.handle_restore(key, ttl_ms, payload, options).await,
         }
     }
 
     async fn handle_ping(&mut self, message: Option<String>) -> Result<BytesFrame> {
-        info!("cmd to ping with message: {:?}", message);
+        debug!("cmd to ping with message: {:?}", message);
         match message {
             Some(msg) => Ok(BytesFrame::SimpleString(msg.into())),
             None => Ok(BytesFrame::SimpleString("PONG".into())),
@@ -39,20 +59,35 @@ impl BasicCmdHandler {
     }
 
     async fn handle_echo(&mut self, message: String) -> Result<BytesFrame> {
-        info!("cmd to echo message: {}", message);
+        debug!("cmd to echo message: {}", message);
         Ok(BytesFrame::BulkString(message.into()))
     }
 
     async fn handle_del(&mut self, keys: Vec<String>) -> Result<BytesFrame> {
-        info!("cmd to del keys: {:?}", keys);
+        debug!("cmd to del keys: {:?}", keys);
         // Placeholder implementation
         let mut store = self.store.write().await;
         let deleted_count = store.delete(keys);
         Ok(BytesFrame::Integer(deleted_count as i64))
     }
 
+    async fn handle_unlink(&mut self, keys: Vec<String>) -> Result<BytesFrame> {
+        debug!("cmd to unlink keys: {:?}", keys);
+        let mut store = self.store.write().await;
+        let unlinked_count = store.unlink(keys);
+        Ok(BytesFrame::Integer(unlinked_count as i64))
+    }
+
+    // There is a single database, so FLUSHDB and FLUSHALL are the same
+    async fn handle_flush(&mut self, lazy: bool) -> Result<BytesFrame> {
+        debug!("cmd to flush all keys, lazy: {}", lazy);
+        let mut store = self.store.write().await;
+        store.flush(lazy);
+        Ok(BytesFrame::SimpleString("OK".into()))
+    }
+
     async fn handle_exists(&mut self, keys: Vec<String>) -> Result<BytesFrame> {
-        info!("cmd to check existence of keys: {:?}", keys);
+        debug!("cmd to check existence of keys: {:?}", keys);
         // Placeholder implementation
         let mut store = self.store.write().await;
         let exists_count = store.exists(keys);
@@ -60,7 +95,7 @@ impl BasicCmdHandler {
     }
 
     async fn handle_expire(&mut self, key: String, seconds: u64) -> Result<BytesFrame> {
-        info!(
+        debug!(
             "cmd to set expire for key: {} with seconds: {}",
             key, seconds
         );
@@ -72,8 +107,79 @@ impl BasicCmdHandler {
         }
     }
 
+    async fn handle_pexpireat(&mut self, key: String, timestamp_ms: u64) -> Result<BytesFrame> {
+        debug!("cmd to expire key: {} at unix ms: {}", key, timestamp_ms);
+        let ttl = Duration::from_millis(timestamp_ms.saturating_sub(unix_time_ms()));
+        let mut store = self.store.write().await;
+        Ok(BytesFrame::Integer(store.expire(&key, ttl) as i64))
+    }
+
+    // The payload is hex encoded, command arguments have to be valid UTF-8
+    async fn handle_dump(&mut self, key: String) -> Result<BytesFrame> {
+        debug!("cmd to dump key: {}", key);
+        let mut store = self.store.write().await;
+        match store
+            .get(&key)?
+            .as_ref()
+            .and_then(SnapshotValue::from_value)
+        {
+            Some(value) => Ok(BytesFrame::BulkString(
+                to_hex(&rdb::dump_payload(&value)).into(),
+            )),
+            None => Ok(BytesFrame::Null),
+        }
+    }
+
+    async fn handle_restore(
+        &mut self,
+        key: String,
+        ttl_ms: u64,
+        payload: String,
+        options: RestoreOptions,
+    ) -> Result<BytesFrame> {
+        debug!("cmd to restore key: {}", key);
+        let value = match from_hex(&payload)
+            .ok_or_else(|| anyhow!("DUMP payload version or checksum are wrong"))
+            .and_then(|payload| rdb::restore_payload(&payload))
+        {
+            Ok(value) => value,
+            Err(e) => return encode_error(&format!("ERR {}", e)),
+        };
+
+        let mut store = self.store.write().await;
+        if !options.replace && store.exists(vec![key.clone()]) > 0 {
+            return encode_error("BUSYKEY Target key name already exists.");
+        }
+        let ttl = match (ttl_ms, options.absttl) {
+            (0, _) => None,
+            (at, true) => Some(Duration::from_millis(at.saturating_sub(unix_time_ms()))),
+            (ttl, false) => Some(Duration::from_millis(ttl)),
+        };
+        // Already expired: nothing to create, though REPLACE still drops the old key
+        if ttl.is_some_and(|ttl| ttl.is_zero()) {
+            if options.replace {
+                store.delete(vec![key]);
+            }
+            return Ok(BytesFrame::SimpleString("OK".into()));
+        }
+
+        let value = match value.into_value(store.encoding_limits()) {
+            Ok(value) => value,
+            Err(e) => return encode_error(&format!("ERR {}", e)),
+        };
+        let mut entry = match ttl {
+            Some(ttl) => Entry::with_expiration(value, ttl),
+            None => Entry::new(value),
+        };
+        if let Some(idle) = options.idle_time {
+            entry.set_idle_time(Duration::from_secs(idle));
+        }
+        store.restore(key, entry);
+        Ok(BytesFrame::SimpleString("OK".into()))
+    }
+
     async fn handle_ttl(&mut self, key: String) -> Result<BytesFrame> {
-        info!("cmd to get ttl for key: {}", key);
+        debug!("cmd to get ttl for key: {}", key);
         let mut store = self.store.write().await;
         let ttl = store.ttl(&key);
         match ttl {
@@ -90,7 +196,7 @@ impl BasicCmdHandler {
     }
 
     async fn handle_keys(&mut self, pattern: String) -> Result<BytesFrame> {
-        info!("cmd to get keys with pattern: {}", pattern);
+        debug!("cmd to get keys with pattern: {}", pattern);
         let mut store = self.store.write().await;
         let keys = store.keys(&pattern);
         let frames: Vec<BytesFrame> = keys
@@ -101,9 +207,9 @@ impl BasicCmdHandler {
     }
 
     async fn handle_type(&mut self, key: String) -> Result<BytesFrame> {
-        info!("cmd to get type of key: {}", key);
+        debug!("cmd to get type of key: {}", key);
         let mut store = self.store.write().await;
-        let data_type = store.type_of(&key);
+        let data_type = store.type_of(&key)?;
         match data_type {
             Some(t) => Ok(BytesFrame::BulkString(t.into())),
             None => Ok(BytesFrame::BulkString("none".into())),
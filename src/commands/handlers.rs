@@ -1,16 +1,19 @@
 use super::Command;
 use crate::{
     commands::{
-        basic::BasicCmdHandler, hash::HashHandler, list::ListHandler, set::SetHandler,
+        BasicCommand, PubSubCommand, ServerCommand, basic::BasicCmdHandler, hash::HashHandler,
+        list::ListHandler, pubsub::PubSubHandler, server::ServerCmdHandler, set::SetHandler,
         sorted_set::SortedSetHandler, string::StringHandler,
     },
+    server::{client::Client, state::ServerState},
     storage::CacheStore,
 };
 use std::sync::Arc;
 
 use anyhow::{Result, anyhow};
 use redis_protocol::resp2::types::BytesFrame;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, mpsc::UnboundedSender};
+use tracing::trace;
 
 pub struct CmdHandler {
     pub string_handler: StringHandler,
@@ -19,10 +22,17 @@ pub struct CmdHandler {
     pub hash_handler: HashHandler,
     pub sorted_set_handler: SortedSetHandler,
     pub basic_handler: BasicCmdHandler,
+    pub server_handler: ServerCmdHandler,
+    pub pubsub_handler: PubSubHandler,
 }
 
 impl CmdHandler {
-    pub fn new(store: Arc<RwLock<CacheStore>>) -> Self {
+    pub fn new(
+        store: Arc<RwLock<CacheStore>>,
+        state: Arc<ServerState>,
+        client: Arc<Client>,
+        push: UnboundedSender<BytesFrame>,
+    ) -> Self {
         Self {
             string_handler: StringHandler::new(store.clone()),
             list_handler: ListHandler::new(store.clone()),
@@ -30,20 +40,55 @@ impl CmdHandler {
             hash_handler: HashHandler::new(store.clone()),
             sorted_set_handler: SortedSetHandler::new(store.clone()),
             basic_handler: BasicCmdHandler::new(store.clone()),
+            server_handler: ServerCmdHandler::new(store.clone(), state.clone(), client.clone()),
+            pubsub_handler: PubSubHandler::new(state, client, push),
         }
     }
 
-    pub async fn handle_cmd(&mut self, cmd: Command) -> Result<BytesFrame> {
-        println!("[CmdHandler] handle_cmd cmd: {:?}", cmd);
+    // Run a command and return its reply, or None when the replies were queued on
+    // the connection's push channel instead
+    pub async fn handle_cmd(&mut self, cmd: Command) -> Result<Option<BytesFrame>> {
+        trace!("[CmdHandler] handle_cmd cmd: {:?}", cmd);
 
-        match cmd {
+        if self.pubsub_handler.subscriptions() > 0 && !allowed_in_subscribed_mode(&cmd) {
+            return Err(anyhow!(
+                "only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context"
+            ));
+        }
+
+        let reply = match cmd {
             Command::String(s_cmd) => self.string_handler.handle_cmd(s_cmd).await,
             Command::List(l_cmd) => self.list_handler.handle_cmd(l_cmd).await,
             Command::Set(set_cmd) => self.set_handler.handle_cmd(set_cmd).await,
             Command::Hash(hash_cmd) => self.hash_handler.handle_cmd(hash_cmd).await,
             Command::SortedSet(ss_cmd) => self.sorted_set_handler.handle_cmd(ss_cmd).await,
             Command::Basic(b_cmd) => self.basic_handler.handle_cmd(b_cmd).await,
+            Command::Server(srv_cmd) => {
+                // Replicas don't read replies to their REPLCONF ACK
+                let ack = matches!(&srv_cmd, ServerCommand::ReplConf { options }
+                    if options.iter().any(|(option, _)| option == "ack"));
+                let reply = self.server_handler.handle_cmd(srv_cmd).await;
+                if ack {
+                    return reply.map(|_| None);
+                }
+                reply
+            }
+            Command::PubSub(ps_cmd) => return self.pubsub_handler.handle_cmd(ps_cmd).await,
             _ => Err(anyhow!("unknown command")),
-        }
+        };
+        reply.map(Some)
     }
 }
+
+fn allowed_in_subscribed_mode(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::Basic(BasicCommand::Ping { .. })
+            | Command::PubSub(
+                PubSubCommand::Subscribe { .. }
+                    | PubSubCommand::Unsubscribe { .. }
+                    | PubSubCommand::PSubscribe { .. }
+                    | PubSubCommand::PUnsubscribe { .. }
+            )
+    )
+}
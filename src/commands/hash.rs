@@ -1,16 +1,13 @@
 use crate::{
     commands::HashCommand,
-    protocol::encode::{encode_error, encode_integer, encode_nil, encode_value},
-    storage::{
-        CacheStore, HashEncoding, HashValue, ListEncoding, ListValue, StringEncoding, StringValue,
-        Value,
-    },
+    protocol::encode::{encode_array, encode_error, encode_integer, encode_nil, encode_value},
+    storage::{CacheStore, StringValue, Value},
 };
 use anyhow::Result;
 use redis_protocol::resp2::types::BytesFrame;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{debug, trace};
 
 pub struct HashHandler {
     pub store: Arc<RwLock<CacheStore>>,
@@ -22,7 +19,7 @@ impl HashHandler {
     }
 
     pub async fn handle_cmd(&mut self, cmd: HashCommand) -> Result<BytesFrame> {
-        info!("[HashHandler] handle_cmd cmd: {:?}", cmd);
+        trace!("[HashHandler] handle_cmd cmd: {:?}", cmd);
 
         match cmd {
             HashCommand::HSet { key, pairs } => self.handle_hset(key, pairs).await,
@@ -44,31 +41,28 @@ impl HashHandler {
         key: String,
         pairs: Vec<(String, String)>,
     ) -> Result<BytesFrame> {
-        info!("cmd to hset pairs {:?} to hash: {}", pairs, key);
+        debug!("cmd to hset pairs {:?} to hash: {}", pairs, key);
         let mut store = self.store.write().await;
 
-        let added_count = store.hset(&key, pairs);
+        let added_count = store.hset(&key, pairs)?;
         encode_integer(added_count as i64)
     }
 
     async fn handle_hget(&mut self, key: String, field: String) -> Result<BytesFrame> {
-        info!("cmd to hget field {} from hash: {}", field, key);
+        debug!("cmd to hget field {} from hash: {}", field, key);
         let mut store = self.store.write().await;
 
-        if let Some(value) = store.hget(&key, &field) {
-            encode_value(Value::String(StringValue {
-                data: value,
-                encoding: StringEncoding::Raw,
-            }))
+        if let Some(value) = store.hget(&key, &field)? {
+            encode_value(Value::String(StringValue::new(value)))
         } else {
             encode_nil()
         }
     }
 
     async fn handle_hdel(&mut self, key: String, fields: Vec<String>) -> Result<BytesFrame> {
-        info!("cmd to hdel fields {:?} from hash: {}", fields, key);
+        debug!("cmd to hdel fields {:?} from hash: {}", fields, key);
         let mut store = self.store.write().await;
-        let deleted_count = store.hdel(&key, &fields);
+        let deleted_count = store.hdel(&key, &fields)?;
         encode_integer(deleted_count as i64)
     }
 
@@ -77,49 +71,47 @@ impl HashHandler {
         key: String,
         pairs: Vec<(String, String)>,
     ) -> Result<BytesFrame> {
-        info!("cmd to hmset pairs {:?} to hash: {}", pairs, key);
+        debug!("cmd to hmset pairs {:?} to hash: {}", pairs, key);
         let mut store = self.store.write().await;
 
-        let added_count = store.hmset(&key, &pairs);
+        let added_count = store.hmset(&key, &pairs)?;
         encode_integer(added_count as i64)
     }
 
     async fn handle_hmget(&mut self, key: String, fields: Vec<String>) -> Result<BytesFrame> {
-        info!("cmd to hmget fields {:?} from hash: {}", fields, key);
+        debug!("cmd to hmget fields {:?} from hash: {}", fields, key);
         let mut store = self.store.write().await;
 
-        let values = store.hmget(&key, &fields);
+        let values = store.hmget(&key, &fields)?;
         match values {
             None => return encode_nil(),
-            Some(v) => encode_value(Value::List(ListValue {
-                elements: v
-                    .into_iter()
+            Some(v) => encode_array(
+                v.into_iter()
                     .map(|v| v.unwrap_or_else(|| b"(nil)".to_vec()))
                     .collect(),
-                encoding: ListEncoding::Quicklist,
-            })),
+            ),
         }
     }
 
     async fn handle_hexists(&mut self, key: String, field: String) -> Result<BytesFrame> {
-        info!("cmd to hexists field {} in hash: {}", field, key);
+        debug!("cmd to hexists field {} in hash: {}", field, key);
         let mut store = self.store.write().await;
-        let exists = store.hexists(&key, &field);
+        let exists = store.hexists(&key, &field)?;
         encode_integer(if exists { 1 } else { 0 })
     }
 
     async fn handle_hlen(&mut self, key: String) -> Result<BytesFrame> {
-        info!("cmd to get length of hash: {}", key);
+        debug!("cmd to get length of hash: {}", key);
         let mut store = self.store.write().await;
-        let hash_length = store.hlen(&key);
+        let hash_length = store.hlen(&key)?;
         encode_integer(hash_length as i64)
     }
 
     async fn handle_hkeys(&mut self, key: &str) -> Result<BytesFrame> {
-        info!("cmd to get keys of hash: {}", key);
+        debug!("cmd to get keys of hash: {}", key);
         let mut store = self.store.write().await;
 
-        if let Some(keys) = store.hkeys(&key) {
+        if let Some(keys) = store.hkeys(&key)? {
             let key_objs: Vec<BytesFrame> = keys
                 .into_iter()
                 .map(|k| BytesFrame::BulkString(k.into()))
@@ -131,10 +123,10 @@ impl HashHandler {
     }
 
     async fn handle_hvals(&mut self, key: &str) -> Result<BytesFrame> {
-        info!("cmd to get values of hash: {}", key);
+        debug!("cmd to get values of hash: {}", key);
         let mut store = self.store.write().await;
 
-        if let Some(values) = store.hvals(&key) {
+        if let Some(values) = store.hvals(&key)? {
             let value_objs: Vec<BytesFrame> = values
                 .into_iter()
                 .map(|v| BytesFrame::BulkString(v.into()))
@@ -146,10 +138,10 @@ impl HashHandler {
     }
 
     async fn handle_hgetall(&mut self, key: &str) -> Result<BytesFrame> {
-        info!("cmd to get all key-value pairs of hash: {}", key);
+        debug!("cmd to get all key-value pairs of hash: {}", key);
         let mut store = self.store.write().await;
 
-        if let Some(hash) = store.hgetall(&key) {
+        if let Some(hash) = store.hgetall(&key)? {
             let mut arr = Vec::with_capacity(hash.len() * 2);
             for (k, v) in hash {
                 arr.push(BytesFrame::BulkString(k.into()));
@@ -1,12 +1,13 @@
 use crate::{
     commands::ListCommand,
-    protocol::encode::{encode_error, encode_integer, encode_value},
-    storage::{CacheStore, ListEncoding, ListValue, Value},
+    protocol::encode::{encode_array, encode_error, encode_integer, encode_nil},
+    storage::CacheStore,
 };
 use anyhow::Result;
 use redis_protocol::resp2::types::BytesFrame;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::{debug, trace};
 
 pub struct ListHandler {
     pub store: Arc<RwLock<CacheStore>>,
@@ -18,7 +19,7 @@ impl ListHandler {
     }
 
     pub async fn handle_cmd(&mut self, cmd: ListCommand) -> Result<BytesFrame> {
-        println!("[ListHandler] handle_cmd cmd: {:?}", cmd);
+        trace!("[ListHandler] handle_cmd cmd: {:?}", cmd);
 
         match cmd {
             ListCommand::LPush { key, values } => self.handle_lpush(key, values).await,
@@ -26,62 +27,57 @@ impl ListHandler {
             ListCommand::LPop { key, count } => self.handle_lpop(key, count).await,
             ListCommand::RPop { key, count } => self.handle_rpop(key, count).await,
             ListCommand::LLen { key } => self.handle_llen(key).await,
+            ListCommand::LIndex { key, index } => self.handle_lindex(key, index).await,
             ListCommand::LRange { key, start, stop } => self.handle_lrange(key, start, stop).await,
             _ => encode_error("unknown command"),
         }
     }
 
     async fn handle_lpush(&mut self, key: String, values: Vec<String>) -> Result<BytesFrame> {
-        println!("cmd to lpush values {:?} to list: {}", values, key);
+        debug!("cmd to lpush values {:?} to list: {}", values, key);
         let mut store = self.store.write().await;
 
-        let list_size = store.lpush(&key, values);
+        let list_size = store.lpush(&key, values)?;
         encode_integer(list_size as i64)
     }
 
     async fn handle_rpush(&mut self, key: String, values: Vec<String>) -> Result<BytesFrame> {
-        println!("cmd to rpush values {:?} to list: {}", values, key);
+        debug!("cmd to rpush values {:?} to list: {}", values, key);
         let mut store = self.store.write().await;
 
-        let list_size = store.rpush(&key, values);
+        let list_size = store.rpush(&key, values)?;
         encode_integer(list_size as i64)
     }
 
     async fn handle_lpop(&mut self, key: String, count: Option<u64>) -> Result<BytesFrame> {
-        println!("cmd to lpop from list: {}, count: {:?}", key, count);
+        debug!("cmd to lpop from list: {}, count: {:?}", key, count);
         let mut store = self.store.write().await;
 
-        let popped_values = store.lpop(&key, count.unwrap_or(1));
+        let popped_values = store.lpop(&key, count.unwrap_or(1))?;
         if popped_values.is_none() {
             encode_error("key not found or list is empty")
         } else {
-            encode_value(Value::List(ListValue {
-                elements: popped_values.unwrap(),
-                encoding: ListEncoding::Quicklist,
-            }))
+            encode_array(popped_values.unwrap())
         }
     }
 
     async fn handle_rpop(&mut self, key: String, count: Option<u64>) -> Result<BytesFrame> {
-        println!("cmd to rpop from list: {}, count: {:?}", key, count);
+        debug!("cmd to rpop from list: {}, count: {:?}", key, count);
         let mut store = self.store.write().await;
 
-        let popped_values = store.rpop(&key, count.unwrap_or(1));
+        let popped_values = store.rpop(&key, count.unwrap_or(1))?;
         if popped_values.is_none() {
             encode_error("key not found or list is empty")
         } else {
-            encode_value(Value::List(ListValue {
-                elements: popped_values.unwrap(),
-                encoding: ListEncoding::Quicklist,
-            }))
+            encode_array(popped_values.unwrap())
         }
     }
 
     async fn handle_llen(&mut self, key: String) -> Result<BytesFrame> {
-        println!("cmd to get length of list: {}", key);
+        debug!("cmd to get length of list: {}", key);
         let mut store = self.store.write().await;
 
-        let list_length = store.llen(&key);
+        let list_length = store.llen(&key)?;
         if list_length.is_none() {
             encode_integer(0 as i64)
         } else {
@@ -89,21 +85,28 @@ impl ListHandler {
         }
     }
 
+    async fn handle_lindex(&mut self, key: String, index: i64) -> Result<BytesFrame> {
+        debug!("cmd to get index {} of list: {}", index, key);
+        let mut store = self.store.write().await;
+
+        match store.lindex(&key, index)? {
+            Some(value) => Ok(BytesFrame::BulkString(value.into())),
+            None => encode_nil(),
+        }
+    }
+
     async fn handle_lrange(&mut self, key: String, start: i64, stop: i64) -> Result<BytesFrame> {
-        println!(
+        debug!(
             "cmd to lrange from list: {}, start: {}, stop: {}",
             key, start, stop
         );
         let mut store = self.store.write().await;
 
-        let range_values = store.lrange(&key, start, stop);
+        let range_values = store.lrange(&key, start, stop)?;
         if range_values.is_none() {
             encode_error("key not found or list is empty")
         } else {
-            encode_value(Value::List(ListValue {
-                elements: range_values.unwrap(),
-                encoding: ListEncoding::Quicklist,
-            }))
+            encode_array(range_values.unwrap())
         }
     }
 }
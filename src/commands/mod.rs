@@ -2,6 +2,8 @@ pub mod basic;
 pub mod handlers;
 pub mod hash;
 pub mod list;
+pub mod pubsub;
+pub mod server;
 pub mod set;
 pub mod sorted_set;
 pub mod string;
@@ -18,20 +20,177 @@ pub enum Command {
     // Basic server operations
     Basic(BasicCommand),
 
+    // Server introspection and administration
+    Server(ServerCommand),
+
+    // Publish/subscribe messaging
+    PubSub(PubSubCommand),
+
     // Unknown command fallback
     Unknown { command: String, args: Vec<String> },
 }
 
+impl Command {
+    // Whether the command may modify the keyspace, and so has to be persisted
+    pub fn is_write(&self) -> bool {
+        match self {
+            Command::String(cmd) => !matches!(
+                cmd,
+                StringCommand::Get { .. }
+                    | StringCommand::MGet { .. }
+                    | StringCommand::Strlen { .. }
+                    | StringCommand::GetRange { .. }
+            ),
+            Command::List(cmd) => !matches!(
+                cmd,
+                ListCommand::LLen { .. } | ListCommand::LIndex { .. } | ListCommand::LRange { .. }
+            ),
+            Command::Set(cmd) => !matches!(
+                cmd,
+                SetCommand::SCard { .. }
+                    | SetCommand::SDiff { .. }
+                    | SetCommand::SInter { .. }
+                    | SetCommand::SIsMember { .. }
+                    | SetCommand::SMIsMember { .. }
+                    | SetCommand::SMembers { .. }
+                    | SetCommand::SRandMember { .. }
+                    | SetCommand::SUnion { .. }
+            ),
+            Command::SortedSet(cmd) => matches!(
+                cmd,
+                SortedSetCommand::ZAdd { .. }
+                    | SortedSetCommand::ZIncrBy { .. }
+                    | SortedSetCommand::ZInterStore { .. }
+                    | SortedSetCommand::ZPopMax { .. }
+                    | SortedSetCommand::ZPopMin { .. }
+                    | SortedSetCommand::ZRem { .. }
+                    | SortedSetCommand::ZRemRangeByLex { .. }
+                    | SortedSetCommand::ZRemRangeByRank { .. }
+                    | SortedSetCommand::ZRemRangeByScore { .. }
+                    | SortedSetCommand::ZUnionStore { .. }
+            ),
+            Command::Hash(cmd) => matches!(
+                cmd,
+                HashCommand::HDel { .. }
+                    | HashCommand::HIncrBy { .. }
+                    | HashCommand::HIncrByFloat { .. }
+                    | HashCommand::HMSet { .. }
+                    | HashCommand::HSet { .. }
+                    | HashCommand::HSetNx { .. }
+            ),
+            Command::Basic(cmd) => matches!(
+                cmd,
+                BasicCommand::Expire { .. }
+                    | BasicCommand::PExpireAt { .. }
+                    | BasicCommand::Del { .. }
+                    | BasicCommand::Unlink { .. }
+                    | BasicCommand::FlushDb { .. }
+                    | BasicCommand::FlushAll { .. }
+                    | BasicCommand::Restore { .. }
+            ),
+            Command::Server(_) | Command::PubSub(_) | Command::Unknown { .. } => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum BasicCommand {
-    Ping { message: Option<String> },
-    Expire { key: String, seconds: u64 },
-    TTL { key: String },
-    Echo { message: String },
-    Del { keys: Vec<String> },
-    Exists { keys: Vec<String> },
-    Keys { pattern: String },
-    Type { key: String },
+    Ping {
+        message: Option<String>,
+    },
+    Expire {
+        key: String,
+        seconds: u64,
+    },
+    PExpireAt {
+        key: String,
+        timestamp_ms: u64,
+    },
+    TTL {
+        key: String,
+    },
+    Echo {
+        message: String,
+    },
+    Del {
+        keys: Vec<String>,
+    },
+    Unlink {
+        keys: Vec<String>,
+    },
+    FlushDb {
+        lazy: bool,
+    },
+    FlushAll {
+        lazy: bool,
+    },
+    Exists {
+        keys: Vec<String>,
+    },
+    Keys {
+        pattern: String,
+    },
+    Type {
+        key: String,
+    },
+    Dump {
+        key: String,
+    },
+    Restore {
+        key: String,
+        ttl_ms: u64,
+        payload: String,
+        options: RestoreOptions,
+    },
+}
+
+// ========== Server Commands ==========
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerCommand {
+    ObjectEncoding { key: String },
+    ObjectIdleTime { key: String },
+    ObjectRefCount { key: String },
+    ObjectHelp,
+    MemoryUsage { key: String, samples: Option<u64> },
+    MemoryStats,
+    MemoryHelp,
+    SlowlogGet { count: Option<i64> },
+    SlowlogLen,
+    SlowlogReset,
+    SlowlogHelp,
+    LatencyLatest,
+    LatencyHistory { event: String },
+    LatencyReset { events: Vec<String> },
+    LatencyHistogram { commands: Vec<String> },
+    LatencyHelp,
+    ClientId,
+    ClientSetName { name: String },
+    ClientGetName,
+    Monitor,
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    DebugReload,
+    Info { sections: Vec<String> },
+    // None for REPLICAOF NO ONE
+    ReplicaOf { master: Option<(String, u16)> },
+    // Option and value pairs
+    ReplConf { options: Vec<(String, String)> },
+    PSync { replid: String, offset: i64 },
+    Role,
+    // Timeout in milliseconds, 0 to wait forever
+    Wait { numreplicas: i64, timeout: i64 },
+}
+
+// ========== Pub/Sub Commands ==========
+#[derive(Debug, Clone, PartialEq)]
+pub enum PubSubCommand {
+    Subscribe { channels: Vec<String> },
+    Unsubscribe { channels: Vec<String> },
+    PSubscribe { patterns: Vec<String> },
+    PUnsubscribe { patterns: Vec<String> },
+    Publish { channel: String, message: String },
 }
 
 // ========== String Commands ==========
@@ -461,6 +620,15 @@ pub enum SetCondition {
     Xx, // Only if key exists
 }
 
+// ========== Restore Options ==========
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RestoreOptions {
+    pub replace: bool,
+    // The TTL is a unix timestamp in milliseconds
+    pub absttl: bool,
+    pub idle_time: Option<u64>, // seconds
+}
+
 // ========== List Options ==========
 #[derive(Debug, Clone, PartialEq)]
 pub enum ListPosition {
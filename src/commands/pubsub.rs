@@ -0,0 +1,151 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+use bytes::Bytes;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, trace};
+
+use crate::{
+    commands::PubSubCommand,
+    server::{client::Client, state::ServerState},
+};
+
+// Subscriptions of one connection. Messages and subscription replies are queued on
+// `push`, which the connection writes out in order.
+pub struct PubSubHandler {
+    pub state: Arc<ServerState>,
+    pub client: Arc<Client>,
+    push: UnboundedSender<BytesFrame>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+}
+
+impl PubSubHandler {
+    pub fn new(
+        state: Arc<ServerState>,
+        client: Arc<Client>,
+        push: UnboundedSender<BytesFrame>,
+    ) -> Self {
+        Self {
+            state,
+            client,
+            push,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+        }
+    }
+
+    // Number of channels and patterns the client is subscribed to
+    pub fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    // (P)(UN)SUBSCRIBE reply once per channel through `push`, so each confirmation
+    // is written before any message of its channel. Only PUBLISH returns a reply.
+    pub async fn handle_cmd(&mut self, cmd: PubSubCommand) -> Result<Option<BytesFrame>> {
+        trace!("[PubSubHandler] handle_cmd cmd: {:?}", cmd);
+
+        match cmd {
+            PubSubCommand::Subscribe { channels } => self.handle_subscribe(channels),
+            PubSubCommand::Unsubscribe { channels } => self.handle_unsubscribe(channels),
+            PubSubCommand::PSubscribe { patterns } => self.handle_psubscribe(patterns),
+            PubSubCommand::PUnsubscribe { patterns } => self.handle_punsubscribe(patterns),
+            PubSubCommand::Publish { channel, message } => {
+                return self.handle_publish(channel, message).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn handle_subscribe(&mut self, channels: Vec<String>) {
+        debug!("cmd to subscribe to channels: {:?}", channels);
+        for channel in channels {
+            let added = self.channels.insert(channel.clone());
+            self.reply("subscribe", Some(&channel));
+            if added {
+                let pubsub = &self.state.pubsub;
+                pubsub.subscribe(&channel, self.client.id, self.push.clone());
+            }
+        }
+    }
+
+    fn handle_unsubscribe(&mut self, channels: Vec<String>) {
+        debug!("cmd to unsubscribe from channels: {:?}", channels);
+        let channels = if channels.is_empty() {
+            self.channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            self.reply("unsubscribe", None);
+        }
+        for channel in channels {
+            if self.channels.remove(&channel) {
+                self.state.pubsub.unsubscribe(&channel, self.client.id);
+            }
+            self.reply("unsubscribe", Some(&channel));
+        }
+    }
+
+    fn handle_psubscribe(&mut self, patterns: Vec<String>) {
+        debug!("cmd to subscribe to patterns: {:?}", patterns);
+        for pattern in patterns {
+            let added = self.patterns.insert(pattern.clone());
+            self.reply("psubscribe", Some(&pattern));
+            if added {
+                let pubsub = &self.state.pubsub;
+                pubsub.psubscribe(&pattern, self.client.id, self.push.clone());
+            }
+        }
+    }
+
+    fn handle_punsubscribe(&mut self, patterns: Vec<String>) {
+        debug!("cmd to unsubscribe from patterns: {:?}", patterns);
+        let patterns = if patterns.is_empty() {
+            self.patterns.iter().cloned().collect()
+        } else {
+            patterns
+        };
+        if patterns.is_empty() {
+            self.reply("punsubscribe", None);
+        }
+        for pattern in patterns {
+            if self.patterns.remove(&pattern) {
+                self.state.pubsub.punsubscribe(&pattern, self.client.id);
+            }
+            self.reply("punsubscribe", Some(&pattern));
+        }
+    }
+
+    fn handle_publish(&mut self, channel: String, message: String) -> Result<BytesFrame> {
+        debug!("cmd to publish to channel: {}", channel);
+        let receivers = self.state.pubsub.publish(&channel, Bytes::from(message));
+        Ok(BytesFrame::Integer(receivers as i64))
+    }
+
+    // Queue a `[kind, channel, subscription count]` confirmation
+    fn reply(&self, kind: &'static str, channel: Option<&str>) {
+        let channel = match channel {
+            Some(channel) => BytesFrame::BulkString(channel.to_string().into()),
+            None => BytesFrame::Null,
+        };
+        let _ = self.push.send(BytesFrame::Array(vec![
+            BytesFrame::BulkString(kind.into()),
+            channel,
+            BytesFrame::Integer(self.subscriptions() as i64),
+        ]));
+    }
+}
+
+impl Drop for PubSubHandler {
+    // Drop the subscriptions when the connection goes away
+    fn drop(&mut self) {
+        for channel in &self.channels {
+            self.state.pubsub.unsubscribe(channel, self.client.id);
+        }
+        for pattern in &self.patterns {
+            self.state.pubsub.punsubscribe(pattern, self.client.id);
+        }
+    }
+}
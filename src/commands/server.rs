@@ -0,0 +1,712 @@
+use anyhow::Result;
+use redis_protocol::resp2::types::BytesFrame;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    commands::ServerCommand,
+    persistence::{aof, snapshot},
+    protocol::encode::{encode_error, encode_integer, encode_nil},
+    server::{
+        client::Client,
+        latency::EVENT_RDB_SAVE,
+        replication::{self, LinkStatus, Psync},
+        state::ServerState,
+    },
+    storage::CacheStore,
+};
+
+// Number of nested elements MEMORY USAGE samples when no SAMPLES option is given
+const DEFAULT_MEMORY_SAMPLES: u64 = 5;
+
+const OBJECT_HELP: &[&str] = &[
+    "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "ENCODING <key>",
+    "    Return the kind of internal representation used in order to store the value",
+    "    associated with a <key>.",
+    "IDLETIME <key>",
+    "    Return the idle time of the <key>, that is the approximated number of",
+    "    seconds elapsed since the last access to the key.",
+    "REFCOUNT <key>",
+    "    Return the number of references of the value associated with the specified",
+    "    <key>.",
+    "HELP",
+    "    Print this help.",
+];
+
+const MEMORY_HELP: &[&str] = &[
+    "MEMORY <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "STATS",
+    "    Return information about the memory usage of the server.",
+    "USAGE <key> [SAMPLES <count>]",
+    "    Return memory in bytes used by <key> and its value. Nested values are",
+    "    sampled up to <count> times (default: 5, 0 means sample all).",
+    "HELP",
+    "    Print this help.",
+];
+
+const SLOWLOG_HELP: &[&str] = &[
+    "SLOWLOG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "GET [<count>]",
+    "    Return top <count> entries from the slowlog (default: 10, -1 mean all).",
+    "    Entries are made of:",
+    "    id, timestamp, time in microseconds, arguments array, client IP and port,",
+    "    client name",
+    "LEN",
+    "    Return the length of the slowlog.",
+    "RESET",
+    "    Reset the slowlog.",
+    "HELP",
+    "    Print this help.",
+];
+
+const LATENCY_HELP: &[&str] = &[
+    "LATENCY <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "LATEST",
+    "    Return the latest latency samples for all events.",
+    "HISTORY <event>",
+    "    Return time-latency samples for the <event> class.",
+    "RESET [<event> ...]",
+    "    Reset latency data of one or more <event> classes.",
+    "    (default: reset all data for all event classes)",
+    "HISTOGRAM [<command> ...]",
+    "    Return a cumulative distribution of latencies in the format of a histogram",
+    "    for the specified command names. If no commands are specified then all",
+    "    histograms are replied.",
+    "HELP",
+    "    Print this help.",
+];
+
+// Entries SLOWLOG GET returns when no count is given
+const DEFAULT_SLOWLOG_GET_COUNT: usize = 10;
+
+pub struct ServerCmdHandler {
+    pub store: Arc<RwLock<CacheStore>>,
+    pub state: Arc<ServerState>,
+    pub client: Arc<Client>,
+}
+
+impl ServerCmdHandler {
+    pub fn new(
+        store: Arc<RwLock<CacheStore>>,
+        state: Arc<ServerState>,
+        client: Arc<Client>,
+    ) -> Self {
+        Self {
+            store,
+            state,
+            client,
+        }
+    }
+
+    pub async fn handle_cmd(&mut self, cmd: ServerCommand) -> Result<BytesFrame> {
+        trace!("[ServerCmdHandler] handle_cmd cmd: {:?}", cmd);
+
+        match cmd {
+            ServerCommand::ObjectEncoding { key } => self.handle_object_encoding(key).await,
+            ServerCommand::ObjectIdleTime { key } => self.handle_object_idletime(key).await,
+            ServerCommand::ObjectRefCount { key } => self.handle_object_refcount(key).await,
+            ServerCommand::ObjectHelp => encode_help(OBJECT_HELP),
+            ServerCommand::MemoryUsage { key, samples } => {
+                self.handle_memory_usage(key, samples).await
+            }
+            ServerCommand::MemoryStats => self.handle_memory_stats().await,
+            ServerCommand::MemoryHelp => encode_help(MEMORY_HELP),
+            ServerCommand::SlowlogGet { count } => self.handle_slowlog_get(count),
+            ServerCommand::SlowlogLen => self.handle_slowlog_len(),
+            ServerCommand::SlowlogReset => self.handle_slowlog_reset(),
+            ServerCommand::SlowlogHelp => encode_help(SLOWLOG_HELP),
+            ServerCommand::LatencyLatest => self.handle_latency_latest(),
+            ServerCommand::LatencyHistory { event } => self.handle_latency_history(event),
+            ServerCommand::LatencyReset { events } => self.handle_latency_reset(events),
+            ServerCommand::LatencyHistogram { commands } => self.handle_latency_histogram(commands),
+            ServerCommand::LatencyHelp => encode_help(LATENCY_HELP),
+            ServerCommand::ClientId => encode_integer(self.client.id as i64),
+            ServerCommand::ClientSetName { name } => self.handle_client_setname(name),
+            ServerCommand::ClientGetName => self.handle_client_getname(),
+            ServerCommand::Monitor => self.handle_monitor(),
+            ServerCommand::Save => self.handle_save().await,
+            ServerCommand::BgSave => self.handle_bgsave().await,
+            ServerCommand::BgRewriteAof => self.handle_bgrewriteaof().await,
+            ServerCommand::DebugReload => self.handle_debug_reload().await,
+            ServerCommand::LastSave => encode_integer(self.state.persistence.last_save() as i64),
+            ServerCommand::Info { sections } => self.handle_info(sections).await,
+            ServerCommand::ReplicaOf { master } => self.handle_replicaof(master),
+            ServerCommand::ReplConf { options } => self.handle_replconf(options),
+            ServerCommand::PSync { replid, offset } => self.handle_psync(replid, offset).await,
+            ServerCommand::Role => self.handle_role(),
+            ServerCommand::Wait {
+                numreplicas,
+                timeout,
+            } => self.handle_wait(numreplicas, timeout).await,
+        }
+    }
+
+    async fn handle_object_encoding(&mut self, key: String) -> Result<BytesFrame> {
+        debug!("cmd to get encoding of key: {}", key);
+        let mut store = self.store.write().await;
+
+        match store.object_encoding(&key)? {
+            Some(encoding) => Ok(BytesFrame::BulkString(encoding.into())),
+            None => encode_nil(),
+        }
+    }
+
+    async fn handle_object_idletime(&mut self, key: String) -> Result<BytesFrame> {
+        debug!("cmd to get idle time of key: {}", key);
+        let mut store = self.store.write().await;
+
+        match store.object_idletime(&key)? {
+            Some(idle) => encode_integer(idle.as_secs() as i64),
+            None => encode_nil(),
+        }
+    }
+
+    async fn handle_object_refcount(&mut self, key: String) -> Result<BytesFrame> {
+        debug!("cmd to get refcount of key: {}", key);
+        let mut store = self.store.write().await;
+
+        match store.object_refcount(&key)? {
+            Some(refcount) => encode_integer(refcount),
+            None => encode_nil(),
+        }
+    }
+
+    async fn handle_memory_usage(
+        &mut self,
+        key: String,
+        samples: Option<u64>,
+    ) -> Result<BytesFrame> {
+        debug!(
+            "cmd to get memory usage of key: {}, samples: {:?}",
+            key, samples
+        );
+        let mut store = self.store.write().await;
+
+        let samples = samples.unwrap_or(DEFAULT_MEMORY_SAMPLES) as usize;
+        match store.memory_usage(&key, samples)? {
+            Some(bytes) => encode_integer(bytes as i64),
+            None => encode_nil(),
+        }
+    }
+
+    async fn handle_memory_stats(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to get memory stats");
+        let store = self.store.read().await;
+        let stats = store.memory_stats();
+
+        let total = stats.total_allocated();
+        let bytes_per_key = total.checked_div(stats.keys_count).unwrap_or(0);
+        let dataset_percentage = if total > 0 {
+            stats.dataset_bytes as f64 * 100.0 / total as f64
+        } else {
+            0.0
+        };
+
+        let db0 = BytesFrame::Array(vec![
+            BytesFrame::BulkString("overhead.hashtable.main".into()),
+            BytesFrame::Integer(stats.overhead_hashtable_main as i64),
+        ]);
+
+        Ok(BytesFrame::Array(vec![
+            BytesFrame::BulkString("total.allocated".into()),
+            BytesFrame::Integer(total as i64),
+            BytesFrame::BulkString("db.0".into()),
+            db0,
+            BytesFrame::BulkString("overhead.total".into()),
+            BytesFrame::Integer(stats.overhead_hashtable_main as i64),
+            BytesFrame::BulkString("keys.count".into()),
+            BytesFrame::Integer(stats.keys_count as i64),
+            BytesFrame::BulkString("keys.bytes-per-key".into()),
+            BytesFrame::Integer(bytes_per_key as i64),
+            BytesFrame::BulkString("dataset.bytes".into()),
+            BytesFrame::Integer(stats.dataset_bytes as i64),
+            BytesFrame::BulkString("dataset.percentage".into()),
+            BytesFrame::BulkString(format!("{:.2}", dataset_percentage).into()),
+        ]))
+    }
+
+    fn handle_slowlog_get(&mut self, count: Option<i64>) -> Result<BytesFrame> {
+        debug!("cmd to get slowlog entries, count: {:?}", count);
+        let count = match count {
+            Some(-1) => usize::MAX,
+            Some(n) => n as usize,
+            None => DEFAULT_SLOWLOG_GET_COUNT,
+        };
+
+        let slowlog = self.state.slowlog.lock().unwrap();
+        let entries = slowlog
+            .get(count)
+            .map(|entry| {
+                BytesFrame::Array(vec![
+                    BytesFrame::Integer(entry.id as i64),
+                    BytesFrame::Integer(entry.timestamp as i64),
+                    BytesFrame::Integer(entry.duration.as_micros() as i64),
+                    BytesFrame::Array(
+                        entry
+                            .args
+                            .iter()
+                            .map(|arg| BytesFrame::BulkString(arg.clone().into()))
+                            .collect(),
+                    ),
+                    BytesFrame::BulkString(entry.client_addr.clone().into()),
+                    BytesFrame::BulkString(entry.client_name.clone().into()),
+                ])
+            })
+            .collect();
+        Ok(BytesFrame::Array(entries))
+    }
+
+    fn handle_slowlog_len(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to get slowlog length");
+        encode_integer(self.state.slowlog.lock().unwrap().len() as i64)
+    }
+
+    fn handle_slowlog_reset(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to reset slowlog");
+        self.state.slowlog.lock().unwrap().reset();
+        Ok(BytesFrame::SimpleString("OK".into()))
+    }
+
+    fn handle_latency_latest(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to get latest latency samples");
+        let latency = self.state.latency.lock().unwrap();
+        let events = latency
+            .events()
+            .filter_map(|(event, series)| {
+                let latest = series.latest()?;
+                Some(BytesFrame::Array(vec![
+                    BytesFrame::BulkString(event.clone().into()),
+                    BytesFrame::Integer(latest.time as i64),
+                    BytesFrame::Integer(latest.latency as i64),
+                    BytesFrame::Integer(series.max as i64),
+                ]))
+            })
+            .collect();
+        Ok(BytesFrame::Array(events))
+    }
+
+    fn handle_latency_history(&mut self, event: String) -> Result<BytesFrame> {
+        debug!("cmd to get latency history of event: {}", event);
+        let latency = self.state.latency.lock().unwrap();
+        let samples = match latency.event(&event) {
+            Some(series) => series
+                .history()
+                .map(|sample| {
+                    BytesFrame::Array(vec![
+                        BytesFrame::Integer(sample.time as i64),
+                        BytesFrame::Integer(sample.latency as i64),
+                    ])
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(BytesFrame::Array(samples))
+    }
+
+    fn handle_latency_reset(&mut self, events: Vec<String>) -> Result<BytesFrame> {
+        debug!("cmd to reset latency events: {:?}", events);
+        encode_integer(self.state.latency.lock().unwrap().reset(&events) as i64)
+    }
+
+    fn handle_latency_histogram(&mut self, commands: Vec<String>) -> Result<BytesFrame> {
+        debug!("cmd to get latency histograms of commands: {:?}", commands);
+        let latency = self.state.latency.lock().unwrap();
+        let histograms: Vec<_> = if commands.is_empty() {
+            latency.histograms().collect()
+        } else {
+            commands
+                .iter()
+                .filter_map(|command| latency.histogram(command))
+                .collect()
+        };
+
+        let mut frames = Vec::with_capacity(histograms.len() * 2);
+        for (name, histogram) in histograms {
+            let buckets = histogram
+                .cumulative()
+                .into_iter()
+                .flat_map(|(usec, calls)| {
+                    [
+                        BytesFrame::Integer(usec as i64),
+                        BytesFrame::Integer(calls as i64),
+                    ]
+                })
+                .collect();
+            frames.push(BytesFrame::BulkString(name.into()));
+            frames.push(BytesFrame::Array(vec![
+                BytesFrame::BulkString("calls".into()),
+                BytesFrame::Integer(histogram.calls as i64),
+                BytesFrame::BulkString("histogram_usec".into()),
+                BytesFrame::Array(buckets),
+            ]));
+        }
+        Ok(BytesFrame::Array(frames))
+    }
+
+    fn handle_client_setname(&mut self, name: String) -> Result<BytesFrame> {
+        debug!("cmd to set client name: {}", name);
+        if name.chars().any(|c| !('!'..='~').contains(&c)) {
+            return encode_error(
+                "ERR Client names cannot contain spaces, newlines or special characters.",
+            );
+        }
+        self.client.set_name(name);
+        Ok(BytesFrame::SimpleString("OK".into()))
+    }
+
+    fn handle_monitor(&mut self) -> Result<BytesFrame> {
+        debug!(
+            "cmd to switch client {} into monitor mode",
+            self.client.addr
+        );
+        self.client.set_monitor();
+        Ok(BytesFrame::SimpleString("OK".into()))
+    }
+
+    // INFO [section ...]: "# Section" headers followed by `field:value` lines. No
+    // section, "all", "default" or "everything" selects every section.
+    async fn handle_info(&mut self, sections: Vec<String>) -> Result<BytesFrame> {
+        debug!("cmd to get server info, sections: {:?}", sections);
+        let all = sections.is_empty()
+            || sections
+                .iter()
+                .any(|s| matches!(s.as_str(), "all" | "default" | "everything"));
+        let wanted = |name: &str| all || sections.iter().any(|s| s == name);
+
+        let store = self.store.read().await;
+        let mut info = String::new();
+        if wanted("persistence") {
+            let persistence = &self.state.persistence;
+            info.push_str("# Persistence\r\n");
+            info.push_str(&format!(
+                "rdb_changes_since_last_save:{}\r\n",
+                persistence.changes_since_save(store.dirty())
+            ));
+            info.push_str(&format!(
+                "rdb_bgsave_in_progress:{}\r\n",
+                persistence.bgsave_in_progress() as u8
+            ));
+            info.push_str(&format!(
+                "rdb_last_save_time:{}\r\n",
+                persistence.last_save()
+            ));
+            info.push_str(&format!(
+                "rdb_last_bgsave_status:{}\r\n",
+                if persistence.last_bgsave_ok() {
+                    "ok"
+                } else {
+                    "err"
+                }
+            ));
+            info.push_str(&format!("aof_enabled:{}\r\n", persistence.appendonly as u8));
+            info.push_str(&format!(
+                "aof_rewrite_in_progress:{}\r\n",
+                persistence.aof_rewrite_in_progress() as u8
+            ));
+        }
+        if wanted("tiered") {
+            let stats = store.tier_stats();
+            let lookups = stats.memory_hits + stats.disk_hits + stats.misses;
+            let rate = |hits: u64| {
+                if lookups > 0 {
+                    hits as f64 * 100.0 / lookups as f64
+                } else {
+                    0.0
+                }
+            };
+            info.push_str("\r\n# Tiered\r\n");
+            info.push_str(&format!(
+                "tiered_storage_enabled:{}\r\n",
+                (stats.memory_limit > 0) as u8
+            ));
+            info.push_str(&format!("tiered_memory_limit:{}\r\n", stats.memory_limit));
+            info.push_str(&format!("tiered_keys_on_disk:{}\r\n", stats.keys_on_disk));
+            info.push_str(&format!("tiered_log_bytes:{}\r\n", stats.log_bytes));
+            info.push_str(&format!("tiered_live_bytes:{}\r\n", stats.live_bytes));
+            info.push_str(&format!("tiered_spilled_values:{}\r\n", stats.spilled));
+            info.push_str(&format!("tiered_memory_hits:{}\r\n", stats.memory_hits));
+            info.push_str(&format!("tiered_disk_hits:{}\r\n", stats.disk_hits));
+            info.push_str(&format!("tiered_misses:{}\r\n", stats.misses));
+            info.push_str(&format!(
+                "tiered_memory_hit_rate:{:.2}\r\n",
+                rate(stats.memory_hits)
+            ));
+            info.push_str(&format!(
+                "tiered_disk_hit_rate:{:.2}\r\n",
+                rate(stats.disk_hits)
+            ));
+        }
+        if wanted("replication") {
+            let repl = self.state.replication.info();
+            info.push_str("\r\n# Replication\r\n");
+            match &repl.master {
+                Some(master) => {
+                    let up = master.status == LinkStatus::Connected;
+                    info.push_str("role:slave\r\n");
+                    info.push_str(&format!("master_host:{}\r\n", master.host));
+                    info.push_str(&format!("master_port:{}\r\n", master.port));
+                    info.push_str(&format!(
+                        "master_link_status:{}\r\n",
+                        if up { "up" } else { "down" }
+                    ));
+                    info.push_str(&format!(
+                        "master_last_io_seconds_ago:{}\r\n",
+                        if up { master.last_io_secs as i64 } else { -1 }
+                    ));
+                    info.push_str(&format!(
+                        "master_sync_in_progress:{}\r\n",
+                        (master.status == LinkStatus::Sync) as u8
+                    ));
+                    info.push_str(&format!("slave_repl_offset:{}\r\n", repl.offset));
+                    info.push_str(&format!(
+                        "slave_read_only:{}\r\n",
+                        self.state.replication.options.read_only as u8
+                    ));
+                }
+                None => info.push_str("role:master\r\n"),
+            }
+            info.push_str(&format!("connected_slaves:{}\r\n", repl.replicas.len()));
+            for (i, replica) in repl.replicas.iter().enumerate() {
+                info.push_str(&format!(
+                    "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                    i,
+                    replica.ip,
+                    replica.port,
+                    if replica.online {
+                        "online"
+                    } else {
+                        "wait_bgsave"
+                    },
+                    replica.ack_offset,
+                    replica.lag
+                ));
+            }
+            info.push_str(&format!("master_replid:{}\r\n", repl.replid));
+            info.push_str(&format!("master_replid2:{}\r\n", repl.replid2));
+            info.push_str(&format!("master_repl_offset:{}\r\n", repl.offset));
+            info.push_str(&format!(
+                "second_repl_offset:{}\r\n",
+                repl.replid2_offset.map_or(-1, |offset| offset as i64)
+            ));
+            let (first_byte, histlen) = repl.backlog.unwrap_or((0, 0));
+            info.push_str(&format!(
+                "repl_backlog_active:{}\r\n",
+                repl.backlog.is_some() as u8
+            ));
+            info.push_str(&format!(
+                "repl_backlog_size:{}\r\n",
+                self.state.replication.options.backlog_size
+            ));
+            info.push_str(&format!(
+                "repl_backlog_first_byte_offset:{}\r\n",
+                first_byte
+            ));
+            info.push_str(&format!("repl_backlog_histlen:{}\r\n", histlen));
+        }
+        if wanted("keyspace") {
+            let (keys, expires) = store.entries().fold((0, 0), |(keys, expires), (_, entry)| {
+                (keys + 1, expires + entry.expires_at.is_some() as usize)
+            });
+            info.push_str("\r\n# Keyspace\r\n");
+            if keys > 0 {
+                info.push_str(&format!("db0:keys={},expires={}\r\n", keys, expires));
+            }
+        }
+
+        let info = info.trim_start_matches("\r\n");
+        Ok(BytesFrame::BulkString(info.to_string().into()))
+    }
+
+    fn handle_replicaof(&mut self, master: Option<(String, u16)>) -> Result<BytesFrame> {
+        let Some((host, port)) = master else {
+            debug!("cmd to stop replicating");
+            self.state.replication.stop_replica();
+            return Ok(BytesFrame::SimpleString("OK".into()));
+        };
+        debug!("cmd to replicate {}:{}", host, port);
+        if !replication::start_replica(&self.store, &self.state, host, port) {
+            return Ok(BytesFrame::SimpleString(
+                "OK Already connected to specified master".into(),
+            ));
+        }
+        Ok(BytesFrame::SimpleString("OK".into()))
+    }
+
+    // REPLCONF from a replica during its handshake
+    fn handle_replconf(&mut self, options: Vec<(String, String)>) -> Result<BytesFrame> {
+        for (option, value) in options {
+            match option.as_str() {
+                "listening-port" => match value.parse::<u16>() {
+                    Ok(port) => self.client.set_listening_port(port),
+                    Err(_) => return encode_error("ERR Invalid listening-port"),
+                },
+                // Capabilities we don't use are fine to announce
+                "capa" => {}
+                "ack" => match value.parse::<u64>() {
+                    Ok(offset) => self.state.replication.ack(self.client.id, offset),
+                    Err(_) => return encode_error("ERR Invalid ACK offset"),
+                },
+                _ => return encode_error(&format!("ERR Unrecognized REPLCONF option: {}", option)),
+            }
+        }
+        Ok(BytesFrame::SimpleString("OK".into()))
+    }
+
+    async fn handle_psync(&mut self, replid: String, offset: i64) -> Result<BytesFrame> {
+        debug!(
+            "cmd to sync replica {} from {} {}",
+            self.client.addr, replid, offset
+        );
+        let reply =
+            match replication::psync(&self.store, &self.state, &self.client, &replid, offset).await
+            {
+                Psync::Continue { replid } => format!("CONTINUE {}", replid),
+                Psync::FullResync { replid, offset } => format!("FULLRESYNC {} {}", replid, offset),
+            };
+        Ok(BytesFrame::SimpleString(reply.into()))
+    }
+
+    // ROLE: "master" with the offset and the replicas, or "slave" with the
+    // master, the state of the link and the offset
+    fn handle_role(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to get the replication role");
+        let info = self.state.replication.info();
+        let reply = match info.master {
+            Some(master) => vec![
+                BytesFrame::BulkString("slave".into()),
+                BytesFrame::BulkString(master.host.into()),
+                BytesFrame::Integer(master.port as i64),
+                BytesFrame::BulkString(master.status.as_str().into()),
+                BytesFrame::Integer(info.offset as i64),
+            ],
+            None => vec![
+                BytesFrame::BulkString("master".into()),
+                BytesFrame::Integer(info.offset as i64),
+                BytesFrame::Array(
+                    info.replicas
+                        .into_iter()
+                        .filter(|replica| replica.online)
+                        .map(|replica| {
+                            BytesFrame::Array(vec![
+                                BytesFrame::BulkString(replica.ip.into()),
+                                BytesFrame::BulkString(replica.port.to_string().into()),
+                                BytesFrame::BulkString(replica.ack_offset.to_string().into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ],
+        };
+        Ok(BytesFrame::Array(reply))
+    }
+
+    async fn handle_wait(&mut self, numreplicas: i64, timeout: i64) -> Result<BytesFrame> {
+        debug!(
+            "cmd to wait for {} replicas, timeout: {}",
+            numreplicas, timeout
+        );
+        if timeout < 0 {
+            return encode_error("ERR timeout is negative");
+        }
+        if self.state.replication.is_replica() {
+            return encode_error("ERR WAIT cannot be used with replica instances");
+        }
+        let timeout = (timeout > 0).then(|| Duration::from_millis(timeout as u64));
+        let acked = self
+            .state
+            .replication
+            .wait(numreplicas.max(0) as usize, timeout)
+            .await;
+        encode_integer(acked as i64)
+    }
+
+    async fn handle_save(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to save the dataset");
+        let persistence = &self.state.persistence;
+        if persistence.bgsave_in_progress() {
+            return encode_error("ERR Background save already in progress");
+        }
+        // Writers wait until the snapshot is on disk
+        let store = self.store.read().await;
+        let start = Instant::now();
+        let saved = persistence.save(&store);
+        self.state
+            .add_latency_sample(EVENT_RDB_SAVE, start.elapsed());
+        match saved {
+            Ok(()) => Ok(BytesFrame::SimpleString("OK".into())),
+            Err(e) => {
+                warn!("Failed saving the DB: {}", e);
+                encode_error("ERR")
+            }
+        }
+    }
+
+    async fn handle_bgsave(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to save the dataset in the background");
+        if let Err(e) = snapshot::save_in_background(&self.store, &self.state).await {
+            return encode_error(&format!("ERR {}", e));
+        }
+        Ok(BytesFrame::SimpleString("Background saving started".into()))
+    }
+
+    async fn handle_bgrewriteaof(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to rewrite the append only file in the background");
+        if let Err(e) = aof::rewrite_in_background(&self.store, &self.state).await {
+            return encode_error(&format!("ERR {}", e));
+        }
+        Ok(BytesFrame::SimpleString(
+            "Background append only file rewriting started".into(),
+        ))
+    }
+
+    // Save the dataset, empty the keyspace and load the file back
+    async fn handle_debug_reload(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to reload the dataset from disk");
+        let persistence = &self.state.persistence;
+        let mut store = self.store.write().await;
+        if let Err(e) = persistence.save(&store) {
+            warn!("Failed saving the DB: {}", e);
+            return encode_error("ERR Error trying to save the DB");
+        }
+
+        store.flush(false);
+        match snapshot::load(
+            &persistence.snapshot_path,
+            &persistence.encryption,
+            &mut store,
+        ) {
+            Ok(_) => {
+                persistence.reset_dirty(store.dirty());
+                Ok(BytesFrame::SimpleString("OK".into()))
+            }
+            Err(e) => {
+                warn!("Failed loading the DB: {}", e);
+                encode_error("ERR Error trying to load the RDB dump")
+            }
+        }
+    }
+
+    fn handle_client_getname(&mut self) -> Result<BytesFrame> {
+        debug!("cmd to get client name");
+        let name = self.client.name();
+        if name.is_empty() {
+            encode_nil()
+        } else {
+            Ok(BytesFrame::BulkString(name.into()))
+        }
+    }
+}
+
+fn encode_help(lines: &[&'static str]) -> Result<BytesFrame> {
+    Ok(BytesFrame::Array(
+        lines
+            .iter()
+            .map(|line| BytesFrame::SimpleString((*line).into()))
+            .collect(),
+    ))
+}
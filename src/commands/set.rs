@@ -1,13 +1,13 @@
 use crate::{
     commands::SetCommand,
-    protocol::encode::{encode_error, encode_integer, encode_value},
-    storage::{CacheStore, SetEncoding, SetValue, Value},
+    protocol::encode::{encode_array, encode_error, encode_integer},
+    storage::CacheStore,
 };
 use anyhow::{Result, anyhow};
 use redis_protocol::resp2::types::BytesFrame;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{debug, trace};
 
 pub struct SetHandler {
     pub store: Arc<RwLock<CacheStore>>,
@@ -19,7 +19,7 @@ impl SetHandler {
     }
 
     pub async fn handle_cmd(&mut self, cmd: SetCommand) -> Result<BytesFrame> {
-        info!("[SetHandler] handle_cmd cmd: {:?}", cmd);
+        trace!("[SetHandler] handle_cmd cmd: {:?}", cmd);
 
         match cmd {
             SetCommand::SAdd { key, members } => self.handle_sadd(&key, members).await,
@@ -32,41 +32,38 @@ impl SetHandler {
     }
 
     async fn handle_sadd(&mut self, key: &str, members: Vec<String>) -> Result<BytesFrame> {
-        info!("cmd to set members {:?} to set", members);
+        debug!("cmd to set members {:?} to set", members);
 
         let mut store = self.store.write().await;
-        let count = store.sadd(key, members);
+        let count = store.sadd(key, members)?;
         encode_integer(count as i64)
     }
 
     async fn handle_srem(&mut self, key: &str, members: Vec<String>) -> Result<BytesFrame> {
-        info!("cmd to remove members {:?} from set", members);
+        debug!("cmd to remove members {:?} from set", members);
 
         let mut store = self.store.write().await;
-        let count = store.srem(key, members);
+        let count = store.srem(key, members)?;
         encode_integer(count as i64)
     }
 
     async fn handle_smembers(&mut self, key: &str) -> Result<BytesFrame> {
-        info!("cmd to get all members of set: {}", key);
+        debug!("cmd to get all members of set: {}", key);
 
         let mut store = self.store.write().await;
-        let members = store.smembers(key);
+        let members = store.smembers(key)?;
         if members.is_none() {
             encode_error("key not found or not a set")
         } else {
-            encode_value(Value::Set(SetValue {
-                members: members.unwrap(),
-                encoding: SetEncoding::HashTable,
-            }))
+            encode_array(members.unwrap())
         }
     }
 
     async fn handle_scard(&mut self, key: &str) -> Result<BytesFrame> {
-        info!("cmd to get cardinality of set: {}", key);
+        debug!("cmd to get cardinality of set: {}", key);
 
         let mut store = self.store.write().await;
-        let count = store.scard(key);
+        let count = store.scard(key)?;
         if count.is_none() {
             encode_error("key not found or not a set")
         } else {
@@ -75,10 +72,10 @@ impl SetHandler {
     }
 
     async fn handle_sismember(&mut self, key: &str, member: &str) -> Result<BytesFrame> {
-        info!("cmd to check if member {} is in set: {}", member, key);
+        debug!("cmd to check if member {} is in set: {}", member, key);
 
         let mut store = self.store.write().await;
-        let is_member = store.s_ismember(key, member);
+        let is_member = store.s_ismember(key, member)?;
         if is_member.is_none() {
             encode_error("key not found or not a set")
         } else {
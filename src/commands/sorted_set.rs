@@ -1,14 +1,13 @@
 use crate::{
     commands::{SortedSetCommand, ZAddOptions, ZRangeOptions},
     protocol::encode::{encode_error, encode_integer, encode_nil, encode_sorted_set, encode_value},
-    storage::{CacheStore, StringEncoding, StringValue, Value},
+    storage::{CacheStore, StringValue, Value},
 };
 use anyhow::Result;
-use bytes::Bytes;
 use redis_protocol::resp2::types::BytesFrame;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{debug, trace};
 
 pub struct SortedSetHandler {
     pub store: Arc<RwLock<CacheStore>>,
@@ -20,7 +19,7 @@ impl SortedSetHandler {
     }
 
     pub async fn handle_cmd(&mut self, cmd: SortedSetCommand) -> Result<BytesFrame> {
-        info!("[SortedSetHandler] handle_cmd cmd: {:?}", cmd);
+        trace!("[SortedSetHandler] handle_cmd cmd: {:?}", cmd);
         match cmd {
             SortedSetCommand::ZAdd {
                 key,
@@ -30,6 +29,10 @@ impl SortedSetHandler {
             SortedSetCommand::ZRem { key, members } => self.handle_zrem(key, members).await,
             SortedSetCommand::ZCard { key } => self.handle_zcard(key).await,
             SortedSetCommand::ZScore { key, member } => self.handle_zscore(key, member).await,
+            SortedSetCommand::ZRank { key, member } => self.handle_zrank(key, member, false).await,
+            SortedSetCommand::ZRevRank { key, member } => {
+                self.handle_zrank(key, member, true).await
+            }
             SortedSetCommand::ZRange {
                 key,
                 start,
@@ -46,43 +49,60 @@ impl SortedSetHandler {
         _: ZAddOptions,
         members: Vec<(f64, String)>,
     ) -> Result<BytesFrame> {
-        info!("cmd to zadd members {:?} to sorted set: {}", members, key);
+        debug!("cmd to zadd members {:?} to sorted set: {}", members, key);
         let mut store = self.store.write().await;
 
-        let added_count = store.zadd(&key, members);
-        encode_integer(added_count as i64)
+        match store.zadd(&key, members) {
+            Ok(added_count) => encode_integer(added_count as i64),
+            Err(e) => encode_error(&e.to_string()),
+        }
     }
 
     async fn handle_zrem(&mut self, key: String, members: Vec<String>) -> Result<BytesFrame> {
-        info!("cmd to zrem members {:?} from sorted set: {}", members, key);
+        debug!("cmd to zrem members {:?} from sorted set: {}", members, key);
         let mut store = self.store.write().await;
 
-        let removed_count = store.zrem(&key, members);
+        let removed_count = store.zrem(&key, members)?;
         encode_integer(removed_count as i64)
     }
 
     async fn handle_zcard(&mut self, key: String) -> Result<BytesFrame> {
-        info!("cmd to zcard sorted set: {}", key);
+        debug!("cmd to zcard sorted set: {}", key);
         let mut store = self.store.write().await;
 
-        let card = store.zcard(&key);
+        let card = store.zcard(&key)?;
         encode_integer(card as i64)
     }
 
     async fn handle_zscore(&mut self, key: String, member: String) -> Result<BytesFrame> {
-        info!("cmd to zscore member {} from sorted set: {}", member, key);
+        debug!("cmd to zscore member {} from sorted set: {}", member, key);
         let mut store = self.store.write().await;
 
-        if let Some(score) = store.zscore(&key, &member) {
-            encode_value(Value::String(StringValue {
-                data: Bytes::from(score.to_string()).to_vec(),
-                encoding: StringEncoding::Raw,
-            }))
+        if let Some(score) = store.zscore(&key, &member)? {
+            encode_value(Value::String(StringValue::new(score.to_string())))
         } else {
             encode_nil()
         }
     }
 
+    async fn handle_zrank(
+        &mut self,
+        key: String,
+        member: String,
+        reverse: bool,
+    ) -> Result<BytesFrame> {
+        debug!(
+            "cmd to get rank of member {} in sorted set: {}",
+            member, key
+        );
+        let mut store = self.store.write().await;
+
+        match store.zrank(&key, &member, reverse)? {
+            Some(rank) => encode_integer(rank as i64),
+            None => encode_nil(),
+        }
+    }
+
     async fn handle_zrange(
         &mut self,
         key: String,
@@ -90,13 +110,13 @@ impl SortedSetHandler {
         stop: i64,
         options: ZRangeOptions,
     ) -> Result<BytesFrame> {
-        info!(
+        debug!(
             "cmd to zrange from sorted set: {}, start: {}, stop: {}",
             key, start, stop
         );
         let mut store = self.store.write().await;
 
-        let members = store.zrange(&key, start, stop, options);
+        let members = store.zrange(&key, start, stop, options)?;
         if members.is_none() {
             encode_nil()
         } else {
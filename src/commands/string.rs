@@ -3,13 +3,13 @@ use std::sync::Arc;
 use anyhow::{Result, anyhow};
 use redis_protocol::resp2::types::BytesFrame;
 use tokio::sync::RwLock;
-use tracing::info;
 
 use crate::{
     commands::{SetOptions, StringCommand},
-    protocol::encode::{encode_error, encode_value},
-    storage::{CacheStore, ListEncoding, ListValue, StringValue, Value},
+    protocol::encode::{encode_array, encode_error, encode_integer, encode_value},
+    storage::{CacheStore, StringValue, Value},
 };
+use tracing::{debug, trace};
 
 pub struct StringHandler {
     pub store: Arc<RwLock<CacheStore>>,
@@ -21,7 +21,7 @@ impl StringHandler {
     }
 
     pub async fn handle_cmd(&mut self, cmd: StringCommand) -> Result<BytesFrame> {
-        println!("[StringHandler] handle_cmd cmd: {:?}", cmd);
+        trace!("[StringHandler] handle_cmd cmd: {:?}", cmd);
 
         match cmd {
             StringCommand::Get { key } => self.handle_get(key).await,
@@ -32,15 +32,26 @@ impl StringHandler {
             } => self.handle_set(key, value, options).await,
             StringCommand::MSet { pairs } => self.handle_mset(pairs).await,
             StringCommand::MGet { keys } => self.handle_mget(keys).await,
+            StringCommand::Strlen { key } => self.handle_strlen(key).await,
+            StringCommand::Incr { key } => self.handle_incr_by(key, 1).await,
+            StringCommand::IncrBy { key, increment } => self.handle_incr_by(key, increment).await,
+            StringCommand::Decr { key } => self.handle_incr_by(key, -1).await,
+            StringCommand::DecrBy { key, decrement } => match decrement.checked_neg() {
+                Some(delta) => self.handle_incr_by(key, delta).await,
+                None => encode_error("ERR decrement would overflow"),
+            },
+            StringCommand::IncrByFloat { key, increment } => {
+                self.handle_incr_by_float(key, increment).await
+            }
             _ => Err(anyhow!("unknown command")),
         }
     }
 
     async fn handle_get(&mut self, key: String) -> Result<BytesFrame> {
-        println!("cmd to get value by: {}", key);
+        debug!("cmd to get value by: {}", key);
         let mut store = self.store.write().await;
 
-        let value = store.get(&key);
+        let value = store.get(&key)?;
         if value.is_none() {
             encode_error("key not found")
         } else {
@@ -54,7 +65,7 @@ impl StringHandler {
         value: String,
         options: SetOptions,
     ) -> Result<BytesFrame> {
-        println!("cmd to set value {}: {}", key, value);
+        debug!("cmd to set value {}: {}", key, value);
         let v = Value::String(StringValue::new(value));
 
         let mut store = self.store.write().await;
@@ -75,7 +86,7 @@ impl StringHandler {
         // For simplicity, we assume the pairs are valid and all keys are strings.
         // In a real implementation, you would need to check the types of existing keys.
 
-        println!("cmd to mset pairs {:?} to string", pairs);
+        debug!("cmd to mset pairs {:?} to string", pairs);
         let mut store = self.store.write().await;
 
         for (key, value) in pairs {
@@ -86,27 +97,59 @@ impl StringHandler {
     }
 
     async fn handle_mget(&mut self, keys: Vec<String>) -> Result<BytesFrame> {
-        info!("cmd to mget keys {:?} from string", keys);
+        debug!("cmd to mget keys {:?} from string", keys);
 
         let mut store = self.store.write().await;
 
         let values = keys
             .into_iter()
             .map(|key| {
-                if let Some(Value::String(s)) = store.get(&key) {
-                    Some(s.data.into())
+                Ok(if let Some(Value::String(s)) = store.get(&key)? {
+                    Some(s.into_vec())
                 } else {
                     None
-                }
+                })
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
 
-        encode_value(Value::List(ListValue {
-            elements: values
+        encode_array(
+            values
                 .into_iter()
                 .map(|v| v.unwrap_or_else(|| b"(nil)".to_vec()))
                 .collect(),
-            encoding: ListEncoding::Quicklist,
-        }))
+        )
+    }
+
+    async fn handle_strlen(&mut self, key: String) -> Result<BytesFrame> {
+        debug!("cmd to get length of string: {}", key);
+        let mut store = self.store.write().await;
+
+        match store.get(&key)? {
+            Some(Value::String(s)) => encode_integer(s.len() as i64),
+            Some(_) => {
+                encode_error("WRONGTYPE Operation against a key holding the wrong kind of value")
+            }
+            None => encode_integer(0),
+        }
+    }
+
+    async fn handle_incr_by(&mut self, key: String, delta: i64) -> Result<BytesFrame> {
+        debug!("cmd to incr key {} by {}", key, delta);
+        let mut store = self.store.write().await;
+
+        match store.incr_by(&key, delta) {
+            Ok(value) => encode_integer(value),
+            Err(e) => encode_error(&e.to_string()),
+        }
+    }
+
+    async fn handle_incr_by_float(&mut self, key: String, delta: f64) -> Result<BytesFrame> {
+        debug!("cmd to incr key {} by float {}", key, delta);
+        let mut store = self.store.write().await;
+
+        match store.incr_by_float(&key, delta) {
+            Ok(value) => encode_value(Value::String(StringValue::new(value.to_string()))),
+            Err(e) => encode_error(&e.to_string()),
+        }
     }
 }
@@ -1,4 +1,321 @@
-#[derive(Debug)]
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    logging::{self, LogConfig, LogFormat, LogLevel},
+    persistence::{
+        SavePoint, SnapshotFormat,
+        aof::AppendFsync,
+        crypto::{EncryptionOptions, Keyring},
+    },
+    server::replication::ReplicationOptions,
+    storage::{EncodingLimits, lazyfree::LazyFreeOptions, notify, tier::TierOptions},
+};
+
+#[derive(Debug, Clone)]
 pub struct CacheConfig {
     pub addr: String,
+    pub encoding_limits: EncodingLimits,
+    // Commands running at least this many microseconds are logged, negative disables
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+    // Internal events taking at least this many milliseconds are sampled, 0 disables
+    pub latency_monitor_threshold: u64,
+    // Keyspace event classes published over pub/sub, see `storage::notify`
+    pub notify_keyspace_events: u32,
+    pub lazyfree: LazyFreeOptions,
+    pub log: LogConfig,
+    // Snapshots are written to `dir`/`dbfilename`
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    pub snapshot_format: SnapshotFormat,
+    // Save points from `save` lines, Redis's defaults when there are none
+    save_points: Option<Vec<SavePoint>>,
+    // Refuse writes with -MISCONF while the last background save failed
+    pub stop_writes_on_bgsave_error: bool,
+    // Log write commands to the AOF directory and load from it at startup
+    pub appendonly: bool,
+    // AOF files live in `dir`/`appenddirname` and are named after `appendfilename`
+    pub appenddirname: String,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    // Load an AOF whose last command was cut short instead of refusing to start
+    pub aof_load_truncated: bool,
+    // Write the base file of a rewritten AOF in the snapshot format
+    pub aof_use_rdb_preamble: bool,
+    // Rewrite the AOF once it grew this many percent since the last rewrite and
+    // is at least `auto_aof_rewrite_min_size` bytes, 0 disables
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: u64,
+    // Spill cold values to a log in `dir` once the dataset outgrows a memory limit
+    pub tiered_storage: TierOptions,
+    // Seal snapshot and AOF files with the keys from `encryption-key-file`
+    pub encryption: EncryptionOptions,
+    // Start as a replica of this master
+    pub replicaof: Option<(String, u16)>,
+    pub replication: ReplicationOptions,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0:6869".to_string(),
+            encoding_limits: EncodingLimits::default(),
+            slowlog_log_slower_than: 10000,
+            slowlog_max_len: 128,
+            latency_monitor_threshold: 0,
+            notify_keyspace_events: 0,
+            lazyfree: LazyFreeOptions::default(),
+            log: LogConfig::default(),
+            dir: PathBuf::from("."),
+            dbfilename: "dump.snap".to_string(),
+            snapshot_format: SnapshotFormat::default(),
+            save_points: None,
+            stop_writes_on_bgsave_error: true,
+            appendonly: false,
+            appenddirname: "appendonlydir".to_string(),
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::default(),
+            aof_load_truncated: true,
+            aof_use_rdb_preamble: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            tiered_storage: TierOptions::default(),
+            encryption: EncryptionOptions::default(),
+            replicaof: None,
+            replication: ReplicationOptions::default(),
+        }
+    }
+}
+
+impl CacheConfig {
+    // Load a redis.conf style file: one `directive value` per line, `#` starts a comment
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read config {}: {}", path.display(), e))?;
+
+        let mut conf = Self::default();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            conf.set(name, value.trim())
+                .map_err(|e| anyhow!("{}:{}: {}", path.display(), n + 1, e))?;
+        }
+        Ok(conf)
+    }
+
+    // The port of `addr`, announced to masters
+    pub fn port(&self) -> u16 {
+        self.addr
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or(0)
+    }
+
+    pub fn save_points(&self) -> Vec<SavePoint> {
+        self.save_points.clone().unwrap_or_else(SavePoint::defaults)
+    }
+
+    // Apply a single directive
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let limits = &mut self.encoding_limits;
+        match name.to_lowercase().as_str() {
+            "addr" => self.addr = value.to_string(),
+            "slowlog-log-slower-than" => self.slowlog_log_slower_than = parse_i64(value)?,
+            "slowlog-max-len" => self.slowlog_max_len = parse_usize(value)?,
+            "latency-monitor-threshold" => {
+                self.latency_monitor_threshold = parse_usize(value)? as u64
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = notify::parse_flags(value.trim_matches('"'))?
+            }
+            "loglevel" => self.log.level = LogLevel::parse(value)?,
+            // Empty means stdout
+            "logfile" => {
+                let path = value.trim_matches('"');
+                self.log.file = (!path.is_empty()).then(|| path.into());
+            }
+            "log-format" => self.log.format = LogFormat::parse(value)?,
+            "log-filter" => self.log.filters = logging::parse_filters(value.trim_matches('"'))?,
+            "dir" => self.dir = value.trim_matches('"').into(),
+            "dbfilename" => {
+                let name = value.trim_matches('"');
+                if name.is_empty() || name.contains('/') {
+                    return Err(anyhow!("dbfilename can't be a path, just a filename"));
+                }
+                self.dbfilename = name.to_string();
+            }
+            "appendonly" => self.appendonly = parse_bool(value)?,
+            "appendfilename" => {
+                let name = value.trim_matches('"');
+                if name.is_empty() || name.contains('/') {
+                    return Err(anyhow!("appendfilename can't be a path, just a filename"));
+                }
+                self.appendfilename = name.to_string();
+            }
+            "appenddirname" => {
+                let name = value.trim_matches('"');
+                if name.is_empty() || name.contains('/') {
+                    return Err(anyhow!("appenddirname can't be a path, just a dirname"));
+                }
+                self.appenddirname = name.to_string();
+            }
+            "aof-use-rdb-preamble" => self.aof_use_rdb_preamble = parse_bool(value)?,
+            "appendfsync" => self.appendfsync = AppendFsync::parse(value)?,
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(value)?,
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = value
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid value: {}", value))?
+            }
+            "auto-aof-rewrite-min-size" => self.auto_aof_rewrite_min_size = parse_bytes(value)?,
+            // Every `save` line adds its save points, `save ""` removes them all
+            "save" => {
+                let points = SavePoint::parse_list(value)?;
+                let save_points = self.save_points.get_or_insert_with(Vec::new);
+                if points.is_empty() {
+                    save_points.clear();
+                }
+                save_points.extend(points);
+            }
+            "tiered-storage-memory-limit" => self.tiered_storage.memory_limit = parse_bytes(value)?,
+            "tiered-storage-min-value-size" => {
+                self.tiered_storage.min_value_size = parse_bytes(value)? as usize
+            }
+            "tiered-storage-filename" => {
+                let name = value.trim_matches('"');
+                if name.is_empty() || name.contains('/') {
+                    return Err(anyhow!(
+                        "tiered-storage-filename can't be a path, just a filename"
+                    ));
+                }
+                self.tiered_storage.filename = name.to_string();
+            }
+            "encryption-key-file" => {
+                let path = value.trim_matches('"');
+                self.encryption.keyring = match path {
+                    "" => None,
+                    path => Some(Arc::new(Keyring::load(Path::new(path))?)),
+                };
+            }
+            "encryption-allow-plaintext" => self.encryption.allow_plaintext = parse_bool(value)?,
+            "replicaof" | "slaveof" => {
+                let (host, port) = value
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| anyhow!("replicaof needs a host and a port"))?;
+                let port = port
+                    .trim()
+                    .parse::<u16>()
+                    .map_err(|_| anyhow!("Invalid master port: {}", port))?;
+                self.replicaof = Some((host.to_string(), port));
+            }
+            "repl-backlog-size" => self.replication.backlog_size = parse_bytes(value)? as usize,
+            "repl-ping-replica-period" => {
+                self.replication.ping_period = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|period| *period > 0)
+                    .ok_or_else(|| anyhow!("Invalid value: {}", value))?
+            }
+            "repl-timeout" => {
+                self.replication.timeout = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|timeout| *timeout > 0)
+                    .ok_or_else(|| anyhow!("Invalid value: {}", value))?
+            }
+            "replica-read-only" | "slave-read-only" => {
+                self.replication.read_only = parse_bool(value)?
+            }
+            "min-replicas-to-write" | "min-slaves-to-write" => {
+                self.replication.min_replicas_to_write = value
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("Invalid value: {}", value))?
+            }
+            "min-replicas-max-lag" | "min-slaves-max-lag" => {
+                self.replication.min_replicas_max_lag = value
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid value: {}", value))?
+            }
+            "stop-writes-on-bgsave-error" => self.stop_writes_on_bgsave_error = parse_bool(value)?,
+            "snapshot-format" => self.snapshot_format = SnapshotFormat::parse(value)?,
+            "lazyfree-lazy-expire" => self.lazyfree.lazy_expire = parse_bool(value)?,
+            // Nothing is evicted yet, the option is checked and ignored so redis.conf
+            // files still load
+            "lazyfree-lazy-eviction" => {
+                parse_bool(value)?;
+            }
+            "lazyfree-lazy-server-del" => self.lazyfree.lazy_server_del = parse_bool(value)?,
+            "hash-max-listpack-entries" => limits.hash_max_listpack_entries = parse_usize(value)?,
+            "hash-max-listpack-value" => limits.hash_max_listpack_value = parse_usize(value)?,
+            "set-max-intset-entries" => limits.set_max_intset_entries = parse_usize(value)?,
+            "set-max-listpack-entries" => limits.set_max_listpack_entries = parse_usize(value)?,
+            "set-max-listpack-value" => limits.set_max_listpack_value = parse_usize(value)?,
+            "zset-max-listpack-entries" => limits.zset_max_listpack_entries = parse_usize(value)?,
+            "zset-max-listpack-value" => limits.zset_max_listpack_value = parse_usize(value)?,
+            "list-max-listpack-size" => {
+                limits.list_max_listpack_size = value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|fill| *fill != 0 && *fill >= -5)
+                    .ok_or_else(|| anyhow!("Invalid value: {}", value))?
+            }
+            _ => return Err(anyhow!("Unknown config directive: {}", name)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_usize(value: &str) -> Result<usize> {
+    value
+        .parse::<usize>()
+        .map_err(|_| anyhow!("Invalid value: {}", value))
+}
+
+// A size like `64mb`: an integer with an optional k, kb, m, mb, g or gb suffix
+fn parse_bytes(value: &str) -> Result<u64> {
+    let lower = value.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Invalid value: {}", value)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow!("Invalid value: {}", value))
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(anyhow!("Invalid value, expected yes or no: {}", value)),
+    }
+}
+
+fn parse_i64(value: &str) -> Result<i64> {
+    value
+        .parse::<i64>()
+        .map_err(|_| anyhow!("Invalid value: {}", value))
 }
@@ -0,0 +1,9 @@
+pub mod commands; // handle command, SET, GET, ZADD, etc
+pub mod config; // handle server config.
+pub mod logging; // log levels, output and rotation.
+mod network; // handle network connection handler.
+pub mod persistence; // data persistence.
+pub mod protocol; // redis protocol decode and encode.
+pub mod server; // ds-cache server
+pub mod storage; // data store
+mod utils; // util functions.
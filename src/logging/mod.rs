@@ -0,0 +1,237 @@
+pub mod json;
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::{Result, anyhow};
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{
+    Layer as _, filter::Targets, fmt, fmt::MakeWriter, layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+
+use crate::logging::json::JsonFormat;
+
+// Redis log levels, from the most to the least verbose. `debug` also logs every
+// command frame, `verbose` one line per command, `notice` server events only.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "debug" => Ok(LogLevel::Debug),
+            "verbose" => Ok(LogLevel::Verbose),
+            "notice" => Ok(LogLevel::Notice),
+            "warning" => Ok(LogLevel::Warning),
+            _ => Err(anyhow!("Invalid log level: {}", value)),
+        }
+    }
+
+    fn level_filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Debug => LevelFilter::TRACE,
+            LogLevel::Verbose => LevelFilter::DEBUG,
+            LogLevel::Notice => LevelFilter::INFO,
+            LogLevel::Warning => LevelFilter::WARN,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Plain,
+    Json, // one JSON object per line
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("Invalid log format: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub file: Option<PathBuf>, // stdout when unset
+    pub format: LogFormat,
+    // Per-module levels overriding `level`, e.g. ("ds_cache::storage", Debug)
+    pub filters: Vec<(String, LogLevel)>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Notice,
+            file: None,
+            format: LogFormat::Plain,
+            filters: Vec::new(),
+        }
+    }
+}
+
+// Parse `module=level` pairs separated by commas
+pub fn parse_filters(value: &str) -> Result<Vec<(String, LogLevel)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|filter| !filter.is_empty())
+        .map(|filter| {
+            let (module, level) = filter
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid log filter, expected module=level: {}", filter))?;
+            Ok((module.trim().to_string(), LogLevel::parse(level.trim())?))
+        })
+        .collect()
+}
+
+// Where log lines go. The file is shared by every writer and can be reopened after
+// logrotate moved it away.
+#[derive(Debug, Clone)]
+pub struct LogOutput {
+    file: Option<Arc<LogFile>>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl LogOutput {
+    fn open(path: Option<&Path>) -> Result<Self> {
+        let file = match path {
+            Some(path) => Some(Arc::new(LogFile {
+                path: path.to_path_buf(),
+                file: Mutex::new(open_append(path)?),
+            })),
+            None => None,
+        };
+        Ok(Self { file })
+    }
+
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Some(log) = &self.file {
+            let file = open_append(&log.path)?;
+            *log.file.lock().unwrap() = file;
+        }
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+pub enum LogWriter<'a> {
+    Stdout(io::StdoutLock<'static>),
+    File(MutexGuard<'a, File>),
+}
+
+impl Write for LogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            LogWriter::Stdout(out) => out.write(buf),
+            LogWriter::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Stdout(out) => out.flush(),
+            LogWriter::File(file) => file.flush(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogOutput {
+    type Writer = LogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        match &self.file {
+            Some(log) => LogWriter::File(log.file.lock().unwrap_or_else(|e| e.into_inner())),
+            None => LogWriter::Stdout(io::stdout().lock()),
+        }
+    }
+}
+
+// Install the global subscriber. Levels are filtered before the event is built, so
+// disabled per-command logs cost a level check and nothing is formatted.
+pub fn init(conf: &LogConfig) -> Result<LogOutput> {
+    let output = LogOutput::open(conf.file.as_deref())?;
+
+    let filter = Targets::new()
+        .with_default(conf.level.level_filter())
+        .with_targets(
+            conf.filters
+                .iter()
+                .map(|(module, level)| (module.clone(), level.level_filter())),
+        );
+    let layer = match conf.format {
+        LogFormat::Plain => fmt::layer()
+            .with_writer(output.clone())
+            .with_ansi(conf.file.is_none())
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .event_format(JsonFormat)
+            .with_writer(output.clone())
+            .with_filter(filter)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer)
+        .try_init()
+        .map_err(|e| anyhow!("Failed to init logging: {}", e))?;
+
+    Ok(output)
+}
+
+// Reopen the log file whenever SIGHUP arrives, for logrotate
+pub fn reopen_on_sighup(output: LogOutput) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match output.reopen() {
+                Ok(()) => info!("log file reopened"),
+                Err(e) => warn!("Failed to reopen log file: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filters() {
+        let filters = parse_filters("ds_cache::storage=debug, ds_cache::server=warning").unwrap();
+        assert_eq!(
+            filters,
+            vec![
+                ("ds_cache::storage".to_string(), LogLevel::Debug),
+                ("ds_cache::server".to_string(), LogLevel::Warning),
+            ]
+        );
+
+        assert!(parse_filters("").unwrap().is_empty());
+        assert!(parse_filters("ds_cache::storage").is_err());
+        assert!(parse_filters("ds_cache::storage=info").is_err());
+    }
+}
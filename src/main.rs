@@ -1,29 +1,140 @@
-mod commands; // handle command, SET, GET, ZADD, etc
-mod config; // handle server config.
-mod network; // handle network connection handler.
-mod persistence; // data persistence.
-mod protocol; // redis protocol decode and encode.
-mod server; // ds-cache server
-mod storage; // data store
-mod utils; // util functions.
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::PathBuf,
+};
 
-use crate::{config::CacheConfig, server::Server};
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use clap::{Parser, Subcommand};
+use ds_cache::{
+    config::CacheConfig,
+    logging,
+    persistence::{Persistence, jsonl, snapshot},
+    server::Server,
+    storage::CacheStore,
+};
 
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{Layer as _, fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::info;
+
+#[derive(Debug, Parser)]
+#[command(about = "A Redis Server Build with Rust")]
+struct Args {
+    /// Path to a redis.conf style config file
+    config: Option<PathBuf>,
+
+    /// Address to listen on, overrides the config file
+    #[arg(long)]
+    addr: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Write the keys the server would load as JSON Lines, from the AOF when
+    /// appendonly is on and from the snapshot file otherwise
+    Export {
+        /// Output file, stdout when omitted
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// Only export keys matching this glob pattern
+        #[arg(long = "match")]
+        pattern: Option<String>,
+    },
+    /// Add the keys in a JSON Lines file to the configured snapshot file
+    Import {
+        /// JSON Lines file written by `export`
+        input: PathBuf,
+
+        /// Only import keys matching this glob pattern
+        #[arg(long = "match")]
+        pattern: Option<String>,
+
+        /// Overwrite keys that already exist instead of skipping them
+        #[arg(long)]
+        replace: bool,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
+    let args = Args::parse();
 
-    info!("A Redis Server Build with Rust");
-
-    let conf = CacheConfig {
-        addr: "0.0.0.0:6869".to_string(),
+    let mut conf = match args.config {
+        Some(path) => CacheConfig::from_file(path)?,
+        None => CacheConfig::default(),
     };
+    if let Some(addr) = args.addr {
+        conf.addr = addr;
+    }
+
+    if let Some(command) = args.command {
+        return run_command(command, &conf).await;
+    }
+
+    let log_output = logging::init(&conf.log)?;
+    logging::reopen_on_sighup(log_output)?;
+
+    info!("A Redis Server Build with Rust");
 
     let server = Server::new(conf, 1000);
     server.run().await
 }
+
+// Export and import work offline, like ds-check. Export loads the dataset the
+// way startup does, import only updates the snapshot file.
+async fn run_command(command: Command, conf: &CacheConfig) -> Result<()> {
+    match command {
+        Command::Export { output, pattern } => {
+            let server = Server::new(conf.clone(), 1000);
+            server.load_dataset().await?;
+            let entries = snapshot::collect(&*server.store.read().await)?;
+            let exported = match output {
+                Some(path) => {
+                    let file = File::create(&path)
+                        .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+                    jsonl::export(BufWriter::new(file), &entries, pattern.as_deref())?
+                }
+                None => jsonl::export(io::stdout().lock(), &entries, pattern.as_deref())?,
+            };
+            eprintln!("Exported {} keys", exported);
+        }
+        Command::Import {
+            input,
+            pattern,
+            replace,
+        } => {
+            let persistence = Persistence::new(conf);
+            let mut store = CacheStore::new(1000);
+            store.set_encoding_limits(conf.encoding_limits.clone());
+            snapshot::load(
+                &persistence.snapshot_path,
+                &persistence.encryption,
+                &mut store,
+            )?;
+            let file = File::open(&input)
+                .map_err(|e| anyhow!("Failed to open {}: {}", input.display(), e))?;
+            let stats = jsonl::import(
+                BufReader::new(file),
+                &mut store,
+                pattern.as_deref(),
+                replace,
+            )?;
+            persistence.write_snapshot(&snapshot::collect(&store)?)?;
+            eprintln!(
+                "Imported {} keys into {}, skipped {} existing and {} expired",
+                stats.imported,
+                persistence.snapshot_path.display(),
+                stats.skipped,
+                stats.expired
+            );
+            if conf.appendonly {
+                eprintln!("Note: appendonly is on, the server loads the AOF instead of this file");
+            }
+        }
+    }
+    io::stderr().flush()?;
+    Ok(())
+}
@@ -0,0 +1,838 @@
+// Append-only file: every write command as the RESP array the client sent, with
+// relative expiries turned into absolute ones so a later replay gives the same
+// deadlines. Commands go to incremental files in the AOF directory, see
+// `manifest`, which rewrites replace with a new base file.
+
+use std::{
+    borrow::{Borrow, Cow},
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use redis_protocol::resp2::decode::decode;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    commands::{Command, handlers::CmdHandler},
+    persistence::{
+        Corruption, Persistence,
+        crypto::{self, Keyring, Opened, Sealer},
+        manifest::{AofFileType, AofInfo, Manifest},
+        rdb,
+        snapshot::{self, SnapshotEntry, SnapshotValue},
+    },
+    protocol::{extract_command_args, from_args},
+    server::{
+        latency::{EVENT_AOF_REWRITE, EVENT_FORK},
+        state::ServerState,
+    },
+    storage::CacheStore,
+    utils::{to_hex, unix_time_ms},
+};
+
+// How often `everysec` flushes the file to disk
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+// Elements per command when a rewrite rebuilds a list, set, sorted set or hash
+const REWRITE_ITEMS_PER_CMD: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AppendFsync {
+    // fsync after every write command, before replying
+    Always,
+    // fsync in the background once per second
+    #[default]
+    EverySec,
+    // leave flushing to the OS
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(anyhow!("Invalid appendfsync value: {}", value)),
+        }
+    }
+}
+
+// The AOF directory while appending: the manifest and the last incremental
+// file, which write commands go to
+#[derive(Debug)]
+pub struct AppendOnlyFile {
+    pub dir: PathBuf,
+    prefix: String,
+    pub manifest: Manifest,
+    file: File,
+    fsync: AppendFsync,
+    // Seals every append as a record of the incremental file when encryption is on
+    keyring: Option<Arc<Keyring>>,
+    sealer: Option<Sealer>,
+    // Whether anything was written since the last fsync
+    unsynced: bool,
+    last_fsync: Instant,
+    // Base and incremental files together
+    pub size: u64,
+    // Size right after the last rewrite, or at startup
+    pub base_size: u64,
+}
+
+impl AppendOnlyFile {
+    // Append to the last incremental file of `manifest`, starting one if there is
+    // none or if it can't take more appends
+    pub fn open(
+        dir: &Path,
+        prefix: &str,
+        mut manifest: Manifest,
+        fsync: AppendFsync,
+        keyring: Option<Arc<Keyring>>,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("Failed to create {}: {}", dir.display(), e))?;
+        let resumable = match manifest.incrs.last() {
+            Some(last) => {
+                let path = dir.join(&last.name);
+                let resumable = appendable(&path, keyring.as_deref())?;
+                if !resumable {
+                    finish_incr(&path, keyring.as_deref())?;
+                }
+                resumable
+            }
+            None => false,
+        };
+        if !resumable {
+            manifest.add_incr(prefix);
+            manifest.write(dir, prefix, keyring.as_deref())?;
+        }
+        let last = &manifest.incrs[manifest.incrs.len() - 1];
+        let (file, sealer) = open_append(&dir.join(&last.name), keyring.as_deref())?;
+
+        let size = files_size(dir, &manifest);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_string(),
+            manifest,
+            file,
+            fsync,
+            keyring,
+            sealer,
+            unsynced: false,
+            last_fsync: Instant::now(),
+            size,
+            base_size: size,
+        })
+    }
+
+    // The incremental file being appended to
+    pub fn path(&self) -> PathBuf {
+        self.dir
+            .join(&self.manifest.incrs[self.manifest.incrs.len() - 1].name)
+    }
+
+    // Log a command, returning how long the fsync took with `always`
+    pub fn append(&mut self, args: &[String]) -> Result<Option<Duration>> {
+        let mut cmd = encode_command(&absolute_expiry(args));
+        if let Some(sealer) = self.sealer.as_mut() {
+            cmd = sealer.seal(&cmd)?;
+        }
+        self.file.write_all(&cmd)?;
+        self.size += cmd.len() as u64;
+        if self.fsync == AppendFsync::Always {
+            let start = Instant::now();
+            self.file.sync_data()?;
+            return Ok(Some(start.elapsed()));
+        }
+        self.unsynced = true;
+        Ok(None)
+    }
+
+    // With `everysec`, a handle to fsync in the background once the interval passed
+    pub fn fsync_due(&mut self) -> Option<File> {
+        if self.fsync != AppendFsync::EverySec
+            || !self.unsynced
+            || self.last_fsync.elapsed() < FSYNC_INTERVAL
+        {
+            return None;
+        }
+        self.unsynced = false;
+        self.last_fsync = Instant::now();
+        self.file.try_clone().ok()
+    }
+
+    // Switch to a new incremental file as a rewrite starts. The rewrite copies the
+    // keyspace as of this point, everything after it lands in the new file.
+    pub fn start_rewrite(&mut self) -> Result<()> {
+        if let Some(sealer) = self.sealer.as_mut() {
+            self.file.write_all(&sealer.finish()?)?;
+        }
+        self.file.sync_data()?;
+        let mut manifest = self.manifest.clone();
+        let name = manifest.add_incr(&self.prefix).name.clone();
+        let (file, sealer) = open_append(&self.dir.join(&name), self.keyring.as_deref())?;
+        manifest.write(&self.dir, &self.prefix, self.keyring.as_deref())?;
+        self.manifest = manifest;
+        self.file = file;
+        self.sealer = sealer;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    // Make `base` the base file, keeping only the incremental file started with
+    // the rewrite, and delete the files it replaces
+    pub fn finish_rewrite(&mut self, base: AofInfo) -> Result<()> {
+        let last = self.manifest.incrs[self.manifest.incrs.len() - 1].clone();
+        let rewritten = Manifest {
+            base: Some(base),
+            incrs: vec![last],
+        };
+        rewritten.write(&self.dir, &self.prefix, self.keyring.as_deref())?;
+        remove_obsolete(&self.dir, &self.manifest, &rewritten);
+        self.manifest = rewritten;
+
+        self.size = files_size(&self.dir, &self.manifest);
+        self.base_size = self.size;
+        Ok(())
+    }
+}
+
+// Open a file to append to, writing the encryption header first to a new one
+// when there is a keyring
+fn open_append(path: &Path, keyring: Option<&Keyring>) -> Result<(File, Option<Sealer>)> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+    let Some(keyring) = keyring else {
+        return Ok((file, None));
+    };
+    let len = file.metadata()?.len();
+    let sealer = Sealer::new(keyring, len);
+    if len == 0 {
+        file.write_all(sealer.header())?;
+    }
+    Ok((file, Some(sealer)))
+}
+
+// Whether appends can go on in the file at `path`: it is sealed like they would
+// be, with the active key or not at all, and didn't get its final record yet.
+// Otherwise they go to a new file.
+fn appendable(path: &Path, keyring: Option<&Keyring>) -> Result<bool> {
+    let Some((file, head)) = read_head(path)? else {
+        return Ok(true);
+    };
+    if head.is_empty() {
+        return Ok(true);
+    }
+    Ok(match keyring {
+        Some(keyring) => {
+            crypto::key_id(&head) == Some(keyring.active_id())
+                && !ends_finished(&file, &head, keyring)?
+        }
+        None => !crypto::is_sealed(&head),
+    })
+}
+
+// Give a sealed incremental file that no more appends go to its final record,
+// with the key it is sealed with. Plain files are left as they are.
+fn finish_incr(path: &Path, keyring: Option<&Keyring>) -> Result<()> {
+    let (Some(keyring), Some((mut file, head))) = (keyring, read_head(path)?) else {
+        return Ok(());
+    };
+    if !crypto::is_sealed(&head) || ends_finished(&file, &head, keyring)? {
+        return Ok(());
+    }
+    let mut sealer = Sealer::for_file(keyring, &head, file.metadata()?.len())
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    file.write_all(&sealer.finish()?)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+// The file at `path`, opened to append, and its first bytes, as many as an
+// encryption header takes. None if there is no such file.
+fn read_head(path: &Path) -> Result<Option<(File, Vec<u8>)>> {
+    let file = match OpenOptions::new().read(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow!("Failed to open {}: {}", path.display(), e)),
+    };
+    let mut head = Vec::new();
+    (&file)
+        .take(crypto::MAX_HEADER_LEN as u64)
+        .read_to_end(&mut head)?;
+    Ok(Some((file, head)))
+}
+
+fn ends_finished(file: &File, head: &[u8], keyring: &Keyring) -> Result<bool> {
+    let len = file.metadata()?.len();
+    let Some(offset) = len.checked_sub(crypto::FINAL_RECORD_LEN as u64) else {
+        return Ok(false);
+    };
+    let mut tail = [0; crypto::FINAL_RECORD_LEN];
+    file.read_exact_at(&mut tail, offset)?;
+    keyring.ends_finished(head, &tail, len)
+}
+
+fn files_size(dir: &Path, manifest: &Manifest) -> u64 {
+    manifest
+        .base
+        .iter()
+        .chain(&manifest.incrs)
+        .map(|info| fs::metadata(dir.join(&info.name)).map_or(0, |meta| meta.len()))
+        .sum()
+}
+
+// Delete the files of `old` that `new` no longer lists
+pub fn remove_obsolete(dir: &Path, old: &Manifest, new: &Manifest) {
+    for name in old.obsolete_in(new) {
+        if let Err(e) = fs::remove_file(dir.join(name)) {
+            warn!("Failed to remove {}: {}", name, e);
+        }
+    }
+}
+
+// `*<n>\r\n` followed by `$<len>\r\n<arg>\r\n` per argument
+pub fn encode_command<S: AsRef<[u8]>>(args: &[S]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        let arg = arg.as_ref();
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+// Rewrite `SET .. EX/PX/EXAT` as `SET .. PXAT`, `EXPIRE` as `PEXPIREAT` and give
+// `RESTORE` an absolute TTL
+pub fn absolute_expiry(args: &[String]) -> Cow<'_, [String]> {
+    let now = unix_time_ms();
+    let at = |value: &str, unit: u64, relative: bool| {
+        let value = value.parse::<u64>().ok()?.saturating_mul(unit);
+        let base = if relative { now } else { 0 };
+        Some(base.saturating_add(value).to_string())
+    };
+
+    match args[0].to_uppercase().as_str() {
+        "SET" => {
+            let mut rewritten = args.to_vec();
+            for i in 3..rewritten.len().saturating_sub(1) {
+                let deadline = match rewritten[i].to_uppercase().as_str() {
+                    "EX" => at(&rewritten[i + 1], 1000, true),
+                    "PX" => at(&rewritten[i + 1], 1, true),
+                    "EXAT" => at(&rewritten[i + 1], 1000, false),
+                    _ => None,
+                };
+                if let Some(deadline) = deadline {
+                    rewritten[i] = "PXAT".to_string();
+                    rewritten[i + 1] = deadline;
+                }
+            }
+            Cow::Owned(rewritten)
+        }
+        "EXPIRE" if args.len() == 3 => match at(&args[2], 1000, true) {
+            Some(deadline) => Cow::Owned(vec!["PEXPIREAT".to_string(), args[1].clone(), deadline]),
+            None => Cow::Borrowed(args),
+        },
+        "RESTORE"
+            if args.len() >= 4
+                && args[2] != "0"
+                && !args[4..]
+                    .iter()
+                    .any(|arg| arg.eq_ignore_ascii_case("ABSTTL")) =>
+        {
+            match at(&args[2], 1, true) {
+                Some(deadline) => {
+                    let mut rewritten = args.to_vec();
+                    rewritten[2] = deadline;
+                    rewritten.push("ABSTTL".to_string());
+                    Cow::Owned(rewritten)
+                }
+                None => Cow::Borrowed(args),
+            }
+        }
+        _ => Cow::Borrowed(args),
+    }
+}
+
+// The shortest command stream that rebuilds `entries`. Arguments are written from
+// the raw bytes. Commands only take UTF-8 arguments though, so a value holding
+// anything else is rebuilt with RESTORE from its hex DUMP payload instead.
+pub fn rewrite_commands<W: Write + ?Sized, E: Borrow<SnapshotEntry>>(
+    out: &mut W,
+    entries: impl IntoIterator<Item = E>,
+) -> Result<()> {
+    for entry in entries {
+        let entry = entry.borrow();
+        let key = entry.key.as_bytes();
+        if !is_text(&entry.value) {
+            let payload = to_hex(&rdb::dump_payload(&entry.value));
+            let at = entry.expires_at.unwrap_or(0).to_string();
+            out.write_all(&encode_command(&[
+                "RESTORE", &entry.key, &at, &payload, "REPLACE", "ABSTTL",
+            ]))?;
+            continue;
+        }
+
+        let batches: Vec<Vec<Cow<[u8]>>> = match &entry.value {
+            SnapshotValue::String(value) => vec![vec![b"SET".into(), key.into(), value.into()]],
+            SnapshotValue::List(elements) => batched("RPUSH", key, elements, |e| vec![e.into()]),
+            SnapshotValue::Set(members) => batched("SADD", key, members, |m| vec![m.into()]),
+            SnapshotValue::SortedSet(members) => batched("ZADD", key, members, |(m, score)| {
+                vec![score.to_string().into_bytes().into(), m.into()]
+            }),
+            SnapshotValue::Hash(fields) => {
+                batched("HSET", key, fields, |(f, v)| vec![f.into(), v.into()])
+            }
+        };
+        for args in batches {
+            out.write_all(&encode_command(&args))?;
+        }
+        if let Some(at) = entry.expires_at {
+            out.write_all(&encode_command(&["PEXPIREAT", &entry.key, &at.to_string()]))?;
+        }
+    }
+    Ok(())
+}
+
+// Whether every string in `value` is valid UTF-8
+fn is_text(value: &SnapshotValue) -> bool {
+    let text = |bytes: &[u8]| std::str::from_utf8(bytes).is_ok();
+    match value {
+        SnapshotValue::String(value) => text(value),
+        SnapshotValue::List(elements) | SnapshotValue::Set(elements) => {
+            elements.iter().all(|e| text(e))
+        }
+        SnapshotValue::SortedSet(members) => members.iter().all(|(m, _)| text(m)),
+        SnapshotValue::Hash(fields) => fields.iter().all(|(f, v)| text(f) && text(v)),
+    }
+}
+
+fn batched<'a, T>(
+    cmd: &'a str,
+    key: &'a [u8],
+    items: &'a [T],
+    item_args: impl Fn(&'a T) -> Vec<Cow<'a, [u8]>>,
+) -> Vec<Vec<Cow<'a, [u8]>>> {
+    items
+        .chunks(REWRITE_ITEMS_PER_CMD)
+        .map(|chunk| {
+            let mut args: Vec<Cow<[u8]>> = vec![cmd.as_bytes().into(), key.into()];
+            args.extend(chunk.iter().flat_map(&item_args));
+            args
+        })
+        .collect()
+}
+
+// Start BGREWRITEAOF. An incremental snapshot of the keyspace starts while no
+// write command is between running and being logged, so the new base plus the
+// incremental file started alongside replay to exactly the live dataset. The
+// snapshot is then written out without holding writes back.
+pub async fn rewrite_in_background(
+    store: &Arc<RwLock<CacheStore>>,
+    state: &Arc<ServerState>,
+) -> Result<()> {
+    let persistence = &state.persistence;
+    if !persistence.start_aof_rewrite() {
+        return Err(anyhow!(
+            "Background append only file rewriting already in progress"
+        ));
+    }
+    let (start, base) = {
+        let _gate = persistence.write_gate.write().await;
+        let mut store = store.write().await;
+        let started = Instant::now();
+        let start = store.begin_snapshot();
+        let base = persistence.begin_aof_rewrite();
+        state.add_latency_sample(EVENT_FORK, started.elapsed());
+        match base {
+            Ok(base) => (start, base),
+            Err(e) => {
+                store.end_snapshot(start.id);
+                persistence.finish_aof_rewrite(None, Err(anyhow!("{}", e)));
+                return Err(e);
+            }
+        }
+    };
+
+    let store = Arc::clone(store);
+    let state = Arc::clone(state);
+    tokio::task::spawn_blocking(move || {
+        let persistence = &state.persistence;
+        let res = persistence.write_aof_base(
+            &base,
+            snapshot::batches(&store, start.id),
+            start.keys,
+            start.expires,
+        );
+        store.blocking_write().end_snapshot(start.id);
+        let started = Instant::now();
+        persistence.finish_aof_rewrite(Some(base), res);
+        state.add_latency_sample(EVENT_AOF_REWRITE, started.elapsed());
+    });
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayStats {
+    pub commands: usize,
+    // Length of the data up to the end of the last complete command
+    pub valid_len: usize,
+    pub truncated: bool,
+}
+
+// Run every command in `data` through `handler`. A final command cut short is
+// dropped when `load_truncated`, anything else malformed fails the replay.
+pub async fn replay(
+    data: &[u8],
+    handler: &mut CmdHandler,
+    load_truncated: bool,
+) -> Result<ReplayStats> {
+    let mut stats = ReplayStats::default();
+    while stats.valid_len < data.len() {
+        let pos = stats.valid_len;
+        let (cmd, len) = match next_command(&data[pos..])
+            .map_err(|e| anyhow!("{} at {}", e, pos))?
+        {
+            Some(next) => next,
+            None if load_truncated => {
+                stats.truncated = true;
+                break;
+            }
+            None => {
+                return Err(anyhow!(
+                    "Unexpected end of file at {}, set aof-load-truncated to yes to load it anyway",
+                    pos
+                ));
+            }
+        };
+
+        // Errors are replies to the original client, they don't stop the replay
+        let _ = handler.handle_cmd(cmd).await;
+
+        stats.commands += 1;
+        stats.valid_len = pos + len;
+    }
+    Ok(stats)
+}
+
+// The command at the start of `data` and its length, None when it is cut short
+fn next_command(data: &[u8]) -> Result<Option<(Command, usize)>> {
+    let Some((frame, len)) = decode(data).map_err(|e| anyhow!("Bad file format: {}", e))? else {
+        return Ok(None);
+    };
+    let args = extract_command_args(frame).map_err(|e| anyhow!("Bad file format: {}", e))?;
+    let cmd = from_args(&args).map_err(|e| anyhow!("Invalid command: {}", e))?;
+    Ok(Some((cmd, len)))
+}
+
+// Validate every command in an AOF file, returning how many there are
+pub fn check(data: &[u8]) -> Result<usize, Corruption> {
+    let mut commands = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        match next_command(&data[pos..]).map_err(|e| Corruption::new(pos, e))? {
+            Some((_, len)) => {
+                commands += 1;
+                pos += len;
+            }
+            None => return Err(Corruption::new(pos, "Unexpected end of file")),
+        }
+    }
+    Ok(commands)
+}
+
+// Replay the commands of the file at `path`, cutting a truncated tail off it. A
+// sealed file can only be cut short between records, which hold whole commands.
+async fn load_commands(
+    path: &Path,
+    file: Opened,
+    len: usize,
+    handler: &mut CmdHandler,
+    load_truncated: bool,
+) -> Result<usize> {
+    if file.truncated && !load_truncated {
+        return Err(anyhow!(
+            "{}: ends in a partial encrypted record",
+            path.display()
+        ));
+    }
+    let stats = replay(&file.data, handler, load_truncated && !file.sealed)
+        .await
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let valid_len = match file.sealed {
+        true => file.valid_len,
+        false => stats.valid_len,
+    };
+    if file.truncated || stats.truncated {
+        warn!(
+            "AOF {} was truncated, dropping the last {} bytes",
+            path.display(),
+            len - valid_len
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_len as u64)?;
+    }
+    Ok(stats.commands)
+}
+
+// Load the AOF directory: the base file, a snapshot or commands, then the
+// incremental files in order. Only the last file may end with a truncated
+// command. An AOF file from before the directory layout becomes the base.
+pub async fn load(
+    persistence: &Persistence,
+    store: &Arc<RwLock<CacheStore>>,
+    handler: &mut CmdHandler,
+) -> Result<Manifest> {
+    let dir = &persistence.aof_dir;
+    let prefix = &persistence.aof_prefix;
+    let manifest = match Manifest::load(dir, prefix, &persistence.encryption)? {
+        Some(manifest) => manifest,
+        None => match migrate_single_file(persistence)? {
+            Some(manifest) => manifest,
+            None => return Ok(Manifest::default()),
+        },
+    };
+
+    let files: Vec<&AofInfo> = manifest.base.iter().chain(&manifest.incrs).collect();
+    let mut commands = 0;
+    for (i, info) in files.iter().enumerate() {
+        let path = dir.join(&info.name);
+        if !path.exists() {
+            return Err(anyhow!(
+                "{} listed in the manifest doesn't exist",
+                path.display()
+            ));
+        }
+        let is_last = i + 1 == files.len();
+        let data =
+            fs::read(&path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let len = data.len();
+        let file = persistence
+            .encryption
+            .open_records(data)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        // Only the incremental file appended to last may still lack its final record
+        if info.file_type == AofFileType::Base || !is_last {
+            file.check_finished()
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        }
+
+        if info.file_type == AofFileType::Base && is_snapshot_data(&file.data) {
+            let stats = snapshot::restore_data(&file.data, &mut *store.write().await)
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            info!(
+                "Loaded base {}: {} keys, {} expired skipped",
+                info.name, stats.loaded, stats.expired
+            );
+        } else {
+            let load_truncated = persistence.aof_load_truncated && is_last;
+            commands += load_commands(&path, file, len, handler, load_truncated).await?;
+        }
+    }
+    if commands > 0 {
+        info!("Replayed {} commands from {}", commands, dir.display());
+    }
+    Ok(manifest)
+}
+
+fn is_snapshot_data(data: &[u8]) -> bool {
+    snapshot::is_snapshot(data) || rdb::is_rdb(data)
+}
+
+// Move an AOF file from before the directory layout into the directory as its base
+fn migrate_single_file(persistence: &Persistence) -> Result<Option<Manifest>> {
+    let legacy = persistence.dir.join(&persistence.aof_prefix);
+    if !legacy.is_file() {
+        return Ok(None);
+    }
+    let dir = &persistence.aof_dir;
+    fs::create_dir_all(dir).map_err(|e| anyhow!("Failed to create {}: {}", dir.display(), e))?;
+
+    let manifest = Manifest {
+        base: Some(Manifest::default().next_base(&persistence.aof_prefix, "aof")),
+        incrs: Vec::new(),
+    };
+    let base = &manifest.base.as_ref().unwrap().name;
+    fs::rename(&legacy, dir.join(base))
+        .map_err(|e| anyhow!("Failed to move {}: {}", legacy.display(), e))?;
+    manifest.write(
+        dir,
+        &persistence.aof_prefix,
+        persistence.encryption.keyring.as_deref(),
+    )?;
+    info!(
+        "Moved {} into {} as its base file",
+        legacy.display(),
+        dir.display()
+    );
+    Ok(Some(manifest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_absolute_expiry() {
+        let set = args(&["SET", "k", "v", "EX", "10", "NX"]);
+        let rewritten = absolute_expiry(&set);
+        assert_eq!(rewritten[3], "PXAT");
+        let deadline = rewritten[4].parse::<u64>().unwrap();
+        assert!(deadline > unix_time_ms() + 9_000);
+        assert_eq!(rewritten[5], "NX");
+
+        let expire = absolute_expiry(&args(&["expire", "k", "5"])).into_owned();
+        assert_eq!(expire[0], "PEXPIREAT");
+
+        let get = args(&["GET", "k"]);
+        assert!(matches!(absolute_expiry(&get), Cow::Borrowed(_)));
+        assert_eq!(
+            encode_command(&get),
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_rewrite_commands() {
+        let entries = vec![
+            SnapshotEntry {
+                key: "l".into(),
+                expires_at: Some(42),
+                value: SnapshotValue::List((0..100).map(|i| i.to_string().into_bytes()).collect()),
+            },
+            SnapshotEntry {
+                key: "z".into(),
+                expires_at: None,
+                value: SnapshotValue::SortedSet(vec![(b"m".to_vec(), 1.5)]),
+            },
+        ];
+        let mut out = Vec::new();
+        rewrite_commands(&mut out, &entries).unwrap();
+
+        let mut commands = Vec::new();
+        let mut pos = 0;
+        while let Some((frame, len)) = decode(&out[pos..]).unwrap() {
+            commands.push(extract_command_args(frame).unwrap());
+            pos += len;
+            if pos == out.len() {
+                break;
+            }
+        }
+        assert_eq!(commands.len(), 4);
+        assert_eq!(commands[0].len(), 2 + REWRITE_ITEMS_PER_CMD);
+        assert_eq!(commands[1][..3], args(&["RPUSH", "l", "64"]));
+        assert_eq!(commands[2], args(&["PEXPIREAT", "l", "42"]));
+        assert_eq!(commands[3], args(&["ZADD", "z", "1.5", "m"]));
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_binary_round_trip() {
+        let entries = vec![
+            SnapshotEntry {
+                key: "bin".into(),
+                expires_at: Some(unix_time_ms() + 60_000),
+                value: SnapshotValue::String(b"\xff\x00\xfe".to_vec()),
+            },
+            SnapshotEntry {
+                key: "h".into(),
+                expires_at: None,
+                value: SnapshotValue::Hash(vec![(b"f".to_vec(), b"\xff\x00\xfe".to_vec())]),
+            },
+        ];
+        let mut out = Vec::new();
+        rewrite_commands(&mut out, &entries).unwrap();
+
+        let state = Arc::new(ServerState::new(&Default::default()));
+        let store = Arc::new(RwLock::new(CacheStore::new(4)));
+        let client = state.new_client(([127, 0, 0, 1], 1).into());
+        let (push, _) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = CmdHandler::new(Arc::clone(&store), Arc::clone(&state), client, push);
+        assert_eq!(replay(&out, &mut handler, false).await.unwrap().commands, 2);
+
+        let mut loaded = snapshot::collect(&*store.read().await).unwrap();
+        loaded.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(loaded[0].value, entries[0].value);
+        assert!(loaded[0].expires_at.is_some());
+        assert_eq!(loaded[1], entries[1]);
+    }
+
+    #[test]
+    fn test_sealed_incr_files_are_finished() {
+        let dir = std::env::temp_dir().join(format!("aof-sealed-{}", std::process::id()));
+        let keyring = Arc::new(
+            Keyring::parse("k 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+                .unwrap(),
+        );
+        let encryption = crypto::EncryptionOptions {
+            keyring: Some(keyring.clone()),
+            allow_plaintext: false,
+        };
+        let prefix = "appendonly.aof";
+        let open = |manifest| {
+            AppendOnlyFile::open(
+                &dir,
+                prefix,
+                manifest,
+                AppendFsync::No,
+                Some(keyring.clone()),
+            )
+            .unwrap()
+        };
+        let opened = |name: &str| {
+            encryption
+                .open_records(fs::read(dir.join(name)).unwrap())
+                .unwrap()
+        };
+
+        let mut aof = open(Manifest::default());
+        aof.append(&args(&["SET", "a", "1"])).unwrap();
+        aof.start_rewrite().unwrap();
+        aof.append(&args(&["SET", "b", "2"])).unwrap();
+        let first = aof.manifest.incrs[0].name.clone();
+        assert!(opened(&first).finished);
+        assert!(!opened(&aof.manifest.incrs[1].name).finished);
+
+        // The manifest is sealed: it doesn't load without the key, and loses no file
+        assert!(Manifest::load(&dir, prefix, &Default::default()).is_err());
+        let manifest = Manifest::load(&dir, prefix, &encryption).unwrap().unwrap();
+        assert_eq!(manifest, aof.manifest);
+
+        // Appends never go after a final record, they move to a new file
+        let mut manifest = manifest;
+        manifest.incrs.truncate(1);
+        let aof = open(manifest);
+        assert_eq!(aof.manifest.incrs.len(), 2);
+        assert_ne!(aof.manifest.incrs[1].name, first);
+        assert!(opened(&first).finished);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        let mut data = encode_command(&["SET", "k", "v"]);
+        let first = data.len();
+        data.extend_from_slice(&encode_command(&["DEL", "k"]));
+        assert_eq!(check(&data), Ok(2));
+
+        let cut = &data[..data.len() - 3];
+        assert_eq!(check(cut).unwrap_err().offset, first);
+        data[first] = b'?';
+        assert_eq!(check(&data).unwrap_err().offset, first);
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::{self, Write};
+
+// CRC-64/Jones as used by Redis: reflected, polynomial 0xad93d23594c935a9, zero
+// initial value and no final xor
+const POLY: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// Continue a checksum over `data`, start from 0
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, byte| {
+        TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// Checksums everything written through it
+pub struct Crc64Writer<W> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> Crc64Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, crc: 0 }
+    }
+
+    pub fn crc(&self) -> u64 {
+        self.crc
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Crc64Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc64(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc64_check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        let split = crc64(crc64(0, b"1234"), b"56789");
+        assert_eq!(split, 0xe9c6d914c4b8d9ca);
+    }
+}
@@ -0,0 +1,495 @@
+// Encryption at rest. With `encryption-key-file` set, the snapshot, RDB and AOF
+// files are sealed with AES-256-GCM:
+//
+//   "DSENC" | version u8 | key id length u8 | key id | records... | final record
+//   record: ciphertext length u32 LE | 12-byte nonce | ciphertext and 16-byte tag
+//
+// Every record authenticates the header and its own offset, so records can't be
+// reordered or moved between files, and a file sealed with another key is caught
+// before anything is decrypted. Whole files are written as a run of records, the
+// AOF seals each append as one. A complete file ends with a final record, which
+// has no plaintext and authenticates as the last one: a file cut short between
+// two records is missing it. The AOF incremental file being appended to gets its
+// final record once appends move on to the next one, and the manifest is sealed
+// too, so dropping one of the files it lists is caught as well.
+//
+// The key file holds one `<key id> <64 hex digits>` per line. The first key seals
+// new files, the others only open older ones, so a key is rotated by adding the
+// new one on top and dropping the old one once no file uses it.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng, Payload},
+};
+use anyhow::{Result, anyhow};
+
+use crate::utils::from_hex;
+
+const MAGIC: &[u8; 5] = b"DSENC";
+const VERSION: u8 = 2;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// Plaintext per record when sealing a whole file
+const RECORD_SIZE: usize = 64 * 1024;
+// The final record: its length, nonce and the tag of an empty plaintext
+pub const FINAL_RECORD_LEN: usize = 4 + NONCE_LEN + TAG_LEN;
+
+// The keys from the key file
+pub struct Keyring {
+    active: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<&String> = self.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("Keyring")
+            .field("active", &self.active)
+            .field("ids", &ids)
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read key file {}: {}", path.display(), e))?;
+        Self::parse(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut active = None;
+        let mut keys = HashMap::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, hex) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("Expected a key id and a key: {}", line))?;
+            if id.len() > u8::MAX as usize {
+                return Err(anyhow!("Key id {} is too long", id));
+            }
+            let key = from_hex(hex.trim())
+                .filter(|key| key.len() == 32)
+                .ok_or_else(|| anyhow!("Key {} isn't 64 hex digits", id))?;
+            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+            if keys.insert(id.to_string(), cipher).is_some() {
+                return Err(anyhow!("Key id {} is listed twice", id));
+            }
+            active.get_or_insert_with(|| id.to_string());
+        }
+        let active = active.ok_or_else(|| anyhow!("No keys"))?;
+        Ok(Self { active, keys })
+    }
+
+    pub fn active_id(&self) -> &str {
+        &self.active
+    }
+
+    // Whether a sealed file of `len` bytes ends with its final record, from its
+    // header and its last FINAL_RECORD_LEN bytes
+    pub fn ends_finished(&self, head: &[u8], tail: &[u8], len: u64) -> Result<bool> {
+        let (id, header_len) = parse_header(head)?;
+        if tail.len() != FINAL_RECORD_LEN || len < (header_len + FINAL_RECORD_LEN) as u64 {
+            return Ok(false);
+        }
+        let offset = len - FINAL_RECORD_LEN as u64;
+        Ok(open_record(self.cipher(id)?, &head[..header_len], offset, tail, true).is_some())
+    }
+
+    fn cipher(&self, id: &str) -> Result<&Aes256Gcm> {
+        self.keys.get(id).ok_or_else(|| {
+            anyhow!(
+                "File is sealed with key {}, which isn't in the key file",
+                id
+            )
+        })
+    }
+}
+
+// Whether the files written are sealed, and whether plain files still load, e.g.
+// while moving an existing dataset over to encryption
+#[derive(Debug, Clone, Default)]
+pub struct EncryptionOptions {
+    pub keyring: Option<Arc<Keyring>>,
+    pub allow_plaintext: bool,
+}
+
+// The plaintext of a file
+#[derive(Debug, Default)]
+pub struct Opened {
+    pub data: Vec<u8>,
+    pub sealed: bool,
+    // The file ends in a partial record, `valid_len` being where it starts
+    pub truncated: bool,
+    pub valid_len: usize,
+    // The sealed file ends with its final record, nothing was cut off at a record
+    // boundary
+    pub finished: bool,
+}
+
+impl EncryptionOptions {
+    // Decrypt a file, refusing one that is cut short
+    pub fn open(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let opened = self.open_records(data)?;
+        opened.check_finished()?;
+        Ok(opened.data)
+    }
+
+    // Decrypt every complete record of a file. Fails on a record that doesn't
+    // authenticate and on files the options don't allow.
+    pub fn open_records(&self, data: Vec<u8>) -> Result<Opened> {
+        if !is_sealed(&data) {
+            if self.keyring.is_some() && !self.allow_plaintext && !data.is_empty() {
+                return Err(anyhow!(
+                    "File isn't encrypted, set encryption-allow-plaintext to load it"
+                ));
+            }
+            return Ok(Opened {
+                valid_len: data.len(),
+                data,
+                ..Default::default()
+            });
+        }
+        let keyring = self.keyring.as_ref().ok_or_else(|| {
+            anyhow!("File is encrypted, but no encryption-key-file is configured")
+        })?;
+        let (id, header_len) = parse_header(&data)?;
+        let cipher = keyring.cipher(id)?;
+        let header = &data[..header_len];
+
+        let mut plain = Vec::new();
+        let mut pos = header_len;
+        let mut finished = false;
+        while pos < data.len() {
+            if finished {
+                return Err(anyhow!("Data after the final record at offset {}", pos));
+            }
+            let Some(len) = data.get(pos..pos + 4) else {
+                break;
+            };
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            let end = pos + 4 + NONCE_LEN + len;
+            if end > data.len() {
+                break;
+            }
+            // Only the final record has no plaintext
+            finished = len == TAG_LEN;
+            let record = open_record(cipher, header, pos as u64, &data[pos..end], finished)
+                .ok_or_else(|| anyhow!("Record at offset {} doesn't authenticate", pos))?;
+            plain.extend_from_slice(&record);
+            pos = end;
+        }
+        Ok(Opened {
+            data: plain,
+            sealed: true,
+            truncated: pos < data.len(),
+            valid_len: pos,
+            finished,
+        })
+    }
+}
+
+impl Opened {
+    // Refuse a sealed file that doesn't end with its final record
+    pub fn check_finished(&self) -> Result<()> {
+        if self.truncated {
+            return Err(anyhow!(
+                "Encrypted file ends in a partial record at offset {}",
+                self.valid_len
+            ));
+        }
+        if self.sealed && !self.finished {
+            return Err(anyhow!(
+                "Encrypted file is cut short, its final record is missing"
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// The key id a sealed file names and the length of its header
+fn parse_header(data: &[u8]) -> Result<(&str, usize)> {
+    let version = *data
+        .get(MAGIC.len())
+        .ok_or_else(|| anyhow!("Truncated encryption header"))?;
+    if version != VERSION {
+        return Err(anyhow!("Unsupported encryption version {}", version));
+    }
+    let id_len = *data
+        .get(MAGIC.len() + 1)
+        .ok_or_else(|| anyhow!("Truncated encryption header"))? as usize;
+    let start = MAGIC.len() + 2;
+    let id = data
+        .get(start..start + id_len)
+        .ok_or_else(|| anyhow!("Truncated encryption header"))?;
+    let id = std::str::from_utf8(id).map_err(|_| anyhow!("Bad key id in encryption header"))?;
+    Ok((id, start + id_len))
+}
+
+// The key id of a sealed file from its first bytes
+pub fn key_id(head: &[u8]) -> Option<&str> {
+    if !is_sealed(head) {
+        return None;
+    }
+    parse_header(head).ok().map(|(id, _)| id)
+}
+
+// The longest header there is
+pub const MAX_HEADER_LEN: usize = MAGIC.len() + 2 + u8::MAX as usize;
+
+fn record_aad(header: &[u8], offset: u64, last: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&offset.to_le_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+// The plaintext of the whole `record` at `offset`, None if it doesn't authenticate
+fn open_record(
+    cipher: &Aes256Gcm,
+    header: &[u8],
+    offset: u64,
+    record: &[u8],
+    last: bool,
+) -> Option<Vec<u8>> {
+    let nonce = Nonce::from_slice(record.get(4..4 + NONCE_LEN)?);
+    let aad = record_aad(header, offset, last);
+    let payload = Payload {
+        msg: &record[4 + NONCE_LEN..],
+        aad: &aad,
+    };
+    cipher.decrypt(nonce, payload).ok()
+}
+
+// Seals records with the active key for a file at a given offset. Nonces are
+// random, which is safe for far more records than a key should ever seal.
+pub struct Sealer {
+    cipher: Aes256Gcm,
+    header: Vec<u8>,
+    offset: u64,
+}
+
+impl fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sealer")
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl Sealer {
+    // Records for a file of `len` bytes, which starts with the header unless it is empty
+    pub fn new(keyring: &Keyring, len: u64) -> Self {
+        let id = keyring.active_id();
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.push(id.len() as u8);
+        header.extend_from_slice(id.as_bytes());
+        Self {
+            cipher: keyring.keys[id].clone(),
+            offset: len.max(header.len() as u64),
+            header,
+        }
+    }
+
+    // Records for an existing sealed file of `len` bytes starting with `head`, with
+    // the key it names rather than the active one
+    pub fn for_file(keyring: &Keyring, head: &[u8], len: u64) -> Result<Self> {
+        let (id, header_len) = parse_header(head)?;
+        Ok(Self {
+            cipher: keyring.cipher(id)?.clone(),
+            header: head[..header_len].to_vec(),
+            offset: len,
+        })
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    // One record holding `plaintext`, to be written at the current offset
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        if plaintext.is_empty() {
+            return Err(anyhow!("Can't seal an empty record"));
+        }
+        self.seal_record(plaintext, false)
+    }
+
+    // The final record, after which nothing more is appended
+    pub fn finish(&mut self) -> Result<Vec<u8>> {
+        self.seal_record(&[], true)
+    }
+
+    fn seal_record(&mut self, plaintext: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = record_aad(&self.header, self.offset, last);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &aad,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow!("Encryption failed"))?;
+        let mut record = Vec::with_capacity(4 + NONCE_LEN + ciphertext.len());
+        record.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&ciphertext);
+        self.offset += record.len() as u64;
+        Ok(record)
+    }
+}
+
+// Seals everything written to it into a new file, a record per `RECORD_SIZE`
+// bytes. `finish` writes what is left and the final record.
+pub struct SealWriter<W: Write> {
+    sealer: Sealer,
+    out: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SealWriter<W> {
+    pub fn new(keyring: &Keyring, mut out: W) -> Result<Self> {
+        let sealer = Sealer::new(keyring, 0);
+        out.write_all(sealer.header())?;
+        Ok(Self {
+            sealer,
+            out,
+            buf: Vec::with_capacity(RECORD_SIZE + TAG_LEN),
+        })
+    }
+
+    fn seal_buffered(&mut self) -> io::Result<()> {
+        let record = self.sealer.seal(&self.buf).map_err(io::Error::other)?;
+        self.out.write_all(&record)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        if !self.buf.is_empty() {
+            self.seal_buffered()?;
+        }
+        let record = self.sealer.finish()?;
+        self.out.write_all(&record)?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(RECORD_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == RECORD_SIZE {
+            self.seal_buffered()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: &str = "# rotated in March\n\
+        new 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n\
+        old 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100\n";
+
+    fn seal_file(keyring: &Keyring, data: &[u8]) -> Vec<u8> {
+        let mut out = SealWriter::new(keyring, Vec::new()).unwrap();
+        out.write_all(data).unwrap();
+        out.finish().unwrap()
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let keyring = Arc::new(Keyring::parse(KEYS).unwrap());
+        let options = EncryptionOptions {
+            keyring: Some(keyring.clone()),
+            allow_plaintext: false,
+        };
+        let data: Vec<u8> = (0..RECORD_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let sealed = seal_file(&keyring, &data);
+        assert_eq!(key_id(&sealed), Some("new"));
+        assert_eq!(options.open(sealed.clone()).unwrap(), data);
+
+        // Files sealed with an older key still open
+        let old = Keyring::parse(&KEYS.lines().skip(2).collect::<Vec<_>>().join("\n")).unwrap();
+        let mut old_file = seal_file(&old, b"hello");
+        assert_eq!(options.open(old_file.clone()).unwrap(), b"hello");
+
+        // Tampering, truncation, unknown keys and plain files are refused
+        let last = old_file.len() - 1;
+        old_file[last] ^= 1;
+        assert!(options.open(old_file).is_err());
+        let opened = options
+            .open_records(sealed[..sealed.len() - 1].to_vec())
+            .unwrap();
+        assert!(opened.truncated);
+        assert_eq!(opened.data, data);
+        assert!(options.open(sealed[..sealed.len() - 1].to_vec()).is_err());
+
+        // Cut at a record boundary, the final record is missing
+        for cut in [
+            FINAL_RECORD_LEN,
+            FINAL_RECORD_LEN + 4 + NONCE_LEN + 10 + TAG_LEN,
+        ] {
+            let opened = options
+                .open_records(sealed[..sealed.len() - cut].to_vec())
+                .unwrap();
+            assert!(!opened.truncated && !opened.finished);
+            assert!(options.open(sealed[..sealed.len() - cut].to_vec()).is_err());
+        }
+        let header_len = MAGIC.len() + 2 + "new".len();
+        assert!(
+            keyring
+                .ends_finished(
+                    &sealed,
+                    &sealed[sealed.len() - FINAL_RECORD_LEN..],
+                    sealed.len() as u64
+                )
+                .unwrap()
+        );
+        assert!(
+            !keyring
+                .ends_finished(
+                    &sealed[..header_len],
+                    &sealed[sealed.len() - FINAL_RECORD_LEN - 1..sealed.len() - 1],
+                    sealed.len() as u64 - 1
+                )
+                .unwrap()
+        );
+        // Nothing may follow the final record
+        let mut appended = sealed.clone();
+        appended.extend(
+            Sealer::new(&keyring, sealed.len() as u64)
+                .seal(b"more")
+                .unwrap(),
+        );
+        assert!(options.open_records(appended).is_err());
+        let other = Keyring::parse(&KEYS.replace("new", "other")).unwrap();
+        assert!(options.open(seal_file(&other, b"x")).is_err());
+        assert!(options.open(b"plain".to_vec()).is_err());
+        assert!(EncryptionOptions::default().open(sealed).is_err());
+    }
+}
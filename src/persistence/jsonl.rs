@@ -0,0 +1,273 @@
+// Dataset export and import as JSON Lines, one key per line:
+//
+//   {"key":"user:1","type":"hash","pttl":5000,"value":[["name","ada"]]}
+//
+// `pttl` is the remaining TTL in milliseconds, null without one. Strings,
+// elements, members, fields and values that aren't valid UTF-8 are written as
+// {"base64":"..."}. Values by type: string, a single string; list and set, an
+// array; zset, [member, score] pairs; hash, [field, value] pairs. Scores that
+// JSON can't hold are the strings "inf" and "-inf".
+
+use std::{
+    borrow::Cow,
+    io::{BufRead, Write},
+};
+
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value, json};
+
+use crate::{
+    persistence::snapshot::{self, SnapshotEntry, SnapshotValue},
+    storage::CacheStore,
+    utils::{glob_match, unix_time_ms},
+};
+
+// Keys written by `export`, or created, skipped and dropped as expired by `import`
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportStats {
+    pub imported: usize,
+    pub skipped: usize,
+    pub expired: usize,
+}
+
+// Write the entries whose key matches `pattern` as JSON Lines
+pub fn export<W: Write>(
+    mut out: W,
+    entries: &[SnapshotEntry],
+    pattern: Option<&str>,
+) -> Result<usize> {
+    let now = unix_time_ms();
+    let mut exported = 0;
+    for entry in entries {
+        if pattern.is_some_and(|p| !glob_match(p.as_bytes(), entry.key.as_bytes())) {
+            continue;
+        }
+        writeln!(out, "{}", encode_entry(entry, now)?)?;
+        exported += 1;
+    }
+    out.flush()?;
+    Ok(exported)
+}
+
+// Read JSON Lines into the store. Keys not matching `pattern` are ignored,
+// existing keys are kept unless `replace`.
+pub fn import<R: BufRead>(
+    input: R,
+    store: &mut CacheStore,
+    pattern: Option<&str>,
+    replace: bool,
+) -> Result<ImportStats> {
+    let now = unix_time_ms();
+    let mut stats = ImportStats::default();
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = parse_entry(&line, now).map_err(|e| anyhow!("line {}: {}", n + 1, e))?;
+        if pattern.is_some_and(|p| !glob_match(p.as_bytes(), entry.key.as_bytes())) {
+            continue;
+        }
+        if entry.expires_at.is_some_and(|at| at <= now) {
+            stats.expired += 1;
+        } else if !replace && store.exists(vec![entry.key.clone()]) > 0 {
+            stats.skipped += 1;
+        } else {
+            snapshot::restore(store, vec![entry]).map_err(|e| anyhow!("line {}: {}", n + 1, e))?;
+            stats.imported += 1;
+        }
+    }
+    Ok(stats)
+}
+
+// One line as `export` writes it and `import` reads it back
+#[derive(Serialize, Deserialize)]
+struct Line<'a> {
+    #[serde(borrow)]
+    key: Cow<'a, str>,
+    #[serde(rename = "type")]
+    type_name: Cow<'a, str>,
+    pttl: Option<i64>,
+    value: Value,
+}
+
+fn encode_entry(entry: &SnapshotEntry, now: u64) -> Result<String> {
+    let (type_name, value) = match &entry.value {
+        SnapshotValue::String(s) => ("string", bytes_to_json(s)),
+        SnapshotValue::List(elements) => {
+            ("list", elements.iter().map(|e| bytes_to_json(e)).collect())
+        }
+        SnapshotValue::Set(members) => ("set", members.iter().map(|m| bytes_to_json(m)).collect()),
+        SnapshotValue::SortedSet(members) => (
+            "zset",
+            members
+                .iter()
+                .map(|(member, score)| {
+                    let score = match Number::from_f64(*score) {
+                        Some(score) => Value::Number(score),
+                        None if *score > 0.0 => Value::from("inf"),
+                        None => Value::from("-inf"),
+                    };
+                    Value::Array(vec![bytes_to_json(member), score])
+                })
+                .collect(),
+        ),
+        SnapshotValue::Hash(fields) => (
+            "hash",
+            fields
+                .iter()
+                .map(|(field, value)| {
+                    Value::Array(vec![bytes_to_json(field), bytes_to_json(value)])
+                })
+                .collect(),
+        ),
+    };
+    let line = Line {
+        key: Cow::Borrowed(&entry.key),
+        type_name: Cow::Borrowed(type_name),
+        pttl: entry.expires_at.map(|at| at.saturating_sub(now) as i64),
+        value,
+    };
+    Ok(serde_json::to_string(&line)?)
+}
+
+// A string, or {"base64": ...} for binary data
+fn bytes_to_json(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Value::from(s),
+        Err(_) => json!({ "base64": STANDARD.encode(bytes) }),
+    }
+}
+
+fn json_to_bytes(value: &Value) -> Result<Vec<u8>> {
+    match value {
+        Value::String(s) => Ok(s.clone().into_bytes()),
+        Value::Object(fields) if fields.len() == 1 => match fields.get("base64") {
+            Some(Value::String(encoded)) => STANDARD
+                .decode(encoded)
+                .map_err(|_| anyhow!("Invalid base64")),
+            _ => Err(anyhow!("Expected a string")),
+        },
+        _ => Err(anyhow!("Expected a string")),
+    }
+}
+
+fn json_to_score(value: &Value) -> Result<f64> {
+    match value {
+        Value::Number(score) => score.as_f64().ok_or_else(|| anyhow!("Invalid score")),
+        Value::String(s) if s == "inf" => Ok(f64::INFINITY),
+        Value::String(s) if s == "-inf" => Ok(f64::NEG_INFINITY),
+        _ => Err(anyhow!("Invalid score")),
+    }
+}
+
+fn pair(value: &Value) -> Result<[&Value; 2]> {
+    match value {
+        Value::Array(items) if items.len() == 2 => Ok([&items[0], &items[1]]),
+        _ => Err(anyhow!("Expected a pair")),
+    }
+}
+
+fn map_array<T>(value: &Value, f: impl Fn(&Value) -> Result<T>) -> Result<Vec<T>> {
+    match value {
+        Value::Array(items) => items.iter().map(f).collect(),
+        _ => Err(anyhow!("Expected an array")),
+    }
+}
+
+// serde_json refuses input nested deeper than 128 levels
+fn parse_entry(line: &str, now: u64) -> Result<SnapshotEntry> {
+    let line: Line = serde_json::from_str(line)?;
+    let expires_at = match line.pttl {
+        None => None,
+        Some(pttl) if pttl <= 0 => Some(now),
+        Some(pttl) => Some(now + pttl as u64),
+    };
+
+    let value = &line.value;
+    let value = match line.type_name.as_ref() {
+        "string" => SnapshotValue::String(json_to_bytes(value)?),
+        "list" => SnapshotValue::List(map_array(value, json_to_bytes)?),
+        "set" => SnapshotValue::Set(map_array(value, json_to_bytes)?),
+        "zset" => SnapshotValue::SortedSet(map_array(value, |item| {
+            let [member, score] = pair(item)?;
+            Ok((json_to_bytes(member)?, json_to_score(score)?))
+        })?),
+        "hash" => SnapshotValue::Hash(map_array(value, |item| {
+            let [field, value] = pair(item)?;
+            Ok((json_to_bytes(field)?, json_to_bytes(value)?))
+        })?),
+        other => return Err(anyhow!("Unknown type {}", other)),
+    };
+    Ok(SnapshotEntry {
+        key: line.key.into_owned(),
+        expires_at,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let entries = vec![
+            SnapshotEntry {
+                key: "s\"q".into(),
+                expires_at: None,
+                value: SnapshotValue::String(vec![0xff, 0x00, b'a']),
+            },
+            SnapshotEntry {
+                key: "z".into(),
+                expires_at: Some(unix_time_ms() + 60_000),
+                value: SnapshotValue::SortedSet(vec![
+                    ("é\n".into(), 1.5),
+                    ("top".into(), f64::INFINITY),
+                ]),
+            },
+            SnapshotEntry {
+                key: "h".into(),
+                expires_at: None,
+                value: SnapshotValue::Hash(vec![("f".into(), "v".into())]),
+            },
+        ];
+        let mut out = Vec::new();
+        assert_eq!(export(&mut out, &entries, None).unwrap(), 3);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with(
+            r#"{"key":"s\"q","type":"string","pttl":null,"value":{"base64":"/wBh"}}"#
+        ));
+
+        let mut store = CacheStore::new(4);
+        let stats = import(text.as_bytes(), &mut store, None, false).unwrap();
+        assert_eq!(stats.imported, 3);
+        let mut copy = snapshot::collect(&store).unwrap();
+        copy.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(copy[0], entries[2]);
+        assert_eq!(copy[1], entries[0]);
+        assert_eq!(copy[2].value, entries[1].value);
+
+        // Existing keys are kept without `replace`, the pattern filters keys
+        let stats = import(text.as_bytes(), &mut store, Some("[hz]"), false).unwrap();
+        assert_eq!(
+            stats,
+            ImportStats {
+                imported: 0,
+                skipped: 2,
+                expired: 0
+            }
+        );
+        assert!(import(&b"{\"key\":1}"[..], &mut store, None, true).is_err());
+
+        // Deeply nested input is an error, not a stack overflow
+        let nested = format!(
+            r#"{{"key":"k","type":"list","pttl":null,"value":{}{}}}"#,
+            "[".repeat(100_000),
+            "]".repeat(100_000)
+        );
+        assert!(import(nested.as_bytes(), &mut store, None, true).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+// LZF, the compression RDB files use for strings. A stream of literal runs and
+// back references:
+//
+//   000LLLLL <L+1 literal bytes>
+//   LLLooooo oooooooo            copy L+2 bytes from `offset + 1` bytes back
+//   111ooooo LLLLLLLL oooooooo   same, with L = 7 + the extra length byte
+
+use anyhow::{Result, anyhow};
+
+const HASH_LOG: usize = 14;
+const MAX_LITERAL: usize = 32;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_MATCH: usize = (1 << 8) + (1 << 3);
+
+// `expected_len` comes from untrusted input: it is checked against what `input`
+// can expand to before anything is allocated
+pub fn decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>> {
+    // At most MAX_MATCH bytes per 3-byte back reference
+    if expected_len > input.len().saturating_mul(MAX_MATCH / 3) {
+        return Err(anyhow!(
+            "LZF output of {} bytes is impossible from {} bytes of input",
+            expected_len,
+            input.len()
+        ));
+    }
+    let mut out = Vec::with_capacity(expected_len.min(input.len().saturating_mul(4)));
+    let mut ip = 0;
+
+    while ip < input.len() {
+        if out.len() > expected_len {
+            break;
+        }
+        let ctrl = input[ip] as usize;
+        ip += 1;
+
+        if ctrl < MAX_LITERAL {
+            let run = ctrl + 1;
+            let literal = input
+                .get(ip..ip + run)
+                .ok_or_else(|| anyhow!("LZF literal run past the end of input"))?;
+            out.extend_from_slice(literal);
+            ip += run;
+            continue;
+        }
+
+        let mut len = ctrl >> 5;
+        if len == 7 {
+            len += *input
+                .get(ip)
+                .ok_or_else(|| anyhow!("LZF back reference past the end of input"))?
+                as usize;
+            ip += 1;
+        }
+        let low = *input
+            .get(ip)
+            .ok_or_else(|| anyhow!("LZF back reference past the end of input"))?
+            as usize;
+        ip += 1;
+
+        let back = ((ctrl & 0x1f) << 8) + low + 1;
+        if back > out.len() {
+            return Err(anyhow!("LZF back reference before the start of output"));
+        }
+        // The reference may overlap the bytes being written, copy one at a time
+        let start = out.len() - back;
+        for i in 0..len + 2 {
+            out.push(out[start + i]);
+        }
+    }
+
+    if out.len() != expected_len {
+        return Err(anyhow!(
+            "LZF output is {} bytes, expected {}",
+            out.len(),
+            expected_len
+        ));
+    }
+    Ok(out)
+}
+
+// Compress `input`, None when that would not make it smaller
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len());
+    // Last position + 1 of every 3 byte sequence hash, 0 when unseen
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut literal_start = 0;
+    let mut literals = 0;
+    let mut ip = 0;
+    out.push(0);
+
+    while ip + 2 < input.len() {
+        let hash = hash3(&input[ip..ip + 3]);
+        let candidate = table[hash];
+        table[hash] = ip + 1;
+
+        if candidate > 0 {
+            let r = candidate - 1;
+            let back = ip - r;
+            if back <= MAX_OFFSET && input[r..r + 3] == input[ip..ip + 3] {
+                let max_len = (input.len() - ip).min(MAX_MATCH);
+                let mut len = 3;
+                while len < max_len && input[r + len] == input[ip + len] {
+                    len += 1;
+                }
+
+                // Close the pending literal run
+                if literals > 0 {
+                    out[literal_start] = (literals - 1) as u8;
+                } else {
+                    out.pop();
+                }
+
+                let offset = back - 1;
+                let encoded = len - 2;
+                if encoded < 7 {
+                    out.push(((offset >> 8) as u8) | ((encoded as u8) << 5));
+                } else {
+                    out.push(((offset >> 8) as u8) | (7 << 5));
+                    out.push((encoded - 7) as u8);
+                }
+                out.push(offset as u8);
+
+                ip += len;
+                literals = 0;
+                literal_start = out.len();
+                out.push(0);
+                if out.len() >= input.len() {
+                    return None;
+                }
+                continue;
+            }
+        }
+
+        push_literal(&mut out, &mut literal_start, &mut literals, input[ip]);
+        ip += 1;
+        if out.len() >= input.len() {
+            return None;
+        }
+    }
+    while ip < input.len() {
+        push_literal(&mut out, &mut literal_start, &mut literals, input[ip]);
+        ip += 1;
+    }
+
+    if literals > 0 {
+        out[literal_start] = (literals - 1) as u8;
+    } else {
+        out.pop();
+    }
+    (out.len() < input.len()).then_some(out)
+}
+
+fn push_literal(out: &mut Vec<u8>, literal_start: &mut usize, literals: &mut usize, byte: u8) {
+    out.push(byte);
+    *literals += 1;
+    if *literals == MAX_LITERAL {
+        out[*literal_start] = (MAX_LITERAL - 1) as u8;
+        *literals = 0;
+        *literal_start = out.len();
+        out.push(0);
+    }
+}
+
+fn hash3(bytes: &[u8]) -> usize {
+    let v = ((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize;
+    (v.wrapping_mul(2654435761) >> 8) & ((1 << HASH_LOG) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lzf_round_trip() {
+        let input = "abcabcabcabc hello hello hello ".repeat(40).into_bytes();
+        let compressed = compress(&input).unwrap();
+        assert!(compressed.len() < input.len() / 4);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+
+        assert!(compress(b"no repeats here").is_none());
+        assert!(decompress(&[0x20, 0x05], 10).is_err());
+        // A length no input this short expands to is refused before allocating
+        assert!(decompress(&[], 1 << 40).is_err());
+        assert!(decompress(&[0x00, b'a'], 1 << 40).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+// The AOF manifest lists the files in the AOF directory that make up the
+// dataset, one per line, in the layout Redis 7 uses:
+//
+//   file appendonly.aof.2.base.rdb seq 2 type b
+//   file appendonly.aof.3.incr.aof seq 3 type i
+//
+// Loading reads the base file, then replays the incremental files in order. With
+// encryption on the manifest is sealed like the files it lists.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::persistence::{
+    crypto::{EncryptionOptions, Keyring},
+    write_atomic,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AofFileType {
+    // Snapshot or rewritten commands the incremental files build on
+    Base,
+    // Write commands logged since the base was written
+    Incr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AofInfo {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: AofFileType,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    pub base: Option<AofInfo>,
+    pub incrs: Vec<AofInfo>,
+}
+
+impl Manifest {
+    pub fn path(dir: &Path, prefix: &str) -> PathBuf {
+        dir.join(format!("{}.manifest", prefix))
+    }
+
+    // The manifest in `dir`, None when there is none yet
+    pub fn load(dir: &Path, prefix: &str, encryption: &EncryptionOptions) -> Result<Option<Self>> {
+        let path = Self::path(dir, prefix);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+        };
+        encryption
+            .open(data)
+            .and_then(|data| String::from_utf8(data).map_err(|_| anyhow!("Not UTF-8")))
+            .and_then(|text| Self::parse(&text))
+            .map(Some)
+            .map_err(|e| anyhow!("Invalid AOF manifest {}: {}", path.display(), e))
+    }
+
+    // Replace the manifest in `dir` atomically, sealed with a keyring
+    pub fn write(&self, dir: &Path, prefix: &str, keyring: Option<&Keyring>) -> Result<()> {
+        write_atomic(&Self::path(dir, prefix), keyring, |out| {
+            out.write_all(self.encode().as_bytes())?;
+            Ok(())
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut manifest = Manifest::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if !words.len().is_multiple_of(2) {
+                return Err(anyhow!("Invalid line: {}", line));
+            }
+
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+            for pair in words.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => file_type = Some(pair[1]),
+                    // Unknown keys are left for newer versions
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(file_type)) = (name, seq, file_type) else {
+                return Err(anyhow!("Invalid line: {}", line));
+            };
+
+            match file_type {
+                "b" if manifest.base.is_some() => return Err(anyhow!("Found duplicate base file")),
+                "b" => {
+                    manifest.base = Some(AofInfo {
+                        name,
+                        seq,
+                        file_type: AofFileType::Base,
+                    })
+                }
+                "i" => {
+                    if manifest.incrs.last().is_some_and(|last| last.seq >= seq) {
+                        return Err(anyhow!("Incremental files out of order: {}", name));
+                    }
+                    manifest.incrs.push(AofInfo {
+                        name,
+                        seq,
+                        file_type: AofFileType::Incr,
+                    });
+                }
+                // History files from Redis are waiting to be deleted
+                "h" => {}
+                _ => return Err(anyhow!("Unknown file type: {}", file_type)),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn encode(&self) -> String {
+        let mut text = String::new();
+        for info in self.base.iter().chain(&self.incrs) {
+            let file_type = match info.file_type {
+                AofFileType::Base => "b",
+                AofFileType::Incr => "i",
+            };
+            text.push_str(&format!(
+                "file {} seq {} type {}\n",
+                info.name, info.seq, file_type
+            ));
+        }
+        text
+    }
+
+    // A base file named after the next base sequence, `extension` telling its format
+    pub fn next_base(&self, prefix: &str, extension: &str) -> AofInfo {
+        let seq = self.base.as_ref().map_or(1, |base| base.seq + 1);
+        AofInfo {
+            name: format!("{}.{}.base.{}", prefix, seq, extension),
+            seq,
+            file_type: AofFileType::Base,
+        }
+    }
+
+    // Append a new incremental file and return it
+    pub fn add_incr(&mut self, prefix: &str) -> &AofInfo {
+        let seq = self.incrs.last().map_or(1, |incr| incr.seq + 1);
+        self.incrs.push(AofInfo {
+            name: format!("{}.{}.incr.aof", prefix, seq),
+            seq,
+            file_type: AofFileType::Incr,
+        });
+        self.incrs.last().unwrap()
+    }
+
+    // Files listed here that `newer` no longer lists
+    pub fn obsolete_in<'a>(&'a self, newer: &'a Manifest) -> impl Iterator<Item = &'a str> {
+        self.base
+            .iter()
+            .chain(&self.incrs)
+            .filter(|info| {
+                !newer
+                    .base
+                    .iter()
+                    .chain(&newer.incrs)
+                    .any(|kept| kept.name == info.name)
+            })
+            .map(|info| info.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let mut manifest = Manifest::default();
+        manifest.base = Some(manifest.next_base("appendonly.aof", "rdb"));
+        manifest.add_incr("appendonly.aof");
+        manifest.add_incr("appendonly.aof");
+
+        let text = manifest.encode();
+        assert_eq!(
+            text,
+            "file appendonly.aof.1.base.rdb seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert_eq!(Manifest::parse(&text).unwrap(), manifest);
+
+        let rewritten = Manifest {
+            base: Some(manifest.next_base("appendonly.aof", "rdb")),
+            incrs: manifest.incrs[1..].to_vec(),
+        };
+        let obsolete: Vec<&str> = manifest.obsolete_in(&rewritten).collect();
+        assert_eq!(
+            obsolete,
+            ["appendonly.aof.1.base.rdb", "appendonly.aof.1.incr.aof"]
+        );
+
+        assert!(Manifest::parse("file a seq 2 type i\nfile b seq 1 type i\n").is_err());
+        assert!(Manifest::parse("file a seq 1 type x\n").is_err());
+    }
+}
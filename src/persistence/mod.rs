@@ -0,0 +1,521 @@
+pub mod aof;
+pub mod crc64;
+pub mod crypto;
+pub mod jsonl;
+pub mod lzf;
+pub mod manifest;
+pub mod rdb;
+pub mod snapshot;
+
+use std::{
+    borrow::Borrow,
+    fmt,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    config::CacheConfig,
+    persistence::{
+        aof::{AppendFsync, AppendOnlyFile},
+        crypto::{EncryptionOptions, Keyring, SealWriter},
+        manifest::{AofInfo, Manifest},
+        snapshot::SnapshotEntry,
+    },
+    storage::CacheStore,
+    utils::unix_time_ms,
+};
+
+// File format SAVE and BGSAVE write. Loading detects the format from the file.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SnapshotFormat {
+    #[default]
+    Native,
+    // Redis RDB, readable by Redis itself
+    Rdb,
+}
+
+impl SnapshotFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "native" => Ok(SnapshotFormat::Native),
+            "rdb" => Ok(SnapshotFormat::Rdb),
+            _ => Err(anyhow!("Invalid snapshot format: {}", value)),
+        }
+    }
+}
+
+// Where a snapshot, RDB or AOF file stops being readable
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corruption {
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl Corruption {
+    pub fn new(offset: usize, reason: impl fmt::Display) -> Self {
+        Self {
+            offset,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.reason, self.offset)
+    }
+}
+
+impl std::error::Error for Corruption {}
+
+// `save <seconds> <changes>`: snapshot once this many changes happened and this
+// many seconds passed since the last save
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl SavePoint {
+    // Redis's defaults: after an hour with 1 change, 5 minutes with 100, a minute with 10000
+    pub fn defaults() -> Vec<SavePoint> {
+        [(3600, 1), (300, 100), (60, 10000)]
+            .into_iter()
+            .map(|(seconds, changes)| SavePoint { seconds, changes })
+            .collect()
+    }
+
+    // Pairs of seconds and changes, `""` for none
+    pub fn parse_list(value: &str) -> Result<Vec<SavePoint>> {
+        let words: Vec<&str> = value.trim_matches('"').split_whitespace().collect();
+        if !words.len().is_multiple_of(2) {
+            return Err(anyhow!("Invalid save parameters: {}", value));
+        }
+        words
+            .chunks(2)
+            .map(
+                |pair| match (pair[0].parse::<u64>(), pair[1].parse::<u64>()) {
+                    (Ok(seconds), Ok(changes)) => Ok(SavePoint { seconds, changes }),
+                    _ => Err(anyhow!("Invalid save parameters: {}", value)),
+                },
+            )
+            .collect()
+    }
+}
+
+// Seconds before retrying a background save that failed
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+// Snapshot bookkeeping behind SAVE, BGSAVE and LASTSAVE, and the append-only file
+#[derive(Debug)]
+pub struct Persistence {
+    pub snapshot_path: PathBuf,
+    pub snapshot_format: SnapshotFormat,
+    pub dir: PathBuf,
+    // The AOF directory and the name its files start with
+    pub aof_dir: PathBuf,
+    pub aof_prefix: String,
+    // Write rewritten AOF base files as snapshots rather than commands
+    aof_use_preamble: bool,
+    pub appendonly: bool,
+    pub aof_load_truncated: bool,
+    appendfsync: AppendFsync,
+    // Seals the snapshot and AOF files, see `crypto`
+    pub encryption: EncryptionOptions,
+    // Rewrite once the AOF grew this many percent over its base size, 0 disables
+    auto_aof_rewrite_percentage: u64,
+    auto_aof_rewrite_min_size: u64,
+    // Open once the existing file was replayed
+    aof: Mutex<Option<AppendOnlyFile>>,
+    // Held shared by write commands from running until they are logged, and
+    // exclusively to copy a keyspace that lines up with the AOF
+    pub write_gate: RwLock<()>,
+    // Unix time in seconds of the last successful save
+    last_save: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+    aof_rewrite_in_progress: AtomicBool,
+    save_points: Vec<SavePoint>,
+    // Refuse writes while the last background save failed
+    stop_writes_on_bgsave_error: bool,
+    // The store's dirty count the last successful save covered
+    dirty_at_last_save: AtomicU64,
+    last_bgsave_ok: AtomicBool,
+    // Unix time in seconds of the last background save attempt
+    last_bgsave_try: AtomicU64,
+}
+
+impl Persistence {
+    pub fn new(conf: &CacheConfig) -> Self {
+        Self {
+            snapshot_path: conf.dir.join(&conf.dbfilename),
+            snapshot_format: conf.snapshot_format,
+            dir: conf.dir.clone(),
+            aof_dir: conf.dir.join(&conf.appenddirname),
+            aof_prefix: conf.appendfilename.clone(),
+            aof_use_preamble: conf.aof_use_rdb_preamble,
+            appendonly: conf.appendonly,
+            aof_load_truncated: conf.aof_load_truncated,
+            appendfsync: conf.appendfsync,
+            encryption: conf.encryption.clone(),
+            auto_aof_rewrite_percentage: conf.auto_aof_rewrite_percentage,
+            auto_aof_rewrite_min_size: conf.auto_aof_rewrite_min_size,
+            aof: Mutex::new(None),
+            write_gate: RwLock::new(()),
+            last_save: AtomicU64::new(unix_time_ms() / 1000),
+            bgsave_in_progress: AtomicBool::new(false),
+            aof_rewrite_in_progress: AtomicBool::new(false),
+            save_points: conf.save_points(),
+            stop_writes_on_bgsave_error: conf.stop_writes_on_bgsave_error,
+            dirty_at_last_save: AtomicU64::new(0),
+            last_bgsave_ok: AtomicBool::new(true),
+            last_bgsave_try: AtomicU64::new(0),
+        }
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress.load(Ordering::Acquire)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::Relaxed)
+    }
+
+    // Write the snapshot in the calling thread
+    pub fn save(&self, store: &CacheStore) -> Result<()> {
+        let entries = snapshot::collect(store)?;
+        self.write_snapshot(&entries)?;
+        self.saved(store.dirty());
+        info!("DB saved on disk");
+        Ok(())
+    }
+
+    // The dataset as of `dirty` is on disk
+    pub fn saved(&self, dirty: u64) {
+        self.last_save
+            .store(unix_time_ms() / 1000, Ordering::Relaxed);
+        self.reset_dirty(dirty);
+        self.last_bgsave_ok.store(true, Ordering::Relaxed);
+    }
+
+    // Count changes for save points from the store at `dirty`
+    pub fn reset_dirty(&self, dirty: u64) {
+        self.dirty_at_last_save.store(dirty, Ordering::Relaxed);
+    }
+
+    // Write a copy of the keyspace to the snapshot file, in the configured format
+    pub fn write_snapshot(&self, entries: &[SnapshotEntry]) -> Result<()> {
+        let expires = entries.iter().filter(|e| e.expires_at.is_some()).count();
+        self.stream_snapshot(entries.iter().map(Ok), entries.len(), expires)
+    }
+
+    // Write entries to the snapshot file as they are produced, `keys` and
+    // `expires` being how many of them there will be. An entry that failed to
+    // read leaves the old file in place.
+    pub fn stream_snapshot<E: Borrow<SnapshotEntry>>(
+        &self,
+        entries: impl IntoIterator<Item = Result<E>>,
+        keys: usize,
+        expires: usize,
+    ) -> Result<()> {
+        self.write_file(&self.snapshot_path, |out| {
+            snapshot::encode_entries(entries, |entries| match self.snapshot_format {
+                SnapshotFormat::Native => snapshot::encode(out, entries),
+                SnapshotFormat::Rdb => rdb::encode_stream(out, entries, keys, expires),
+            })
+        })
+    }
+
+    // Write a file atomically, sealed when encryption is on
+    fn write_file<F>(&self, path: &Path, encode: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        write_atomic(path, self.encryption.keyring.as_deref(), encode)
+    }
+
+    // Claim the background save slot, false if a save is already running
+    pub fn start_bgsave(&self) -> bool {
+        let started = self
+            .bgsave_in_progress
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if started {
+            self.last_bgsave_try
+                .store(unix_time_ms() / 1000, Ordering::Relaxed);
+        }
+        started
+    }
+
+    // `dirty` is the store's count when the keyspace was copied
+    pub fn finish_bgsave(&self, res: Result<()>, dirty: u64) {
+        match res {
+            Ok(()) => {
+                self.saved(dirty);
+                info!("Background saving terminated with success");
+            }
+            Err(e) => {
+                self.last_bgsave_ok.store(false, Ordering::Relaxed);
+                warn!("Background saving error: {}", e);
+            }
+        }
+        self.bgsave_in_progress.store(false, Ordering::Release);
+    }
+
+    // The save point reached with the store at `dirty`, if any. After a failed
+    // background save, the next attempt waits `BGSAVE_RETRY_DELAY` seconds.
+    pub fn save_point_due(&self, dirty: u64) -> Option<SavePoint> {
+        if self.bgsave_in_progress() {
+            return None;
+        }
+        let now = unix_time_ms() / 1000;
+        if !self.last_bgsave_ok()
+            && now.saturating_sub(self.last_bgsave_try.load(Ordering::Relaxed)) < BGSAVE_RETRY_DELAY
+        {
+            return None;
+        }
+        let changes = self.changes_since_save(dirty);
+        let elapsed = now.saturating_sub(self.last_save());
+        self.save_points
+            .iter()
+            .find(|point| changes >= point.changes && elapsed >= point.seconds)
+            .copied()
+    }
+
+    // Changes since the last successful save
+    pub fn changes_since_save(&self, dirty: u64) -> u64 {
+        dirty.saturating_sub(self.dirty_at_last_save.load(Ordering::Relaxed))
+    }
+
+    // Whether write commands are refused because the last background save failed
+    pub fn writes_refused(&self) -> bool {
+        self.stop_writes_on_bgsave_error && !self.save_points.is_empty() && !self.last_bgsave_ok()
+    }
+
+    // Start appending write commands to the AOF loaded with `manifest`
+    pub fn open_aof(&self, manifest: Manifest) -> Result<()> {
+        let aof = AppendOnlyFile::open(
+            &self.aof_dir,
+            &self.aof_prefix,
+            manifest,
+            self.appendfsync,
+            self.encryption.keyring.clone(),
+        )?;
+        *self.aof.lock().unwrap() = Some(aof);
+        Ok(())
+    }
+
+    // Log a write command that ran successfully. Returns how long the fsync took
+    // with `appendfsync always`.
+    pub fn feed_aof(&self, args: &[String]) -> Option<Duration> {
+        let mut aof = self.aof.lock().unwrap();
+        let aof = aof.as_mut()?;
+        aof.append(args)
+            .inspect_err(|e| warn!("Error writing to the AOF {}: {}", aof.path().display(), e))
+            .ok()
+            .flatten()
+    }
+
+    // A handle to fsync when `appendfsync everysec` is due
+    pub fn aof_fsync_due(&self) -> Option<File> {
+        self.aof.lock().unwrap().as_mut()?.fsync_due()
+    }
+
+    pub fn aof_rewrite_in_progress(&self) -> bool {
+        self.aof_rewrite_in_progress.load(Ordering::Acquire)
+    }
+
+    // Whether the AOF grew enough since the last rewrite to rewrite it again
+    pub fn aof_rewrite_due(&self) -> bool {
+        if self.auto_aof_rewrite_percentage == 0 || self.aof_rewrite_in_progress() {
+            return false;
+        }
+        let aof = self.aof.lock().unwrap();
+        let Some(aof) = aof.as_ref() else {
+            return false;
+        };
+        let base = aof.base_size.max(1);
+        aof.size >= self.auto_aof_rewrite_min_size
+            && aof.size.saturating_sub(base) * 100 / base >= self.auto_aof_rewrite_percentage
+    }
+
+    // Claim the rewrite slot, false if a rewrite is already running
+    pub fn start_aof_rewrite(&self) -> bool {
+        self.aof_rewrite_in_progress
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    // Start a new incremental file for the writes that follow, and name the base
+    // file the rewrite will produce
+    pub fn begin_aof_rewrite(&self) -> Result<AofInfo> {
+        let extension = match (self.aof_use_preamble, self.snapshot_format) {
+            (false, _) => "aof",
+            (true, SnapshotFormat::Native) => "snap",
+            (true, SnapshotFormat::Rdb) => "rdb",
+        };
+        match self.aof.lock().unwrap().as_mut() {
+            Some(aof) => {
+                aof.start_rewrite()?;
+                Ok(aof.manifest.next_base(&self.aof_prefix, extension))
+            }
+            None => {
+                let manifest = Manifest::load(&self.aof_dir, &self.aof_prefix, &self.encryption)?
+                    .unwrap_or_default();
+                Ok(manifest.next_base(&self.aof_prefix, extension))
+            }
+        }
+    }
+
+    // Write the rewritten base file, as a snapshot or as commands. `keys` and
+    // `expires` are how many entries there will be.
+    pub fn write_aof_base<E: Borrow<SnapshotEntry>>(
+        &self,
+        base: &AofInfo,
+        entries: impl IntoIterator<Item = Result<E>>,
+        keys: usize,
+        expires: usize,
+    ) -> Result<()> {
+        fs::create_dir_all(&self.aof_dir)
+            .map_err(|e| anyhow!("Failed to create {}: {}", self.aof_dir.display(), e))?;
+        let path = self.aof_dir.join(&base.name);
+        self.write_file(&path, |out| {
+            snapshot::encode_entries(entries, |entries| {
+                match (self.aof_use_preamble, self.snapshot_format) {
+                    (false, _) => aof::rewrite_commands(out, entries),
+                    (true, SnapshotFormat::Native) => snapshot::encode(out, entries),
+                    (true, SnapshotFormat::Rdb) => rdb::encode_stream(out, entries, keys, expires),
+                }
+            })
+        })
+    }
+
+    // Switch the manifest over to the new base, None when the rewrite never started
+    pub fn finish_aof_rewrite(&self, base: Option<AofInfo>, res: Result<()>) {
+        let res = res.and_then(|()| match base {
+            Some(base) => self.install_aof_base(base),
+            None => Ok(()),
+        });
+        match res {
+            Ok(()) => info!("Background AOF rewrite terminated with success"),
+            Err(e) => warn!("Background AOF rewrite error: {}", e),
+        }
+        self.aof_rewrite_in_progress.store(false, Ordering::Release);
+    }
+
+    fn install_aof_base(&self, base: AofInfo) -> Result<()> {
+        if let Some(aof) = self.aof.lock().unwrap().as_mut() {
+            return aof.finish_rewrite(base);
+        }
+        // With the AOF off, the base alone holds the dataset
+        let old =
+            Manifest::load(&self.aof_dir, &self.aof_prefix, &self.encryption)?.unwrap_or_default();
+        let rewritten = Manifest {
+            base: Some(base),
+            incrs: Vec::new(),
+        };
+        rewritten.write(
+            &self.aof_dir,
+            &self.aof_prefix,
+            self.encryption.keyring.as_deref(),
+        )?;
+        aof::remove_obsolete(&self.aof_dir, &old, &rewritten);
+        Ok(())
+    }
+}
+
+// Write a file through `encode` into a temp file next to `path`, then rename it
+// over `path`, so a crash mid-write never leaves a truncated file behind. With a
+// keyring the file is sealed.
+pub fn write_atomic<F>(path: &Path, keyring: Option<&Keyring>, encode: F) -> Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!("temp-{}.{}", process::id(), name));
+
+    let res = write_file(&tmp, keyring, encode).and_then(|()| {
+        fs::rename(&tmp, path).map_err(|e| anyhow!("Failed to rename {}: {}", tmp.display(), e))
+    });
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+fn write_file<F>(path: &Path, keyring: Option<&Keyring>, encode: F) -> Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    let file =
+        File::create(path).map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+    let mut out = BufWriter::new(file);
+    match keyring {
+        Some(keyring) => {
+            let mut sealed = SealWriter::new(keyring, &mut out)?;
+            encode(&mut sealed)?;
+            sealed.finish()?;
+        }
+        None => encode(&mut out)?,
+    }
+    out.flush()?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_points() {
+        assert_eq!(
+            SavePoint::parse_list("900 1 300 10").unwrap(),
+            [
+                SavePoint {
+                    seconds: 900,
+                    changes: 1
+                },
+                SavePoint {
+                    seconds: 300,
+                    changes: 10
+                }
+            ]
+        );
+        assert!(SavePoint::parse_list("\"\"").unwrap().is_empty());
+        assert!(SavePoint::parse_list("900").is_err());
+
+        let mut conf = CacheConfig::default();
+        conf.set("save", "0 2").unwrap();
+        let persistence = Persistence::new(&conf);
+        assert_eq!(persistence.save_point_due(1), None);
+        assert!(persistence.save_point_due(2).is_some());
+        persistence.saved(2);
+        assert_eq!(persistence.save_point_due(3), None);
+
+        // A failed background save holds writes back and delays the retry
+        assert!(persistence.start_bgsave());
+        persistence.finish_bgsave(Err(anyhow!("No space left on device")), 3);
+        assert!(persistence.writes_refused());
+        assert_eq!(persistence.save_point_due(10), None);
+    }
+}
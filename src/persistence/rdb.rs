@@ -0,0 +1,778 @@
+// Redis RDB files, for moving data between Redis and ds-cache.
+//
+//   "REDIS" <4 digit version> <aux fields> (<SELECTDB> <RESIZEDB> <keys>)* <EOF> <CRC64>
+//
+// Every value type Redis 7 writes for strings, lists, sets, sorted sets and hashes
+// is read, including the ziplist, intset and listpack encodings of small values.
+// Files are written as version 9 with the plain encoding of each type, which every
+// Redis since 5.0 loads.
+
+use std::{borrow::Borrow, io::Write};
+
+use anyhow::{Result, anyhow};
+use tracing::{debug, warn};
+
+use crate::{
+    persistence::{
+        Corruption,
+        crc64::{Crc64Writer, crc64},
+        lzf,
+        snapshot::{LoadStats, SnapshotEntry, SnapshotValue},
+    },
+    storage::{intset::IntSet, listpack::Listpack},
+    utils::unix_time_ms,
+};
+
+const RDB_MAGIC: &[u8; 5] = b"REDIS";
+const RDB_VERSION: u32 = 9;
+// Newest version this reader understands (Redis 7.4)
+const RDB_MAX_VERSION: u32 = 12;
+
+// Value types
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+// Opcodes
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+// Length encodings: the two top bits of the first byte
+const LEN_6BIT: u8 = 0;
+const LEN_14BIT: u8 = 1;
+const LEN_32BIT: u8 = 0x80;
+const LEN_64BIT: u8 = 0x81;
+const LEN_ENCVAL: u8 = 3;
+
+// Special string encodings, after a LEN_ENCVAL byte
+const ENC_INT8: u64 = 0;
+const ENC_INT16: u64 = 1;
+const ENC_INT32: u64 = 2;
+const ENC_LZF: u64 = 3;
+
+// Quicklist 2 node containers
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+// Strings shorter than this are never worth compressing
+const LZF_MIN_LEN: usize = 20;
+
+pub fn is_rdb(data: &[u8]) -> bool {
+    data.starts_with(RDB_MAGIC)
+}
+
+// ========== Reading ==========
+
+// Decode an RDB file. Only database 0 exists here, keys of other databases are
+// skipped, as are keys whose TTL already passed.
+pub fn read(data: &[u8]) -> Result<(Vec<SnapshotEntry>, LoadStats)> {
+    let now = unix_time_ms();
+    let mut entries = Vec::new();
+    let mut stats = LoadStats::default();
+    let mut other_dbs = 0;
+    decode(data, |db, entry| {
+        if db != 0 {
+            other_dbs += 1;
+        } else if entry.expires_at.is_some_and(|at| at <= now) {
+            stats.expired += 1;
+        } else {
+            stats.loaded += 1;
+            entries.push(entry);
+        }
+    })?;
+    if other_dbs > 0 {
+        warn!("Skipped {} keys of RDB databases other than 0", other_dbs);
+    }
+    Ok((entries, stats))
+}
+
+// Walk every key of an RDB file with the database it belongs to, then verify
+// the checksum
+pub fn decode(data: &[u8], mut on_entry: impl FnMut(u64, SnapshotEntry)) -> Result<(), Corruption> {
+    if !is_rdb(data) {
+        return Err(Corruption::new(0, "Not an RDB file"));
+    }
+    let version = data
+        .get(5..9)
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or_else(|| Corruption::new(5, "Invalid RDB version"))?;
+    if version > RDB_MAX_VERSION {
+        return Err(Corruption::new(
+            5,
+            format!("Can't handle RDB format version {}", version),
+        ));
+    }
+
+    let mut reader = RdbReader { data, pos: 9 };
+    let mut db = 0;
+    let mut expires_at = None;
+    loop {
+        let start = reader.pos;
+        match reader
+            .record(&mut db, &mut expires_at)
+            .map_err(|e| Corruption::new(start, e))?
+        {
+            Record::Eof => break,
+            Record::Entry(entry) => on_entry(db, entry),
+            Record::Meta => {}
+        }
+    }
+
+    // Files written without a checksum carry 0
+    if version >= 5 {
+        let end = reader.pos;
+        let expected = u64::from_le_bytes(
+            reader
+                .array()
+                .map_err(|_| Corruption::new(end, "Missing RDB checksum"))?,
+        );
+        if expected != 0 && crc64(0, &data[..end]) != expected {
+            return Err(Corruption::new(end, "Wrong RDB checksum"));
+        }
+    }
+    Ok(())
+}
+
+enum Record {
+    Eof,
+    Entry(SnapshotEntry),
+    // An opcode that only updates the reader state
+    Meta,
+}
+
+struct RdbReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    // The next opcode and what follows it
+    fn record(&mut self, db: &mut u64, expires_at: &mut Option<u64>) -> Result<Record> {
+        let opcode = self.byte()?;
+        match opcode {
+            OPCODE_EOF => return Ok(Record::Eof),
+            OPCODE_SELECTDB => *db = self.length()?,
+            OPCODE_RESIZEDB => {
+                self.length()?;
+                self.length()?;
+            }
+            OPCODE_AUX => {
+                let field = self.string()?;
+                let value = self.string()?;
+                debug!(
+                    "RDB aux field {}: {}",
+                    String::from_utf8_lossy(&field),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+            OPCODE_EXPIRETIME_MS => *expires_at = Some(u64::from_le_bytes(self.array()?)),
+            OPCODE_EXPIRETIME => {
+                *expires_at = Some(u32::from_le_bytes(self.array()?) as u64 * 1000)
+            }
+            OPCODE_FREQ => {
+                self.byte()?;
+            }
+            OPCODE_IDLE => {
+                self.length()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    self.length()?;
+                }
+            }
+            OPCODE_FUNCTION2 => {
+                self.string()?;
+                warn!("Skipping a function library found in the RDB file");
+            }
+            value_type => {
+                let key = String::from_utf8(self.string()?)
+                    .map_err(|_| anyhow!("RDB key is not valid UTF-8"))?;
+                let value = self.value(value_type)?;
+                return Ok(Record::Entry(SnapshotEntry {
+                    key,
+                    expires_at: expires_at.take(),
+                    value,
+                }));
+            }
+        }
+        Ok(Record::Meta)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| anyhow!("Unexpected end of RDB file"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    // A length, or the id of a special string encoding when the flag is set
+    fn encoded_length(&mut self) -> Result<(u64, bool)> {
+        let first = self.byte()?;
+        match first >> 6 {
+            LEN_6BIT => Ok(((first & 0x3F) as u64, false)),
+            LEN_14BIT => Ok(((((first & 0x3F) as u64) << 8) | self.byte()? as u64, false)),
+            LEN_ENCVAL => Ok(((first & 0x3F) as u64, true)),
+            _ => match first {
+                LEN_32BIT => Ok((u32::from_be_bytes(self.array()?) as u64, false)),
+                LEN_64BIT => Ok((u64::from_be_bytes(self.array()?), false)),
+                _ => Err(anyhow!("Unknown RDB length encoding {:#x}", first)),
+            },
+        }
+    }
+
+    fn length(&mut self) -> Result<u64> {
+        match self.encoded_length()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(anyhow!("Unexpected string encoding")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let (len, encoded) = self.encoded_length()?;
+        if !encoded {
+            return Ok(self.take(len as usize)?.to_vec());
+        }
+        let int = match len {
+            ENC_INT8 => self.byte()? as i8 as i64,
+            ENC_INT16 => i16::from_le_bytes(self.array()?) as i64,
+            ENC_INT32 => i32::from_le_bytes(self.array()?) as i64,
+            ENC_LZF => {
+                let compressed_len = self.length()? as usize;
+                let len = self.length()? as usize;
+                return lzf::decompress(self.take(compressed_len)?, len);
+            }
+            _ => return Err(anyhow!("Unknown RDB string encoding {}", len)),
+        };
+        Ok(int.to_string().into_bytes())
+    }
+
+    // Score of the old ZSET type: length prefixed text, with 253-255 for NaN, +inf, -inf
+    fn string_double(&mut self) -> Result<f64> {
+        match self.byte()? {
+            253 => score(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_double(self.take(len as usize)?),
+        }
+    }
+
+    fn strings(&mut self) -> Result<Vec<Vec<u8>>> {
+        let len = self.length()?;
+        (0..len).map(|_| self.string()).collect()
+    }
+
+    fn pairs(&mut self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let len = self.length()?;
+        (0..len)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect()
+    }
+
+    fn value(&mut self, value_type: u8) -> Result<SnapshotValue> {
+        Ok(match value_type {
+            TYPE_STRING => SnapshotValue::String(self.string()?),
+            TYPE_LIST => SnapshotValue::List(self.strings()?),
+            TYPE_SET => SnapshotValue::Set(self.strings()?),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.length()?;
+                let mut members = Vec::new();
+                for _ in 0..len {
+                    let member = self.string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        score(f64::from_le_bytes(self.array()?))?
+                    } else {
+                        self.string_double()?
+                    };
+                    members.push((member, score));
+                }
+                SnapshotValue::SortedSet(members)
+            }
+            TYPE_HASH => SnapshotValue::Hash(self.pairs()?),
+            TYPE_LIST_ZIPLIST => SnapshotValue::List(ziplist_entries(&self.string()?)?),
+            TYPE_SET_INTSET => {
+                let set = IntSet::from_bytes(&self.string()?)?;
+                SnapshotValue::Set(
+                    (0..set.len())
+                        .map(|i| set.get(i).to_string().into_bytes())
+                        .collect(),
+                )
+            }
+            TYPE_SET_LISTPACK => SnapshotValue::Set(listpack_entries(self.string()?)?),
+            TYPE_ZSET_ZIPLIST => {
+                SnapshotValue::SortedSet(scored(ziplist_entries(&self.string()?)?)?)
+            }
+            TYPE_ZSET_LISTPACK => {
+                SnapshotValue::SortedSet(scored(listpack_entries(self.string()?)?)?)
+            }
+            TYPE_HASH_ZIPLIST => SnapshotValue::Hash(paired(ziplist_entries(&self.string()?)?)?),
+            TYPE_HASH_LISTPACK => SnapshotValue::Hash(paired(listpack_entries(self.string()?)?)?),
+            TYPE_LIST_QUICKLIST => {
+                let nodes = self.length()?;
+                let mut elements = Vec::new();
+                for _ in 0..nodes {
+                    elements.extend(ziplist_entries(&self.string()?)?);
+                }
+                SnapshotValue::List(elements)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.length()?;
+                let mut elements = Vec::new();
+                for _ in 0..nodes {
+                    match self.length()? {
+                        QUICKLIST_NODE_PLAIN => elements.push(self.string()?),
+                        QUICKLIST_NODE_PACKED => elements.extend(listpack_entries(self.string()?)?),
+                        container => {
+                            return Err(anyhow!("Unknown quicklist node container {}", container));
+                        }
+                    }
+                }
+                SnapshotValue::List(elements)
+            }
+            _ => return Err(anyhow!("Unsupported RDB value type {}", value_type)),
+        })
+    }
+}
+
+fn parse_double(bytes: &[u8]) -> Result<f64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .ok_or_else(|| anyhow!("Invalid RDB double value"))
+        .and_then(score)
+}
+
+// Sorted sets can't order NaN, a score decoding to it is corrupt
+fn score(value: f64) -> Result<f64> {
+    if value.is_nan() {
+        return Err(anyhow!("Invalid RDB score NaN"));
+    }
+    Ok(value)
+}
+
+fn listpack_entries(blob: Vec<u8>) -> Result<Vec<Vec<u8>>> {
+    Ok(Listpack::from_bytes(blob)?
+        .iter()
+        .map(|entry| entry.to_vec())
+        .collect())
+}
+
+// Flat member, score, member, score... as written by small sorted sets
+fn scored(flat: Vec<Vec<u8>>) -> Result<Vec<(Vec<u8>, f64)>> {
+    if !flat.len().is_multiple_of(2) {
+        return Err(anyhow!("Sorted set blob has an odd number of entries"));
+    }
+    let mut members = Vec::with_capacity(flat.len() / 2);
+    let mut iter = flat.into_iter();
+    while let (Some(member), Some(score)) = (iter.next(), iter.next()) {
+        members.push((member, parse_double(&score)?));
+    }
+    Ok(members)
+}
+
+// Flat field, value, field, value... as written by small hashes
+fn paired(flat: Vec<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if !flat.len().is_multiple_of(2) {
+        return Err(anyhow!("Hash blob has an odd number of entries"));
+    }
+    let mut fields = Vec::with_capacity(flat.len() / 2);
+    let mut iter = flat.into_iter();
+    while let (Some(field), Some(value)) = (iter.next(), iter.next()) {
+        fields.push((field, value));
+    }
+    Ok(fields)
+}
+
+// Entries of a ziplist, the compact encoding before listpacks:
+//
+//   <zlbytes: u32> <zltail: u32> <zllen: u16> (<prevlen> <encoding> <data>)* <0xFF>
+fn ziplist_entries(blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut reader = RdbReader {
+        data: blob,
+        pos: 10,
+    };
+    if blob.len() < 11 || u32::from_le_bytes(blob[0..4].try_into().unwrap()) as usize != blob.len()
+    {
+        return Err(anyhow!("Ziplist header does not match its size"));
+    }
+
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = reader.byte()?;
+        if prevlen == 0xFF {
+            break;
+        }
+        if prevlen == 0xFE {
+            reader.take(4)?;
+        }
+
+        let enc = reader.byte()?;
+        let entry = match enc >> 6 {
+            0 => reader.take((enc & 0x3F) as usize)?.to_vec(),
+            1 => {
+                let len = (((enc & 0x3F) as usize) << 8) | reader.byte()? as usize;
+                reader.take(len)?.to_vec()
+            }
+            2 => {
+                let len = u32::from_be_bytes(reader.array()?) as usize;
+                reader.take(len)?.to_vec()
+            }
+            _ => {
+                let int = match enc {
+                    0xC0 => i16::from_le_bytes(reader.array()?) as i64,
+                    0xD0 => i32::from_le_bytes(reader.array()?) as i64,
+                    0xE0 => i64::from_le_bytes(reader.array()?),
+                    0xF0 => {
+                        let [a, b, c] = reader.array()?;
+                        i32::from_le_bytes([0, a, b, c]) as i64 >> 8
+                    }
+                    0xFE => reader.byte()? as i8 as i64,
+                    0xF1..=0xFD => (enc & 0x0F) as i64 - 1,
+                    _ => return Err(anyhow!("Invalid ziplist encoding {:#x}", enc)),
+                };
+                int.to_string().into_bytes()
+            }
+        };
+        entries.push(entry);
+    }
+    if reader.pos != blob.len() {
+        return Err(anyhow!("Ziplist has data after its terminator"));
+    }
+    Ok(entries)
+}
+
+// ========== DUMP payloads ==========
+
+// A single value the way Redis's DUMP serializes it: the RDB value type, the
+// value in RDB encoding, the RDB version as 2 bytes and the CRC64 of all that,
+// little endian
+pub fn dump_payload(value: &SnapshotValue) -> Vec<u8> {
+    let mut w = RdbWriter {
+        out: Crc64Writer::new(Vec::new()),
+    };
+    // Writing to memory can't fail
+    w.raw(&[value_type(value)]).unwrap();
+    w.value_body(value).unwrap();
+    w.raw(&(RDB_VERSION as u16).to_le_bytes()).unwrap();
+    let crc = w.out.crc();
+    let mut payload = w.out.into_inner();
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+// Check the version and checksum of a DUMP payload and decode its value
+pub fn restore_payload(payload: &[u8]) -> Result<SnapshotValue> {
+    let footer = payload
+        .len()
+        .checked_sub(10)
+        .ok_or_else(|| anyhow!("DUMP payload version or checksum are wrong"))?;
+    let (body, trailer) = payload.split_at(footer + 2);
+    let version = u16::from_le_bytes([payload[footer], payload[footer + 1]]);
+    let crc = u64::from_le_bytes(trailer.try_into().unwrap());
+    if version as u32 > RDB_MAX_VERSION || crc64(0, body) != crc {
+        return Err(anyhow!("DUMP payload version or checksum are wrong"));
+    }
+
+    let mut reader = RdbReader {
+        data: &payload[..footer],
+        pos: 0,
+    };
+    let value = reader
+        .byte()
+        .and_then(|value_type| reader.value(value_type))
+        .map_err(|_| anyhow!("Bad data format"))?;
+    if reader.pos != footer {
+        return Err(anyhow!("Bad data format"));
+    }
+    Ok(value)
+}
+
+// ========== Writing ==========
+
+pub fn encode<W: Write>(out: W, entries: &[SnapshotEntry]) -> Result<()> {
+    let expires = entries.iter().filter(|e| e.expires_at.is_some()).count();
+    encode_stream(out, entries, entries.len(), expires)
+}
+
+// `keys` and `expires` are the sizes RESIZEDB announces before the entries
+pub fn encode_stream<W: Write, E: Borrow<SnapshotEntry>>(
+    out: W,
+    entries: impl IntoIterator<Item = E>,
+    keys: usize,
+    expires: usize,
+) -> Result<()> {
+    let mut w = RdbWriter {
+        out: Crc64Writer::new(out),
+    };
+    w.raw(RDB_MAGIC)?;
+    w.raw(format!("{:04}", RDB_VERSION).as_bytes())?;
+    w.aux("redis-ver", env!("CARGO_PKG_VERSION"))?;
+    w.aux("redis-bits", &(usize::BITS).to_string())?;
+    w.aux("ctime", &(unix_time_ms() / 1000).to_string())?;
+
+    w.raw(&[OPCODE_SELECTDB])?;
+    w.length(0)?;
+    w.raw(&[OPCODE_RESIZEDB])?;
+    w.length(keys as u64)?;
+    w.length(expires as u64)?;
+
+    for entry in entries {
+        let entry = entry.borrow();
+        if let Some(at) = entry.expires_at {
+            w.raw(&[OPCODE_EXPIRETIME_MS])?;
+            w.raw(&at.to_le_bytes())?;
+        }
+        w.value(&entry.key, &entry.value)?;
+    }
+    w.raw(&[OPCODE_EOF])?;
+
+    let crc = w.out.crc();
+    w.out.into_inner().write_all(&crc.to_le_bytes())?;
+    Ok(())
+}
+
+struct RdbWriter<W: Write> {
+    out: Crc64Writer<W>,
+}
+
+impl<W: Write> RdbWriter<W> {
+    fn raw(&mut self, bytes: &[u8]) -> Result<()> {
+        self.out.write_all(bytes)?;
+        Ok(())
+    }
+
+    fn length(&mut self, len: u64) -> Result<()> {
+        if len < 1 << 6 {
+            self.raw(&[(LEN_6BIT << 6) | len as u8])
+        } else if len < 1 << 14 {
+            self.raw(&[(LEN_14BIT << 6) | (len >> 8) as u8, len as u8])
+        } else if len <= u32::MAX as u64 {
+            self.raw(&[LEN_32BIT])?;
+            self.raw(&(len as u32).to_be_bytes())
+        } else {
+            self.raw(&[LEN_64BIT])?;
+            self.raw(&len.to_be_bytes())
+        }
+    }
+
+    // Small integers are stored as integers and long strings compressed, like
+    // Redis does with rdbcompression on
+    fn string(&mut self, s: &[u8]) -> Result<()> {
+        if s.len() <= 11
+            && let Some(int) = canonical_i32(s)
+        {
+            let enc = LEN_ENCVAL << 6;
+            return if let Ok(v) = i8::try_from(int) {
+                self.raw(&[enc | ENC_INT8 as u8, v as u8])
+            } else if let Ok(v) = i16::try_from(int) {
+                self.raw(&[enc | ENC_INT16 as u8])?;
+                self.raw(&v.to_le_bytes())
+            } else {
+                self.raw(&[enc | ENC_INT32 as u8])?;
+                self.raw(&int.to_le_bytes())
+            };
+        }
+
+        if s.len() > LZF_MIN_LEN
+            && let Some(compressed) = lzf::compress(s)
+        {
+            self.raw(&[(LEN_ENCVAL << 6) | ENC_LZF as u8])?;
+            self.length(compressed.len() as u64)?;
+            self.length(s.len() as u64)?;
+            return self.raw(&compressed);
+        }
+
+        self.length(s.len() as u64)?;
+        self.raw(s)
+    }
+
+    fn aux(&mut self, field: &str, value: &str) -> Result<()> {
+        self.raw(&[OPCODE_AUX])?;
+        self.string(field.as_bytes())?;
+        self.string(value.as_bytes())
+    }
+
+    fn value(&mut self, key: &str, value: &SnapshotValue) -> Result<()> {
+        self.raw(&[value_type(value)])?;
+        self.string(key.as_bytes())?;
+        self.value_body(value)
+    }
+
+    fn value_body(&mut self, value: &SnapshotValue) -> Result<()> {
+        match value {
+            SnapshotValue::String(s) => self.string(s)?,
+            SnapshotValue::List(elements) | SnapshotValue::Set(elements) => {
+                self.length(elements.len() as u64)?;
+                for element in elements {
+                    self.string(element)?;
+                }
+            }
+            SnapshotValue::SortedSet(members) => {
+                self.length(members.len() as u64)?;
+                for (member, score) in members {
+                    self.string(member)?;
+                    self.raw(&score.to_le_bytes())?;
+                }
+            }
+            SnapshotValue::Hash(fields) => {
+                self.length(fields.len() as u64)?;
+                for (field, value) in fields {
+                    self.string(field)?;
+                    self.string(value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn value_type(value: &SnapshotValue) -> u8 {
+    match value {
+        SnapshotValue::String(_) => TYPE_STRING,
+        SnapshotValue::List(_) => TYPE_LIST,
+        SnapshotValue::Set(_) => TYPE_SET,
+        SnapshotValue::SortedSet(_) => TYPE_ZSET_2,
+        SnapshotValue::Hash(_) => TYPE_HASH,
+    }
+}
+
+// `s` as an i32 when it is exactly the decimal rendering of one
+fn canonical_i32(s: &[u8]) -> Option<i32> {
+    let v = std::str::from_utf8(s).ok()?.parse::<i32>().ok()?;
+    (v.to_string().as_bytes() == s).then_some(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rdb_round_trip() {
+        let entries = vec![
+            SnapshotEntry {
+                key: "counter".into(),
+                expires_at: None,
+                value: SnapshotValue::String(b"-12345".to_vec()),
+            },
+            SnapshotEntry {
+                key: "text".into(),
+                expires_at: Some(unix_time_ms() + 60_000),
+                value: SnapshotValue::String("abc".repeat(100).into_bytes()),
+            },
+            SnapshotEntry {
+                key: "zset".into(),
+                expires_at: None,
+                value: SnapshotValue::SortedSet(vec![
+                    (b"a".to_vec(), 1.5),
+                    (b"b".to_vec(), f64::INFINITY),
+                ]),
+            },
+            SnapshotEntry {
+                key: "hash".into(),
+                expires_at: None,
+                value: SnapshotValue::Hash(vec![(b"f".to_vec(), b"70000".to_vec())]),
+            },
+        ];
+
+        let mut data = Vec::new();
+        encode(&mut data, &entries).unwrap();
+        assert!(data.starts_with(b"REDIS0009"));
+        let (read_back, stats) = read(&data).unwrap();
+        assert_eq!(read_back, entries);
+        assert_eq!(stats.loaded, 4);
+
+        let last = data.len() - 9;
+        data[last - 1] ^= 0xff;
+        assert!(read(&data).is_err());
+    }
+
+    #[test]
+    fn test_ziplist_and_listpack_values() {
+        // A ziplist holding "a", 5 and 300, and a listpack holding "x", 7
+        let ziplist = [
+            0x14, 0, 0, 0, 0x0F, 0, 0, 0, 3, 0, // header
+            0, 0x01, b'a', // "a"
+            3, 0xF6, // 5 as an immediate
+            2, 0xC0, 0x2C, 0x01, // 300 as int16
+            0xFF,
+        ];
+        assert_eq!(
+            ziplist_entries(&ziplist).unwrap(),
+            vec![b"a".to_vec(), b"5".to_vec(), b"300".to_vec()]
+        );
+
+        let listpack = vec![12, 0, 0, 0, 2, 0, 0x81, b'x', 2, 0x07, 1, 0xFF];
+        assert_eq!(
+            listpack_entries(listpack).unwrap(),
+            vec![b"x".to_vec(), b"7".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_dump_payload() {
+        let value = SnapshotValue::SortedSet(vec![(b"a".to_vec(), 1.0), (b"b".to_vec(), -2.5)]);
+        let payload = dump_payload(&value);
+        assert_eq!(payload[0], TYPE_ZSET_2);
+        assert_eq!(restore_payload(&payload).unwrap(), value);
+
+        let mut corrupt = payload.clone();
+        corrupt[3] ^= 0xff;
+        assert!(restore_payload(&corrupt).is_err());
+        assert!(restore_payload(&payload[..5]).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_values_are_errors() {
+        // An LZF string claiming 1 TiB of output from no input
+        let mut data = vec![TYPE_STRING, 0xC0 | ENC_LZF as u8, 0x00, LEN_64BIT];
+        data.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        let mut reader = RdbReader {
+            data: &data,
+            pos: 0,
+        };
+        assert!(reader.byte().and_then(|t| reader.value(t)).is_err());
+
+        // NaN scores, binary and as text
+        let mut nan = vec![TYPE_ZSET_2, 1, 1, b'a'];
+        nan.extend_from_slice(&f64::NAN.to_le_bytes());
+        let mut text = vec![TYPE_ZSET, 1, 1, b'a', 3];
+        text.extend_from_slice(b"nan");
+        for data in [nan, text, vec![TYPE_ZSET, 1, 1, b'a', 253]] {
+            let mut reader = RdbReader {
+                data: &data,
+                pos: 0,
+            };
+            assert!(reader.byte().and_then(|t| reader.value(t)).is_err());
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    borrow::Borrow,
+    fs,
+    io::Write,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use bincode::{Decode, Encode};
+use tokio::sync::RwLock;
+
+use crate::{
+    persistence::{
+        Corruption,
+        crc64::{Crc64Writer, crc64},
+        crypto::EncryptionOptions,
+        rdb, write_atomic,
+    },
+    server::{latency::EVENT_FORK, state::ServerState},
+    storage::{
+        CacheStore, EncodingLimits, HashValue, ListValue, SetValue, SortedSetValue, StringValue,
+        Value, entry::Entry,
+    },
+    utils::unix_time_ms,
+};
+
+// File layout: magic, version, one TAG_ENTRY + bincode entry per key, TAG_EOF,
+// then the CRC64 of everything before it, little endian
+const SNAPSHOT_MAGIC: &[u8; 7] = b"DSCACHE";
+const SNAPSHOT_VERSION: u16 = 1;
+const TAG_ENTRY: u8 = 0x01;
+const TAG_EOF: u8 = 0xff;
+
+// Keys a background save copies per hold of the store lock
+const SNAPSHOT_BATCH_KEYS: usize = 1024;
+
+// A key as written to disk. Values are stored as plain elements and re-encoded
+// with the limits of the server loading them.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct SnapshotEntry {
+    pub key: String,
+    // Absolute unix time in milliseconds
+    pub expires_at: Option<u64>,
+    pub value: SnapshotValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub enum SnapshotValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    SortedSet(Vec<(Vec<u8>, f64)>),
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+impl SnapshotValue {
+    pub fn from_value(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::String(s) => SnapshotValue::String(s.as_bytes().into_owned()),
+            Value::List(l) => SnapshotValue::List(l.iter().map(|e| e.into_owned()).collect()),
+            Value::Set(s) => SnapshotValue::Set(s.members()),
+            Value::SortedSet(zs) => {
+                SnapshotValue::SortedSet(zs.iter().map(|(m, s)| (m.into_owned(), s)).collect())
+            }
+            Value::Hash(h) => SnapshotValue::Hash(
+                h.iter()
+                    .map(|(f, v)| (f.into_owned(), v.into_owned()))
+                    .collect(),
+            ),
+            Value::Nil => return None,
+        })
+    }
+
+    // Build the stored value. Fails on a NaN score, which sorted sets can't order.
+    pub fn into_value(self, limits: &EncodingLimits) -> Result<Value> {
+        Ok(match self {
+            SnapshotValue::String(s) => Value::String(StringValue::new(s)),
+            SnapshotValue::List(elements) => {
+                let mut list = ListValue::new();
+                for element in elements {
+                    list.push_right(element, limits);
+                }
+                Value::List(list)
+            }
+            SnapshotValue::Set(members) => {
+                let mut set = SetValue::new();
+                for member in members {
+                    set.add(member, limits);
+                }
+                Value::Set(set)
+            }
+            SnapshotValue::SortedSet(members) => {
+                let mut zset = SortedSetValue::new();
+                for (member, score) in members {
+                    if score.is_nan() {
+                        return Err(anyhow!(
+                            "NaN score for member {}",
+                            String::from_utf8_lossy(&member)
+                        ));
+                    }
+                    zset.add(score, member, limits);
+                }
+                Value::SortedSet(zset)
+            }
+            SnapshotValue::Hash(fields) => {
+                let mut hash = HashValue::new();
+                for (field, value) in fields {
+                    hash.set(field, value, limits);
+                }
+                Value::Hash(hash)
+            }
+        })
+    }
+}
+
+// Keys loaded from a snapshot, and keys skipped because their TTL passed
+#[derive(Debug, Default, PartialEq)]
+pub struct LoadStats {
+    pub loaded: usize,
+    pub expired: usize,
+}
+
+// Copy the live keyspace. Done under the store lock, writing happens afterwards.
+pub fn collect(store: &CacheStore) -> Result<Vec<SnapshotEntry>> {
+    store
+        .entries()
+        .filter_map(|(key, entry)| store.snapshot_entry(key, entry).transpose())
+        .collect()
+}
+
+// Start BGSAVE without copying the keyspace: a blocking thread walks it
+// `SNAPSHOT_BATCH_KEYS` keys per store lock, while the store sets aside the old
+// image of keys written to before the walk reaches them
+pub async fn save_in_background(
+    store: &Arc<RwLock<CacheStore>>,
+    state: &Arc<ServerState>,
+) -> Result<()> {
+    if !state.persistence.start_bgsave() {
+        return Err(anyhow!("Background save already in progress"));
+    }
+    let (start, dirty) = {
+        let mut store = store.write().await;
+        let started = Instant::now();
+        let start = store.begin_snapshot();
+        state.add_latency_sample(EVENT_FORK, started.elapsed());
+        (start, store.dirty())
+    };
+
+    let store = Arc::clone(store);
+    let state = Arc::clone(state);
+    tokio::task::spawn_blocking(move || {
+        let res =
+            state
+                .persistence
+                .stream_snapshot(batches(&store, start.id), start.keys, start.expires);
+        store.blocking_write().end_snapshot(start.id);
+        state.persistence.finish_bgsave(res, dirty);
+    });
+    Ok(())
+}
+
+// The entries of the incremental snapshot `id`, a batch taken per store lock. For
+// blocking threads.
+pub fn batches(
+    store: &RwLock<CacheStore>,
+    id: u64,
+) -> impl Iterator<Item = Result<SnapshotEntry>> + '_ {
+    std::iter::from_fn(move || {
+        store
+            .blocking_write()
+            .snapshot_batch(id, SNAPSHOT_BATCH_KEYS)
+            .transpose()
+    })
+    .flat_map(|batch| {
+        let (entries, failed) = match batch {
+            Ok(entries) => (entries, None),
+            Err(e) => (Vec::new(), Some(Err(e))),
+        };
+        entries.into_iter().map(Ok).chain(failed)
+    })
+}
+
+// Hand `encode` the entries up to the first one that failed to read, then fail
+// with that error so the output is thrown away rather than missing keys
+pub fn encode_entries<E>(
+    entries: impl IntoIterator<Item = Result<E>>,
+    encode: impl FnOnce(&mut dyn Iterator<Item = E>) -> Result<()>,
+) -> Result<()> {
+    let mut failed = None;
+    let mut entries = entries
+        .into_iter()
+        .map_while(|entry| entry.map_err(|e| failed = Some(e)).ok());
+    let res = encode(&mut entries);
+    drop(entries);
+    res.and(failed.map_or(Ok(()), Err))
+}
+
+// Write `entries` atomically to `path` in the native format, unencrypted
+pub fn write<E: Borrow<SnapshotEntry>>(
+    path: &Path,
+    entries: impl IntoIterator<Item = E>,
+) -> Result<()> {
+    write_atomic(path, None, |out| encode(out, entries))
+}
+
+pub fn encode<W: Write, E: Borrow<SnapshotEntry>>(
+    out: W,
+    entries: impl IntoIterator<Item = E>,
+) -> Result<()> {
+    let mut out = Crc64Writer::new(out);
+    out.write_all(SNAPSHOT_MAGIC)?;
+    out.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    for entry in entries {
+        out.write_all(&[TAG_ENTRY])?;
+        bincode::encode_into_std_write(entry.borrow(), &mut out, bincode::config::standard())?;
+    }
+    out.write_all(&[TAG_EOF])?;
+
+    let crc = out.crc();
+    out.into_inner().write_all(&crc.to_le_bytes())?;
+    Ok(())
+}
+
+// Whether `data` starts like a snapshot written by `write`
+pub fn is_snapshot(data: &[u8]) -> bool {
+    data.starts_with(SNAPSHOT_MAGIC)
+}
+
+// Decode a snapshot, dropping the keys whose TTL passed while it sat on disk
+pub fn read(data: &[u8]) -> Result<(Vec<SnapshotEntry>, LoadStats)> {
+    let now = unix_time_ms();
+    let mut entries = Vec::new();
+    let mut stats = LoadStats::default();
+    decode(data, |entry| {
+        if entry.expires_at.is_some_and(|at| at <= now) {
+            stats.expired += 1;
+        } else {
+            stats.loaded += 1;
+            entries.push(entry);
+        }
+    })?;
+    Ok((entries, stats))
+}
+
+// Verify the checksum, then walk every entry of a snapshot
+pub fn decode(data: &[u8], on_entry: impl FnMut(SnapshotEntry)) -> Result<(), Corruption> {
+    let header = SNAPSHOT_MAGIC.len() + 2;
+    if !is_snapshot(data) {
+        return Err(Corruption::new(0, "Not a snapshot file"));
+    }
+    if data.len() < header + 1 + 8 {
+        return Err(Corruption::new(data.len(), "Snapshot is too short"));
+    }
+    let version = u16::from_le_bytes([data[header - 2], data[header - 1]]);
+    if version != SNAPSHOT_VERSION {
+        return Err(Corruption::new(
+            header - 2,
+            format!("Unsupported snapshot version {}", version),
+        ));
+    }
+
+    let (body, trailer) = data.split_at(data.len() - 8);
+    let expected = u64::from_le_bytes(trailer.try_into().unwrap());
+    if crc64(0, body) != expected {
+        // Point at the first entry that doesn't decode, if any, for ds-check
+        walk(body, header, |_| ())?;
+        return Err(Corruption::new(body.len(), "Snapshot checksum mismatch"));
+    }
+    walk(body, header, on_entry)
+}
+
+fn walk(
+    body: &[u8],
+    mut pos: usize,
+    mut on_entry: impl FnMut(SnapshotEntry),
+) -> Result<(), Corruption> {
+    loop {
+        match body.get(pos) {
+            Some(&TAG_ENTRY) => {
+                let (entry, len) = decode_entry(&body[pos + 1..])
+                    .map_err(|e| Corruption::new(pos, format!("Corrupt entry: {}", e)))?;
+                pos += 1 + len;
+                on_entry(entry);
+            }
+            Some(&TAG_EOF) if pos + 1 == body.len() => return Ok(()),
+            Some(tag) => {
+                return Err(Corruption::new(pos, format!("Unexpected tag {:#04x}", tag)));
+            }
+            None => return Err(Corruption::new(pos, "Snapshot ends without EOF marker")),
+        }
+    }
+}
+
+// Decode one entry from `input`, allocating no more than the rest of the file
+// could hold. bincode claims the in-memory size of every container before
+// reading it, up to 24 bytes per byte of input for lists and hashes.
+fn decode_entry(input: &[u8]) -> Result<(SnapshotEntry, usize), bincode::error::DecodeError> {
+    fn limited<const N: usize>(
+        input: &[u8],
+    ) -> Result<(SnapshotEntry, usize), bincode::error::DecodeError> {
+        bincode::decode_from_slice(input, bincode::config::standard().with_limit::<N>())
+    }
+    match input.len().saturating_mul(24) {
+        n if n <= 1 << 20 => limited::<{ 1 << 20 }>(input),
+        n if n <= 1 << 24 => limited::<{ 1 << 24 }>(input),
+        n if n <= 1 << 28 => limited::<{ 1 << 28 }>(input),
+        n if n <= 1 << 32 => limited::<{ 1 << 32 }>(input),
+        n if n <= 1 << 36 => limited::<{ 1 << 36 }>(input),
+        _ => limited::<{ 1 << 40 }>(input),
+    }
+}
+
+// Insert decoded entries into the store, replacing keys with the same name
+pub fn restore(store: &mut CacheStore, entries: Vec<SnapshotEntry>) -> Result<()> {
+    let now = unix_time_ms();
+    for entry in entries {
+        let value = entry
+            .value
+            .into_value(store.encoding_limits())
+            .map_err(|e| anyhow!("Key {}: {}", entry.key, e))?;
+        let loaded = match entry.expires_at {
+            Some(at) => {
+                Entry::with_expiration(value, Duration::from_millis(at.saturating_sub(now)))
+            }
+            None => Entry::new(value),
+        };
+        store.load_entry(entry.key, loaded);
+    }
+    Ok(())
+}
+
+// Load the snapshot or Redis RDB file at `path` into the store, decrypting it as
+// `encryption` says. A missing file is an empty dataset.
+pub fn load(
+    path: &Path,
+    encryption: &EncryptionOptions,
+    store: &mut CacheStore,
+) -> Result<LoadStats> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(LoadStats::default()),
+        Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+    };
+    let data = encryption.open(data)?;
+    restore_data(&data, store)
+}
+
+// Load a decrypted snapshot or Redis RDB file into the store
+pub fn restore_data(data: &[u8], store: &mut CacheStore) -> Result<LoadStats> {
+    let (entries, stats) = if rdb::is_rdb(data) {
+        rdb::read(data)?
+    } else {
+        read(data)?
+    };
+    restore(store, entries)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tier::{DiskTier, TierOptions};
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let limits = EncodingLimits::default();
+        let mut list = ListValue::new();
+        list.push_right("a", &limits);
+        list.push_right("b", &limits);
+        let mut hash = HashValue::new();
+        hash.set("f", "v", &limits);
+
+        let mut store = CacheStore::new(4);
+        store.load_entry(
+            "s".into(),
+            Entry::new(Value::String(StringValue::new("hi"))),
+        );
+        store.load_entry("l".into(), Entry::new(Value::List(list)));
+        store.load_entry(
+            "h".into(),
+            Entry::with_expiration(Value::Hash(hash), Duration::from_secs(60)),
+        );
+
+        let path = std::env::temp_dir().join(format!("snapshot-test-{}.snap", std::process::id()));
+        write(&path, &collect(&store).unwrap()).unwrap();
+        let mut loaded = CacheStore::new(4);
+        let stats = load(&path, &EncryptionOptions::default(), &mut loaded).unwrap();
+        assert_eq!(
+            stats,
+            LoadStats {
+                loaded: 3,
+                expired: 0
+            }
+        );
+        assert_eq!(loaded.get("s").unwrap(), store.get("s").unwrap());
+        assert_eq!(loaded.get("l").unwrap(), store.get("l").unwrap());
+        assert!(loaded.ttl("h").0 > Duration::from_secs(50));
+
+        // A flipped byte fails the checksum
+        let mut data = fs::read(&path).unwrap();
+        data[12] ^= 0xff;
+        assert!(read(&data).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_length_fails_before_allocating() {
+        // An entry claiming a 16 TiB key
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend(SNAPSHOT_VERSION.to_le_bytes());
+        data.extend([TAG_ENTRY, 0xfd]);
+        data.extend((1u64 << 44).to_le_bytes());
+        data.extend([0; 21]);
+        let mut seen = 0;
+        let corruption = decode(&data, |_| seen += 1).unwrap_err();
+        assert_eq!((corruption.offset, seen), (9, 0));
+
+        // With a matching checksum the length is still bounded by the file
+        let body = data.len() - 8;
+        let crc = crc64(0, &data[..body]);
+        data[body..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(decode(&data, |_| ()).unwrap_err().offset, 9);
+
+        // Many small elements are within the bound
+        let entry = SnapshotEntry {
+            key: String::new(),
+            expires_at: None,
+            value: SnapshotValue::Hash(vec![(Vec::new(), Vec::new()); 100_000]),
+        };
+        let mut data = Vec::new();
+        encode(&mut data, [&entry]).unwrap();
+        let mut decoded = Vec::new();
+        decode(&data, |entry| decoded.push(entry)).unwrap();
+        assert_eq!(decoded, [entry]);
+    }
+
+    #[test]
+    fn test_corrupt_entry_length_is_reported() {
+        let entries = [
+            SnapshotEntry {
+                key: "a".into(),
+                expires_at: None,
+                value: SnapshotValue::String(b"1".to_vec()),
+            },
+            SnapshotEntry {
+                key: "b".into(),
+                expires_at: None,
+                value: SnapshotValue::List(vec![b"x".to_vec(); 3]),
+            },
+        ];
+        let mut data = Vec::new();
+        encode(&mut data, &entries).unwrap();
+        // The second entry's list length turns into a huge varint
+        let first = bincode::encode_to_vec(&entries[0], bincode::config::standard()).unwrap();
+        let second = SNAPSHOT_MAGIC.len() + 2 + 1 + first.len();
+        data[second + 5..second + 6].copy_from_slice(&[0xfd]);
+
+        // ds-check gets the offset of the entry, no key is handed out
+        let mut seen = 0;
+        let corruption = decode(&data, |_| seen += 1).unwrap_err();
+        assert_eq!(corruption.offset, second);
+        assert_eq!(seen, 0);
+    }
+
+    #[test]
+    fn test_unreadable_spilled_value_fails_snapshot() {
+        let path = std::env::temp_dir().join(format!("snapshot-tier-{}.log", std::process::id()));
+        let mut store = CacheStore::new(4);
+        store.set_tier(DiskTier::new(
+            TierOptions {
+                memory_limit: 1,
+                min_value_size: 0,
+                filename: String::new(),
+            },
+            path.clone(),
+        ));
+        for key in ["a", "b"] {
+            store.load_entry(
+                key.into(),
+                Entry::new(Value::String(StringValue::new(vec![b'x'; 100]))),
+            );
+        }
+        assert_eq!(store.spill_cold().unwrap(), 2);
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+
+        assert!(collect(&store).is_err());
+        let start = store.begin_snapshot();
+        assert!(store.snapshot_batch(start.id, 10).is_err());
+        store.end_snapshot(start.id);
+        // Also when the key was set aside by a write before the walk reached it
+        let start = store.begin_snapshot();
+        store.delete(vec!["a".into()]);
+        assert!(store.snapshot_batch(start.id, 10).is_err());
+        store.end_snapshot(start.id);
+        assert!(store.get("b").is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_nan_score_is_rejected() {
+        let limits = EncodingLimits::default();
+        let zset = SnapshotValue::SortedSet(vec![(b"a".to_vec(), 1.0), (b"b".to_vec(), f64::NAN)]);
+        assert!(zset.into_value(&limits).is_err());
+    }
+
+    #[test]
+    fn test_incremental_snapshot_is_point_in_time() {
+        let mut store = CacheStore::new(4);
+        for key in ["a", "b", "c", "d"] {
+            store.load_entry(key.into(), Entry::new(Value::String(StringValue::new(key))));
+        }
+        let mut before = collect(&store).unwrap();
+        let start = store.begin_snapshot();
+        assert_eq!((start.keys, start.expires), (4, 0));
+        // A second snapshot running alongside sees the same point in time
+        let other = store.begin_snapshot();
+
+        let mut taken = store.snapshot_batch(start.id, 2).unwrap().unwrap();
+        // Writes to visited and unvisited keys, a delete and a new key
+        let new_value = || Entry::new(Value::String(StringValue::new("new")));
+        for key in ["a", "b", "c"] {
+            store.restore(key.into(), new_value());
+        }
+        store.delete(vec!["d".into()]);
+        store.restore("e".into(), new_value());
+        // A deleted key set again is a new key, its writes keep the old image
+        for _ in 0..2 {
+            store.restore("d".into(), new_value());
+        }
+        store.delete(vec!["a".into()]);
+        store.restore("a".into(), new_value());
+
+        while let Some(batch) = store.snapshot_batch(start.id, 2).unwrap() {
+            taken.extend(batch);
+        }
+        store.end_snapshot(start.id);
+        let mut other_taken = Vec::new();
+        while let Some(batch) = store.snapshot_batch(other.id, 3).unwrap() {
+            other_taken.extend(batch);
+        }
+        store.end_snapshot(other.id);
+        before.sort_by(|a, b| a.key.cmp(&b.key));
+        taken.sort_by(|a, b| a.key.cmp(&b.key));
+        other_taken.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(taken, before);
+        assert_eq!(other_taken, before);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::commands::BasicCommand;
+use crate::commands::{BasicCommand, RestoreOptions};
 
 use anyhow::{Result, anyhow};
 
@@ -42,6 +42,22 @@ impl BasicCommand {
                     keys: args[1..].to_vec(),
                 })
             }
+            "UNLINK" => {
+                if args.len() < 2 {
+                    return Err(anyhow!(
+                        "UNLINK command requires at least one key".to_string()
+                    ));
+                }
+                Ok(BasicCommand::Unlink {
+                    keys: args[1..].to_vec(),
+                })
+            }
+            "FLUSHDB" => Ok(BasicCommand::FlushDb {
+                lazy: parse_flush_mode(args)?,
+            }),
+            "FLUSHALL" => Ok(BasicCommand::FlushAll {
+                lazy: parse_flush_mode(args)?,
+            }),
             "EXISTS" => {
                 if args.len() < 2 {
                     return Err(anyhow!(
@@ -66,6 +82,31 @@ impl BasicCommand {
                     seconds,
                 })
             }
+            "PEXPIREAT" => {
+                if args.len() != 3 {
+                    return Err(anyhow!(
+                        "PEXPIREAT command requires exactly two arguments".to_string()
+                    ));
+                }
+                let timestamp_ms = args[2]
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid timestamp value for PEXPIREAT".to_string()))?;
+                Ok(BasicCommand::PExpireAt {
+                    key: args[1].clone(),
+                    timestamp_ms,
+                })
+            }
+            "DUMP" => {
+                if args.len() != 2 {
+                    return Err(anyhow!(
+                        "DUMP command requires exactly one argument".to_string()
+                    ));
+                }
+                Ok(BasicCommand::Dump {
+                    key: args[1].clone(),
+                })
+            }
+            "RESTORE" => parse_restore(args),
             "TTL" => {
                 if args.len() != 2 {
                     return Err(anyhow!(
@@ -100,3 +141,100 @@ impl BasicCommand {
         }
     }
 }
+
+// RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency].
+// FREQ is checked and ignored, keys have no LFU counter to restore.
+fn parse_restore(args: &[String]) -> Result<BasicCommand> {
+    if args.len() < 4 {
+        return Err(anyhow!("RESTORE command requires at least three arguments"));
+    }
+    let ttl_ms = args[2]
+        .parse::<i64>()
+        .map_err(|_| anyhow!("value is not an integer or out of range"))?;
+    let ttl_ms = u64::try_from(ttl_ms).map_err(|_| anyhow!("Invalid TTL value, must be >= 0"))?;
+
+    let mut options = RestoreOptions::default();
+    let mut freq = false;
+    let mut i = 4;
+    while i < args.len() {
+        match (args[i].to_uppercase().as_str(), args.get(i + 1)) {
+            ("REPLACE", _) => options.replace = true,
+            ("ABSTTL", _) => options.absttl = true,
+            ("IDLETIME", Some(value)) => {
+                let idle = value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|idle| *idle >= 0)
+                    .ok_or_else(|| anyhow!("Invalid IDLETIME value, must be >= 0"))?;
+                options.idle_time = Some(idle as u64);
+                i += 1;
+            }
+            ("FREQ", Some(value)) => {
+                value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|freq| (0..=255).contains(freq))
+                    .ok_or_else(|| anyhow!("Invalid FREQ value, must be >= 0 and <= 255"))?;
+                freq = true;
+                i += 1;
+            }
+            _ => return Err(anyhow!("syntax error")),
+        }
+        i += 1;
+    }
+    // An LRU idle time and an LFU frequency don't go together
+    if freq && options.idle_time.is_some() {
+        return Err(anyhow!("syntax error"));
+    }
+
+    Ok(BasicCommand::Restore {
+        key: args[1].clone(),
+        ttl_ms,
+        payload: args[3].clone(),
+        options,
+    })
+}
+
+// FLUSHDB/FLUSHALL [ASYNC | SYNC], synchronous by default
+fn parse_flush_mode(args: &[String]) -> Result<bool> {
+    match args.len() {
+        1 => Ok(false),
+        2 => match args[1].to_uppercase().as_str() {
+            "ASYNC" => Ok(true),
+            "SYNC" => Ok(false),
+            _ => Err(anyhow!("syntax error")),
+        },
+        _ => Err(anyhow!("syntax error")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(cmd: &str) -> Vec<String> {
+        cmd.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_restore_freq() {
+        let cmd = parse_restore(&to_args("RESTORE k 0 payload REPLACE FREQ 255")).unwrap();
+        assert_eq!(
+            cmd,
+            BasicCommand::Restore {
+                key: "k".to_string(),
+                ttl_ms: 0,
+                payload: "payload".to_string(),
+                options: RestoreOptions {
+                    replace: true,
+                    ..Default::default()
+                },
+            }
+        );
+        assert!(parse_restore(&to_args("RESTORE k 0 payload FREQ 256")).is_err());
+        assert!(parse_restore(&to_args("RESTORE k 0 payload FREQ -1")).is_err());
+        assert!(parse_restore(&to_args("RESTORE k 0 payload FREQ")).is_err());
+        assert!(parse_restore(&to_args("RESTORE k 0 payload FREQ 1 IDLETIME 5")).is_err());
+        assert!(parse_restore(&to_args("RESTORE k 0 payload IDLETIME 5 FREQ 1")).is_err());
+    }
+}
@@ -15,15 +15,14 @@ pub fn encode_value(value: Value) -> Result<BytesFrame> {
 }
 
 fn encode_string(s_v: StringValue) -> Result<BytesFrame> {
-    Ok(BytesFrame::BulkString(s_v.data.into()))
+    Ok(BytesFrame::BulkString(s_v.to_bytes()))
 }
 
 fn encode_list(list_v: ListValue) -> Result<BytesFrame> {
     Ok(BytesFrame::Array(
         list_v
-            .elements
-            .into_iter()
-            .map(|e| BytesFrame::BulkString(e.into()))
+            .iter()
+            .map(|e| BytesFrame::BulkString(e.into_owned().into()))
             .collect(),
     ))
 }
@@ -31,22 +30,30 @@ fn encode_list(list_v: ListValue) -> Result<BytesFrame> {
 fn encode_set(set_v: SetValue) -> Result<BytesFrame> {
     Ok(BytesFrame::Array(
         set_v
-            .members
-            .into_iter()
-            .map(|m| BytesFrame::BulkString(m.into()))
+            .iter()
+            .map(|m| BytesFrame::BulkString(m.into_owned().into()))
             .collect(),
     ))
 }
 
 fn encode_hash(hash_v: HashValue) -> Result<BytesFrame> {
-    let mut arr = Vec::with_capacity(hash_v.fields.len() * 2);
-    for (k, v) in hash_v.fields {
-        arr.push(BytesFrame::BulkString(k.into()));
-        arr.push(BytesFrame::BulkString(v.into()));
+    let mut arr = Vec::with_capacity(hash_v.len() * 2);
+    for (k, v) in hash_v.iter() {
+        arr.push(BytesFrame::BulkString(k.into_owned().into()));
+        arr.push(BytesFrame::BulkString(v.into_owned().into()));
     }
     Ok(BytesFrame::Array(arr))
 }
 
+pub fn encode_array(items: Vec<Vec<u8>>) -> Result<BytesFrame> {
+    Ok(BytesFrame::Array(
+        items
+            .into_iter()
+            .map(|item| BytesFrame::BulkString(item.into()))
+            .collect(),
+    ))
+}
+
 pub fn encode_integer(v: i64) -> Result<BytesFrame> {
     Ok(BytesFrame::Integer(v))
 }
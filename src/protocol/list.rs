@@ -15,6 +15,7 @@ impl ListCommand {
             "LPOP" => parse_lpop(args),
             "RPOP" => parse_rpop(args),
             "LLEN" => parse_llen(args),
+            "LINDEX" => parse_lindex(args),
             "LRANGE" => parse_lrange(args),
             _ => Err(anyhow!("Unknown list command: {}", cmd_name)),
         }
@@ -44,22 +45,30 @@ fn parse_rpush(args: &[String]) -> Result<ListCommand> {
 }
 
 fn parse_lpop(args: &[String]) -> Result<ListCommand> {
-    if args.len() != 2 {
-        return Err(anyhow!("LPOP requires exactly 2 argument".to_string()));
+    if args.len() != 2 && args.len() != 3 {
+        return Err(anyhow!("LPOP requires 1 or 2 arguments".to_string()));
     }
 
     let key = args[1].clone();
-    let count = args.get(2).and_then(|s| s.parse::<u64>().ok());
+    let count = args
+        .get(2)
+        .map(|s| s.parse::<u64>())
+        .transpose()
+        .map_err(|_| anyhow!("Invalid count".to_string()))?;
     Ok(ListCommand::LPop { key, count })
 }
 
 fn parse_rpop(args: &[String]) -> Result<ListCommand> {
-    if args.len() != 2 {
-        return Err(anyhow!("RPOP requires exactly 2 argument".to_string()));
+    if args.len() != 2 && args.len() != 3 {
+        return Err(anyhow!("RPOP requires 1 or 2 arguments".to_string()));
     }
 
     let key = args[1].clone();
-    let count = args.get(2).and_then(|s| s.parse::<u64>().ok());
+    let count = args
+        .get(2)
+        .map(|s| s.parse::<u64>())
+        .transpose()
+        .map_err(|_| anyhow!("Invalid count".to_string()))?;
     Ok(ListCommand::RPop { key, count })
 }
 
@@ -72,6 +81,19 @@ fn parse_llen(args: &[String]) -> Result<ListCommand> {
     Ok(ListCommand::LLen { key })
 }
 
+fn parse_lindex(args: &[String]) -> Result<ListCommand> {
+    if args.len() != 3 {
+        return Err(anyhow!("LINDEX requires exactly 2 arguments".to_string()));
+    }
+
+    let key = args[1].clone();
+    let index = args[2]
+        .parse::<i64>()
+        .map_err(|_| anyhow!("Invalid index".to_string()))?;
+
+    Ok(ListCommand::LIndex { key, index })
+}
+
 fn parse_lrange(args: &[String]) -> Result<ListCommand> {
     if args.len() != 4 {
         return Err(anyhow!("LRANGE requires exactly 3 arguments".to_string()));
@@ -1,19 +1,22 @@
 use crate::commands::{
-    BasicCommand, Command, HashCommand, ListCommand, SetCommand, SortedSetCommand, StringCommand,
+    BasicCommand, Command, HashCommand, ListCommand, PubSubCommand, ServerCommand, SetCommand,
+    SortedSetCommand, StringCommand,
 };
 use anyhow::{Result, anyhow};
 use redis_protocol::resp2::types::OwnedFrame as Frame;
-use tracing::info;
+use tracing::trace;
 
 pub mod basic;
 pub mod encode;
 pub mod hash;
 pub mod list;
+pub mod pubsub;
+pub mod server;
 pub mod set;
 pub mod sorted_set;
 pub mod strings;
 
-fn extract_command_args(frame: Frame) -> Result<Vec<String>> {
+pub fn extract_command_args(frame: Frame) -> Result<Vec<String>> {
     match frame {
         Frame::Array(data) => {
             let mut args = Vec::new();
@@ -54,40 +57,157 @@ impl From<Frame> for Command {
 
 pub fn from_frame(frame: Frame) -> Result<Command> {
     let args = extract_command_args(frame)?;
+    from_args(&args)
+}
+
+// Every command `from_args` knows, lowercase, as latency histograms name them
+pub const COMMANDS: &[&str] = &[
+    // String commands
+    "get",
+    "set",
+    "getset",
+    "setnx",
+    "setex",
+    "mget",
+    "mset",
+    "msetnx",
+    "append",
+    "strlen",
+    "incr",
+    "incrby",
+    "incrbyfloat",
+    "decr",
+    "decrby",
+    "getrange",
+    "setrange",
+    // List commands
+    "lpush",
+    "rpush",
+    "lpop",
+    "rpop",
+    "llen",
+    "lindex",
+    "lrange",
+    // Set commands
+    "sadd",
+    "srem",
+    "smembers",
+    "scard",
+    "sismember",
+    // Hash commands
+    "hset",
+    "hget",
+    "hdel",
+    "hgetall",
+    "hlen",
+    "hmset",
+    "hmget",
+    "hexists",
+    "hkeys",
+    "hvals",
+    // Sorted Set commands
+    "zadd",
+    "zrem",
+    "zrange",
+    "zcard",
+    "zscore",
+    "zrank",
+    "zrevrank",
+    // Basic commands
+    "ping",
+    "expire",
+    "pexpireat",
+    "ttl",
+    "echo",
+    "del",
+    "unlink",
+    "exists",
+    "keys",
+    "type",
+    "flushdb",
+    "flushall",
+    "dump",
+    "restore",
+    // Server commands
+    "object",
+    "memory",
+    "slowlog",
+    "client",
+    "latency",
+    "monitor",
+    "save",
+    "bgsave",
+    "lastsave",
+    "bgrewriteaof",
+    "debug",
+    "info",
+    "replicaof",
+    "slaveof",
+    "replconf",
+    "psync",
+    "role",
+    "wait",
+    // Pub/Sub commands
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "publish",
+];
+
+// The name of a known command as listed in `COMMANDS`
+pub fn command_name(name: &str) -> Option<&'static str> {
+    COMMANDS
+        .iter()
+        .find(|command| command.eq_ignore_ascii_case(name))
+        .copied()
+}
 
-    info!("[from_frame] args: {:?}", args);
+pub fn from_args(args: &[String]) -> Result<Command> {
+    trace!("[from_frame] args: {:?}", args);
     if args.is_empty() {
         return Err(anyhow!("Empty command".to_string()));
     }
 
     let cmd_name = args[0].to_uppercase();
-    info!("[from_frame] cmd_name: {}", cmd_name);
+    trace!("[from_frame] cmd_name: {}", cmd_name);
 
     match cmd_name.as_str() {
         // String commands
         "GET" | "SET" | "GETSET" | "SETNX" | "SETEX" | "MGET" | "MSET" | "MSETNX" | "APPEND"
         | "STRLEN" | "INCR" | "INCRBY" | "INCRBYFLOAT" | "DECR" | "DECRBY" | "GETRANGE"
-        | "SETRANGE" => Ok(Command::String(StringCommand::from_frame_args(&args)?)),
+        | "SETRANGE" => Ok(Command::String(StringCommand::from_frame_args(args)?)),
         // List commands
-        "LPUSH" | "RPUSH" | "LPOP" | "RPOP" | "LLEN" | "LRANGE" => {
-            Ok(Command::List(ListCommand::from_frame_args(&args)?))
+        "LPUSH" | "RPUSH" | "LPOP" | "RPOP" | "LLEN" | "LINDEX" | "LRANGE" => {
+            Ok(Command::List(ListCommand::from_frame_args(args)?))
         }
         // Set commands
         "SADD" | "SREM" | "SMEMBERS" | "SCARD" | "SISMEMBER" => {
-            Ok(Command::Set(SetCommand::from_frame_args(&args)?))
+            Ok(Command::Set(SetCommand::from_frame_args(args)?))
         }
         // Hash commands
         "HSET" | "HGET" | "HDEL" | "HGETALL" | "HLEN" | "HMSET" | "HMGET" | "HEXISTS" | "HKEYS"
-        | "HVALS" => Ok(Command::Hash(HashCommand::from_frame_args(&args)?)),
+        | "HVALS" => Ok(Command::Hash(HashCommand::from_frame_args(args)?)),
 
         // Sorted Set commands
-        "ZADD" | "ZREM" | "ZRANGE" | "ZCARD" | "ZSCORE" => Ok(Command::SortedSet(
-            SortedSetCommand::from_frame_args(&args)?,
-        )),
+        "ZADD" | "ZREM" | "ZRANGE" | "ZCARD" | "ZSCORE" | "ZRANK" | "ZREVRANK" => {
+            Ok(Command::SortedSet(SortedSetCommand::from_frame_args(args)?))
+        }
 
         // Basic commands
-        "PING" | "EXPIRE" | "TTL" | "ECHO" | "DEL" | "EXISTS" | "KEYS" | "TYPE" => {
-            Ok(Command::Basic(BasicCommand::from_frame_args(&args)?))
+        "PING" | "EXPIRE" | "PEXPIREAT" | "TTL" | "ECHO" | "DEL" | "UNLINK" | "EXISTS" | "KEYS"
+        | "TYPE" | "FLUSHDB" | "FLUSHALL" | "DUMP" | "RESTORE" => {
+            Ok(Command::Basic(BasicCommand::from_frame_args(args)?))
+        }
+
+        // Server commands
+        "OBJECT" | "MEMORY" | "SLOWLOG" | "CLIENT" | "LATENCY" | "MONITOR" | "SAVE" | "BGSAVE"
+        | "LASTSAVE" | "BGREWRITEAOF" | "DEBUG" | "INFO" | "REPLICAOF" | "SLAVEOF" | "REPLCONF"
+        | "PSYNC" | "ROLE" | "WAIT" => Ok(Command::Server(ServerCommand::from_frame_args(args)?)),
+
+        // Pub/Sub commands
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "PUBLISH" => {
+            Ok(Command::PubSub(PubSubCommand::from_frame_args(args)?))
         }
 
         // Unknown command
@@ -97,3 +217,18 @@ pub fn from_frame(frame: Frame) -> Result<Command> {
         }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_names_are_known() {
+        for name in COMMANDS {
+            let parsed = from_args(&[name.to_string()]);
+            assert!(!matches!(parsed, Ok(Command::Unknown { .. })), "{}", name);
+        }
+        assert_eq!(command_name("GeT"), Some("get"));
+        assert_eq!(command_name("nosuchcommand"), None);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::commands::PubSubCommand;
+
+use anyhow::{Result, anyhow};
+
+impl PubSubCommand {
+    pub fn from_frame_args(args: &[String]) -> Result<Self> {
+        if args.is_empty() {
+            return Err(anyhow!("Empty command".to_string()));
+        }
+
+        let cmd_name = args[0].to_uppercase();
+        match cmd_name.as_str() {
+            "SUBSCRIBE" => {
+                if args.len() < 2 {
+                    return Err(anyhow!("SUBSCRIBE requires at least 1 channel".to_string()));
+                }
+                Ok(PubSubCommand::Subscribe {
+                    channels: args[1..].to_vec(),
+                })
+            }
+            "UNSUBSCRIBE" => Ok(PubSubCommand::Unsubscribe {
+                channels: args[1..].to_vec(),
+            }),
+            "PSUBSCRIBE" => {
+                if args.len() < 2 {
+                    return Err(anyhow!(
+                        "PSUBSCRIBE requires at least 1 pattern".to_string()
+                    ));
+                }
+                Ok(PubSubCommand::PSubscribe {
+                    patterns: args[1..].to_vec(),
+                })
+            }
+            "PUNSUBSCRIBE" => Ok(PubSubCommand::PUnsubscribe {
+                patterns: args[1..].to_vec(),
+            }),
+            "PUBLISH" => {
+                if args.len() != 3 {
+                    return Err(anyhow!("PUBLISH requires exactly 2 arguments".to_string()));
+                }
+                Ok(PubSubCommand::Publish {
+                    channel: args[1].clone(),
+                    message: args[2].clone(),
+                })
+            }
+            _ => Err(anyhow!("Unknown pubsub command: {}", cmd_name)),
+        }
+    }
+}
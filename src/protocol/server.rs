@@ -0,0 +1,250 @@
+use crate::commands::ServerCommand;
+
+use anyhow::{Result, anyhow};
+
+impl ServerCommand {
+    pub fn from_frame_args(args: &[String]) -> Result<Self> {
+        if args.is_empty() {
+            return Err(anyhow!("Empty command".to_string()));
+        }
+
+        let cmd_name = args[0].to_uppercase();
+        match cmd_name.as_str() {
+            "OBJECT" => parse_object(args),
+            "MEMORY" => parse_memory(args),
+            "SLOWLOG" => parse_slowlog(args),
+            "CLIENT" => parse_client(args),
+            "LATENCY" => parse_latency(args),
+            "MONITOR" if args.len() == 1 => Ok(ServerCommand::Monitor),
+            "SAVE" if args.len() == 1 => Ok(ServerCommand::Save),
+            "BGSAVE" if args.len() == 1 => Ok(ServerCommand::BgSave),
+            "LASTSAVE" if args.len() == 1 => Ok(ServerCommand::LastSave),
+            "BGREWRITEAOF" if args.len() == 1 => Ok(ServerCommand::BgRewriteAof),
+            "DEBUG" => parse_debug(args),
+            "INFO" => Ok(ServerCommand::Info {
+                sections: args[1..].iter().map(|s| s.to_lowercase()).collect(),
+            }),
+            "REPLICAOF" | "SLAVEOF" => parse_replicaof(args),
+            "REPLCONF" if args.len() % 2 == 1 => Ok(ServerCommand::ReplConf {
+                options: args[1..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_lowercase(), pair[1].clone()))
+                    .collect(),
+            }),
+            "PSYNC" if args.len() == 3 => Ok(ServerCommand::PSync {
+                replid: args[1].clone(),
+                offset: args[2]
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid PSYNC offset: {}", args[2]))?,
+            }),
+            "ROLE" if args.len() == 1 => Ok(ServerCommand::Role),
+            "WAIT" if args.len() == 3 => Ok(ServerCommand::Wait {
+                numreplicas: args[1]
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid number of replicas: {}", args[1]))?,
+                timeout: args[2]
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid timeout: {}", args[2]))?,
+            }),
+            _ => Err(anyhow!("Unknown server command: {}", cmd_name)),
+        }
+    }
+}
+
+fn parse_replicaof(args: &[String]) -> Result<ServerCommand> {
+    if args.len() != 3 {
+        return Err(anyhow!("REPLICAOF requires a host and a port, or NO ONE"));
+    }
+    if args[1].eq_ignore_ascii_case("no") && args[2].eq_ignore_ascii_case("one") {
+        return Ok(ServerCommand::ReplicaOf { master: None });
+    }
+    let port = args[2]
+        .parse::<u16>()
+        .map_err(|_| anyhow!("Invalid master port: {}", args[2]))?;
+    Ok(ServerCommand::ReplicaOf {
+        master: Some((args[1].clone(), port)),
+    })
+}
+
+fn parse_debug(args: &[String]) -> Result<ServerCommand> {
+    if args.len() != 2 {
+        return Err(anyhow!("DEBUG requires exactly 1 subcommand".to_string()));
+    }
+
+    match args[1].to_uppercase().as_str() {
+        "RELOAD" => Ok(ServerCommand::DebugReload),
+        _ => Err(anyhow!("Unknown DEBUG subcommand: {}", args[1])),
+    }
+}
+
+fn parse_object(args: &[String]) -> Result<ServerCommand> {
+    if args.len() < 2 {
+        return Err(anyhow!("OBJECT requires a subcommand".to_string()));
+    }
+
+    let subcommand = args[1].to_uppercase();
+    if subcommand == "HELP" {
+        return Ok(ServerCommand::ObjectHelp);
+    }
+
+    if args.len() != 3 {
+        return Err(anyhow!("OBJECT {} requires exactly 1 key", subcommand));
+    }
+
+    let key = args[2].clone();
+    match subcommand.as_str() {
+        "ENCODING" => Ok(ServerCommand::ObjectEncoding { key }),
+        "IDLETIME" => Ok(ServerCommand::ObjectIdleTime { key }),
+        "REFCOUNT" => Ok(ServerCommand::ObjectRefCount { key }),
+        _ => Err(anyhow!("Unknown OBJECT subcommand: {}", args[1])),
+    }
+}
+
+fn parse_memory(args: &[String]) -> Result<ServerCommand> {
+    if args.len() < 2 {
+        return Err(anyhow!("MEMORY requires a subcommand".to_string()));
+    }
+
+    match args[1].to_uppercase().as_str() {
+        "USAGE" => {
+            if args.len() != 3 && args.len() != 5 {
+                return Err(anyhow!(
+                    "MEMORY USAGE requires a key and optionally SAMPLES count".to_string()
+                ));
+            }
+
+            let key = args[2].clone();
+            let mut samples = None;
+            if args.len() == 5 {
+                if args[3].to_uppercase() != "SAMPLES" {
+                    return Err(anyhow!("Unknown MEMORY USAGE option: {}", args[3]));
+                }
+                samples = Some(
+                    args[4]
+                        .parse::<u64>()
+                        .map_err(|_| anyhow!("Invalid SAMPLES value".to_string()))?,
+                );
+            }
+
+            Ok(ServerCommand::MemoryUsage { key, samples })
+        }
+        "STATS" => Ok(ServerCommand::MemoryStats),
+        "HELP" => Ok(ServerCommand::MemoryHelp),
+        _ => Err(anyhow!("Unknown MEMORY subcommand: {}", args[1])),
+    }
+}
+
+fn parse_slowlog(args: &[String]) -> Result<ServerCommand> {
+    if args.len() < 2 {
+        return Err(anyhow!("SLOWLOG requires a subcommand".to_string()));
+    }
+
+    match (args[1].to_uppercase().as_str(), args.len()) {
+        ("GET", 2) => Ok(ServerCommand::SlowlogGet { count: None }),
+        ("GET", 3) => {
+            let count = args[2]
+                .parse::<i64>()
+                .ok()
+                .filter(|count| *count >= -1)
+                .ok_or_else(|| anyhow!("Invalid SLOWLOG GET count".to_string()))?;
+            Ok(ServerCommand::SlowlogGet { count: Some(count) })
+        }
+        ("LEN", 2) => Ok(ServerCommand::SlowlogLen),
+        ("RESET", 2) => Ok(ServerCommand::SlowlogReset),
+        ("HELP", 2) => Ok(ServerCommand::SlowlogHelp),
+        _ => Err(anyhow!(
+            "Unknown SLOWLOG subcommand or wrong number of arguments"
+        )),
+    }
+}
+
+fn parse_latency(args: &[String]) -> Result<ServerCommand> {
+    if args.len() < 2 {
+        return Err(anyhow!("LATENCY requires a subcommand".to_string()));
+    }
+
+    match (args[1].to_uppercase().as_str(), args.len()) {
+        ("LATEST", 2) => Ok(ServerCommand::LatencyLatest),
+        ("HISTORY", 3) => Ok(ServerCommand::LatencyHistory {
+            event: args[2].clone(),
+        }),
+        ("RESET", _) => Ok(ServerCommand::LatencyReset {
+            events: args[2..].to_vec(),
+        }),
+        ("HISTOGRAM", _) => Ok(ServerCommand::LatencyHistogram {
+            commands: args[2..].to_vec(),
+        }),
+        ("HELP", 2) => Ok(ServerCommand::LatencyHelp),
+        _ => Err(anyhow!(
+            "Unknown LATENCY subcommand or wrong number of arguments"
+        )),
+    }
+}
+
+fn parse_client(args: &[String]) -> Result<ServerCommand> {
+    if args.len() < 2 {
+        return Err(anyhow!("CLIENT requires a subcommand".to_string()));
+    }
+
+    match (args[1].to_uppercase().as_str(), args.len()) {
+        ("ID", 2) => Ok(ServerCommand::ClientId),
+        ("SETNAME", 3) => Ok(ServerCommand::ClientSetName {
+            name: args[2].clone(),
+        }),
+        ("GETNAME", 2) => Ok(ServerCommand::ClientGetName),
+        _ => Err(anyhow!(
+            "Unknown CLIENT subcommand or wrong number of arguments"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(cmd: &str) -> Vec<String> {
+        cmd.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let cmd = ServerCommand::from_frame_args(&to_args("OBJECT encoding mykey")).unwrap();
+        assert_eq!(
+            cmd,
+            ServerCommand::ObjectEncoding {
+                key: "mykey".to_string()
+            }
+        );
+
+        let cmd = ServerCommand::from_frame_args(&to_args("OBJECT HELP")).unwrap();
+        assert_eq!(cmd, ServerCommand::ObjectHelp);
+
+        assert!(ServerCommand::from_frame_args(&to_args("OBJECT FREQ mykey")).is_err());
+    }
+
+    #[test]
+    fn test_parse_memory_usage() {
+        let cmd = ServerCommand::from_frame_args(&to_args("MEMORY USAGE mykey SAMPLES 0")).unwrap();
+        assert_eq!(
+            cmd,
+            ServerCommand::MemoryUsage {
+                key: "mykey".to_string(),
+                samples: Some(0)
+            }
+        );
+
+        assert!(ServerCommand::from_frame_args(&to_args("MEMORY USAGE mykey SAMPLES")).is_err());
+    }
+
+    #[test]
+    fn test_parse_slowlog() {
+        let cmd = ServerCommand::from_frame_args(&to_args("SLOWLOG get 5")).unwrap();
+        assert_eq!(cmd, ServerCommand::SlowlogGet { count: Some(5) });
+
+        let cmd = ServerCommand::from_frame_args(&to_args("SLOWLOG GET")).unwrap();
+        assert_eq!(cmd, ServerCommand::SlowlogGet { count: None });
+
+        assert!(ServerCommand::from_frame_args(&to_args("SLOWLOG GET -2")).is_err());
+        assert!(ServerCommand::from_frame_args(&to_args("SLOWLOG LEN 1")).is_err());
+    }
+}
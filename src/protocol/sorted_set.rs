@@ -14,6 +14,8 @@ impl SortedSetCommand {
             "ZRANGE" => parse_zrange(args),
             "ZCARD" => parse_zcard(args),
             "ZSCORE" => parse_zscore(args),
+            "ZRANK" => parse_zrank(args),
+            "ZREVRANK" => parse_zrevrank(args),
             _ => Err(anyhow!("Unknown list command: {}", cmd_name)),
         }
     }
@@ -43,7 +45,9 @@ fn parse_zadd(args: &[String]) -> Result<SortedSetCommand> {
     for i in (start_index..args.len()).step_by(2) {
         let score = args[i]
             .parse::<f64>()
-            .map_err(|_| anyhow!("Invalid score value: {}", args[i]))?;
+            .ok()
+            .filter(|score| !score.is_nan())
+            .ok_or_else(|| anyhow!("Invalid score value: {}", args[i]))?;
         let member = args[i + 1].clone();
         pairs.push((score, member));
     }
@@ -86,6 +90,28 @@ fn parse_zscore(args: &[String]) -> Result<SortedSetCommand> {
     Ok(SortedSetCommand::ZScore { key, member })
 }
 
+fn parse_zrank(args: &[String]) -> Result<SortedSetCommand> {
+    if args.len() != 3 {
+        return Err(anyhow!("ZRANK requires exactly 2 arguments".to_string()));
+    }
+
+    let key = args[1].clone();
+    let member = args[2].clone();
+
+    Ok(SortedSetCommand::ZRank { key, member })
+}
+
+fn parse_zrevrank(args: &[String]) -> Result<SortedSetCommand> {
+    if args.len() != 3 {
+        return Err(anyhow!("ZREVRANK requires exactly 2 arguments".to_string()));
+    }
+
+    let key = args[1].clone();
+    let member = args[2].clone();
+
+    Ok(SortedSetCommand::ZRevRank { key, member })
+}
+
 fn parse_zrange(args: &[String]) -> Result<SortedSetCommand> {
     if args.len() < 4 {
         return Err(anyhow!("ZRANGE requires at least 3 arguments".to_string()));
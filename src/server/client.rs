@@ -0,0 +1,69 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU16, Ordering},
+    },
+};
+
+use bytes::Bytes;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+// A connected client, shared by its connection task and the handlers running its
+// commands
+#[derive(Debug)]
+pub struct Client {
+    pub id: u64,
+    pub addr: SocketAddr,
+    name: Mutex<String>,
+    monitor: AtomicBool, // set by MONITOR, the connection then streams executed commands
+    // Set by PSYNC, the connection then writes the replication stream out
+    replica_stream: Mutex<Option<UnboundedReceiver<Bytes>>>,
+    // The port a replica announced with REPLCONF listening-port, 0 if none
+    listening_port: AtomicU16,
+}
+
+impl Client {
+    pub fn new(id: u64, addr: SocketAddr) -> Self {
+        Self {
+            id,
+            addr,
+            name: Mutex::new(String::new()),
+            monitor: AtomicBool::new(false),
+            replica_stream: Mutex::new(None),
+            listening_port: AtomicU16::new(0),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().unwrap().clone()
+    }
+
+    pub fn set_name(&self, name: String) {
+        *self.name.lock().unwrap() = name;
+    }
+
+    pub fn is_monitor(&self) -> bool {
+        self.monitor.load(Ordering::Relaxed)
+    }
+
+    pub fn set_monitor(&self) {
+        self.monitor.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_replica_stream(&self, stream: UnboundedReceiver<Bytes>) {
+        *self.replica_stream.lock().unwrap() = Some(stream);
+    }
+
+    pub fn take_replica_stream(&self) -> Option<UnboundedReceiver<Bytes>> {
+        self.replica_stream.lock().unwrap().take()
+    }
+
+    pub fn listening_port(&self) -> u16 {
+        self.listening_port.load(Ordering::Relaxed)
+    }
+
+    pub fn set_listening_port(&self, port: u16) {
+        self.listening_port.store(port, Ordering::Relaxed);
+    }
+}
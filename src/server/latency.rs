@@ -0,0 +1,218 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Samples kept per event
+const LATENCY_TS_LEN: usize = 160;
+
+// Histogram buckets are powers of two from 1us up to 2^(BUCKETS - 1)us, slower calls
+// land in the last one
+const LATENCY_HISTOGRAM_BUCKETS: usize = 31;
+
+// Internal events recorded against `latency-monitor-threshold`
+pub const EVENT_EXPIRE_CYCLE: &str = "expire-cycle";
+// Starting a background save, AOF rewrite or full sync, while writes wait
+pub const EVENT_FORK: &str = "fork";
+// SAVE, which holds back writers until the file is on disk
+pub const EVENT_RDB_SAVE: &str = "rdb-save";
+// Switching the AOF over to a rewritten base file
+pub const EVENT_AOF_REWRITE: &str = "aof-rewrite";
+// The fsync after every write with `appendfsync always`
+pub const EVENT_AOF_FSYNC_ALWAYS: &str = "aof-fsync-always";
+// Moving cold values to the disk tier
+pub const EVENT_TIER_SPILL: &str = "tier-spill";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencySample {
+    pub time: u64,    // unix time in seconds
+    pub latency: u64, // milliseconds
+}
+
+// Recent samples of one event, at most one per second
+#[derive(Debug, Default)]
+pub struct LatencyTimeSeries {
+    samples: VecDeque<LatencySample>,
+    pub max: u64,
+}
+
+impl LatencyTimeSeries {
+    fn add(&mut self, time: u64, latency: u64) {
+        self.max = self.max.max(latency);
+        if let Some(last) = self.samples.back_mut()
+            && last.time == time
+        {
+            last.latency = last.latency.max(latency);
+            return;
+        }
+        self.samples.push_back(LatencySample { time, latency });
+        if self.samples.len() > LATENCY_TS_LEN {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn latest(&self) -> Option<&LatencySample> {
+        self.samples.back()
+    }
+
+    // Samples, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &LatencySample> {
+        self.samples.iter()
+    }
+}
+
+// Distribution of the call durations of one command
+#[derive(Debug, Clone)]
+pub struct CommandHistogram {
+    pub calls: u64,
+    buckets: [u64; LATENCY_HISTOGRAM_BUCKETS],
+}
+
+impl Default for CommandHistogram {
+    fn default() -> Self {
+        Self {
+            calls: 0,
+            buckets: [0; LATENCY_HISTOGRAM_BUCKETS],
+        }
+    }
+}
+
+impl CommandHistogram {
+    fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros().max(1);
+        // Smallest power of two that is >= micros
+        let bucket = (u128::BITS - (micros - 1).leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_HISTOGRAM_BUCKETS - 1)] += 1;
+        self.calls += 1;
+    }
+
+    // Cumulative (upper bound in microseconds, calls) pairs, skipping buckets that
+    // add no calls
+    pub fn cumulative(&self) -> Vec<(u64, u64)> {
+        let mut total = 0;
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| {
+                total += count;
+                (1u64 << i, total)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct LatencyMonitor {
+    threshold: u64, // milliseconds, 0 disables event sampling
+    events: HashMap<String, LatencyTimeSeries>,
+    // Keyed by `protocol::COMMANDS` names, so unknown commands can't add entries
+    commands: BTreeMap<&'static str, CommandHistogram>,
+}
+
+impl LatencyMonitor {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            events: HashMap::new(),
+            commands: BTreeMap::new(),
+        }
+    }
+
+    // Record an internal event if it reached `latency-monitor-threshold`
+    pub fn add_sample(&mut self, event: &str, duration: Duration) {
+        let latency = duration.as_millis() as u64;
+        if self.threshold == 0 || latency < self.threshold {
+            return;
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.events
+            .entry(event.to_string())
+            .or_default()
+            .add(time, latency);
+    }
+
+    // Record a call of `command`, every call counts regardless of the threshold
+    pub fn record_command(&mut self, command: &'static str, duration: Duration) {
+        self.commands.entry(command).or_default().record(duration);
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = (&String, &LatencyTimeSeries)> {
+        self.events.iter()
+    }
+
+    pub fn event(&self, event: &str) -> Option<&LatencyTimeSeries> {
+        self.events.get(event)
+    }
+
+    // Reset the given events, or all of them when none is given. Returns how many
+    // were reset.
+    pub fn reset(&mut self, events: &[String]) -> usize {
+        if events.is_empty() {
+            let count = self.events.len();
+            self.events.clear();
+            return count;
+        }
+        events
+            .iter()
+            .filter(|event| self.events.remove(event.as_str()).is_some())
+            .count()
+    }
+
+    pub fn histogram(&self, command: &str) -> Option<(&'static str, &CommandHistogram)> {
+        self.commands
+            .get_key_value(command.to_lowercase().as_str())
+            .map(|(name, histogram)| (*name, histogram))
+    }
+
+    pub fn histograms(&self) -> impl Iterator<Item = (&'static str, &CommandHistogram)> {
+        self.commands
+            .iter()
+            .map(|(name, histogram)| (*name, histogram))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_threshold_and_reset() {
+        let mut monitor = LatencyMonitor::new(10);
+        monitor.add_sample(EVENT_EXPIRE_CYCLE, Duration::from_millis(9));
+        assert!(monitor.event(EVENT_EXPIRE_CYCLE).is_none());
+
+        // Samples within the same second are merged, keeping the worst one
+        monitor.add_sample(EVENT_EXPIRE_CYCLE, Duration::from_millis(15));
+        monitor.add_sample(EVENT_EXPIRE_CYCLE, Duration::from_millis(12));
+        let series = monitor.event(EVENT_EXPIRE_CYCLE).unwrap();
+        assert_eq!(series.history().count(), 1);
+        assert_eq!(series.latest().unwrap().latency, 15);
+        assert_eq!(series.max, 15);
+
+        assert_eq!(monitor.reset(&["unknown".to_string()]), 0);
+        assert_eq!(monitor.reset(&[]), 1);
+        assert_eq!(monitor.events().count(), 0);
+
+        let mut disabled = LatencyMonitor::new(0);
+        disabled.add_sample(EVENT_EXPIRE_CYCLE, Duration::from_secs(1));
+        assert_eq!(disabled.events().count(), 0);
+    }
+
+    #[test]
+    fn test_command_histogram() {
+        let mut monitor = LatencyMonitor::new(0);
+        for micros in [0, 1, 3, 4, 1000] {
+            monitor.record_command("get", Duration::from_micros(micros));
+        }
+
+        let (name, histogram) = monitor.histogram("GET").unwrap();
+        assert_eq!(name, "get");
+        assert_eq!(histogram.calls, 5);
+        assert_eq!(histogram.cumulative(), vec![(1, 2), (4, 4), (1024, 5)]);
+    }
+}
@@ -1,96 +1,415 @@
+pub mod client;
+pub mod latency;
+pub mod monitor;
+pub mod pubsub;
+pub mod replication;
+pub mod slowlog;
+pub mod state;
+
 use anyhow::{Result, anyhow};
+use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
 use redis_protocol::codec::Resp2;
+use redis_protocol::resp2::types::BytesFrame;
 use std::sync::Arc;
-use tokio::io;
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncWriteExt, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{info, warn};
+use tracing::{debug, info, trace, warn};
 
-use crate::commands::Command;
 use crate::commands::handlers::CmdHandler;
-use crate::{config::CacheConfig, storage::CacheStore};
+use crate::persistence::{aof, manifest::Manifest, snapshot};
+use crate::protocol::{command_name, extract_command_args, from_args};
+use crate::server::{
+    client::Client,
+    latency::{EVENT_EXPIRE_CYCLE, EVENT_TIER_SPILL},
+    state::ServerState,
+};
+use crate::{
+    config::CacheConfig,
+    storage::{CacheStore, lazyfree::LazyFreer, notify::KeyspaceNotifier, tier::DiskTier},
+};
+
+// How often the active expire cycle runs
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+// Time per cron tick the active expire cycle may hold the store
+const ACTIVE_EXPIRE_BUDGET: Duration = Duration::from_millis(25);
+// Reply to write commands while `stop-writes-on-bgsave-error` holds them back
+const MISCONF_ERROR: &str = "MISCONF Errors writing the snapshot to disk, commands that may modify \
+    the data set are disabled until a background save succeeds (stop-writes-on-bgsave-error)";
 
 #[derive(Debug)]
 pub struct Server {
     pub conf: CacheConfig,
     pub store: Arc<RwLock<CacheStore>>,
+    pub state: Arc<ServerState>,
 }
 
 impl Server {
     pub fn new(conf: CacheConfig, cap: usize) -> Self {
+        let state = Arc::new(ServerState::new(&conf));
+        let mut store = CacheStore::new(cap);
+        store.set_encoding_limits(conf.encoding_limits.clone());
+        store.set_lazyfree(LazyFreer::start(conf.lazyfree.clone()));
+        store.set_tier(DiskTier::new(
+            conf.tiered_storage.clone(),
+            conf.dir.join(&conf.tiered_storage.filename),
+        ));
+        store.set_notifier(KeyspaceNotifier::new(
+            conf.notify_keyspace_events,
+            Arc::clone(&state.pubsub),
+        ));
+        store.set_propagation(Arc::clone(&state));
+
         Self {
-            conf: conf,
-            store: Arc::new(RwLock::new(CacheStore::new(cap))),
+            conf,
+            store: Arc::new(RwLock::new(store)),
+            state,
+        }
+    }
+
+    // Load the dataset and start appending to the AOF when it is enabled
+    async fn load_data(&self) -> Result<()> {
+        if let Some(manifest) = self.load_dataset().await? {
+            let persistence = &self.state.persistence;
+            persistence.open_aof(manifest)?;
+            // Replayed commands are on disk already, they don't count towards save points
+            persistence.reset_dirty(self.store.read().await.dirty());
+        }
+        Ok(())
+    }
+
+    // Load the dataset from the AOF when it is enabled, from the snapshot otherwise.
+    // Returns the manifest of the replayed AOF, which stays closed: offline commands
+    // like export load the dataset this way too.
+    pub async fn load_dataset(&self) -> Result<Option<Manifest>> {
+        let start = Instant::now();
+        let persistence = &self.state.persistence;
+
+        if persistence.appendonly {
+            // Replayed commands run as a client of their own, never logged again
+            let client = self.state.new_client(([0, 0, 0, 0], 0).into());
+            let (push_tx, _push_rx) = mpsc::unbounded_channel();
+            let mut handler = CmdHandler::new(
+                Arc::clone(&self.store),
+                Arc::clone(&self.state),
+                client,
+                push_tx,
+            );
+            let manifest = aof::load(persistence, &self.store, &mut handler)
+                .await
+                .map_err(|e| anyhow!("Failed to load the AOF: {}", e))?;
+            info!(
+                "DB loaded from append only file: {:.3} seconds",
+                start.elapsed().as_secs_f64()
+            );
+            return Ok(Some(manifest));
+        }
+
+        let path = &persistence.snapshot_path;
+        let mut store = self.store.write().await;
+        let stats = snapshot::load(path, &persistence.encryption, &mut store)
+            .map_err(|e| anyhow!("Failed to load {}: {}", path.display(), e))?;
+        if stats.loaded + stats.expired > 0 {
+            info!(
+                "DB loaded from disk: {} keys, {} expired skipped, {:.3} seconds",
+                stats.loaded,
+                stats.expired,
+                start.elapsed().as_secs_f64()
+            );
         }
+        Ok(None)
+    }
+
+    // Background jobs that run every `CRON_INTERVAL`
+    fn spawn_cron(&self) {
+        let store = Arc::clone(&self.store);
+        let state = Arc::clone(&self.state);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CRON_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                // Replicas leave expiring keys to the DELs of their master
+                if !state.replication.is_replica() {
+                    let start = Instant::now();
+                    let expired = store.write().await.cleanup_expired(ACTIVE_EXPIRE_BUDGET);
+                    state.add_latency_sample(EVENT_EXPIRE_CYCLE, start.elapsed());
+                    if expired > 0 {
+                        debug!("active expire cycle removed {} keys", expired);
+                    }
+                }
+                state.replication.cron();
+
+                let start = Instant::now();
+                let spilled = store.write().await.spill_cold();
+                state.add_latency_sample(EVENT_TIER_SPILL, start.elapsed());
+                match spilled {
+                    Ok(0) => {}
+                    Ok(spilled) => debug!("spilled {} cold values to tiered storage", spilled),
+                    Err(e) => warn!("Failed to spill values to tiered storage: {}", e),
+                }
+
+                if let Some(file) = state.persistence.aof_fsync_due() {
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = file.sync_data() {
+                            warn!("Failed to fsync the AOF: {}", e);
+                        }
+                    });
+                }
+                let dirty = store.read().await.dirty();
+                if let Some(point) = state.persistence.save_point_due(dirty) {
+                    info!(
+                        "{} changes in {} seconds. Saving...",
+                        point.changes, point.seconds
+                    );
+                    if let Err(e) = snapshot::save_in_background(&store, &state).await {
+                        warn!("Can't start a background save: {}", e);
+                    }
+                }
+                if state.persistence.aof_rewrite_due() {
+                    info!("Starting automatic rewriting of AOF");
+                    if let Err(e) = aof::rewrite_in_background(&store, &state).await {
+                        warn!("Can't rewrite the AOF: {}", e);
+                    }
+                }
+            }
+        });
     }
 
     pub async fn run(&self) -> Result<()> {
+        self.load_data().await?;
+
         let addr = self.conf.addr.clone();
         let listener = TcpListener::bind(&addr)
             .await
             .map_err(|e| anyhow!("Faile to listen on {}: {}", addr, e))?;
 
-        println!("server listen on: {}", addr);
+        info!("server listen on: {}", addr);
+
+        self.spawn_cron();
+        if let Some((host, port)) = &self.conf.replicaof {
+            replication::start_replica(&self.store, &self.state, host.clone(), *port);
+        }
 
         loop {
             match listener.accept().await {
                 Ok((socket, client_addr)) => {
-                    info!("accept conn from: {}", client_addr);
+                    debug!("accept conn from: {}", client_addr);
 
                     let store = Arc::clone(&self.store);
+                    let state = Arc::clone(&self.state);
+                    let client = state.new_client(client_addr);
+
+                    tokio::spawn(handle_connection(socket, store, state, client));
+                }
+                Err(e) => warn!("Faield to accept conn: {}", e),
+            }
+        }
+    }
+}
 
-                    tokio::spawn(async move {
-                        // Split the socket into read and write halves
-                        let (reader, writer) = io::split(socket);
-
-                        // Create framed reader and writer with Resp2Codec
-                        let mut framed_read = FramedRead::new(reader, Resp2::default());
-
-                        let mut framed_write = FramedWrite::new(writer, Resp2::default());
-
-                        loop {
-                            match framed_read.next().await {
-                                Some(frame_res) => match frame_res {
-                                    Ok(ref frame) => {
-                                        info!("read frame from framed: {:?}", frame_res);
-                                        let owned_frame = frame.to_owned_frame();
-
-                                        let cmd = Command::from(owned_frame);
-                                        info!("success parsed Command: {:?}", cmd);
-
-                                        let mut cmd_handler = CmdHandler::new(Arc::clone(&store));
-
-                                        let cmd_res = cmd_handler.handle_cmd(cmd).await;
-
-                                        if let Ok(write_frame) = cmd_res {
-                                            let _ =
-                                                framed_write.send(write_frame).await.map_err(|e| {
-                                                    anyhow!("Failed to send response: {}", e)
-                                                });
-                                        } else {
-                                            eprintln!(
-                                                "failed to encode frame: {:?}",
-                                                cmd_res.err()
-                                            );
-                                        }
-                                    }
-                                    Err(e) => {
-                                        warn!("fail read frame: {:?}", e);
-                                        break;
-                                    }
-                                },
-                                None => {
-                                    warn!("No frame");
-                                    break;
-                                }
+async fn handle_connection(
+    socket: TcpStream,
+    store: Arc<RwLock<CacheStore>>,
+    state: Arc<ServerState>,
+    client: Arc<Client>,
+) {
+    // Split the socket into read and write halves
+    let (reader, writer) = io::split(socket);
+
+    // Create framed reader and writer with Resp2Codec
+    let mut framed_read = FramedRead::new(reader, Resp2::default());
+
+    let mut framed_write = FramedWrite::new(writer, Resp2::default());
+
+    // Frames queued for this connection: pub/sub messages and subscription replies
+    let (push_tx, mut push_rx) = mpsc::unbounded_channel();
+
+    let mut cmd_handler = CmdHandler::new(
+        Arc::clone(&store),
+        Arc::clone(&state),
+        client.clone(),
+        push_tx,
+    );
+
+    // Commands executed by every client, once this one entered MONITOR mode
+    let mut monitor: Option<broadcast::Receiver<Arc<str>>> = None;
+    // The replication stream, once this connection is a replica's after PSYNC
+    let mut replica_stream: Option<mpsc::UnboundedReceiver<Bytes>> = None;
+
+    loop {
+        let frame_res = tokio::select! {
+            frame_res = framed_read.next() => frame_res,
+            Some(frame) = push_rx.recv() => {
+                if let Err(e) = framed_write.send(frame).await {
+                    warn!("Failed to send pushed frame: {}", e);
+                    break;
+                }
+                continue;
+            }
+            line = next_monitor_line(&mut monitor) => {
+                match line {
+                    Ok(line) => {
+                        let frame = BytesFrame::SimpleString(line.as_bytes().to_vec().into());
+                        if let Err(e) = framed_write.send(frame).await {
+                            warn!("Failed to send monitor line: {}", e);
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("monitor {} is too slow, skipped {} lines", client.addr, skipped);
+                    }
+                    Err(RecvError::Closed) => monitor = None,
+                }
+                continue;
+            }
+            data = next_stream_data(&mut replica_stream) => {
+                // The stream goes out as is, replies were flushed before it started
+                let Some(data) = data else {
+                    debug!("replica {} detached", client.addr);
+                    break;
+                };
+                let out = framed_write.get_mut();
+                if let Err(e) = out.write_all(&data).await.and(out.flush().await) {
+                    warn!("Failed to stream to replica {}: {}", client.addr, e);
+                    break;
+                }
+                continue;
+            }
+        };
+
+        match frame_res {
+            Some(frame_res) => match frame_res {
+                Ok(ref frame) => {
+                    trace!("read frame from framed: {:?}", frame_res);
+                    let owned_frame = frame.to_owned_frame();
+
+                    let args = match extract_command_args(owned_frame) {
+                        Ok(args) => args,
+                        Err(e) => {
+                            let err = format!("ERR Protocol error: {}", e);
+                            let _ = framed_write.send(BytesFrame::Error(err.into())).await;
+                            break;
+                        }
+                    };
+
+                    let start = Instant::now();
+                    let cmd_res = match from_args(&args).map(|cmd| {
+                        let refusal = if cmd.is_write() {
+                            write_refusal(&state)
+                        } else {
+                            None
+                        };
+                        (cmd, refusal)
+                    }) {
+                        Ok((_, Some(refusal))) => Ok(Some(BytesFrame::Error(refusal.into()))),
+                        Ok((cmd, None)) => {
+                            trace!("success parsed Command: {:?}", cmd);
+                            state.monitor.feed(&args, &client);
+                            let gate = if cmd.is_write() {
+                                Some(state.persistence.write_gate.read().await)
+                            } else {
+                                None
+                            };
+                            let res = cmd_handler.handle_cmd(cmd).await;
+                            if gate.is_some()
+                                && !matches!(res, Err(_) | Ok(Some(BytesFrame::Error(_))))
+                            {
+                                state.propagate(&args);
+                            }
+                            drop(gate);
+                            if let Some(name) = command_name(&args[0]) {
+                                state
+                                    .latency
+                                    .lock()
+                                    .unwrap()
+                                    .record_command(name, start.elapsed());
                             }
+                            res
                         }
-                    });
+                        Err(e) => Ok(Some(BytesFrame::Error(format!("ERR {}", e).into()))),
+                    };
+                    let duration = start.elapsed();
+                    state
+                        .slowlog
+                        .lock()
+                        .unwrap()
+                        .record(&args, duration, &client);
+
+                    let reply = cmd_res
+                        .unwrap_or_else(|e| Some(BytesFrame::Error(format!("ERR {}", e).into())));
+                    if let Err(e) = write_replies(&mut framed_write, &mut push_rx, reply).await {
+                        warn!("Failed to send response: {}", e);
+                        break;
+                    }
+
+                    if monitor.is_none() && client.is_monitor() {
+                        monitor = Some(state.monitor.subscribe());
+                    }
+                    if let Some(stream) = client.take_replica_stream() {
+                        replica_stream = Some(stream);
+                    }
                 }
-                Err(e) => warn!("Faield to accept conn: {}", e),
+                Err(e) => {
+                    warn!("fail read frame: {:?}", e);
+                    break;
+                }
+            },
+            None => {
+                debug!("client {} closed the connection", client.addr);
+                break;
             }
         }
     }
+    state.replication.detach(client.id);
+}
+
+// The error a write command gets when writes are held back, if they are
+fn write_refusal(state: &ServerState) -> Option<&'static str> {
+    if state.persistence.writes_refused() {
+        return Some(MISCONF_ERROR);
+    }
+    state.replication.write_refusal()
+}
+
+// Write the frames the command queued, then its own reply, so pipelined replies
+// stay in order
+async fn write_replies(
+    framed_write: &mut FramedWrite<WriteHalf<TcpStream>, Resp2>,
+    push_rx: &mut mpsc::UnboundedReceiver<BytesFrame>,
+    reply: Option<BytesFrame>,
+) -> Result<()> {
+    while let Ok(frame) = push_rx.try_recv() {
+        framed_write.feed(frame).await?;
+    }
+    if let Some(reply) = reply {
+        framed_write.feed(reply).await?;
+    }
+    SinkExt::<BytesFrame>::flush(framed_write).await?;
+    Ok(())
+}
+
+// Wait for the next chunk of the replication stream, or forever when the client
+// is not a replica. None once the replica was dropped.
+async fn next_stream_data(stream: &mut Option<mpsc::UnboundedReceiver<Bytes>>) -> Option<Bytes> {
+    match stream {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Wait for the next monitor line, or forever when the client is not monitoring
+async fn next_monitor_line(
+    monitor: &mut Option<broadcast::Receiver<Arc<str>>>,
+) -> Result<Arc<str>, RecvError> {
+    match monitor {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
 }
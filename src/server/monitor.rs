@@ -0,0 +1,103 @@
+use std::{
+    fmt::Write as _,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::broadcast;
+
+use crate::server::client::Client;
+
+// Lines buffered per monitor, a monitor falling further behind skips the oldest ones
+const MONITOR_BACKLOG: usize = 1024;
+
+// Fans every executed command out to the connections in MONITOR mode. Feeding
+// never waits on the monitors, so a slow one cannot stall the clients it watches.
+#[derive(Debug)]
+pub struct MonitorFeed {
+    tx: broadcast::Sender<Arc<str>>,
+}
+
+impl MonitorFeed {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(MONITOR_BACKLOG);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<str>> {
+        self.tx.subscribe()
+    }
+
+    pub fn feed(&self, args: &[String], client: &Client) {
+        // Skip the formatting when nobody is watching
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.tx.send(format_line(args, client).into());
+    }
+}
+
+impl Default for MonitorFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// `1339518083.107412 [0 127.0.0.1:60866] "set" "key" "value"`
+fn format_line(args: &[String], client: &Client) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = format!(
+        "{}.{:06} [0 {}]",
+        now.as_secs(),
+        now.subsec_micros(),
+        client.addr
+    );
+    for arg in args {
+        line.push(' ');
+        quote_arg(&mut line, arg);
+    }
+    line
+}
+
+// Quote an argument the way redis-cli prints it, escaping anything non printable
+fn quote_arg(out: &mut String, arg: &str) {
+    out.push('"');
+    for byte in arg.bytes() {
+        match byte {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\x{:02x}", byte);
+            }
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let client = Client::new(1, "127.0.0.1:5000".parse().unwrap());
+        let args = vec![
+            "set".to_string(),
+            "k".to_string(),
+            "a \"b\"\n\u{e9}".to_string(),
+        ];
+
+        let line = format_line(&args, &client);
+        let (timestamp, rest) = line.split_once(' ').unwrap();
+        assert!(timestamp.parse::<f64>().is_ok());
+        assert_eq!(rest, r#"[0 127.0.0.1:5000] "set" "k" "a \"b\"\n\xc3\xa9""#);
+    }
+}
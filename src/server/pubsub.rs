@@ -0,0 +1,141 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use bytes::Bytes;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::utils::glob_match;
+
+// Subscribed clients by id, each with the channel feeding its connection
+type Subscribers = HashMap<u64, UnboundedSender<BytesFrame>>;
+
+// Channel and pattern subscriptions of every client. Publishing only queues the
+// message on each subscriber's connection, it never waits for the socket.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    channels: Mutex<HashMap<String, Subscribers>>,
+    patterns: Mutex<HashMap<String, Subscribers>>,
+}
+
+impl PubSub {
+    pub fn subscribe(&self, channel: &str, client_id: u64, tx: UnboundedSender<BytesFrame>) {
+        add_subscriber(&self.channels, channel, client_id, tx);
+    }
+
+    pub fn unsubscribe(&self, channel: &str, client_id: u64) {
+        remove_subscriber(&self.channels, channel, client_id);
+    }
+
+    pub fn psubscribe(&self, pattern: &str, client_id: u64, tx: UnboundedSender<BytesFrame>) {
+        add_subscriber(&self.patterns, pattern, client_id, tx);
+    }
+
+    pub fn punsubscribe(&self, pattern: &str, client_id: u64) {
+        remove_subscriber(&self.patterns, pattern, client_id);
+    }
+
+    // Deliver a message to the channel and matching pattern subscribers. Returns
+    // how many clients received it.
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let mut receivers = 0;
+        let channel_frame = || BytesFrame::BulkString(Bytes::copy_from_slice(channel.as_bytes()));
+
+        if let Some(subscribers) = self.channels.lock().unwrap().get(channel) {
+            for tx in subscribers.values() {
+                let frame = BytesFrame::Array(vec![
+                    BytesFrame::BulkString("message".into()),
+                    channel_frame(),
+                    BytesFrame::BulkString(message.clone()),
+                ]);
+                if tx.send(frame).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        for (pattern, subscribers) in self.patterns.lock().unwrap().iter() {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                continue;
+            }
+            for tx in subscribers.values() {
+                let frame = BytesFrame::Array(vec![
+                    BytesFrame::BulkString("pmessage".into()),
+                    BytesFrame::BulkString(pattern.clone().into()),
+                    channel_frame(),
+                    BytesFrame::BulkString(message.clone()),
+                ]);
+                if tx.send(frame).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        receivers
+    }
+
+    // Whether anyone is subscribed at all, so publishers can skip building messages
+    pub fn is_empty(&self) -> bool {
+        self.channels.lock().unwrap().is_empty() && self.patterns.lock().unwrap().is_empty()
+    }
+}
+
+fn add_subscriber(
+    map: &Mutex<HashMap<String, Subscribers>>,
+    name: &str,
+    client_id: u64,
+    tx: UnboundedSender<BytesFrame>,
+) {
+    map.lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .insert(client_id, tx);
+}
+
+fn remove_subscriber(map: &Mutex<HashMap<String, Subscribers>>, name: &str, client_id: u64) {
+    let mut map = map.lock().unwrap();
+    if let Some(subscribers) = map.get_mut(name) {
+        subscribers.remove(&client_id);
+        if subscribers.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_publish_to_channels_and_patterns() {
+        let pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        pubsub.subscribe("news", 1, tx.clone());
+        pubsub.psubscribe("n*", 1, tx);
+
+        assert_eq!(pubsub.publish("news", Bytes::from_static(b"hi")), 2);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("message".into()),
+                BytesFrame::BulkString("news".into()),
+                BytesFrame::BulkString("hi".into()),
+            ])
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("pmessage".into()),
+                BytesFrame::BulkString("n*".into()),
+                BytesFrame::BulkString("news".into()),
+                BytesFrame::BulkString("hi".into()),
+            ])
+        );
+
+        pubsub.unsubscribe("news", 1);
+        pubsub.punsubscribe("n*", 1);
+        assert!(pubsub.is_empty());
+        assert_eq!(pubsub.publish("news", Bytes::from_static(b"hi")), 0);
+    }
+}
@@ -0,0 +1,918 @@
+// Primary-replica replication. A master streams every write command it runs, and
+// every key it expires as a DEL, to its replicas as RESP arrays. The same stream
+// fills a backlog of its last `repl-backlog-size` bytes. A replication id names
+// the history of the stream and an offset counts its bytes, so a replica that
+// lost its link for a short while asks for what it missed with PSYNC <id>
+// <offset>. Otherwise it gets a full sync: an RDB snapshot of the keyspace, then
+// the writes made since it was taken.
+//
+// A replica applies the stream and feeds it unchanged into its own backlog, so it
+// can serve replicas of its own and resume as a master after REPLICAOF NO ONE.
+// The old id then stays valid up to the offset of the switch, which lets the
+// replicas of the old master continue with a partial sync against it.
+//
+// Replicas acknowledge the offset they applied with REPLCONF ACK every second,
+// or right away when the master asks with REPLCONF GETACK. WAIT waits on those
+// acknowledgements, and min-replicas-to-write refuses writes while too few
+// replicas acknowledged recently.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use bytes::{Bytes, BytesMut};
+use redis_protocol::resp2::{decode::decode, types::BytesFrame};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{Notify, RwLock, mpsc},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{info, warn};
+
+use crate::{
+    commands::handlers::CmdHandler,
+    persistence::{
+        aof::{self, absolute_expiry, encode_command},
+        rdb, snapshot,
+    },
+    protocol::{extract_command_args, from_args},
+    server::{client::Client, latency::EVENT_FORK, state::ServerState},
+    storage::CacheStore,
+    utils::{random_hex, unix_time_ms},
+};
+
+// Wait between attempts to reach the master
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// Each step of the handshake with the master
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How often a replica acknowledges its offset
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+const READONLY_ERROR: &str = "READONLY You can't write against a read only replica.";
+const NOREPLICAS_ERROR: &str = "NOREPLICAS Not enough good replicas to write.";
+
+#[derive(Debug, Clone)]
+pub struct ReplicationOptions {
+    // Bytes of the stream kept for partial resyncs
+    pub backlog_size: usize,
+    // Seconds between the PINGs a master sends down its stream
+    pub ping_period: u64,
+    // Seconds without data from the master before a replica reconnects
+    pub timeout: u64,
+    // Whether a replica refuses writes from its clients
+    pub read_only: bool,
+    // Writes are refused while fewer replicas acknowledged within
+    // `min_replicas_max_lag` seconds, 0 to never refuse them
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
+}
+
+impl Default for ReplicationOptions {
+    fn default() -> Self {
+        Self {
+            backlog_size: 1024 * 1024,
+            ping_period: 10,
+            timeout: 60,
+            read_only: true,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: 10,
+        }
+    }
+}
+
+// State of a replica's link to its master, as ROLE names it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkStatus {
+    // Waiting to reconnect
+    Connect,
+    // Connected, in the handshake
+    Connecting,
+    // Receiving the snapshot of a full sync
+    Sync,
+    Connected,
+}
+
+impl LinkStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LinkStatus::Connect => "connect",
+            LinkStatus::Connecting => "connecting",
+            LinkStatus::Sync => "sync",
+            LinkStatus::Connected => "connected",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MasterLink {
+    // Tells the task of this link from the ones of earlier links
+    id: u64,
+    host: String,
+    port: u16,
+    task: JoinHandle<()>,
+    status: LinkStatus,
+    // Last time data came from the master
+    last_io: Instant,
+}
+
+// A replica attached to this server
+#[derive(Debug)]
+struct ReplicaConn {
+    client_id: u64,
+    // Where the replica listens for clients
+    ip: String,
+    port: u16,
+    tx: mpsc::UnboundedSender<Bytes>,
+    // Stream data held back until the snapshot of its full sync is sent
+    pending: Option<Vec<Bytes>>,
+    // Offset of the last REPLCONF ACK and when it came, or when the stream
+    // started before the first one
+    ack_offset: u64,
+    ack_time: Instant,
+}
+
+impl ReplicaConn {
+    fn new(client: &Client, tx: mpsc::UnboundedSender<Bytes>, pending: Option<Vec<Bytes>>) -> Self {
+        Self {
+            client_id: client.id,
+            ip: client.addr.ip().to_string(),
+            port: client.listening_port(),
+            tx,
+            pending,
+            ack_offset: 0,
+            ack_time: Instant::now(),
+        }
+    }
+
+    fn is_online(&self) -> bool {
+        self.pending.is_none()
+    }
+}
+
+#[derive(Debug)]
+struct ReplState {
+    replid: String,
+    // The id of the history before the last switch of master, which stays valid
+    // for partial resyncs up to `replid2_offset`
+    replid2: String,
+    replid2_offset: Option<u64>,
+    // Bytes fed to the stream of `replid`
+    offset: u64,
+    // The last bytes of the stream, kept from the first time a replica attaches
+    backlog: Option<VecDeque<u8>>,
+    replicas: Vec<ReplicaConn>,
+    master: Option<MasterLink>,
+    next_link_id: u64,
+    last_ping: Instant,
+}
+
+impl ReplState {
+    fn feed(&mut self, data: &[u8], backlog_size: usize) {
+        let Some(backlog) = self.backlog.as_mut() else {
+            return;
+        };
+        backlog.extend(data);
+        let excess = backlog.len().saturating_sub(backlog_size);
+        backlog.drain(..excess);
+        self.offset += data.len() as u64;
+
+        let data = Bytes::copy_from_slice(data);
+        self.replicas
+            .retain_mut(|replica| match replica.pending.as_mut() {
+                Some(pending) => {
+                    pending.push(data.clone());
+                    true
+                }
+                None => replica.tx.send(data.clone()).is_ok(),
+            });
+    }
+
+    // A fresh history, e.g. after a master took over
+    fn shift_replid(&mut self) {
+        self.replid2 = std::mem::replace(&mut self.replid, random_hex(20));
+        self.replid2_offset = Some(self.offset + 1);
+    }
+
+    fn acked(&self, offset: u64) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.is_online() && replica.ack_offset >= offset)
+            .count()
+    }
+}
+
+// A replica as ROLE and INFO report it
+#[derive(Debug)]
+pub struct ReplicaInfo {
+    pub ip: String,
+    pub port: u16,
+    pub online: bool,
+    pub ack_offset: u64,
+    // Seconds since its last acknowledgement
+    pub lag: u64,
+}
+
+#[derive(Debug)]
+pub struct MasterInfo {
+    pub host: String,
+    pub port: u16,
+    pub status: LinkStatus,
+    pub last_io_secs: u64,
+}
+
+#[derive(Debug)]
+pub struct ReplicationInfo {
+    pub replid: String,
+    pub replid2: String,
+    pub replid2_offset: Option<u64>,
+    pub offset: u64,
+    // Offset of the first byte in the backlog and its length, once there is one
+    pub backlog: Option<(u64, usize)>,
+    pub master: Option<MasterInfo>,
+    pub replicas: Vec<ReplicaInfo>,
+}
+
+// Reply to PSYNC
+#[derive(Debug, PartialEq)]
+pub enum Psync {
+    Continue { replid: String },
+    FullResync { replid: String, offset: u64 },
+}
+
+#[derive(Debug)]
+pub struct Replication {
+    pub options: ReplicationOptions,
+    // Announced to masters with REPLCONF listening-port
+    listening_port: u16,
+    state: Mutex<ReplState>,
+    // Woken by every REPLCONF ACK, for WAIT
+    acked: Notify,
+}
+
+impl Replication {
+    pub fn new(options: ReplicationOptions, listening_port: u16) -> Self {
+        Self {
+            options,
+            listening_port,
+            state: Mutex::new(ReplState {
+                replid: random_hex(20),
+                replid2: "0".repeat(40),
+                replid2_offset: None,
+                offset: 0,
+                backlog: None,
+                replicas: Vec::new(),
+                master: None,
+                next_link_id: 1,
+                last_ping: Instant::now(),
+            }),
+            acked: Notify::new(),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.state.lock().unwrap().master.is_some()
+    }
+
+    // The error a write command from a client gets, if writes are refused: by a
+    // read-only replica, or by a master short of good replicas
+    pub fn write_refusal(&self) -> Option<&'static str> {
+        let state = self.state.lock().unwrap();
+        if state.master.is_some() {
+            return self.options.read_only.then_some(READONLY_ERROR);
+        }
+        if self.options.min_replicas_to_write == 0 {
+            return None;
+        }
+        let max_lag = Duration::from_secs(self.options.min_replicas_max_lag);
+        let good = state
+            .replicas
+            .iter()
+            .filter(|replica| replica.is_online() && replica.ack_time.elapsed() <= max_lag)
+            .count();
+        (good < self.options.min_replicas_to_write).then_some(NOREPLICAS_ERROR)
+    }
+
+    pub fn info(&self) -> ReplicationInfo {
+        let state = self.state.lock().unwrap();
+        ReplicationInfo {
+            replid: state.replid.clone(),
+            replid2: state.replid2.clone(),
+            replid2_offset: state.replid2_offset,
+            offset: state.offset,
+            backlog: state
+                .backlog
+                .as_ref()
+                .map(|backlog| (state.offset + 1 - backlog.len() as u64, backlog.len())),
+            master: state.master.as_ref().map(|link| MasterInfo {
+                host: link.host.clone(),
+                port: link.port,
+                status: link.status,
+                last_io_secs: link.last_io.elapsed().as_secs(),
+            }),
+            replicas: state
+                .replicas
+                .iter()
+                .map(|replica| ReplicaInfo {
+                    ip: replica.ip.clone(),
+                    port: replica.port,
+                    online: replica.is_online(),
+                    ack_offset: replica.ack_offset,
+                    lag: replica.ack_time.elapsed().as_secs(),
+                })
+                .collect(),
+        }
+    }
+
+    // REPLCONF ACK from the replica on the connection of `client_id`
+    pub fn ack(&self, client_id: u64, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state
+            .replicas
+            .iter_mut()
+            .find(|replica| replica.client_id == client_id)
+        {
+            replica.ack_offset = replica.ack_offset.max(offset);
+            replica.ack_time = Instant::now();
+        }
+        self.acked.notify_waiters();
+    }
+
+    // WAIT: until `numreplicas` replicas acknowledged the current offset, or for
+    // `timeout` at most. Returns how many did.
+    pub async fn wait(&self, numreplicas: usize, timeout: Option<Duration>) -> usize {
+        let target = {
+            let mut state = self.state.lock().unwrap();
+            if state.acked(state.offset) >= numreplicas {
+                return state.acked(state.offset);
+            }
+            let target = state.offset;
+            // Ask for acknowledgements now rather than at the next ACK_PERIOD
+            state.feed(
+                &encode_command(&["REPLCONF", "GETACK", "*"]),
+                self.options.backlog_size,
+            );
+            target
+        };
+
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        loop {
+            let notified = self.acked.notified();
+            tokio::pin!(notified);
+            // Registered before counting, so no ACK in between is missed
+            notified.as_mut().enable();
+            let acked = self.state.lock().unwrap().acked(target);
+            if acked >= numreplicas {
+                return acked;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                        return self.state.lock().unwrap().acked(target);
+                    }
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    // Stream a write command this server ran for a client. Replicas only stream
+    // what their master sends.
+    pub fn feed(&self, args: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        if state.master.is_none() && state.backlog.is_some() {
+            state.feed(
+                &encode_command(&absolute_expiry(args)),
+                self.options.backlog_size,
+            );
+        }
+    }
+
+    // PING the replicas every `ping_period` seconds, so they can tell a quiet
+    // master from a lost one
+    pub fn cron(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.master.is_some()
+            || state.replicas.is_empty()
+            || state.last_ping.elapsed() < Duration::from_secs(self.options.ping_period)
+        {
+            return;
+        }
+        state.last_ping = Instant::now();
+        state.feed(&encode_command(&["PING"]), self.options.backlog_size);
+    }
+
+    // Resume the stream of a replica at `offset` when the backlog still holds it
+    pub fn resume(&self, client: &Client, replid: &str, offset: i64) -> Option<Psync> {
+        let mut state = self.state.lock().unwrap();
+        let offset = u64::try_from(offset).ok().filter(|offset| *offset > 0)?;
+        let known = replid == state.replid
+            || (replid == state.replid2 && state.replid2_offset.is_some_and(|end| offset <= end));
+        let backlog = state.backlog.as_ref()?;
+        let first = state.offset + 1 - backlog.len() as u64;
+        if !known || offset < first || offset > state.offset + 1 {
+            return None;
+        }
+
+        let missed: Vec<u8> = backlog
+            .range((offset - first) as usize..)
+            .copied()
+            .collect();
+        let (tx, rx) = mpsc::unbounded_channel();
+        if !missed.is_empty() {
+            let _ = tx.send(missed.into());
+        }
+        client.set_replica_stream(rx);
+        state.replicas.push(ReplicaConn::new(client, tx, None));
+        Some(Psync::Continue {
+            replid: state.replid.clone(),
+        })
+    }
+
+    // Attach a replica for a full sync, holding its stream back until
+    // `snapshot_ready`. Returns the id and offset the snapshot stands for.
+    fn attach_for_sync(&self, client: &Client) -> (String, u64) {
+        let mut state = self.state.lock().unwrap();
+        state.backlog.get_or_insert_with(VecDeque::new);
+        let (tx, rx) = mpsc::unbounded_channel();
+        client.set_replica_stream(rx);
+        state
+            .replicas
+            .push(ReplicaConn::new(client, tx, Some(Vec::new())));
+        (state.replid.clone(), state.offset)
+    }
+
+    // Send the snapshot of a full sync as `$<len>\r\n<payload>`, then the stream
+    // held back meanwhile
+    fn snapshot_ready(&self, client_id: u64, payload: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(replica) = state
+            .replicas
+            .iter_mut()
+            .find(|replica| replica.client_id == client_id)
+        else {
+            return;
+        };
+        let mut data = format!("${}\r\n", payload.len()).into_bytes();
+        data.extend_from_slice(&payload);
+        let _ = replica.tx.send(data.into());
+        for data in replica.pending.take().unwrap_or_default() {
+            let _ = replica.tx.send(data);
+        }
+        replica.ack_time = Instant::now();
+    }
+
+    // Stop streaming to the replica on the connection of `client_id`, if it is one
+    pub fn detach(&self, client_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .replicas
+            .retain(|replica| replica.client_id != client_id);
+    }
+
+    // REPLICAOF NO ONE: drop the link to the master and start a history of our own
+    pub fn stop_replica(&self) {
+        let mut state = self.state.lock().unwrap();
+        let Some(link) = state.master.take() else {
+            return;
+        };
+        link.task.abort();
+        state.shift_replid();
+        info!(
+            "MASTER MODE enabled, replicas can resume from {} up to offset {}",
+            state.replid2, state.offset
+        );
+    }
+
+    fn update_link(&self, link_id: u64, update: impl FnOnce(&mut ReplState)) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.master.as_mut() {
+            Some(link) if link.id == link_id => {
+                update(&mut state);
+                Ok(())
+            }
+            _ => Err(anyhow!("Replication link replaced")),
+        }
+    }
+
+    fn set_link_status(&self, link_id: u64, status: LinkStatus) -> Result<()> {
+        self.update_link(link_id, |state| {
+            if let Some(link) = state.master.as_mut() {
+                link.status = status;
+                link.last_io = Instant::now();
+            }
+        })
+    }
+}
+
+// REPLICAOF host port: replicate the master at `host`:`port`, replacing the link
+// to any other. Returns false when already replicating it.
+pub fn start_replica(
+    store: &Arc<RwLock<CacheStore>>,
+    state: &Arc<ServerState>,
+    host: String,
+    port: u16,
+) -> bool {
+    let mut repl = state.replication.state.lock().unwrap();
+    if let Some(link) = repl.master.take() {
+        if link.host == host && link.port == port {
+            repl.master = Some(link);
+            return false;
+        }
+        link.task.abort();
+    }
+    // Replicas of this server follow the new history once they reconnect
+    repl.replicas.clear();
+
+    let id = repl.next_link_id;
+    repl.next_link_id += 1;
+    let task = tokio::spawn(run_link(
+        Arc::clone(store),
+        Arc::clone(state),
+        id,
+        host.clone(),
+        port,
+    ));
+    info!("Connecting to MASTER {}:{}", host, port);
+    repl.master = Some(MasterLink {
+        id,
+        host,
+        port,
+        task,
+        status: LinkStatus::Connect,
+        last_io: Instant::now(),
+    });
+    true
+}
+
+// PSYNC from a replica: resume its stream, or snapshot the keyspace for a full
+// sync. The incremental snapshot starts with writes held back like for an AOF
+// rewrite, so it lines up with the offset the stream starts at, and is encoded
+// while they go on.
+pub async fn psync(
+    store: &Arc<RwLock<CacheStore>>,
+    state: &Arc<ServerState>,
+    client: &Client,
+    replid: &str,
+    offset: i64,
+) -> Psync {
+    if let Some(reply) = state.replication.resume(client, replid, offset) {
+        info!(
+            "Partial resynchronization accepted for replica {}, from offset {}",
+            client.addr, offset
+        );
+        return reply;
+    }
+
+    let (start, replid, offset) = {
+        let _gate = state.persistence.write_gate.write().await;
+        let started = Instant::now();
+        let start = store.write().await.begin_snapshot();
+        state.add_latency_sample(EVENT_FORK, started.elapsed());
+        let (replid, offset) = state.replication.attach_for_sync(client);
+        (start, replid, offset)
+    };
+    info!(
+        "Full resync requested by replica {}, {} keys at offset {}",
+        client.addr, start.keys, offset
+    );
+
+    let store = Arc::clone(store);
+    let state = Arc::clone(state);
+    let client_id = client.id;
+    tokio::task::spawn_blocking(move || {
+        let mut payload = Vec::new();
+        let res = snapshot::encode_entries(snapshot::batches(&store, start.id), |entries| {
+            rdb::encode_stream(&mut payload, entries, start.keys, start.expires)
+        });
+        store.blocking_write().end_snapshot(start.id);
+        match res {
+            Ok(()) => state.replication.snapshot_ready(client_id, payload),
+            Err(e) => {
+                warn!("Failed to encode the snapshot for a replica: {}", e);
+                state.replication.detach(client_id);
+            }
+        }
+    });
+    Psync::FullResync { replid, offset }
+}
+
+// The task behind a replica's link to its master, reconnecting until replaced
+async fn run_link(
+    store: Arc<RwLock<CacheStore>>,
+    state: Arc<ServerState>,
+    link_id: u64,
+    host: String,
+    port: u16,
+) {
+    loop {
+        if let Err(e) = sync_with_master(&store, &state, link_id, &host, port).await {
+            warn!(
+                "Replication link with MASTER {}:{} failed: {}",
+                host, port, e
+            );
+        }
+        if state
+            .replication
+            .set_link_status(link_id, LinkStatus::Connect)
+            .is_err()
+        {
+            return;
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+// The connection to the master and the bytes read from it but not used yet
+struct MasterConn {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl MasterConn {
+    async fn send(&mut self, args: &[&str]) -> Result<()> {
+        self.stream.write_all(&encode_command(args)).await?;
+        Ok(())
+    }
+
+    async fn fill(&mut self, wait: Duration) -> Result<()> {
+        let read = timeout(wait, self.stream.read_buf(&mut self.buf))
+            .await
+            .map_err(|_| anyhow!("Timeout reading from MASTER"))??;
+        if read == 0 {
+            return Err(anyhow!("Connection closed by MASTER"));
+        }
+        Ok(())
+    }
+
+    // REPLCONF ACK with the offset applied so far. The master doesn't reply.
+    async fn ack(&mut self, repl: &Replication) -> Result<()> {
+        let offset = repl.state.lock().unwrap().offset;
+        self.send(&["REPLCONF", "ACK", &offset.to_string()]).await
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buf.split_to(end + 2);
+                return Ok(String::from_utf8_lossy(&line[..end]).into_owned());
+            }
+            self.fill(HANDSHAKE_TIMEOUT).await?;
+        }
+    }
+
+    async fn command(&mut self, args: &[&str]) -> Result<String> {
+        self.send(args).await?;
+        let reply = self.read_line().await?;
+        match reply.strip_prefix('-') {
+            Some(e) => Err(anyhow!("MASTER replied to {}: {}", args[0], e)),
+            None => Ok(reply),
+        }
+    }
+}
+
+async fn sync_with_master(
+    store: &Arc<RwLock<CacheStore>>,
+    state: &Arc<ServerState>,
+    link_id: u64,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    let repl = &state.replication;
+    let stream = timeout(HANDSHAKE_TIMEOUT, TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| anyhow!("Timeout connecting"))??;
+    let master_addr = stream.peer_addr()?;
+    repl.set_link_status(link_id, LinkStatus::Connecting)?;
+    let mut conn = MasterConn {
+        stream,
+        buf: BytesMut::new(),
+    };
+    conn.command(&["PING"]).await?;
+    let port = repl.listening_port.to_string();
+    conn.command(&["REPLCONF", "listening-port", &port]).await?;
+    conn.command(&["REPLCONF", "capa", "psync2"]).await?;
+
+    // Our own id and offset: those of the master's history once synced with it,
+    // or of the history a former master may share with us
+    let (replid, offset) = {
+        let state = repl.state.lock().unwrap();
+        (state.replid.clone(), state.offset + 1)
+    };
+    let reply = conn
+        .command(&["PSYNC", &replid, &offset.to_string()])
+        .await?;
+    let words: Vec<&str> = reply.trim_start_matches('+').split(' ').collect();
+    match words.as_slice() {
+        ["FULLRESYNC", replid, offset] => {
+            let offset: u64 = offset.parse()?;
+            repl.set_link_status(link_id, LinkStatus::Sync)?;
+            full_sync(&mut conn, store, state, link_id, replid, offset).await?;
+        }
+        ["CONTINUE", rest @ ..] => {
+            repl.update_link(link_id, |repl| {
+                if let [replid] = rest
+                    && *replid != repl.replid
+                {
+                    repl.shift_replid();
+                    repl.replid = replid.to_string();
+                }
+            })?;
+            info!("Partial resynchronization with MASTER succeeded");
+        }
+        _ => return Err(anyhow!("Unexpected reply to PSYNC: {}", reply)),
+    }
+    repl.set_link_status(link_id, LinkStatus::Connected)?;
+    conn.ack(repl).await?;
+
+    // Replayed commands run as a client of their own, their replies are dropped
+    let client = state.new_client(master_addr);
+    let (push_tx, _push_rx) = mpsc::unbounded_channel();
+    let mut handler = CmdHandler::new(Arc::clone(store), Arc::clone(state), client, push_tx);
+    let wait = Duration::from_secs(repl.options.timeout);
+    let mut acks = tokio::time::interval(ACK_PERIOD);
+    let mut last_io = Instant::now();
+    loop {
+        while let Some((frame, len)) = decode(&conn.buf)? {
+            let data = conn.buf.split_to(len);
+            if apply(state, &mut handler, frame, &data, link_id).await? {
+                conn.ack(repl).await?;
+            }
+        }
+        tokio::select! {
+            read = conn.stream.read_buf(&mut conn.buf) => {
+                if read? == 0 {
+                    return Err(anyhow!("Connection closed by MASTER"));
+                }
+                last_io = Instant::now();
+                repl.update_link(link_id, |state| {
+                    if let Some(link) = state.master.as_mut() {
+                        link.last_io = last_io;
+                    }
+                })?;
+            }
+            _ = acks.tick() => {
+                if last_io.elapsed() > wait {
+                    return Err(anyhow!("Timeout reading from MASTER"));
+                }
+                conn.ack(repl).await?;
+            }
+        }
+    }
+}
+
+// Load the snapshot that follows FULLRESYNC in place of the keyspace
+async fn full_sync(
+    conn: &mut MasterConn,
+    store: &Arc<RwLock<CacheStore>>,
+    state: &Arc<ServerState>,
+    link_id: u64,
+    replid: &str,
+    offset: u64,
+) -> Result<()> {
+    let header = conn.read_line().await?;
+    let len: usize = header
+        .strip_prefix('$')
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| anyhow!("Bad snapshot header from MASTER: {}", header))?;
+    let wait = Duration::from_secs(state.replication.options.timeout);
+    while conn.buf.len() < len {
+        conn.fill(wait).await?;
+    }
+    let payload = conn.buf.split_to(len);
+
+    let start = unix_time_ms();
+    let stats = {
+        let _gate = state.persistence.write_gate.write().await;
+        let mut store = store.write().await;
+        store.flush(false);
+        snapshot::restore_data(&payload, &mut store)?
+    };
+    state.replication.update_link(link_id, |repl| {
+        repl.replid = replid.to_string();
+        repl.replid2 = "0".repeat(40);
+        repl.replid2_offset = None;
+        repl.offset = offset;
+        repl.backlog = Some(VecDeque::new());
+        repl.replicas.clear();
+    })?;
+    info!(
+        "MASTER <-> REPLICA sync: loaded {} keys in {} ms",
+        stats.loaded,
+        unix_time_ms() - start
+    );
+
+    // The AOF describes the keyspace replaced, start it over from the new one
+    if state.persistence.appendonly
+        && let Err(e) = aof::rewrite_in_background(store, state).await
+    {
+        warn!("Can't rewrite the AOF after a full sync: {}", e);
+    }
+    Ok(())
+}
+
+// Run a command from the master, log it and pass it down to our own replicas.
+// Returns true when it was REPLCONF GETACK, which asks for an ACK instead.
+async fn apply(
+    state: &Arc<ServerState>,
+    handler: &mut CmdHandler,
+    frame: redis_protocol::resp2::types::OwnedFrame,
+    data: &[u8],
+    link_id: u64,
+) -> Result<bool> {
+    let args = extract_command_args(frame)?;
+    let getack = args.len() == 3
+        && args[0].eq_ignore_ascii_case("REPLCONF")
+        && args[1].eq_ignore_ascii_case("GETACK");
+    let _gate = state.persistence.write_gate.read().await;
+    match from_args(&args) {
+        _ if getack => {}
+        Ok(cmd) => {
+            let is_write = cmd.is_write();
+            match handler.handle_cmd(cmd).await {
+                Ok(Some(BytesFrame::Error(e))) => {
+                    warn!("Error running {} from MASTER: {:?}", args[0], e)
+                }
+                Err(e) => warn!("Error running {} from MASTER: {}", args[0], e),
+                Ok(_) if is_write => state.feed_aof(&args),
+                Ok(_) => {}
+            }
+        }
+        Err(e) => warn!("Unknown command from MASTER: {}", e),
+    }
+    state.replication.update_link(link_id, |repl| {
+        repl.feed(data, state.replication.options.backlog_size)
+    })?;
+    Ok(getack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_from_backlog() {
+        let repl = Replication::new(
+            ReplicationOptions {
+                backlog_size: 64,
+                ..Default::default()
+            },
+            0,
+        );
+        let client = Client::new(1, ([127, 0, 0, 1], 1).into());
+        let (replid, offset) = repl.attach_for_sync(&client);
+        assert_eq!(offset, 0);
+        repl.detach(client.id);
+
+        let set = encode_command(&["SET", "k", "v"]);
+        for _ in 0..4 {
+            repl.feed(&["SET".into(), "k".into(), "v".into()]);
+        }
+        let end = 4 * set.len() as i64;
+        // Only the last 64 bytes are kept
+        assert!(repl.resume(&client, &replid, 1).is_none());
+        assert!(repl.resume(&client, "other", end - 2).is_none());
+        assert_eq!(
+            repl.resume(&client, &replid, end - set.len() as i64 + 1),
+            Some(Psync::Continue {
+                replid: replid.clone()
+            })
+        );
+        let mut stream = client.take_replica_stream().unwrap();
+        assert_eq!(stream.try_recv().unwrap(), set);
+
+        // After a switch of master the old history is still known up to its end
+        repl.state.lock().unwrap().shift_replid();
+        assert!(repl.resume(&client, &replid, end + 1).is_some());
+        assert!(repl.resume(&client, &replid, end + 2).is_none());
+    }
+
+    #[test]
+    fn test_min_replicas_fencing() {
+        let repl = Replication::new(
+            ReplicationOptions {
+                min_replicas_to_write: 1,
+                ..Default::default()
+            },
+            0,
+        );
+        assert_eq!(repl.write_refusal(), Some(NOREPLICAS_ERROR));
+
+        // A replica counts once its full sync is done
+        let client = Client::new(1, ([127, 0, 0, 1], 1).into());
+        repl.attach_for_sync(&client);
+        assert_eq!(repl.write_refusal(), Some(NOREPLICAS_ERROR));
+        repl.snapshot_ready(client.id, Vec::new());
+        assert_eq!(repl.write_refusal(), None);
+
+        repl.feed(&["SET".into(), "k".into(), "v".into()]);
+        let offset = repl.info().offset;
+        assert_eq!(repl.state.lock().unwrap().acked(offset), 0);
+        repl.ack(client.id, offset);
+        assert_eq!(repl.state.lock().unwrap().acked(offset), 1);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::server::client::Client;
+
+// Arguments kept per entry; the last slot notes how many were dropped
+const SLOWLOG_ENTRY_MAX_ARGC: usize = 32;
+
+// Bytes kept per argument
+const SLOWLOG_ENTRY_MAX_STRING: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    pub timestamp: u64, // unix time in seconds
+    pub duration: Duration,
+    pub args: Vec<String>,
+    pub client_addr: String,
+    pub client_name: String,
+}
+
+// Ring buffer of the most recent commands that ran longer than the threshold
+#[derive(Debug)]
+pub struct SlowLog {
+    entries: VecDeque<SlowLogEntry>,
+    next_id: u64,
+    log_slower_than: i64, // microseconds, negative disables the log
+    max_len: usize,
+}
+
+impl SlowLog {
+    pub fn new(log_slower_than: i64, max_len: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            next_id: 0,
+            log_slower_than,
+            max_len,
+        }
+    }
+
+    // Record the command if it ran for at least `slowlog-log-slower-than`
+    pub fn record(&mut self, args: &[String], duration: Duration, client: &Client) {
+        if self.log_slower_than < 0 || duration.as_micros() < self.log_slower_than as u128 {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.entries.push_front(SlowLogEntry {
+            id: self.next_id,
+            timestamp,
+            duration,
+            args: truncate_args(args),
+            client_addr: client.addr.to_string(),
+            client_name: client.name(),
+        });
+        self.next_id += 1;
+        self.entries.truncate(self.max_len);
+    }
+
+    // Up to `count` entries, newest first
+    pub fn get(&self, count: usize) -> impl Iterator<Item = &SlowLogEntry> {
+        self.entries.iter().take(count)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+fn truncate_args(args: &[String]) -> Vec<String> {
+    let kept = if args.len() > SLOWLOG_ENTRY_MAX_ARGC {
+        SLOWLOG_ENTRY_MAX_ARGC - 1
+    } else {
+        args.len()
+    };
+
+    let mut out: Vec<String> = args[..kept]
+        .iter()
+        .map(|arg| {
+            if arg.len() > SLOWLOG_ENTRY_MAX_STRING {
+                let mut end = SLOWLOG_ENTRY_MAX_STRING;
+                while !arg.is_char_boundary(end) {
+                    end -= 1;
+                }
+                format!("{}... ({} more bytes)", &arg[..end], arg.len() - end)
+            } else {
+                arg.clone()
+            }
+        })
+        .collect();
+    if kept < args.len() {
+        out.push(format!("... ({} more arguments)", args.len() - kept));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> Client {
+        Client::new(1, "127.0.0.1:5000".parse().unwrap())
+    }
+
+    #[test]
+    fn test_threshold_and_ring_buffer() {
+        let mut slowlog = SlowLog::new(1000, 2);
+        let args = vec!["GET".to_string(), "k".to_string()];
+
+        slowlog.record(&args, Duration::from_micros(999), &client());
+        assert_eq!(slowlog.len(), 0);
+
+        for _ in 0..3 {
+            slowlog.record(&args, Duration::from_millis(5), &client());
+        }
+        assert_eq!(slowlog.len(), 2);
+        let ids: Vec<u64> = slowlog.get(10).map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 1]);
+        assert_eq!(slowlog.get(1).next().unwrap().client_addr, "127.0.0.1:5000");
+
+        slowlog.reset();
+        assert_eq!(slowlog.len(), 0);
+    }
+
+    #[test]
+    fn test_truncate_args() {
+        let args: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        let truncated = truncate_args(&args);
+        assert_eq!(truncated.len(), SLOWLOG_ENTRY_MAX_ARGC);
+        assert_eq!(truncated[31], "... (9 more arguments)");
+
+        let truncated = truncate_args(&["x".repeat(200)]);
+        assert_eq!(
+            truncated[0],
+            format!("{}... (72 more bytes)", "x".repeat(128))
+        );
+    }
+}
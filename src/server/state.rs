@@ -0,0 +1,80 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{
+    config::CacheConfig,
+    persistence::Persistence,
+    server::{
+        client::Client,
+        latency::{EVENT_AOF_FSYNC_ALWAYS, LatencyMonitor},
+        monitor::MonitorFeed,
+        pubsub::PubSub,
+        replication::Replication,
+        slowlog::SlowLog,
+    },
+};
+
+// Server-wide state shared by every connection, next to the keyspace
+#[derive(Debug)]
+pub struct ServerState {
+    pub slowlog: Mutex<SlowLog>,
+    pub latency: Mutex<LatencyMonitor>,
+    pub monitor: MonitorFeed,
+    pub pubsub: Arc<PubSub>,
+    pub persistence: Persistence,
+    pub replication: Replication,
+    next_client_id: AtomicU64,
+}
+
+impl ServerState {
+    pub fn new(conf: &CacheConfig) -> Self {
+        Self {
+            slowlog: Mutex::new(SlowLog::new(
+                conf.slowlog_log_slower_than,
+                conf.slowlog_max_len,
+            )),
+            latency: Mutex::new(LatencyMonitor::new(conf.latency_monitor_threshold)),
+            monitor: MonitorFeed::new(),
+            pubsub: Arc::new(PubSub::default()),
+            persistence: Persistence::new(conf),
+            replication: Replication::new(conf.replication.clone(), conf.port()),
+            next_client_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn new_client(&self, addr: SocketAddr) -> Arc<Client> {
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        Arc::new(Client::new(id, addr))
+    }
+
+    // Log a write command that ran successfully and stream it to replicas
+    pub fn propagate(&self, args: &[String]) {
+        self.feed_aof(args);
+        self.replication.feed(args);
+    }
+
+    // Log a write command to the AOF, sampling the latency of `always` fsyncs
+    pub fn feed_aof(&self, args: &[String]) {
+        if let Some(fsync) = self.persistence.feed_aof(args) {
+            self.add_latency_sample(EVENT_AOF_FSYNC_ALWAYS, fsync);
+        }
+    }
+
+    // Sample the latency of `event` against `latency-monitor-threshold`
+    pub fn add_latency_sample(&self, event: &str, duration: Duration) {
+        self.latency.lock().unwrap().add_sample(event, duration);
+    }
+
+    // A master propagates the keys it expires as DEL, replicas wait for its DEL
+    pub fn propagate_expired(&self, key: &str) {
+        if !self.replication.is_replica() {
+            self.propagate(&["DEL".to_string(), key.to_string()]);
+        }
+    }
+}
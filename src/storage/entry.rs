@@ -1,6 +1,9 @@
 use std::time::{Duration, Instant};
 
-use crate::storage::Value;
+use crate::{
+    storage::{Value, tier::SpillRef},
+    utils::unix_time_ms,
+};
 
 #[derive(Debug, Clone)]
 pub struct Entry {
@@ -8,6 +11,10 @@ pub struct Entry {
     pub expires_at: Option<Instant>,
     pub created_at: Instant,
     pub last_accessed: Option<Instant>,
+    // Set while the value lives in the disk tier, `value` is Nil meanwhile
+    pub spilled: Option<SpillRef>,
+    // Bytes of the key and value as the disk tier last measured them
+    pub size: usize,
 }
 
 impl Entry {
@@ -17,6 +24,8 @@ impl Entry {
             expires_at: None,
             created_at: Instant::now(),
             last_accessed: None,
+            spilled: None,
+            size: 0,
         }
     }
 
@@ -26,6 +35,8 @@ impl Entry {
             expires_at: Some(Instant::now() + ttl),
             created_at: Instant::now(),
             last_accessed: None,
+            spilled: None,
+            size: 0,
         }
     }
 
@@ -40,10 +51,29 @@ impl Entry {
         self.last_accessed = Some(Instant::now());
     }
 
+    // Time since the entry was last read, or since it was created if never read
+    pub fn idle_time(&self) -> Duration {
+        self.last_accessed.unwrap_or(self.created_at).elapsed()
+    }
+
+    // Pretend the entry was last read `idle` ago
+    pub fn set_idle_time(&mut self, idle: Duration) {
+        self.last_accessed = Instant::now().checked_sub(idle).or(self.last_accessed);
+    }
+
     pub fn set_expiration(&mut self, ttl: Duration) {
         self.expires_at = Some(Instant::now() + ttl);
     }
 
+    // Absolute expiry as unix time in milliseconds
+    pub fn expires_at_unix_ms(&self) -> Option<u64> {
+        let remaining = self
+            .expires_at?
+            .saturating_duration_since(Instant::now())
+            .as_millis() as u64;
+        Some(unix_time_ms() + remaining)
+    }
+
     pub fn remove_expiration(&mut self) {
         self.expires_at = None;
     }
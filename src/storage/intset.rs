@@ -0,0 +1,180 @@
+// An intset: a sorted array of integers that all share the narrowest width (2, 4 or
+// 8 bytes) able to hold the largest member. Adding a wider integer upgrades the whole
+// array. The serialized form matches Redis:
+//
+//   <encoding: u32 LE> <length: u32 LE> <contents, little endian>
+
+use anyhow::{Result, anyhow};
+
+const INTSET_ENC_INT16: usize = 2;
+const INTSET_ENC_INT32: usize = 4;
+const INTSET_ENC_INT64: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntSet {
+    width: usize,
+    contents: Vec<u8>,
+}
+
+impl Default for IntSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntSet {
+    pub fn new() -> Self {
+        Self {
+            width: INTSET_ENC_INT16,
+            contents: Vec::new(),
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 8 {
+            return Err(anyhow!("intset too short"));
+        }
+        let width = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+        if ![INTSET_ENC_INT16, INTSET_ENC_INT32, INTSET_ENC_INT64].contains(&width) {
+            return Err(anyhow!("invalid intset encoding {}", width));
+        }
+        if buf.len() != 8 + width * len {
+            return Err(anyhow!("intset length does not match its size"));
+        }
+
+        let set = Self {
+            width,
+            contents: buf[8..].to_vec(),
+        };
+        for i in 1..len {
+            if set.get(i - 1) >= set.get(i) {
+                return Err(anyhow!("intset members are not sorted"));
+            }
+        }
+        Ok(set)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.contents.len());
+        out.extend_from_slice(&(self.width as u32).to_le_bytes());
+        out.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.contents);
+        out
+    }
+
+    pub fn len(&self) -> usize {
+        self.contents.len() / self.width
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.contents.capacity()
+    }
+
+    pub fn get(&self, index: usize) -> i64 {
+        let bytes = &self.contents[index * self.width..(index + 1) * self.width];
+        match self.width {
+            INTSET_ENC_INT16 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            INTSET_ENC_INT32 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+        }
+    }
+
+    pub fn contains(&self, value: i64) -> bool {
+        value_width(value) <= self.width && self.search(value).is_ok()
+    }
+
+    // Returns false if the value was already present
+    pub fn add(&mut self, value: i64) -> bool {
+        if value_width(value) > self.width {
+            self.upgrade(value_width(value));
+        }
+        match self.search(value) {
+            Ok(_) => false,
+            Err(pos) => {
+                let at = pos * self.width;
+                let bytes = encode(value, self.width);
+                self.contents.splice(at..at, bytes);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, value: i64) -> bool {
+        if value_width(value) > self.width {
+            return false;
+        }
+        match self.search(value) {
+            Ok(pos) => {
+                self.contents
+                    .drain(pos * self.width..(pos + 1) * self.width);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+
+    fn search(&self, value: i64) -> std::result::Result<usize, usize> {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.get(mid).cmp(&value) {
+                std::cmp::Ordering::Equal => return Ok(mid),
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+            }
+        }
+        Err(lo)
+    }
+
+    fn upgrade(&mut self, width: usize) {
+        let values: Vec<i64> = self.iter().collect();
+        self.width = width;
+        self.contents = values.into_iter().flat_map(|v| encode(v, width)).collect();
+    }
+}
+
+fn value_width(value: i64) -> usize {
+    if i16::try_from(value).is_ok() {
+        INTSET_ENC_INT16
+    } else if i32::try_from(value).is_ok() {
+        INTSET_ENC_INT32
+    } else {
+        INTSET_ENC_INT64
+    }
+}
+
+fn encode(value: i64, width: usize) -> Vec<u8> {
+    value.to_le_bytes()[..width].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_remove_and_upgrade() {
+        let mut set = IntSet::new();
+        assert!(set.add(5));
+        assert!(set.add(-3));
+        assert!(!set.add(5));
+        assert_eq!(set.to_bytes().len(), 8 + 2 * 2);
+
+        assert!(set.add(1 << 40));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![-3, 5, 1 << 40]);
+        assert!(set.contains(-3));
+        assert!(set.remove(5));
+        assert!(!set.contains(5));
+
+        let restored = IntSet::from_bytes(&set.to_bytes()).unwrap();
+        assert_eq!(restored, set);
+    }
+}
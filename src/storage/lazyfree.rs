@@ -0,0 +1,98 @@
+use std::{sync::mpsc, thread};
+
+use tracing::warn;
+
+use crate::storage::{Keyspace, Value};
+
+// Values cheaper to free than this are dropped inline, queuing them costs more
+pub const LAZYFREE_THRESHOLD: usize = 64;
+
+// Anything the background thread drops
+type Garbage = Box<dyn Send>;
+
+// Which implicit deletions go through the background thread, besides UNLINK and
+// FLUSHALL/FLUSHDB ASYNC which always do
+#[derive(Debug, Clone, Default)]
+pub struct LazyFreeOptions {
+    pub lazy_expire: bool,     // keys removed by lazy or active expiry
+    pub lazy_server_del: bool, // values replaced by SET or removed as a side effect
+}
+
+// Drops large values on a background thread, so detaching them from the keyspace
+// under the write lock stays O(1)
+#[derive(Debug, Clone, Default)]
+pub struct LazyFreer {
+    pub options: LazyFreeOptions,
+    tx: Option<mpsc::Sender<Garbage>>,
+}
+
+impl LazyFreer {
+    // Start the background thread. Without it, e.g. in tests, everything is freed
+    // inline.
+    pub fn start(options: LazyFreeOptions) -> Self {
+        let (tx, rx) = mpsc::channel::<Garbage>();
+        let spawned = thread::Builder::new()
+            .name("lazyfree".to_string())
+            .spawn(move || {
+                for garbage in rx {
+                    drop(garbage);
+                }
+            });
+
+        match spawned {
+            Ok(_) => Self {
+                options,
+                tx: Some(tx),
+            },
+            Err(e) => {
+                warn!("Failed to start lazyfree thread, freeing inline: {}", e);
+                Self { options, tx: None }
+            }
+        }
+    }
+
+    // Free a value in the background if it is big enough to be worth it
+    pub fn free_value(&self, value: Value) {
+        if value.free_effort() > LAZYFREE_THRESHOLD {
+            self.send(Box::new(value));
+        }
+    }
+
+    // Free a whole detached keyspace in the background
+    pub fn free_keyspace(&self, keyspace: Keyspace) {
+        if !keyspace.is_empty() {
+            self.send(Box::new(keyspace));
+        }
+    }
+
+    fn send(&self, garbage: Garbage) {
+        if let Some(tx) = &self.tx {
+            // A closed channel hands the garbage back, dropping it here frees inline
+            let _ = tx.send(garbage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    struct SignalOnDrop(mpsc::Sender<thread::ThreadId>);
+
+    impl Drop for SignalOnDrop {
+        fn drop(&mut self) {
+            let _ = self.0.send(thread::current().id());
+        }
+    }
+
+    #[test]
+    fn test_garbage_dropped_on_background_thread() {
+        let freer = LazyFreer::start(LazyFreeOptions::default());
+        let (tx, rx) = mpsc::channel();
+
+        freer.send(Box::new(SignalOnDrop(tx)));
+        let dropped_on = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_ne!(dropped_on, thread::current().id());
+    }
+}
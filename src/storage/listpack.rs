@@ -0,0 +1,553 @@
+// A listpack: a sequence of strings and integers packed into one contiguous buffer,
+// laid out the same way Redis lays them out.
+//
+//   <total-bytes: u32 LE> <num-elements: u16 LE> <entry> ... <entry> <0xFF>
+//
+// Every entry is <encoding+data> <backlen>, where backlen stores the size of
+// <encoding+data> so the buffer can be walked from either end. Entries are addressed
+// by their byte offset into the buffer.
+
+use std::borrow::Cow;
+
+use anyhow::{Result, anyhow};
+
+const HEADER_SIZE: usize = 6;
+const EOF: u8 = 0xFF;
+const UNKNOWN_COUNT: u16 = u16::MAX;
+
+const ENC_7BIT_UINT: u8 = 0x00;
+const ENC_6BIT_STR: u8 = 0x80;
+const ENC_13BIT_INT: u8 = 0xC0;
+const ENC_12BIT_STR: u8 = 0xE0;
+const ENC_32BIT_STR: u8 = 0xF0;
+const ENC_16BIT_INT: u8 = 0xF1;
+const ENC_24BIT_INT: u8 = 0xF2;
+const ENC_32BIT_INT: u8 = 0xF3;
+const ENC_64BIT_INT: u8 = 0xF4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListpackEntry<'a> {
+    Str(&'a [u8]),
+    Int(i64),
+}
+
+impl<'a> ListpackEntry<'a> {
+    pub fn as_bytes(self) -> Cow<'a, [u8]> {
+        match self {
+            ListpackEntry::Str(s) => Cow::Borrowed(s),
+            ListpackEntry::Int(v) => Cow::Owned(v.to_string().into_bytes()),
+        }
+    }
+
+    pub fn to_vec(self) -> Vec<u8> {
+        self.as_bytes().into_owned()
+    }
+
+    pub fn eq_bytes(self, other: &[u8]) -> bool {
+        match self {
+            ListpackEntry::Str(s) => s == other,
+            ListpackEntry::Int(v) => parse_canonical_int(other) == Some(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listpack {
+    buf: Vec<u8>,
+}
+
+impl Default for Listpack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Listpack {
+    pub fn new() -> Self {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + 1);
+        buf.extend_from_slice(&((HEADER_SIZE + 1) as u32).to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.push(EOF);
+        Self { buf }
+    }
+
+    // Wrap a serialized listpack, checking that every entry is well formed
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        if buf.len() < HEADER_SIZE + 1 {
+            return Err(anyhow!("listpack too short"));
+        }
+        let total = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+        if total != buf.len() || buf[total - 1] != EOF {
+            return Err(anyhow!("listpack header does not match its size"));
+        }
+
+        let lp = Self { buf };
+        let mut count = 0usize;
+        let mut off = HEADER_SIZE;
+        while lp.buf[off] != EOF {
+            let size = lp.checked_entry_size(off)?;
+            let backlen_end = off + size + backlen_size(size);
+            if backlen_end >= lp.buf.len() || decode_backlen(&lp.buf, backlen_end - 1).0 != size {
+                return Err(anyhow!("listpack entry at {} is corrupt", off));
+            }
+            off = backlen_end;
+            count += 1;
+        }
+        if off != lp.buf.len() - 1 {
+            return Err(anyhow!("listpack has data after its terminator"));
+        }
+
+        let header_count = u16::from_le_bytes(lp.buf[4..6].try_into().unwrap());
+        if header_count != UNKNOWN_COUNT && header_count as usize != count {
+            return Err(anyhow!("listpack element count does not match its entries"));
+        }
+        Ok(lp)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn len(&self) -> usize {
+        let count = u16::from_le_bytes(self.buf[4..6].try_into().unwrap());
+        if count != UNKNOWN_COUNT {
+            return count as usize;
+        }
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf[HEADER_SIZE] == EOF
+    }
+
+    // Total size of the encoded buffer
+    pub fn bytes_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    pub fn first(&self) -> Option<usize> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(HEADER_SIZE)
+        }
+    }
+
+    pub fn last(&self) -> Option<usize> {
+        self.prev(self.buf.len() - 1)
+    }
+
+    pub fn next(&self, off: usize) -> Option<usize> {
+        let size = self.entry_size(off);
+        let next = off + size + backlen_size(size);
+        if self.buf[next] == EOF {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    pub fn prev(&self, off: usize) -> Option<usize> {
+        if off <= HEADER_SIZE {
+            return None;
+        }
+        let (size, backlen) = decode_backlen(&self.buf, off - 1);
+        Some(off - backlen - size)
+    }
+
+    // Offset of the entry at `index`, negative indexes count from the tail
+    pub fn seek(&self, index: i64) -> Option<usize> {
+        if index >= 0 {
+            let mut off = self.first();
+            for _ in 0..index {
+                off = self.next(off?);
+            }
+            off
+        } else {
+            let mut off = self.last();
+            for _ in 0..(-index - 1) {
+                off = self.prev(off?);
+            }
+            off
+        }
+    }
+
+    pub fn get(&self, off: usize) -> ListpackEntry<'_> {
+        let buf = &self.buf[off..];
+        let enc = buf[0];
+        if enc & 0x80 == ENC_7BIT_UINT {
+            ListpackEntry::Int((enc & 0x7F) as i64)
+        } else if enc & 0xC0 == ENC_6BIT_STR {
+            let len = (enc & 0x3F) as usize;
+            ListpackEntry::Str(&buf[1..1 + len])
+        } else if enc & 0xE0 == ENC_13BIT_INT {
+            let uv = (((enc & 0x1F) as u16) << 8) | buf[1] as u16;
+            // sign extend from 13 bits
+            ListpackEntry::Int(((uv << 3) as i16 >> 3) as i64)
+        } else if enc & 0xF0 == ENC_12BIT_STR {
+            let len = (((enc & 0x0F) as usize) << 8) | buf[1] as usize;
+            ListpackEntry::Str(&buf[2..2 + len])
+        } else {
+            match enc {
+                ENC_32BIT_STR => {
+                    let len = u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize;
+                    ListpackEntry::Str(&buf[5..5 + len])
+                }
+                ENC_16BIT_INT => {
+                    ListpackEntry::Int(i16::from_le_bytes(buf[1..3].try_into().unwrap()) as i64)
+                }
+                ENC_24BIT_INT => {
+                    let v = i32::from_le_bytes([0, buf[1], buf[2], buf[3]]) >> 8;
+                    ListpackEntry::Int(v as i64)
+                }
+                ENC_32BIT_INT => {
+                    ListpackEntry::Int(i32::from_le_bytes(buf[1..5].try_into().unwrap()) as i64)
+                }
+                ENC_64BIT_INT => {
+                    ListpackEntry::Int(i64::from_le_bytes(buf[1..9].try_into().unwrap()))
+                }
+                _ => unreachable!("invalid listpack encoding byte {:#x}", enc),
+            }
+        }
+    }
+
+    pub fn iter(&self) -> ListpackIter<'_> {
+        ListpackIter {
+            lp: self,
+            next: self.first(),
+        }
+    }
+
+    // Offset of the first entry equal to `value`, looking at every `step`-th entry
+    pub fn find(&self, value: &[u8], step: usize) -> Option<usize> {
+        let mut off = self.first();
+        while let Some(o) = off {
+            if self.get(o).eq_bytes(value) {
+                return Some(o);
+            }
+            off = Some(o);
+            for _ in 0..step {
+                off = self.next(off?);
+            }
+        }
+        None
+    }
+
+    // Insert `value` in front of the entry at `off`. Passing `end()` appends.
+    pub fn insert(&mut self, off: usize, value: &[u8]) {
+        let encoded = encode_entry(value);
+        self.buf.splice(off..off, encoded);
+        self.update_header(1);
+    }
+
+    pub fn delete(&mut self, off: usize) {
+        let size = self.entry_size(off);
+        self.buf.drain(off..off + size + backlen_size(size));
+        self.update_header(-1);
+    }
+
+    pub fn replace(&mut self, off: usize, value: &[u8]) {
+        let size = self.entry_size(off);
+        let encoded = encode_entry(value);
+        self.buf
+            .splice(off..off + size + backlen_size(size), encoded);
+        self.update_header(0);
+    }
+
+    // Offset of the terminator, which is where appended entries go
+    pub fn end(&self) -> usize {
+        self.buf.len() - 1
+    }
+
+    pub fn push_back(&mut self, value: &[u8]) {
+        self.insert(self.end(), value);
+    }
+
+    pub fn push_front(&mut self, value: &[u8]) {
+        self.insert(HEADER_SIZE, value);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        let off = self.first()?;
+        let value = self.get(off).to_vec();
+        self.delete(off);
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        let off = self.last()?;
+        let value = self.get(off).to_vec();
+        self.delete(off);
+        Some(value)
+    }
+
+    fn update_header(&mut self, delta: i64) {
+        let total = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&total.to_le_bytes());
+
+        let count = u16::from_le_bytes(self.buf[4..6].try_into().unwrap());
+        if count != UNKNOWN_COUNT {
+            let new_count = count as i64 + delta;
+            let new_count = if new_count >= UNKNOWN_COUNT as i64 {
+                UNKNOWN_COUNT
+            } else {
+                new_count as u16
+            };
+            self.buf[4..6].copy_from_slice(&new_count.to_le_bytes());
+        } else if delta < 0 {
+            let count = self.iter().count();
+            if count < UNKNOWN_COUNT as usize {
+                self.buf[4..6].copy_from_slice(&(count as u16).to_le_bytes());
+            }
+        }
+    }
+
+    // Size of <encoding+data> for the entry at `off`
+    fn entry_size(&self, off: usize) -> usize {
+        let enc = self.buf[off];
+        if enc & 0x80 == ENC_7BIT_UINT {
+            1
+        } else if enc & 0xC0 == ENC_6BIT_STR {
+            1 + (enc & 0x3F) as usize
+        } else if enc & 0xE0 == ENC_13BIT_INT {
+            2
+        } else if enc & 0xF0 == ENC_12BIT_STR {
+            2 + ((((enc & 0x0F) as usize) << 8) | self.buf[off + 1] as usize)
+        } else {
+            match enc {
+                ENC_32BIT_STR => {
+                    5 + u32::from_le_bytes(self.buf[off + 1..off + 5].try_into().unwrap()) as usize
+                }
+                ENC_16BIT_INT => 3,
+                ENC_24BIT_INT => 4,
+                ENC_32BIT_INT => 5,
+                ENC_64BIT_INT => 9,
+                _ => unreachable!("invalid listpack encoding byte {:#x}", enc),
+            }
+        }
+    }
+
+    // `entry_size` for untrusted buffers
+    fn checked_entry_size(&self, off: usize) -> Result<usize> {
+        let enc = self.buf[off];
+        let header = match enc {
+            e if e & 0x80 == ENC_7BIT_UINT => 1,
+            e if e & 0xC0 == ENC_6BIT_STR => 1,
+            e if e & 0xE0 == ENC_13BIT_INT => 2,
+            e if e & 0xF0 == ENC_12BIT_STR => 2,
+            ENC_32BIT_STR => 5,
+            ENC_16BIT_INT | ENC_24BIT_INT | ENC_32BIT_INT | ENC_64BIT_INT => 1,
+            _ => return Err(anyhow!("invalid listpack encoding byte {:#x}", enc)),
+        };
+        if off + header >= self.buf.len() {
+            return Err(anyhow!("listpack entry at {} is truncated", off));
+        }
+        let size = self.entry_size(off);
+        if off + size >= self.buf.len() {
+            return Err(anyhow!("listpack entry at {} is truncated", off));
+        }
+        Ok(size)
+    }
+}
+
+pub struct ListpackIter<'a> {
+    lp: &'a Listpack,
+    next: Option<usize>,
+}
+
+impl<'a> Iterator for ListpackIter<'a> {
+    type Item = ListpackEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let off = self.next?;
+        self.next = self.lp.next(off);
+        Some(self.lp.get(off))
+    }
+}
+
+// Parse `s` as an i64 only if that is its canonical text form, so that the integer
+// can be stored instead of the string and rendered back to exactly the same bytes.
+pub fn parse_canonical_int(s: &[u8]) -> Option<i64> {
+    if s.is_empty() || s.len() > 20 {
+        return None;
+    }
+    let v = std::str::from_utf8(s).ok()?.parse::<i64>().ok()?;
+    if v.to_string().as_bytes() == s {
+        Some(v)
+    } else {
+        None
+    }
+}
+
+// Bytes `value` takes once encoded, including its backlen
+pub fn encoded_entry_size(value: &[u8]) -> usize {
+    let size = match parse_canonical_int(value) {
+        Some(v) if (0..=127).contains(&v) => 1,
+        Some(v) if (-4096..=4095).contains(&v) => 2,
+        Some(v) if (i16::MIN as i64..=i16::MAX as i64).contains(&v) => 3,
+        Some(v) if (-(1 << 23)..(1 << 23)).contains(&v) => 4,
+        Some(v) if (i32::MIN as i64..=i32::MAX as i64).contains(&v) => 5,
+        Some(_) => 9,
+        None if value.len() < 64 => 1 + value.len(),
+        None if value.len() < 4096 => 2 + value.len(),
+        None => 5 + value.len(),
+    };
+    size + backlen_size(size)
+}
+
+fn encode_entry(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len() + 10);
+    match parse_canonical_int(value) {
+        Some(v) => encode_int(&mut out, v),
+        None => encode_str(&mut out, value),
+    }
+    let size = out.len();
+    encode_backlen(&mut out, size);
+    out
+}
+
+fn encode_int(out: &mut Vec<u8>, v: i64) {
+    if (0..=127).contains(&v) {
+        out.push(v as u8);
+    } else if (-4096..=4095).contains(&v) {
+        let uv = (v as u16) & 0x1FFF;
+        out.push(ENC_13BIT_INT | (uv >> 8) as u8);
+        out.push((uv & 0xFF) as u8);
+    } else if (i16::MIN as i64..=i16::MAX as i64).contains(&v) {
+        out.push(ENC_16BIT_INT);
+        out.extend_from_slice(&(v as i16).to_le_bytes());
+    } else if (-(1 << 23)..(1 << 23)).contains(&v) {
+        out.push(ENC_24BIT_INT);
+        out.extend_from_slice(&(v as i32).to_le_bytes()[..3]);
+    } else if (i32::MIN as i64..=i32::MAX as i64).contains(&v) {
+        out.push(ENC_32BIT_INT);
+        out.extend_from_slice(&(v as i32).to_le_bytes());
+    } else {
+        out.push(ENC_64BIT_INT);
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn encode_str(out: &mut Vec<u8>, s: &[u8]) {
+    let len = s.len();
+    if len < 64 {
+        out.push(ENC_6BIT_STR | len as u8);
+    } else if len < 4096 {
+        out.push(ENC_12BIT_STR | (len >> 8) as u8);
+        out.push((len & 0xFF) as u8);
+    } else {
+        out.push(ENC_32BIT_STR);
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+    out.extend_from_slice(s);
+}
+
+fn backlen_size(size: usize) -> usize {
+    match size {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+// The most significant 7-bit group comes first; every byte after the first has its
+// high bit set, so reading backwards stops at the first byte without it.
+fn encode_backlen(out: &mut Vec<u8>, size: usize) {
+    let n = backlen_size(size);
+    for i in (0..n).rev() {
+        let group = ((size >> (7 * i)) & 0x7F) as u8;
+        out.push(if i == n - 1 { group } else { group | 0x80 });
+    }
+}
+
+// Decode the backlen whose last byte is at `end`, returning (entry size, backlen size)
+fn decode_backlen(buf: &[u8], end: usize) -> (usize, usize) {
+    let mut size = 0usize;
+    let mut shift = 0;
+    let mut p = end;
+    loop {
+        size |= ((buf[p] & 0x7F) as usize) << shift;
+        if buf[p] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        p -= 1;
+    }
+    (size, end - p + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_and_iterate_both_ways() {
+        let mut lp = Listpack::new();
+        let values: Vec<Vec<u8>> = vec![
+            b"hello".to_vec(),
+            b"42".to_vec(),
+            b"-5000".to_vec(),
+            b"007".to_vec(),
+            vec![b'x'; 300],
+            b"9223372036854775807".to_vec(),
+            vec![b'y'; 5000],
+        ];
+        for v in &values {
+            let before = lp.bytes_len();
+            lp.push_back(v);
+            assert_eq!(lp.bytes_len() - before, encoded_entry_size(v));
+        }
+
+        assert_eq!(lp.len(), values.len());
+        let forward: Vec<Vec<u8>> = lp.iter().map(|e| e.to_vec()).collect();
+        assert_eq!(forward, values);
+
+        let mut backward = Vec::new();
+        let mut off = lp.last();
+        while let Some(o) = off {
+            backward.push(lp.get(o).to_vec());
+            off = lp.prev(o);
+        }
+        backward.reverse();
+        assert_eq!(backward, values);
+
+        assert_eq!(lp.get(lp.seek(1).unwrap()), ListpackEntry::Int(42));
+        assert_eq!(lp.get(lp.seek(-6).unwrap()), ListpackEntry::Int(42));
+        assert!(Listpack::from_bytes(lp.as_bytes().to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_insert_delete_replace() {
+        let mut lp = Listpack::new();
+        lp.push_back(b"b");
+        lp.push_front(b"a");
+        lp.push_back(b"c");
+
+        let off = lp.find(b"b", 1).unwrap();
+        lp.replace(off, b"123456");
+        lp.delete(lp.first().unwrap());
+
+        let items: Vec<Vec<u8>> = lp.iter().map(|e| e.to_vec()).collect();
+        assert_eq!(items, vec![b"123456".to_vec(), b"c".to_vec()]);
+        assert_eq!(lp.pop_back(), Some(b"c".to_vec()));
+        assert_eq!(lp.pop_front(), Some(b"123456".to_vec()));
+        assert!(lp.is_empty());
+        assert_eq!(lp.bytes_len(), HEADER_SIZE + 1);
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_corruption() {
+        let mut lp = Listpack::new();
+        lp.push_back(b"value");
+        let mut bytes = lp.as_bytes().to_vec();
+        let last = bytes.len() - 2;
+        bytes[last] ^= 0x01;
+        assert!(Listpack::from_bytes(bytes).is_err());
+    }
+}
@@ -1,17 +1,45 @@
 pub mod entry;
+pub mod intset;
+pub mod lazyfree;
+pub mod listpack;
+pub mod notify;
+pub mod quicklist;
+pub mod skiplist;
+pub mod tier;
 pub mod value;
 
 use anyhow::{Result, anyhow};
 use regex::Regex;
 
 use crate::commands::{SetCondition, SetExpire, SetOptions, ZRangeOptions};
+use crate::persistence::snapshot::{SnapshotEntry, SnapshotValue};
+use crate::server::state::ServerState;
 use crate::storage::entry::Entry;
+use crate::storage::intset::IntSet;
+use crate::storage::lazyfree::LazyFreer;
+use crate::storage::listpack::Listpack;
+use crate::storage::notify::{
+    KeyspaceNotifier, NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_HASH, NOTIFY_LIST, NOTIFY_NEW,
+    NOTIFY_SET, NOTIFY_STRING, NOTIFY_ZSET,
+};
+use crate::storage::quicklist::Quicklist;
+use crate::storage::skiplist::SkipList;
+use crate::storage::tier::{DiskTier, SpillRef, TierStats};
+use crate::storage::value::{hash_table_size, malloc_size};
+use crate::utils::unix_time_ms;
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{HashMap, HashSet},
+    hash::{BuildHasher, Hasher, RandomState},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
+// Keys with a TTL the active expire cycle samples per round. It runs another
+// round while more than a quarter of them had expired.
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+const ACTIVE_EXPIRE_REPEAT: usize = ACTIVE_EXPIRE_SAMPLE / 4;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     String(StringValue),
@@ -25,145 +53,390 @@ pub enum Value {
 // ========== String Value ==========
 #[derive(Debug, Clone, PartialEq)]
 pub struct StringValue {
-    pub data: Vec<u8>, // Store as bytes to handle binary data
-    pub encoding: StringEncoding,
+    data: StringData,
+}
+
+// Longest string stored inline in the value instead of in its own heap buffer
+pub const EMBSTR_SIZE_LIMIT: usize = 44;
+
+// Integers in [0, SHARED_INTEGERS) are rendered from a shared pool
+pub const SHARED_INTEGERS: i64 = 10000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum StringData {
+    Int(i64),       // Integer stored natively, rendered on read
+    Embstr(EmbStr), // Short string stored inline
+    Raw(Vec<u8>),   // Raw string/binary data
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct EmbStr {
+    len: u8,
+    buf: [u8; EMBSTR_SIZE_LIMIT],
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum StringEncoding {
     Raw,    // Raw string/binary data
-    Int,    // Integer stored as string
+    Int,    // Integer stored as i64
     Embstr, // Embedded string (short strings)
 }
 
 // ========== List Value ==========
 #[derive(Debug, Clone, PartialEq)]
 pub struct ListValue {
-    pub elements: Vec<Vec<u8>>, // List of byte arrays
-    pub encoding: ListEncoding,
+    data: ListData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ListData {
+    Listpack(Listpack),   // Small lists packed into one buffer
+    Quicklist(Quicklist), // Deque of listpack nodes
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ListEncoding {
-    Ziplist,    // Compressed list for small lists
-    LinkedList, // Standard doubly-linked list
-    Quicklist,  // Hybrid of ziplist and linkedlist
+    Listpack,  // Compact list for small lists
+    Quicklist, // Hybrid of listpack and linkedlist
 }
 
 // ========== Set Value ==========
 #[derive(Debug, Clone, PartialEq)]
 pub struct SetValue {
-    pub members: HashSet<Vec<u8>>, // Set of byte arrays
-    pub encoding: SetEncoding,
+    data: SetData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SetData {
+    IntSet(IntSet),              // Sorted array of integers
+    Listpack(Listpack),          // Small sets packed into one buffer
+    HashTable(HashSet<Vec<u8>>), // Set of byte arrays
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum SetEncoding {
     HashTable, // Standard hash table
     IntSet,    // Optimized for integer-only sets
+    Listpack,  // Compact set for small sets
 }
 
 // ========== Sorted Set Value ==========
 #[derive(Debug, Clone, PartialEq)]
 pub struct SortedSetValue {
-    // BTreeMap maintains sorted order by score
-    pub members: BTreeMap<OrderedFloat, Vec<u8>>,
-    // Reverse lookup: member -> score
-    pub member_scores: HashMap<Vec<u8>, OrderedFloat>,
-    pub encoding: SortedSetEncoding,
+    data: SortedSetData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SortedSetData {
+    // member, score pairs kept in score order
+    Listpack(Listpack),
+    SkipList {
+        // Elements ordered by (score, member), indexable by rank
+        zsl: SkipList,
+        // Reverse lookup: member -> score
+        dict: HashMap<Vec<u8>, f64>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum SortedSetEncoding {
-    Ziplist,  // Compressed for small sorted sets
+    Listpack, // Compact for small sorted sets
     SkipList, // Skip list + hash table for large sets
 }
 
-// Wrapper for f64 to make it Ord for BTreeMap
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
-pub struct OrderedFloat(pub f64);
+// ========== Hash Value ==========
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashValue {
+    data: HashData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HashData {
+    Listpack(Listpack),                   // field, value pairs packed into one buffer
+    HashTable(HashMap<Vec<u8>, Vec<u8>>), // field -> value mapping
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashEncoding {
+    Listpack,  // Compact for small hashes
+    HashTable, // Standard hash table
+}
 
-impl Eq for OrderedFloat {}
+// Size limits up to which aggregates keep their compact encoding. Past either limit
+// a value is converted to its full structure and never converted back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodingLimits {
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    pub set_max_intset_entries: usize,
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    pub zset_max_listpack_entries: usize,
+    pub zset_max_listpack_value: usize,
+    // Per-node fill factor, see `Quicklist`
+    pub list_max_listpack_size: i64,
+}
 
-impl Ord for OrderedFloat {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap_or(std::cmp::Ordering::Equal)
+impl Default for EncodingLimits {
+    fn default() -> Self {
+        Self {
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+            set_max_intset_entries: 512,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+            zset_max_listpack_entries: 128,
+            zset_max_listpack_value: 64,
+            list_max_listpack_size: -2,
+        }
     }
 }
 
-impl From<f64> for OrderedFloat {
-    fn from(f: f64) -> Self {
-        OrderedFloat(f)
+// Memory breakdown reported by MEMORY STATS
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStats {
+    pub keys_count: usize,
+    pub overhead_hashtable_main: usize, // keyspace table, including inline entries
+    pub dataset_bytes: usize,           // key strings and value heap allocations
+}
+
+impl MemoryStats {
+    pub fn total_allocated(&self) -> usize {
+        self.overhead_hashtable_main + self.dataset_bytes
     }
 }
 
-// ========== Hash Value ==========
-#[derive(Debug, Clone, PartialEq)]
-pub struct HashValue {
-    pub fields: HashMap<Vec<u8>, Vec<u8>>, // field -> value mapping
-    pub encoding: HashEncoding,
+// The keyspace. Keys are shared with the lists that snapshots and the active
+// expire cycle walk, so those don't copy them.
+pub type Keyspace = HashMap<Arc<str>, Entry>;
+
+// Field-value pairs of a hash, as HGETALL returns them
+type FieldPairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+// State of an incremental snapshot. Keys are visited in batches while writes go
+// on, and a key modified before its turn first has its image from the start of
+// the snapshot set aside, so the file still shows a single point in time.
+#[derive(Debug, Clone, Default)]
+struct CowSnapshot {
+    // Keys left to visit, shared with the keyspace and sorted by address, taken
+    // from the back. Holding them keeps their addresses from being reused.
+    order: Vec<Arc<str>>,
+    // Images of keys modified before they were visited, None if already expired,
+    // the error if the image couldn't be read from the disk tier
+    shadow: HashMap<Arc<str>, Result<Option<SnapshotEntry>, String>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum HashEncoding {
-    Ziplist,   // Compressed for small hashes
-    HashTable, // Standard hash table
+impl CowSnapshot {
+    // Whether the keyspace key `key` still holds its image from the start: it is
+    // the very key the snapshot holds, not yet visited, and wasn't set aside. A
+    // key deleted and set again gets a new allocation, never a pending one.
+    fn is_pending(&self, key: &Arc<str>) -> bool {
+        !self.shadow.contains_key(key)
+            && self
+                .order
+                .binary_search_by_key(&key_addr(key), key_addr)
+                .is_ok()
+    }
+}
+
+fn key_addr(key: &Arc<str>) -> usize {
+    Arc::as_ptr(key) as *const u8 as usize
+}
+
+// Heap bytes of a keyspace key: its reference counts and the string
+fn key_size(key: &Arc<str>) -> usize {
+    malloc_size(2 * size_of::<usize>() + key.len())
+}
+
+// A snapshot started with `begin_snapshot`: its id, how many keys it holds and how
+// many of those have a TTL
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SnapshotStart {
+    pub id: u64,
+    pub keys: usize,
+    pub expires: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct CacheStore {
-    data: HashMap<String, Entry>,
+    data: Keyspace,
+    limits: EncodingLimits,
+    notifier: KeyspaceNotifier,
+    lazyfree: LazyFreer,
+    tier: DiskTier,
+    // Running incremental snapshots by id: BGSAVE, BGREWRITEAOF and full syncs of
+    // replicas may overlap
+    cows: HashMap<u64, CowSnapshot>,
+    next_cow_id: u64,
+    // Where expired keys are propagated as DEL, to the AOF and replicas
+    propagation: Option<Arc<ServerState>>,
+    // Every key with a TTL, for the active expire cycle to sample. Keys deleted or
+    // persisted since stay until a sample finds them.
+    volatile: Vec<String>,
+    rng: u64,
+    // Changes since startup: one per write that modified a key, one per key
+    // deleted, expired or flushed. Save points compare it with the last save.
+    dirty: u64,
 }
 
 impl CacheStore {
     pub fn new(cap: usize) -> Self {
         Self {
             data: HashMap::with_capacity(cap),
+            limits: EncodingLimits::default(),
+            notifier: KeyspaceNotifier::default(),
+            lazyfree: LazyFreer::default(),
+            tier: DiskTier::default(),
+            cows: HashMap::new(),
+            next_cow_id: 1,
+            propagation: None,
+            volatile: Vec::new(),
+            rng: RandomState::new().build_hasher().finish() | 1,
+            dirty: 0,
+        }
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty
+    }
+
+    pub fn set_encoding_limits(&mut self, limits: EncodingLimits) {
+        self.limits = limits;
+    }
+
+    pub fn set_notifier(&mut self, notifier: KeyspaceNotifier) {
+        self.notifier = notifier;
+    }
+
+    pub fn set_lazyfree(&mut self, lazyfree: LazyFreer) {
+        self.lazyfree = lazyfree;
+    }
+
+    pub fn set_tier(&mut self, tier: DiskTier) {
+        self.tier = tier;
+    }
+
+    pub fn set_propagation(&mut self, state: Arc<ServerState>) {
+        self.propagation = Some(state);
+    }
+
+    // Drop a value detached from the keyspace, on the lazyfree thread when `lazy`
+    fn free_value(&self, value: Value, lazy: bool) {
+        if lazy {
+            self.lazyfree.free_value(value);
+        }
+    }
+
+    // Drop a key whose TTL has passed
+    fn expire_key(&mut self, key: &str) {
+        self.preserve(key);
+        if let Some(entry) = self.data.remove(key) {
+            self.free_value(entry.value, self.lazyfree.options.lazy_expire);
+        }
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_EXPIRED, "expired", key);
+        if let Some(state) = &self.propagation {
+            state.propagate_expired(key);
         }
     }
 
-    // Clean up expired keys
-    pub fn cleanup_expired(&mut self) -> u64 {
-        let expired_keys: Vec<String> = self
+    // Aggregates are deleted once their last element is removed
+    fn remove_if_empty(&mut self, key: &str) {
+        if self
             .data
-            .iter()
-            .filter(|(_, entry)| entry.is_expired())
-            .map(|(key, _)| key.clone())
-            .collect();
+            .get(key)
+            .is_some_and(|entry| entry.spilled.is_none() && entry.value.is_empty())
+        {
+            self.data.remove(key);
+            self.notifier.notify(NOTIFY_GENERIC, "del", key);
+        }
+    }
 
-        let count = expired_keys.len() as u64;
-        for key in expired_keys {
-            self.data.remove(&key);
+    // Remember that `key` has a TTL now, unless it had one already
+    fn track_expiry(&mut self, key: &str, had_expiry: bool) {
+        if had_expiry {
+            return;
+        }
+        // Keys persisted and given a TTL again can be listed twice
+        if self.volatile.len() > 2 * self.data.len() + 1024 {
+            self.volatile = self
+                .data
+                .iter()
+                .filter(|(_, entry)| entry.expires_at.is_some())
+                .map(|(key, _)| key.to_string())
+                .collect();
+        } else {
+            self.volatile.push(key.to_string());
         }
+    }
 
-        count
+    // Active expire cycle: sample keys with a TTL and drop the expired ones, round
+    // after round while many had expired, until `budget` is used up
+    pub fn cleanup_expired(&mut self, budget: Duration) -> u64 {
+        let start = Instant::now();
+        let mut count = 0;
+        loop {
+            let mut dropped = 0;
+            for _ in 0..ACTIVE_EXPIRE_SAMPLE {
+                if self.volatile.is_empty() {
+                    return count;
+                }
+                let i = (self.next_random() % self.volatile.len() as u64) as usize;
+                match self.data.get(self.volatile[i].as_str()) {
+                    Some(entry) if entry.is_expired() => {
+                        let key = self.volatile.swap_remove(i);
+                        self.expire_key(&key);
+                        count += 1;
+                        dropped += 1;
+                    }
+                    Some(entry) if entry.expires_at.is_some() => {}
+                    _ => {
+                        self.volatile.swap_remove(i);
+                        dropped += 1;
+                    }
+                }
+            }
+            if dropped <= ACTIVE_EXPIRE_REPEAT || start.elapsed() >= budget {
+                return count;
+            }
+        }
+    }
+
+    // xorshift64
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
     }
 
     // Get value and update access time
-    pub fn get(&mut self, key: &str) -> Option<Value> {
-        match self.data.get_mut(key) {
+    pub fn get(&mut self, key: &str) -> Result<Option<Value>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => {
                 entry.update_access_time();
                 Some(entry.value.clone())
             }
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None,
-        }
+        })
     }
 
     // Set value without expiration
     pub fn set(&mut self, key: String, value: Value, opts: SetOptions) -> Result<Option<Value>> {
+        self.preserve(&key);
         // Return the old string stored at key, or nil if key did not exist. An error is returned and SET aborted if the value stored at key is not a string.
         let mut old_str: Option<Value> = None;
         let mut entry = Entry::new(value);
 
         // Handle expiration options
         if opts.get {
-            if let Some(existing_entry) = self.data.get(key.as_str()) {
+            if let Some(existing_entry) = self.tier.resident(&mut self.data, &self.limits, &key)? {
                 if let Value::String(existing_value) = &existing_entry.value {
                     old_str = Some(Value::String(existing_value.clone()));
                 } else {
@@ -180,13 +453,13 @@ impl CacheStore {
             match cond {
                 // NX: Only set if key does not exist
                 SetCondition::Nx => {
-                    if self.data.contains_key(&key) {
+                    if self.data.contains_key(key.as_str()) {
                         return Ok(None); // Key exists, do not set
                     }
                 }
                 // XX: Only set if key exists
                 SetCondition::Xx => {
-                    if !self.data.contains_key(&key) {
+                    if !self.data.contains_key(key.as_str()) {
                         return Ok(None); // Key does not exist, do not set
                     }
                 }
@@ -201,45 +474,134 @@ impl CacheStore {
                     entry.set_expiration(Duration::from_millis(milliseconds));
                 }
                 SetExpire::ExAt(timestamp_secs) => {
-                    let now = Instant::now();
-                    let expire_time = Duration::from_secs(timestamp_secs)
-                        .checked_sub(now.elapsed())
-                        .unwrap_or(Duration::ZERO);
-                    entry.set_expiration(expire_time);
+                    let expire_ms = timestamp_secs.saturating_mul(1000);
+                    entry.set_expiration(Duration::from_millis(
+                        expire_ms.saturating_sub(unix_time_ms()),
+                    ));
                 }
                 SetExpire::PxAt(timestamp_millis) => {
-                    let now = Instant::now();
-                    let expire_time = Duration::from_millis(timestamp_millis)
-                        .checked_sub(now.elapsed())
-                        .unwrap_or(Duration::ZERO);
-                    entry.set_expiration(expire_time);
+                    entry.set_expiration(Duration::from_millis(
+                        timestamp_millis.saturating_sub(unix_time_ms()),
+                    ));
                 }
                 SetExpire::KeepTtl => {
-                    if let Some(existing_entry) = self.data.get(key.as_str()) {
-                        if let Some(ttl) = existing_entry.ttl() {
-                            entry.set_expiration(ttl);
-                        }
+                    if let Some(existing_entry) = self.data.get(key.as_str())
+                        && let Some(ttl) = existing_entry.ttl()
+                    {
+                        entry.set_expiration(ttl);
                     }
                 }
             }
         }
 
-        self.data.insert(key.clone(), entry);
+        let has_expire = entry.expires_at.is_some();
+        let had_expire = match self.data.insert(key.as_str().into(), entry) {
+            Some(old) => {
+                let had_expire = old.expires_at.is_some();
+                self.free_value(old.value, self.lazyfree.options.lazy_server_del);
+                had_expire
+            }
+            None => {
+                self.notifier.notify(NOTIFY_NEW, "new", &key);
+                false
+            }
+        };
+        if has_expire {
+            self.track_expiry(&key, had_expire);
+        }
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_STRING, "set", &key);
+        if has_expire {
+            self.notifier.notify(NOTIFY_GENERIC, "expire", &key);
+        }
         Ok(old_str)
     }
 
-    pub fn lpush(&mut self, key: &str, values: Vec<String>) -> usize {
-        let list_value = match self.data.get_mut(key) {
+    // Add `delta` to the integer stored at key, treating a missing key as 0. The
+    // key keeps its TTL.
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64> {
+        self.preserve(key);
+        let current = match self.tier.resident(&mut self.data, &self.limits, key)? {
+            Some(entry) if !entry.is_expired() => match &entry.value {
+                Value::String(s) => s
+                    .as_int()
+                    .ok_or_else(|| anyhow!("ERR value is not an integer or out of range"))?,
+                _ => {
+                    return Err(anyhow!(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                    ));
+                }
+            },
+            Some(_) => {
+                // Key exists but is expired - remove it
+                self.expire_key(key);
+                0
+            }
+            None => 0,
+        };
+
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| anyhow!("ERR increment or decrement would overflow"))?;
+        self.store_string(key, StringValue::from_int(value))?;
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_STRING, "incrby", key);
+        Ok(value)
+    }
+
+    pub fn incr_by_float(&mut self, key: &str, delta: f64) -> Result<f64> {
+        self.preserve(key);
+        let current = match self.tier.resident(&mut self.data, &self.limits, key)? {
+            Some(entry) if !entry.is_expired() => match &entry.value {
+                Value::String(s) => s
+                    .as_float()
+                    .ok_or_else(|| anyhow!("ERR value is not a valid float"))?,
+                _ => {
+                    return Err(anyhow!(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                    ));
+                }
+            },
+            Some(_) => {
+                // Key exists but is expired - remove it
+                self.expire_key(key);
+                0.0
+            }
+            None => 0.0,
+        };
+
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err(anyhow!("ERR increment would produce NaN or Infinity"));
+        }
+        self.store_string(key, StringValue::new(value.to_string()))?;
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_STRING, "incrbyfloat", key);
+        Ok(value)
+    }
+
+    // Replace the value at key, keeping the existing entry's expiry
+    fn store_string(&mut self, key: &str, value: StringValue) -> Result<()> {
+        match self.tier.resident(&mut self.data, &self.limits, key)? {
+            Some(entry) => entry.value = Value::String(value),
+            None => {
+                self.data
+                    .insert(key.into(), Entry::new(Value::String(value)));
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn lpush(&mut self, key: &str, values: Vec<String>) -> Result<usize> {
+        self.preserve(key);
+        let list_value = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => {
                 match &mut entry.value {
                     Value::List(list) => list,
                     _ => {
                         // Key exists but is not a list - overwrite with new list
-                        let new_list = ListValue {
-                            elements: Vec::new(),
-                            encoding: ListEncoding::Quicklist,
-                        };
-                        entry.value = Value::List(new_list);
+                        entry.value = Value::List(ListValue::new());
                         match &mut entry.value {
                             Value::List(list) => list,
                             _ => unreachable!(),
@@ -249,27 +611,31 @@ impl CacheStore {
             }
             Some(_) => {
                 // Key exists but is expired - remove it and create new list
-                self.data.remove(key);
-                let new_list = ListValue {
-                    elements: Vec::new(),
-                    encoding: ListEncoding::Quicklist,
-                };
-                let entry = Entry::new(Value::List(new_list));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                self.expire_key(key);
+                let entry = Entry::new(Value::List(ListValue::new()));
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::List(list) => list,
                     _ => unreachable!(),
                 }
             }
             None => {
                 // Key does not exist - create new list
-                let new_list = ListValue {
-                    elements: Vec::new(),
-                    encoding: ListEncoding::Quicklist,
-                };
-                let entry = Entry::new(Value::List(new_list));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                let entry = Entry::new(Value::List(ListValue::new()));
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::List(list) => list,
                     _ => unreachable!(),
                 }
@@ -277,25 +643,25 @@ impl CacheStore {
         };
 
         // Prepend values to the list
-        for value in values.into_iter().rev() {
-            list_value.elements.insert(0, value.into_bytes());
+        for value in values {
+            list_value.push_left(value, &self.limits);
         }
 
-        list_value.elements.len()
+        let len = list_value.len();
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_LIST, "lpush", key);
+        Ok(len)
     }
 
-    pub fn rpush(&mut self, key: &str, values: Vec<String>) -> usize {
-        let list_value = match self.data.get_mut(key) {
+    pub fn rpush(&mut self, key: &str, values: Vec<String>) -> Result<usize> {
+        self.preserve(key);
+        let list_value = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => {
                 match &mut entry.value {
                     Value::List(list) => list,
                     _ => {
                         // Key exists but is not a list - overwrite with new list
-                        let new_list = ListValue {
-                            elements: Vec::new(),
-                            encoding: ListEncoding::Quicklist,
-                        };
-                        entry.value = Value::List(new_list);
+                        entry.value = Value::List(ListValue::new());
                         match &mut entry.value {
                             Value::List(list) => list,
                             _ => unreachable!(),
@@ -305,27 +671,31 @@ impl CacheStore {
             }
             Some(_) => {
                 // Key exists but is expired - remove it and create new list
-                self.data.remove(key);
-                let new_list = ListValue {
-                    elements: Vec::new(),
-                    encoding: ListEncoding::Quicklist,
-                };
-                let entry = Entry::new(Value::List(new_list));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                self.expire_key(key);
+                let entry = Entry::new(Value::List(ListValue::new()));
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::List(list) => list,
                     _ => unreachable!(),
                 }
             }
             None => {
                 // Key does not exist - create new list
-                let new_list = ListValue {
-                    elements: Vec::new(),
-                    encoding: ListEncoding::Quicklist,
-                };
-                let entry = Entry::new(Value::List(new_list));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                let entry = Entry::new(Value::List(ListValue::new()));
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::List(list) => list,
                     _ => unreachable!(),
                 }
@@ -334,14 +704,18 @@ impl CacheStore {
 
         // Append values to the list
         for value in values {
-            list_value.elements.push(value.into_bytes());
+            list_value.push_right(value, &self.limits);
         }
 
-        list_value.elements.len()
+        let len = list_value.len();
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_LIST, "rpush", key);
+        Ok(len)
     }
 
-    pub fn lpop(&mut self, key: &str, count: u64) -> Option<Vec<Vec<u8>>> {
-        match self.data.get_mut(key) {
+    pub fn lpop(&mut self, key: &str, count: u64) -> Result<Option<Vec<Vec<u8>>>> {
+        self.preserve(key);
+        let popped = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => match &mut entry.value {
                 Value::List(list) => {
                     let mut popped = Vec::new();
@@ -363,15 +737,23 @@ impl CacheStore {
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
+        };
+
+        if popped.is_some() {
+            self.dirty += 1;
+            self.notifier.notify(NOTIFY_LIST, "lpop", key);
+            self.remove_if_empty(key);
         }
+        Ok(popped)
     }
 
-    pub fn rpop(&mut self, key: &str, count: u64) -> Option<Vec<Vec<u8>>> {
-        match self.data.get_mut(key) {
+    pub fn rpop(&mut self, key: &str, count: u64) -> Result<Option<Vec<Vec<u8>>>> {
+        self.preserve(key);
+        let popped = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => match &mut entry.value {
                 Value::List(list) => {
                     let mut popped = Vec::new();
@@ -393,30 +775,55 @@ impl CacheStore {
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
+        };
+
+        if popped.is_some() {
+            self.dirty += 1;
+            self.notifier.notify(NOTIFY_LIST, "rpop", key);
+            self.remove_if_empty(key);
         }
+        Ok(popped)
     }
 
-    pub fn llen(&mut self, key: &str) -> Option<usize> {
-        match self.data.get_mut(key) {
+    pub fn llen(&mut self, key: &str) -> Result<Option<usize>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
                 Value::List(list) => Some(list.len()),
                 _ => None, // Key exists but is not a list
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
-    pub fn lrange(&mut self, key: &str, start: i64, stop: i64) -> Option<Vec<Vec<u8>>> {
-        match self.data.get_mut(key) {
+    pub fn lindex(&mut self, key: &str, index: i64) -> Result<Option<Vec<u8>>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
+            Some(entry) if !entry.is_expired() => match &entry.value {
+                Value::List(list) => list.get(index),
+                _ => None, // Key exists but is not a list
+            },
+            Some(_) => {
+                // Key exists but is expired - remove it
+                self.expire_key(key);
+                None
+            }
+            None => None, // Key does not exist
+        })
+    }
+
+    pub fn lrange(&mut self, key: &str, start: i64, stop: i64) -> Result<Option<Vec<Vec<u8>>>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
                 Value::List(list) => {
                     let len = list.len() as i64;
@@ -434,35 +841,32 @@ impl CacheStore {
                     } as usize;
 
                     if start_idx >= stop_idx || start_idx >= list.len() {
-                        return Some(vec![]);
+                        return Ok(Some(vec![]));
                     }
 
-                    Some(list.elements[start_idx..stop_idx].to_vec())
+                    Some(list.range(start_idx, stop_idx))
                 }
                 _ => None, // Key exists but is not a list
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
     // ------- Set Value Methods -------
-    pub fn sadd(&mut self, key: &str, members: Vec<String>) -> usize {
-        let set_value = match self.data.get_mut(key) {
+    pub fn sadd(&mut self, key: &str, members: Vec<String>) -> Result<usize> {
+        self.preserve(key);
+        let set_value = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => {
                 match &mut entry.value {
                     Value::Set(set) => set,
                     _ => {
                         // Key exists but is not a set - overwrite with new set
-                        let new_set = SetValue {
-                            members: HashSet::new(),
-                            encoding: SetEncoding::HashTable,
-                        };
-                        entry.value = Value::Set(new_set);
+                        entry.value = Value::Set(SetValue::new());
                         match &mut entry.value {
                             Value::Set(set) => set,
                             _ => unreachable!(),
@@ -472,120 +876,135 @@ impl CacheStore {
             }
             Some(_) => {
                 // Key exists but is expired - remove it and create new set
-                self.data.remove(key);
-                let new_set = SetValue {
-                    members: HashSet::new(),
-                    encoding: SetEncoding::HashTable,
-                };
-                let entry = Entry::new(Value::Set(new_set));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                self.expire_key(key);
+                let entry = Entry::new(Value::Set(SetValue::new()));
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::Set(set) => set,
                     _ => unreachable!(),
                 }
             }
             None => {
                 // Key does not exist - create new set
-                let new_set = SetValue {
-                    members: HashSet::new(),
-                    encoding: SetEncoding::HashTable,
-                };
-                let entry = Entry::new(Value::Set(new_set));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                let entry = Entry::new(Value::Set(SetValue::new()));
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::Set(set) => set,
                     _ => unreachable!(),
                 }
             }
         };
 
-        let initial_size = set_value.members.len();
+        let mut added = 0;
         for member in members {
-            set_value.members.insert(member.into_bytes());
+            if set_value.add(member, &self.limits) {
+                added += 1;
+            }
         }
 
-        set_value.members.len() - initial_size
+        if added > 0 {
+            self.dirty += 1;
+            self.notifier.notify(NOTIFY_SET, "sadd", key);
+        }
+        Ok(added)
     }
 
-    pub fn srem(&mut self, key: &str, members: Vec<String>) -> usize {
-        match self.data.get_mut(key) {
+    pub fn srem(&mut self, key: &str, members: Vec<String>) -> Result<usize> {
+        self.preserve(key);
+        let removed = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => match &mut entry.value {
-                Value::Set(set) => {
-                    let initial_size = set.members.len();
-                    for member in members {
-                        set.members.remove(&member.into_bytes());
-                    }
-                    initial_size - set.members.len()
-                }
+                Value::Set(set) => members
+                    .iter()
+                    .filter(|member| set.remove(member.as_bytes()))
+                    .count(),
                 _ => 0, // Key exists but is not a set
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 0
             }
             None => 0, // Key does not exist
+        };
+
+        if removed > 0 {
+            self.dirty += 1;
+            self.notifier.notify(NOTIFY_SET, "srem", key);
+            self.remove_if_empty(key);
         }
+        Ok(removed)
     }
 
-    pub fn smembers(&mut self, key: &str) -> Option<HashSet<Vec<u8>>> {
-        match self.data.get_mut(key) {
+    pub fn smembers(&mut self, key: &str) -> Result<Option<Vec<Vec<u8>>>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Set(set) => Some(set.members.clone()),
+                Value::Set(set) => Some(set.members()),
                 _ => None, // Key exists but is not a set
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
-    pub fn scard(&mut self, key: &str) -> Option<usize> {
-        match self.data.get_mut(key) {
+    pub fn scard(&mut self, key: &str) -> Result<Option<usize>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Set(set) => Some(set.members.len()),
+                Value::Set(set) => Some(set.len()),
                 _ => None, // Key exists but is not a set
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
-    pub fn s_ismember(&mut self, key: &str, member: &str) -> Option<bool> {
-        match self.data.get_mut(key) {
+    pub fn s_ismember(&mut self, key: &str, member: &str) -> Result<Option<bool>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Set(set) => Some(set.members.contains(&member.as_bytes().to_vec())),
+                Value::Set(set) => Some(set.contains(member.as_bytes())),
                 _ => None, // Key exists but is not a set
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
     // ------- Hash Value Methods -------
-    pub fn hset(&mut self, key: &str, pairs: Vec<(String, String)>) -> usize {
-        let hash_value = match self.data.get_mut(key) {
+    pub fn hset(&mut self, key: &str, pairs: Vec<(String, String)>) -> Result<usize> {
+        self.preserve(key);
+        let hash_value = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => {
                 match &mut entry.value {
                     Value::Hash(hash) => hash,
                     _ => {
                         // Key exists but is not a hash - overwrite with new hash
-                        let new_hash = HashValue {
-                            fields: HashMap::new(),
-                            encoding: HashEncoding::HashTable,
-                        };
-                        entry.value = Value::Hash(new_hash);
+                        entry.value = Value::Hash(HashValue::new());
                         match &mut entry.value {
                             Value::Hash(hash) => hash,
                             _ => unreachable!(),
@@ -595,95 +1014,103 @@ impl CacheStore {
             }
             Some(_) => {
                 // Key exists but is expired - remove it and create new hash
-                self.data.remove(key);
-                let new_hash = HashValue {
-                    fields: HashMap::new(),
-                    encoding: HashEncoding::HashTable,
-                };
-                let entry = Entry::new(Value::Hash(new_hash));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                self.expire_key(key);
+                let entry = Entry::new(Value::Hash(HashValue::new()));
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::Hash(hash) => hash,
                     _ => unreachable!(),
                 }
             }
             None => {
                 // Key does not exist - create new hash
-                let new_hash = HashValue {
-                    fields: HashMap::new(),
-                    encoding: HashEncoding::HashTable,
-                };
-
-                let entry = Entry::new(Value::Hash(new_hash));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                let entry = Entry::new(Value::Hash(HashValue::new()));
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::Hash(hash) => hash,
                     _ => unreachable!(),
                 }
             }
         };
 
-        let mut sz = 0 as usize;
+        let mut sz = 0;
         for (field, value) in pairs {
-            let key_bs = field.into_bytes();
-            let key = &key_bs;
-
-            if !hash_value.fields.contains_key(key) {
+            if hash_value.set(field, value, &self.limits) {
                 sz += 1;
             }
-            hash_value.fields.insert(key_bs, value.into_bytes());
         }
 
-        sz
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_HASH, "hset", key);
+        Ok(sz)
     }
 
-    pub fn hget(&mut self, key: &str, field: &str) -> Option<Vec<u8>> {
-        match self.data.get_mut(key) {
+    pub fn hget(&mut self, key: &str, field: &str) -> Result<Option<Vec<u8>>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Hash(hash) => hash.fields.get(field.as_bytes()).cloned(),
+                Value::Hash(hash) => hash.get(field.as_bytes()),
                 _ => None, // Key exists but is not a hash
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
-    pub fn hdel(&mut self, key: &str, fields: &[String]) -> usize {
-        match self.data.get_mut(key) {
+    pub fn hdel(&mut self, key: &str, fields: &[String]) -> Result<usize> {
+        self.preserve(key);
+        let removed = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => match &mut entry.value {
-                Value::Hash(hash) => {
-                    let initial_size = hash.fields.len();
-                    for field in fields {
-                        hash.fields.remove(field.as_bytes());
-                    }
-                    initial_size - hash.fields.len()
-                }
+                Value::Hash(hash) => fields
+                    .iter()
+                    .filter(|field| hash.remove(field.as_bytes()))
+                    .count(),
                 _ => 0, // Key exists but is not a hash
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 0
             }
             None => 0, // Key does not exist
+        };
+
+        if removed > 0 {
+            self.dirty += 1;
+            self.notifier.notify(NOTIFY_HASH, "hdel", key);
+            self.remove_if_empty(key);
         }
+        Ok(removed)
     }
 
-    pub fn hmset(&mut self, key: &str, pairs: &[(String, String)]) -> usize {
+    pub fn hmset(&mut self, key: &str, pairs: &[(String, String)]) -> Result<usize> {
         self.hset(key, pairs.to_vec())
     }
 
-    pub fn hmget(&mut self, key: &str, fields: &[String]) -> Option<Vec<Option<Vec<u8>>>> {
-        match self.data.get_mut(key) {
+    pub fn hmget(&mut self, key: &str, fields: &[String]) -> Result<Option<Vec<Option<Vec<u8>>>>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
                 Value::Hash(hash) => {
                     let mut values = Vec::with_capacity(fields.len());
                     for field in fields {
-                        values.push(hash.fields.get(field.as_bytes()).cloned());
+                        values.push(hash.get(field.as_bytes()));
                     }
                     Some(values)
                 }
@@ -691,97 +1118,105 @@ impl CacheStore {
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
-    pub fn hexists(&mut self, key: &str, field: &str) -> bool {
-        match self.data.get_mut(key) {
+    pub fn hexists(&mut self, key: &str, field: &str) -> Result<bool> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Hash(hash) => hash.fields.contains_key(field.as_bytes()),
+                Value::Hash(hash) => hash.contains_field(field.as_bytes()),
                 _ => false, // Key exists but is not a hash
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 false
             }
             None => false, // Key does not exist
-        }
+        })
     }
 
-    pub fn hlen(&mut self, key: &str) -> usize {
-        match self.data.get_mut(key) {
+    pub fn hlen(&mut self, key: &str) -> Result<usize> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Hash(hash) => hash.fields.len(),
+                Value::Hash(hash) => hash.len(),
                 _ => 0, // Key exists but is not a hash
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 0
             }
             None => 0, // Key does not exist
-        }
+        })
     }
 
-    pub fn hkeys(&mut self, key: &str) -> Option<Vec<Vec<u8>>> {
-        match self.data.get_mut(key) {
+    pub fn hkeys(&mut self, key: &str) -> Result<Option<Vec<Vec<u8>>>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Hash(hash) => Some(hash.fields.keys().cloned().collect()),
+                Value::Hash(hash) => Some(hash.keys()),
                 _ => None, // Key exists but is not a hash
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
-    pub fn hvals(&mut self, key: &str) -> Option<Vec<Vec<u8>>> {
-        match self.data.get_mut(key) {
+    pub fn hvals(&mut self, key: &str) -> Result<Option<Vec<Vec<u8>>>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Hash(hash) => Some(hash.fields.values().cloned().collect()),
+                Value::Hash(hash) => Some(hash.values()),
                 _ => None, // Key exists but is not a hash
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
-    pub fn hgetall(&mut self, key: &str) -> Option<HashMap<Vec<u8>, Vec<u8>>> {
-        match self.data.get_mut(key) {
+    pub fn hgetall(&mut self, key: &str) -> Result<Option<FieldPairs>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::Hash(hash) => {
-                    let mut map = HashMap::new();
-                    for (k, v) in &hash.fields {
-                        map.insert(k.clone(), v.clone());
-                    }
-                    Some(map)
-                }
+                Value::Hash(hash) => Some(
+                    hash.iter()
+                        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                        .collect(),
+                ),
                 _ => None, // Key exists but is not a hash
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
     // -------- Sorted Set Value Methods -------
-    pub fn zadd(&mut self, key: &str, members: Vec<(f64, String)>) -> usize {
-        let zset_value = match self.data.get_mut(key) {
+    pub fn zadd(&mut self, key: &str, members: Vec<(f64, String)>) -> Result<usize> {
+        self.preserve(key);
+        if members.iter().any(|(score, _)| score.is_nan()) {
+            return Err(anyhow!("ERR resulting score is not a number (NaN)"));
+        }
+
+        let zset_value = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => {
                 match &mut entry.value {
                     Value::SortedSet(zset) => zset,
@@ -797,10 +1232,16 @@ impl CacheStore {
             }
             Some(_) => {
                 // Key exists but is expired - remove it and create new sorted set
-                self.data.remove(key);
+                self.expire_key(key);
                 let entry = Entry::new(Value::SortedSet(SortedSetValue::new()));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::SortedSet(zset) => zset,
                     _ => unreachable!(),
                 }
@@ -808,8 +1249,14 @@ impl CacheStore {
             None => {
                 // Key does not exist - create new sorted set
                 let entry = Entry::new(Value::SortedSet(SortedSetValue::new()));
-                self.data.insert(key.to_string(), entry);
-                match &mut self.data.get_mut(key).unwrap().value {
+                self.data.insert(key.into(), entry);
+                self.notifier.notify(NOTIFY_NEW, "new", key);
+                match &mut self
+                    .tier
+                    .resident(&mut self.data, &self.limits, key)?
+                    .unwrap()
+                    .value
+                {
                     Value::SortedSet(zset) => zset,
                     _ => unreachable!(),
                 }
@@ -817,41 +1264,39 @@ impl CacheStore {
         };
         let mut added = 0;
         for (score, member) in members {
-            let member_bytes = member.into_bytes();
-            let of_score = OrderedFloat::from(score);
-            let exists = zset_value.member_scores.contains_key(&member_bytes);
-            if !exists {
-                zset_value.members.insert(of_score, member_bytes.clone());
-                zset_value.member_scores.insert(member_bytes, of_score);
+            if zset_value.add(score, member.into_bytes(), &self.limits) {
                 added += 1;
             }
         }
-        added
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_ZSET, "zadd", key);
+        Ok(added)
     }
 
-    pub fn zrem(&mut self, key: &str, members: Vec<String>) -> usize {
-        match self.data.get_mut(key) {
+    pub fn zrem(&mut self, key: &str, members: Vec<String>) -> Result<usize> {
+        self.preserve(key);
+        let removed = match self.tier.resident(&mut self.data, &self.limits, key)? {
             Some(entry) if !entry.is_expired() => match &mut entry.value {
-                Value::SortedSet(zset) => {
-                    let mut removed = 0;
-                    for member in members {
-                        let member_bytes = member.into_bytes();
-                        if let Some(score) = zset.member_scores.remove(&member_bytes) {
-                            zset.members.remove(&score);
-                            removed += 1;
-                        }
-                    }
-                    removed
-                }
+                Value::SortedSet(zset) => members
+                    .iter()
+                    .filter(|member| zset.remove(member.as_bytes()))
+                    .count(),
                 _ => 0, // Key exists but is not a sorted set
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 0
             }
             None => 0, // Key does not exist
+        };
+
+        if removed > 0 {
+            self.dirty += 1;
+            self.notifier.notify(NOTIFY_ZSET, "zrem", key);
+            self.remove_if_empty(key);
         }
+        Ok(removed)
     }
 
     pub fn zrange(
@@ -860,11 +1305,12 @@ impl CacheStore {
         start: i64,
         stop: i64,
         options: ZRangeOptions,
-    ) -> Option<Vec<(String, f64)>> {
-        match self.data.get_mut(key) {
+    ) -> Result<Option<Vec<(String, f64)>>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
                 Value::SortedSet(zset) => {
-                    let len = zset.members.len() as i64;
+                    let len = zset.len() as i64;
 
                     let start_idx = if start < 0 {
                         (len + start).max(0)
@@ -878,22 +1324,16 @@ impl CacheStore {
                         (stop + 1).min(len)
                     } as usize;
 
-                    if start_idx >= stop_idx || start_idx >= zset.members.len() {
-                        return Some(vec![]);
+                    if start_idx >= stop_idx || start_idx >= zset.len() {
+                        return Ok(Some(vec![]));
                     }
 
-                    let range_iter = zset
-                        .members
-                        .iter()
-                        .skip(start_idx)
-                        .take(stop_idx - start_idx);
-
                     let mut result = Vec::new();
-                    for (score, member) in range_iter {
+                    for (member, score) in zset.range(start_idx, stop_idx) {
                         if options.with_scores {
-                            result.push((String::from_utf8_lossy(member).to_string(), score.0));
+                            result.push((String::from_utf8_lossy(&member).to_string(), score));
                         } else {
-                            result.push((String::from_utf8_lossy(member).to_string(), 0.0));
+                            result.push((String::from_utf8_lossy(&member).to_string(), 0.0));
                         }
                     }
                     Some(result)
@@ -902,69 +1342,300 @@ impl CacheStore {
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
     }
 
-    pub fn zcard(&mut self, key: &str) -> usize {
-        match self.data.get_mut(key) {
+    pub fn zcard(&mut self, key: &str) -> Result<usize> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::SortedSet(zset) => zset.members.len(),
+                Value::SortedSet(zset) => zset.len(),
                 _ => 0, // Key exists but is not a sorted set
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 0
             }
             None => 0, // Key does not exist
-        }
+        })
     }
 
-    pub fn zscore(&mut self, key: &str, member: &str) -> Option<f64> {
-        match self.data.get_mut(key) {
+    pub fn zscore(&mut self, key: &str, member: &str) -> Result<Option<f64>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
             Some(entry) if !entry.is_expired() => match &entry.value {
-                Value::SortedSet(zset) => zset.member_scores.get(member.as_bytes()).map(|of| of.0),
+                Value::SortedSet(zset) => zset.score(member.as_bytes()),
                 _ => None, // Key exists but is not a sorted set
             },
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 None
             }
             None => None, // Key does not exist
-        }
+        })
+    }
+
+    // 0-based rank of the member, counted from the highest score when `reverse`
+    pub fn zrank(&mut self, key: &str, member: &str, reverse: bool) -> Result<Option<usize>> {
+        let entry = self.tier.resident(&mut self.data, &self.limits, key)?;
+        Ok(match entry {
+            Some(entry) if !entry.is_expired() => match &entry.value {
+                Value::SortedSet(zset) => zset
+                    .rank(member.as_bytes())
+                    .map(|rank| if reverse { zset.len() - 1 - rank } else { rank }),
+                _ => None, // Key exists but is not a sorted set
+            },
+            Some(_) => {
+                // Key exists but is expired - remove it
+                self.expire_key(key);
+                None
+            }
+            None => None, // Key does not exist
+        })
     }
+
     // Set value with expiration
     pub fn set_with_expiration(&mut self, key: String, value: Value, ttl: Duration) {
+        self.preserve(&key);
         let entry = Entry::with_expiration(value, ttl);
-        self.data.insert(key, entry);
+        let old = self.data.insert(key.as_str().into(), entry);
+        if old.is_none() {
+            self.notifier.notify(NOTIFY_NEW, "new", &key);
+        }
+        self.track_expiry(&key, old.is_some_and(|old| old.expires_at.is_some()));
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_STRING, "set", &key);
+        self.notifier.notify(NOTIFY_GENERIC, "expire", &key);
     }
 
     // Delete key
     pub fn delete(&mut self, keys: Vec<String>) -> usize {
         let mut deleted = 0;
         for key in keys {
-            if self.data.remove(&key).is_some() {
-                deleted += 1;
+            self.preserve(&key);
+            match self.data.get(key.as_str()) {
+                Some(entry) if !entry.is_expired() => {
+                    self.data.remove(key.as_str());
+                    self.dirty += 1;
+                    self.notifier.notify(NOTIFY_GENERIC, "del", &key);
+                    deleted += 1;
+                }
+                Some(_) => self.expire_key(&key),
+                None => {}
             }
         }
         deleted
     }
 
+    // Detach keys from the keyspace and free their values in the background
+    pub fn unlink(&mut self, keys: Vec<String>) -> usize {
+        let mut unlinked = 0;
+        for key in keys {
+            self.preserve(&key);
+            match self.data.get(key.as_str()) {
+                Some(entry) if !entry.is_expired() => {
+                    if let Some(entry) = self.data.remove(key.as_str()) {
+                        self.lazyfree.free_value(entry.value);
+                    }
+                    self.dirty += 1;
+                    self.notifier.notify(NOTIFY_GENERIC, "del", &key);
+                    unlinked += 1;
+                }
+                Some(_) => self.expire_key(&key),
+                None => {}
+            }
+        }
+        unlinked
+    }
+
+    // Remove every key, dropping them on the lazyfree thread when `lazy`
+    pub fn flush(&mut self, lazy: bool) {
+        self.preserve_all();
+        let keyspace = std::mem::take(&mut self.data);
+        self.volatile.clear();
+        self.tier.clear();
+        self.dirty += keyspace.len() as u64;
+        if lazy {
+            self.lazyfree.free_keyspace(keyspace);
+        }
+    }
+
+    // Live entries, for writing snapshots
+    pub fn entries(&self) -> impl Iterator<Item = (&Arc<str>, &Entry)> {
+        self.data.iter().filter(|(_, entry)| !entry.is_expired())
+    }
+
+    // ------- Incremental Snapshots -------
+    // A live entry as snapshots store it. Fails if a spilled value can't be read
+    // back, a snapshot missing the key must not replace a good one.
+    pub fn snapshot_entry(&self, key: &str, entry: &Entry) -> Result<Option<SnapshotEntry>> {
+        if entry.is_expired() {
+            return Ok(None);
+        }
+        let value = match entry.spilled {
+            Some(spill) => self
+                .spilled_value(spill)
+                .map_err(|e| anyhow!("Failed to read {} from tiered storage: {}", key, e))?,
+            None => match SnapshotValue::from_value(&entry.value) {
+                Some(value) => value,
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(SnapshotEntry {
+            key: key.to_string(),
+            expires_at: entry.expires_at_unix_ms(),
+            value,
+        }))
+    }
+
+    // Start a snapshot of the live keys that `snapshot_batch` then hands out a few
+    // at a time, until `end_snapshot`
+    pub fn begin_snapshot(&mut self) -> SnapshotStart {
+        let mut cow = CowSnapshot::default();
+        let mut expires = 0;
+        for (key, entry) in self.entries() {
+            cow.order.push(key.clone());
+            expires += entry.expires_at.is_some() as usize;
+        }
+        cow.order.sort_unstable_by_key(key_addr);
+        let start = SnapshotStart {
+            id: self.next_cow_id,
+            keys: cow.order.len(),
+            expires,
+        };
+        self.next_cow_id += 1;
+        self.cows.insert(start.id, cow);
+        start
+    }
+
+    // Up to `count` more keys of snapshot `id`, None once all were visited
+    pub fn snapshot_batch(&mut self, id: u64, count: usize) -> Result<Option<Vec<SnapshotEntry>>> {
+        let Some(cow) = self.cows.get_mut(&id).filter(|cow| !cow.order.is_empty()) else {
+            return Ok(None);
+        };
+        let keys = cow.order.split_off(cow.order.len().saturating_sub(count));
+
+        let mut batch = Vec::with_capacity(keys.len());
+        for key in keys {
+            let Some(cow) = self.cows.get_mut(&id) else {
+                return Ok(None);
+            };
+            let image = match cow.shadow.remove(&key) {
+                Some(image) => image.map_err(|e| anyhow!(e))?,
+                None => match self.data.get(&key) {
+                    Some(entry) => self.snapshot_entry(&key, entry)?,
+                    None => None,
+                },
+            };
+            batch.extend(image);
+        }
+        Ok(Some(batch))
+    }
+
+    pub fn end_snapshot(&mut self, id: u64) {
+        self.cows.remove(&id);
+    }
+
+    // Set aside the snapshot image of `key` before it is modified, for every
+    // snapshot that didn't visit it yet. The disk tier measures it again later.
+    fn preserve(&mut self, key: &str) {
+        self.tier.touch(key, self.data.get(key));
+        // Keys missing from the keyspace were preserved when they went away
+        let Some((shared, entry)) = self.data.get_key_value(key) else {
+            return;
+        };
+        if !self.cows.values().any(|cow| cow.is_pending(shared)) {
+            return;
+        }
+        let shared = shared.clone();
+        let image = self.snapshot_entry(key, entry).map_err(|e| e.to_string());
+        for cow in self.cows.values_mut() {
+            if cow.is_pending(&shared) {
+                cow.shadow.insert(shared.clone(), image.clone());
+            }
+        }
+    }
+
+    fn preserve_all(&mut self) {
+        let keys: Vec<Arc<str>> = self
+            .cows
+            .values()
+            .flat_map(|cow| cow.order.iter().cloned())
+            .collect();
+        for key in keys {
+            self.preserve(&key);
+        }
+    }
+
+    // A spilled value read back from the disk tier without paging it in
+    pub fn spilled_value(&self, spill: SpillRef) -> Result<SnapshotValue> {
+        self.tier.read(spill)
+    }
+
+    // Move cold values to the disk tier while the dataset exceeds its memory limit
+    pub fn spill_cold(&mut self) -> Result<usize> {
+        if !self.tier.enabled() {
+            return Ok(0);
+        }
+        self.tier.spill_cold(&mut self.data)
+    }
+
+    pub fn tier_stats(&self) -> TierStats {
+        self.tier.stats(&self.data)
+    }
+
+    pub fn encoding_limits(&self) -> &EncodingLimits {
+        &self.limits
+    }
+
+    // Insert an entry recreated by RESTORE, replacing whatever is at key
+    pub fn restore(&mut self, key: String, entry: Entry) {
+        self.preserve(&key);
+        let has_expire = entry.expires_at.is_some();
+        let had_expire = match self.data.insert(key.as_str().into(), entry) {
+            Some(old) => {
+                let had_expire = old.expires_at.is_some();
+                self.free_value(old.value, self.lazyfree.options.lazy_server_del);
+                had_expire
+            }
+            None => {
+                self.notifier.notify(NOTIFY_NEW, "new", &key);
+                false
+            }
+        };
+        if has_expire {
+            self.track_expiry(&key, had_expire);
+        }
+        self.dirty += 1;
+        self.notifier.notify(NOTIFY_GENERIC, "restore", &key);
+    }
+
+    // Insert an entry read back from disk, without keyspace events
+    pub fn load_entry(&mut self, key: String, entry: Entry) {
+        self.preserve(&key);
+        let has_expire = entry.expires_at.is_some();
+        let old = self.data.insert(key.as_str().into(), entry);
+        if has_expire {
+            self.track_expiry(&key, old.is_some_and(|old| old.expires_at.is_some()));
+        }
+    }
+
     // Check if key exists (and is not expired)
     pub fn exists(&mut self, keys: Vec<String>) -> usize {
         let mut count = 0;
         for key in keys {
-            if let Some(entry) = self.data.get_mut(&key) {
+            if let Some(entry) = self.data.get_mut(key.as_str()) {
                 if !entry.is_expired() {
                     count += 1;
                 } else {
                     // Key is expired - remove it
-                    self.data.remove(&key);
+                    self.expire_key(&key);
                 }
             }
         }
@@ -972,27 +1643,32 @@ impl CacheStore {
     }
 
     // Get key type
-    pub fn key_type(&mut self, key: &str) -> Option<&'static str> {
-        self.get(key).map(|value| match value {
+    pub fn key_type(&mut self, key: &str) -> Result<Option<&'static str>> {
+        Ok(self.get(key)?.map(|value| match value {
             Value::String(_) => "string",
             Value::List(_) => "list",
             Value::Set(_) => "set",
             Value::SortedSet(_) => "zset",
             Value::Hash(_) => "hash",
             Value::Nil => "nil",
-        })
+        }))
     }
 
     // Set expiration for existing key
     pub fn expire(&mut self, key: &str, ttl: Duration) -> bool {
+        self.preserve(key);
         match self.data.get_mut(key) {
             Some(entry) if !entry.is_expired() => {
+                let had_expire = entry.expires_at.is_some();
                 entry.set_expiration(ttl);
+                self.track_expiry(key, had_expire);
+                self.dirty += 1;
+                self.notifier.notify(NOTIFY_GENERIC, "expire", key);
                 true
             }
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 false
             }
             None => false,
@@ -1001,14 +1677,17 @@ impl CacheStore {
 
     // Remove expiration from key
     pub fn persist(&mut self, key: &str) -> bool {
+        self.preserve(key);
         match self.data.get_mut(key) {
             Some(entry) if !entry.is_expired() => {
                 entry.remove_expiration();
+                self.dirty += 1;
+                self.notifier.notify(NOTIFY_GENERIC, "persist", key);
                 true
             }
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 false
             }
             None => false,
@@ -1029,7 +1708,7 @@ impl CacheStore {
             }
             Some(_) => {
                 // Key exists but is expired - remove it
-                self.data.remove(key);
+                self.expire_key(key);
                 (Duration::ZERO, -1)
             }
             None => (Duration::ZERO, -2), // Key does not exist
@@ -1049,7 +1728,7 @@ impl CacheStore {
             .iter()
             .filter_map(|(key, entry)| {
                 if !entry.is_expired() && regex.is_match(key) {
-                    Some(key.clone())
+                    Some(key.to_string())
                 } else {
                     None
                 }
@@ -1057,7 +1736,99 @@ impl CacheStore {
             .collect()
     }
 
-    pub fn type_of(&mut self, key: &str) -> Option<&'static str> {
+    pub fn type_of(&mut self, key: &str) -> Result<Option<&'static str>> {
         self.key_type(key)
     }
+
+    // ------- Introspection Methods -------
+    // Look up a live entry without counting it as an access
+    fn peek(&mut self, key: &str) -> Result<Option<(&Arc<str>, &Entry)>> {
+        if self.data.get(key).is_some_and(|entry| entry.is_expired()) {
+            self.expire_key(key);
+        }
+        if self
+            .tier
+            .resident(&mut self.data, &self.limits, key)?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        Ok(self.data.get_key_value(key))
+    }
+
+    pub fn object_encoding(&mut self, key: &str) -> Result<Option<&'static str>> {
+        Ok(self
+            .peek(key)?
+            .map(|(_, entry)| entry.value.encoding_name()))
+    }
+
+    // Values are owned by exactly one entry, except pooled integers which report
+    // the same refcount Redis gives its shared objects
+    pub fn object_refcount(&mut self, key: &str) -> Result<Option<i64>> {
+        Ok(self.peek(key)?.map(|(_, entry)| match &entry.value {
+            Value::String(s) if s.is_shared() => i32::MAX as i64,
+            _ => 1,
+        }))
+    }
+
+    pub fn object_idletime(&mut self, key: &str) -> Result<Option<Duration>> {
+        Ok(self.peek(key)?.map(|(_, entry)| entry.idle_time()))
+    }
+
+    // Bytes used by a key: its slot in the keyspace table, the key string and the value
+    pub fn memory_usage(&mut self, key: &str, samples: usize) -> Result<Option<usize>> {
+        Ok(self.peek(key)?.map(|(k, entry)| {
+            size_of::<(Arc<str>, Entry)>()
+                + 1
+                + key_size(k)
+                + entry.value.memory_usage_with_samples(samples)
+        }))
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            keys_count: self.data.len(),
+            overhead_hashtable_main: hash_table_size(
+                self.data.capacity(),
+                size_of::<(Arc<str>, Entry)>(),
+            ),
+            dataset_bytes: self
+                .data
+                .iter()
+                .map(|(k, entry)| key_size(k) + entry.value.memory_usage())
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_expire_cycle_samples_volatile_keys() {
+        let mut store = CacheStore::new(4);
+        let value = || Value::String(StringValue::new("v"));
+        for i in 0..1000 {
+            store.set_with_expiration(format!("gone:{}", i), value(), Duration::ZERO);
+        }
+        for i in 0..10 {
+            store.set_with_expiration(format!("live:{}", i), value(), Duration::from_secs(60));
+        }
+        store.load_entry("plain".into(), Entry::new(value()));
+        // A persisted key is dropped from the sample list, not expired
+        store.persist("live:0");
+        std::thread::sleep(Duration::from_millis(2));
+
+        // Rounds continue while many samples had expired, the last few keys are
+        // left to later cycles
+        let mut expired = store.cleanup_expired(Duration::from_secs(10));
+        assert!(expired > 900);
+        for _ in 0..1000 {
+            expired += store.cleanup_expired(Duration::from_secs(10));
+        }
+        assert_eq!(expired, 1000);
+        assert_eq!(store.entries().count(), 11);
+        assert_eq!(store.volatile.len(), 9);
+    }
 }
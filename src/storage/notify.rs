@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+
+use crate::server::pubsub::PubSub;
+
+// Event classes selected by `notify-keyspace-events`
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; // K, __keyspace@<db>__ channels
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; // E, __keyevent@<db>__ channels
+pub const NOTIFY_GENERIC: u32 = 1 << 2; // g, DEL, EXPIRE, ...
+pub const NOTIFY_STRING: u32 = 1 << 3; // $
+pub const NOTIFY_LIST: u32 = 1 << 4; // l
+pub const NOTIFY_SET: u32 = 1 << 5; // s
+pub const NOTIFY_HASH: u32 = 1 << 6; // h
+pub const NOTIFY_ZSET: u32 = 1 << 7; // z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; // x
+pub const NOTIFY_EVICTED: u32 = 1 << 9; // e
+pub const NOTIFY_STREAM: u32 = 1 << 10; // t
+pub const NOTIFY_MODULE: u32 = 1 << 11; // d
+pub const NOTIFY_NEW: u32 = 1 << 12; // n
+// A, every class except new key events
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM
+    | NOTIFY_MODULE;
+
+// Parse a `notify-keyspace-events` value such as "KEA" or "Ex"
+pub fn parse_flags(value: &str) -> Result<u32> {
+    value.chars().try_fold(0, |flags, c| {
+        let class = match c {
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            'g' => NOTIFY_GENERIC,
+            '$' => NOTIFY_STRING,
+            'l' => NOTIFY_LIST,
+            's' => NOTIFY_SET,
+            'h' => NOTIFY_HASH,
+            'z' => NOTIFY_ZSET,
+            'x' => NOTIFY_EXPIRED,
+            'e' => NOTIFY_EVICTED,
+            't' => NOTIFY_STREAM,
+            'd' => NOTIFY_MODULE,
+            'n' => NOTIFY_NEW,
+            'A' => NOTIFY_ALL,
+            _ => return Err(anyhow!("Invalid event class character: {}", c)),
+        };
+        Ok(flags | class)
+    })
+}
+
+// Publishes keyspace events for the classes enabled in `flags`
+#[derive(Debug, Clone, Default)]
+pub struct KeyspaceNotifier {
+    flags: u32,
+    pubsub: Option<Arc<PubSub>>,
+}
+
+impl KeyspaceNotifier {
+    pub fn new(flags: u32, pubsub: Arc<PubSub>) -> Self {
+        Self {
+            flags,
+            pubsub: Some(pubsub),
+        }
+    }
+
+    pub fn notify(&self, class: u32, event: &str, key: &str) {
+        let Some(pubsub) = &self.pubsub else {
+            return;
+        };
+        if self.flags & class == 0 || pubsub.is_empty() {
+            return;
+        }
+
+        if self.flags & NOTIFY_KEYSPACE != 0 {
+            pubsub.publish(
+                &format!("__keyspace@0__:{}", key),
+                Bytes::copy_from_slice(event.as_bytes()),
+            );
+        }
+        if self.flags & NOTIFY_KEYEVENT != 0 {
+            pubsub.publish(
+                &format!("__keyevent@0__:{}", event),
+                Bytes::copy_from_slice(key.as_bytes()),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(parse_flags("").unwrap(), 0);
+        assert_eq!(parse_flags("Ex").unwrap(), NOTIFY_KEYEVENT | NOTIFY_EXPIRED);
+        assert_eq!(parse_flags("KA").unwrap() & NOTIFY_NEW, 0);
+        assert_eq!(
+            parse_flags("KEA").unwrap(),
+            NOTIFY_KEYSPACE | NOTIFY_KEYEVENT | NOTIFY_ALL
+        );
+        assert!(parse_flags("Q").is_err());
+    }
+}
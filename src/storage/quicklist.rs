@@ -0,0 +1,231 @@
+// A quicklist: a deque of listpack nodes. Pushes and pops only touch the head or tail
+// node, and every node is capped by the fill factor, so both are O(1) in the length
+// of the list. Index lookups walk node counts from the nearer end.
+//
+// The fill factor follows `list-max-listpack-size`: a positive value caps the number
+// of entries per node, a negative one caps the node size at 4KB (-1) up to 64KB (-5).
+
+use std::collections::VecDeque;
+
+use super::{
+    listpack::{Listpack, ListpackEntry, encoded_entry_size},
+    value::malloc_size,
+};
+
+// Byte cap for nodes when the fill factor counts entries, so a node holding a few
+// huge elements still stays small
+const SIZE_SAFETY_LIMIT: usize = 8192;
+
+const NODE_SIZE_LIMITS: [usize; 5] = [4096, 8192, 16384, 32768, 65536];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quicklist {
+    nodes: VecDeque<Listpack>,
+    count: usize,
+    fill: i64,
+}
+
+impl Quicklist {
+    pub fn new(fill: i64) -> Self {
+        Self {
+            nodes: VecDeque::new(),
+            count: 0,
+            fill,
+        }
+    }
+
+    // Start a quicklist whose only node is an existing listpack
+    pub fn from_listpack(lp: Listpack, fill: i64) -> Self {
+        let mut ql = Self::new(fill);
+        if !lp.is_empty() {
+            ql.count = lp.len();
+            ql.nodes.push_back(lp);
+        }
+        ql
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Listpack> {
+        self.nodes.iter()
+    }
+
+    pub fn push_front(&mut self, value: &[u8]) {
+        match self.nodes.front_mut() {
+            Some(node) if fits_in_node(node, value, self.fill) => node.push_front(value),
+            _ => {
+                let mut node = Listpack::new();
+                node.push_front(value);
+                self.nodes.push_front(node);
+            }
+        }
+        self.count += 1;
+    }
+
+    pub fn push_back(&mut self, value: &[u8]) {
+        match self.nodes.back_mut() {
+            Some(node) if fits_in_node(node, value, self.fill) => node.push_back(value),
+            _ => {
+                let mut node = Listpack::new();
+                node.push_back(value);
+                self.nodes.push_back(node);
+            }
+        }
+        self.count += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        let node = self.nodes.front_mut()?;
+        let value = node.pop_front()?;
+        if node.is_empty() {
+            self.nodes.pop_front();
+        }
+        self.count -= 1;
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        let node = self.nodes.back_mut()?;
+        let value = node.pop_back()?;
+        if node.is_empty() {
+            self.nodes.pop_back();
+        }
+        self.count -= 1;
+        Some(value)
+    }
+
+    pub fn get(&self, index: usize) -> Option<ListpackEntry<'_>> {
+        let (node, offset) = self.locate(index)?;
+        let lp = &self.nodes[node];
+        lp.seek(offset as i64).map(|off| lp.get(off))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ListpackEntry<'_>> {
+        self.nodes.iter().flat_map(|node| node.iter())
+    }
+
+    // Entries from `index` onwards, skipping whole nodes to get there
+    pub fn iter_from(&self, index: usize) -> impl Iterator<Item = ListpackEntry<'_>> {
+        let (node, offset) = self.locate(index).unwrap_or((self.nodes.len(), 0));
+        self.nodes
+            .range(node..)
+            .flat_map(|node| node.iter())
+            .skip(offset)
+    }
+
+    // Bytes allocated for the node buffers and the deque holding them
+    pub fn allocated(&self) -> usize {
+        malloc_size(self.nodes.capacity() * size_of::<Listpack>())
+            + self
+                .nodes
+                .iter()
+                .map(|node| malloc_size(node.capacity()))
+                .sum::<usize>()
+    }
+
+    // Node holding `index` and the position inside it, searching from whichever end
+    // of the list is closer
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        if index >= self.count {
+            return None;
+        }
+
+        if index < self.count / 2 {
+            let mut remaining = index;
+            for (i, node) in self.nodes.iter().enumerate() {
+                let len = node.len();
+                if remaining < len {
+                    return Some((i, remaining));
+                }
+                remaining -= len;
+            }
+        } else {
+            let mut remaining = self.count - 1 - index;
+            for (i, node) in self.nodes.iter().enumerate().rev() {
+                let len = node.len();
+                if remaining < len {
+                    return Some((i, len - 1 - remaining));
+                }
+                remaining -= len;
+            }
+        }
+        None
+    }
+}
+
+// Whether a listpack holding everything would still fit in one node
+pub fn fits_in_node(lp: &Listpack, value: &[u8], fill: i64) -> bool {
+    lp.is_empty()
+        || within_fill(
+            lp.len() + 1,
+            lp.bytes_len() + encoded_entry_size(value),
+            fill,
+        )
+}
+
+fn within_fill(entries: usize, bytes: usize, fill: i64) -> bool {
+    if fill >= 0 {
+        entries <= (fill as usize).max(1) && bytes <= SIZE_SAFETY_LIMIT
+    } else {
+        let class = ((-fill) as usize).min(NODE_SIZE_LIMITS.len()) - 1;
+        bytes <= NODE_SIZE_LIMITS[class]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_pop_both_ends() {
+        let mut ql = Quicklist::new(3);
+        for i in 0..10 {
+            ql.push_back(format!("r{}", i).as_bytes());
+            ql.push_front(format!("l{}", i).as_bytes());
+        }
+        assert_eq!(ql.len(), 20);
+        assert!(ql.nodes().all(|node| node.len() <= 3));
+
+        assert_eq!(ql.get(0).unwrap().to_vec(), b"l9");
+        assert_eq!(ql.get(19).unwrap().to_vec(), b"r9");
+        assert_eq!(ql.get(10).unwrap().to_vec(), b"r0");
+        assert!(ql.get(20).is_none());
+
+        let tail: Vec<Vec<u8>> = ql.iter_from(17).map(|e| e.to_vec()).collect();
+        assert_eq!(tail, vec![b"r7".to_vec(), b"r8".to_vec(), b"r9".to_vec()]);
+
+        for i in (0..10).rev() {
+            assert_eq!(ql.pop_front(), Some(format!("l{}", i).into_bytes()));
+        }
+        for i in (0..10).rev() {
+            assert_eq!(ql.pop_back(), Some(format!("r{}", i).into_bytes()));
+        }
+        assert_eq!(ql.pop_back(), None);
+        assert_eq!(ql.node_count(), 0);
+    }
+
+    #[test]
+    fn test_nodes_capped_by_size() {
+        let mut ql = Quicklist::new(-1);
+        let value = vec![b'x'; 1000];
+        for _ in 0..10 {
+            ql.push_back(&value);
+        }
+        assert!(ql.nodes().all(|node| node.bytes_len() <= 4096));
+        assert!(ql.node_count() >= 3);
+
+        // Oversized elements still get a node of their own
+        ql.push_back(&vec![b'y'; 10000]);
+        assert_eq!(ql.len(), 11);
+    }
+}
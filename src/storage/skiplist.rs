@@ -0,0 +1,372 @@
+// An indexable skiplist ordered by (score, member), the structure behind large sorted
+// sets. Every forward link records its span, the number of level 0 steps it jumps
+// over, so the rank of an element is the sum of spans on the way to it and looking up
+// the element at a rank is O(log n) just like a search by score.
+//
+// Nodes live in an arena and refer to each other by index. Slot 0 is the header,
+// which holds no element and has a link on every level.
+
+use std::{
+    cmp::Ordering,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use super::value::malloc_size;
+
+const MAX_LEVEL: usize = 32;
+
+const HEADER: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    forward: Option<usize>,
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Vec<u8>,
+    score: f64,
+    levels: Vec<Link>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    rng: u64,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Two lists are equal when they hold the same elements, whatever their shape
+impl PartialEq for SkipList {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        let header = Node {
+            member: Vec::new(),
+            score: 0.0,
+            levels: vec![
+                Link {
+                    forward: None,
+                    span: 0,
+                };
+                MAX_LEVEL
+            ],
+        };
+        Self {
+            nodes: vec![header],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            rng: RandomState::new().build_hasher().finish() | 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Insert an element. The caller makes sure the member is not already present and
+    // the score is not NaN.
+    pub fn insert(&mut self, score: f64, member: Vec<u8>) {
+        debug_assert!(!score.is_nan());
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut rank = [0usize; MAX_LEVEL];
+
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].levels[i].forward
+                && self.cmp_node(next, score, &member) == Ordering::Less
+            {
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEADER;
+                self.nodes[HEADER].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let new = self.alloc(Node {
+            member,
+            score,
+            levels: vec![
+                Link {
+                    forward: None,
+                    span: 0,
+                };
+                level
+            ],
+        });
+        for i in 0..level {
+            let prev = update[i];
+            let prev_link = self.nodes[prev].levels[i];
+            self.nodes[new].levels[i] = Link {
+                forward: prev_link.forward,
+                span: prev_link.span - (rank[0] - rank[i]),
+            };
+            self.nodes[prev].levels[i] = Link {
+                forward: Some(new),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[*prev].levels[i].span += 1;
+        }
+        self.len += 1;
+    }
+
+    // Remove the element with this score and member, returning whether it was found
+    pub fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward
+                && self.cmp_node(next, score, member) == Ordering::Less
+            {
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        match self.nodes[x].levels[0].forward {
+            Some(target) if self.cmp_node(target, score, member) == Ordering::Equal => {
+                self.unlink(target, &update);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // 0-based rank of the element with this score and member
+    pub fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward
+                && self.cmp_node(next, score, member) != Ordering::Greater
+            {
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if x != HEADER && self.cmp_node(x, score, member) == Ordering::Equal {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    pub fn get_by_rank(&self, rank: usize) -> Option<(&[u8], f64)> {
+        self.node_by_rank(rank).map(|x| self.element(x))
+    }
+
+    pub fn iter(&self) -> SkipListIter<'_> {
+        SkipListIter {
+            list: self,
+            next: self.nodes[HEADER].levels[0].forward,
+        }
+    }
+
+    // Elements from `rank` onwards, in order
+    pub fn iter_from(&self, rank: usize) -> SkipListIter<'_> {
+        SkipListIter {
+            list: self,
+            next: self.node_by_rank(rank),
+        }
+    }
+
+    // Bytes allocated for the arena and every node's link array, not counting members
+    pub fn allocated(&self) -> usize {
+        malloc_size(self.nodes.capacity() * size_of::<Node>())
+            + self
+                .nodes
+                .iter()
+                .map(|node| malloc_size(node.levels.capacity() * size_of::<Link>()))
+                .sum::<usize>()
+    }
+
+    fn node_by_rank(&self, rank: usize) -> Option<usize> {
+        if rank >= self.len {
+            return None;
+        }
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward
+                && traversed + self.nodes[x].levels[i].span <= target
+            {
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    fn element(&self, x: usize) -> (&[u8], f64) {
+        (&self.nodes[x].member, self.nodes[x].score)
+    }
+
+    // Order of node `x` relative to (score, member): by score, then member bytes
+    fn cmp_node(&self, x: usize, score: f64, member: &[u8]) -> Ordering {
+        let node = &self.nodes[x];
+        node.score
+            .partial_cmp(&score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| node.member.as_slice().cmp(member))
+    }
+
+    fn unlink(&mut self, x: usize, update: &[usize; MAX_LEVEL]) {
+        for (i, prev) in update.iter().enumerate().take(self.level) {
+            let prev_link = self.nodes[*prev].levels[i];
+            if prev_link.forward == Some(x) {
+                let link = self.nodes[x].levels[i];
+                self.nodes[*prev].levels[i] = Link {
+                    forward: link.forward,
+                    span: prev_link.span + link.span - 1,
+                };
+            } else {
+                self.nodes[*prev].levels[i].span -= 1;
+            }
+        }
+
+        while self.level > 1 && self.nodes[HEADER].levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+        self.len -= 1;
+
+        // Drop the member now, the slot is reused by the next insert
+        let node = &mut self.nodes[x];
+        node.member = Vec::new();
+        node.levels = Vec::new();
+        self.free.push(x);
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(x) => {
+                self.nodes[x] = node;
+                x
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    // Each extra level is kept with probability 1/4
+    fn random_level(&mut self) -> usize {
+        let mut level = 1;
+        while level < MAX_LEVEL && self.next_random() & 0xFFFF < 0xFFFF / 4 {
+            level += 1;
+        }
+        level
+    }
+
+    // xorshift64
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+pub struct SkipListIter<'a> {
+    list: &'a SkipList,
+    next: Option<usize>,
+}
+
+impl<'a> Iterator for SkipListIter<'a> {
+    type Item = (&'a [u8], f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let x = self.next?;
+        self.next = self.list.nodes[x].levels[0].forward;
+        Some(self.list.element(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_and_rank() {
+        let mut zsl = SkipList::new();
+        for i in 0..1000 {
+            // Equal scores are ordered by member
+            zsl.insert((i / 10) as f64, format!("m{:04}", i).into_bytes());
+        }
+        assert_eq!(zsl.len(), 1000);
+
+        let members: Vec<Vec<u8>> = zsl.iter().map(|(m, _)| m.to_vec()).collect();
+        let mut sorted = members.clone();
+        sorted.sort();
+        assert_eq!(members, sorted);
+
+        for i in (0..1000).step_by(37) {
+            let member = format!("m{:04}", i).into_bytes();
+            assert_eq!(zsl.rank((i / 10) as f64, &member), Some(i));
+            assert_eq!(
+                zsl.get_by_rank(i),
+                Some((member.as_slice(), (i / 10) as f64))
+            );
+        }
+        assert_eq!(zsl.rank(5.0, b"missing"), None);
+        assert_eq!(zsl.get_by_rank(1000), None);
+    }
+
+    #[test]
+    fn test_remove_keeps_spans() {
+        let mut zsl = SkipList::new();
+        for i in 0..200 {
+            zsl.insert(i as f64, i.to_string().into_bytes());
+        }
+        for i in (0..200).step_by(2) {
+            assert!(zsl.remove(i as f64, i.to_string().as_bytes()));
+        }
+        assert!(!zsl.remove(0.0, b"0"));
+        assert_eq!(zsl.len(), 100);
+
+        for (rank, i) in (1..200).step_by(2).enumerate() {
+            assert_eq!(zsl.rank(i as f64, i.to_string().as_bytes()), Some(rank));
+        }
+        let tail: Vec<f64> = zsl.iter_from(98).map(|(_, s)| s).collect();
+        assert_eq!(tail, vec![197.0, 199.0]);
+
+        // Freed slots are reused
+        zsl.insert(1000.0, b"x".to_vec());
+        assert_eq!(zsl.nodes.len(), 201);
+        assert_eq!(zsl.get_by_rank(100), Some((b"x".as_slice(), 1000.0)));
+    }
+}
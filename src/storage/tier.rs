@@ -0,0 +1,418 @@
+// A tier below the keyspace for values too cold to keep in memory. Once the
+// dataset outgrows `memory_limit`, the values of the least recently used keys are
+// appended to a log file and their entries keep only a `SpillRef` stub. Looking a
+// key up pages its value back in. The log is scratch space: it is truncated when
+// first used and compacted once most of it is stale, snapshots and the AOF still
+// hold the whole dataset.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::{Result, anyhow};
+use tracing::error;
+
+use crate::{
+    persistence::{rdb, snapshot::SnapshotValue},
+    storage::{EncodingLimits, Keyspace, Value, entry::Entry, value::malloc_size},
+};
+
+// The log isn't compacted before it reaches this size
+const COMPACT_MIN_SIZE: u64 = 1024 * 1024;
+
+// Nested elements measured per aggregate when sizing entries, as MEMORY USAGE does
+const SIZE_SAMPLES: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct TierOptions {
+    // Dataset bytes kept in memory before cold values are spilled, 0 disables the tier
+    pub memory_limit: u64,
+    // Smaller values always stay in memory, spilling them would save next to nothing
+    pub min_value_size: usize,
+    // Log file in `dir`
+    pub filename: String,
+}
+
+impl Default for TierOptions {
+    fn default() -> Self {
+        Self {
+            memory_limit: 0,
+            min_value_size: 64,
+            filename: "tier.log".to_string(),
+        }
+    }
+}
+
+// Where a spilled value lives in the log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpillRef {
+    offset: u64,
+    len: u32,
+}
+
+// Reported by INFO tiered. Hits and misses count key lookups.
+#[derive(Debug, Clone, Default)]
+pub struct TierStats {
+    pub memory_limit: u64,
+    pub memory_hits: u64,
+    pub disk_hits: u64,
+    pub misses: u64,
+    pub spilled: u64,
+    pub keys_on_disk: usize,
+    pub log_bytes: u64,
+    pub live_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiskTier {
+    pub options: TierOptions,
+    path: PathBuf,
+    // Opened and truncated on the first spill
+    file: Option<Arc<File>>,
+    end: u64,
+    // Bytes of keys and values in memory and of values in the log still referenced,
+    // as of the last `spill_cold`
+    resident: usize,
+    live: u64,
+    // Keys changed since the last `spill_cold`, with what they added to `resident`
+    // and `live` before the change
+    touched: HashMap<String, (usize, u64)>,
+    memory_hits: u64,
+    disk_hits: u64,
+    misses: u64,
+    spilled: u64,
+}
+
+impl DiskTier {
+    pub fn new(options: TierOptions, path: PathBuf) -> Self {
+        Self {
+            options,
+            path,
+            ..Default::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.options.memory_limit > 0
+    }
+
+    // The entry at `key` with its value back in memory, counting the lookup. A
+    // value that can't be read back fails the lookup and stays spilled.
+    pub fn resident<'a>(
+        &mut self,
+        data: &'a mut Keyspace,
+        limits: &EncodingLimits,
+        key: &str,
+    ) -> Result<Option<&'a mut Entry>> {
+        let spilled = match data.get(key) {
+            Some(entry) => entry.spilled,
+            None => {
+                self.misses += 1;
+                return Ok(None);
+            }
+        };
+        let Some(spill) = spilled else {
+            self.memory_hits += 1;
+            return Ok(data.get_mut(key));
+        };
+        self.touch(key, data.get(key));
+
+        let value = self
+            .read(spill)
+            .and_then(|value| value.into_value(limits))
+            .map_err(|e| {
+                error!("Failed to page in {} from tiered storage: {}", key, e);
+                anyhow!("Failed to read {} from tiered storage: {}", key, e)
+            })?;
+        self.disk_hits += 1;
+        let Some(entry) = data.get_mut(key) else {
+            return Ok(None);
+        };
+        entry.value = value;
+        entry.spilled = None;
+        Ok(Some(entry))
+    }
+
+    pub fn read(&self, spill: SpillRef) -> Result<SnapshotValue> {
+        rdb::restore_payload(&self.read_raw(spill)?)
+    }
+
+    fn read_raw(&self, spill: SpillRef) -> Result<Vec<u8>> {
+        let file = self
+            .file
+            .as_ref()
+            .ok_or_else(|| anyhow!("Tiered storage log isn't open"))?;
+        let mut payload = vec![0; spill.len as usize];
+        file.read_exact_at(&mut payload, spill.offset)?;
+        Ok(payload)
+    }
+
+    fn write(&mut self, value: &Value) -> Result<Option<SpillRef>> {
+        let Some(value) = SnapshotValue::from_value(value) else {
+            return Ok(None);
+        };
+        if self.file.is_none() {
+            self.file = Some(Arc::new(open_log(&self.path)?));
+            self.end = 0;
+        }
+        let payload = rdb::dump_payload(&value);
+        let offset = self.end;
+        self.file.as_ref().unwrap().write_all_at(&payload, offset)?;
+        self.end += payload.len() as u64;
+        self.spilled += 1;
+        Ok(Some(SpillRef {
+            offset,
+            len: payload.len() as u32,
+        }))
+    }
+
+    // Note that the entry at `key` is about to change, before it does. Its size is
+    // measured again on the next `spill_cold`.
+    pub fn touch(&mut self, key: &str, entry: Option<&Entry>) {
+        if !self.enabled() || self.touched.contains_key(key) {
+            return;
+        }
+        let footprint = entry.map_or((0, 0), |entry| {
+            (
+                entry.size,
+                entry.spilled.map_or(0, |spill| spill.len as u64),
+            )
+        });
+        self.touched.insert(key.to_string(), footprint);
+    }
+
+    // Forget every entry, once the keyspace was emptied
+    pub fn clear(&mut self) {
+        self.resident = 0;
+        self.live = 0;
+        self.touched.clear();
+    }
+
+    // Measure the entries touched since the last call
+    fn update_sizes(&mut self, data: &mut Keyspace) {
+        for (key, (resident, live)) in self.touched.drain() {
+            self.resident = self.resident.saturating_sub(resident);
+            self.live = self.live.saturating_sub(live);
+            if let Some(entry) = data.get_mut(key.as_str()) {
+                entry.size = entry_size(&key, entry);
+                self.resident += entry.size;
+                self.live += entry.spilled.map_or(0, |spill| spill.len as u64);
+            }
+        }
+    }
+
+    // Spill the values of the least recently used keys until the dataset in memory
+    // fits `memory_limit`, then compact the log if most of it is stale. Returns the
+    // number of values spilled. Only the keys touched since the last call are
+    // measured, the keyspace is scanned only while it is over the limit.
+    pub fn spill_cold(&mut self, data: &mut Keyspace) -> Result<usize> {
+        self.update_sizes(data);
+
+        let mut spilled = 0;
+        if self.resident as u64 > self.options.memory_limit {
+            let mut candidates: Vec<_> = data
+                .iter()
+                .filter(|(_, entry)| {
+                    entry.spilled.is_none()
+                        && !entry.is_expired()
+                        && entry.size >= self.options.min_value_size
+                })
+                .map(|(key, entry)| (entry.idle_time(), key.clone()))
+                .collect();
+            candidates.sort_unstable_by_key(|(idle, _)| std::cmp::Reverse(*idle));
+            for (_, key) in candidates {
+                if self.resident as u64 <= self.options.memory_limit {
+                    break;
+                }
+                let Some(entry) = data.get_mut(&key) else {
+                    continue;
+                };
+                if let Some(spill) = self.write(&entry.value)? {
+                    entry.value = Value::Nil;
+                    entry.spilled = Some(spill);
+                    self.resident -= entry.size;
+                    entry.size = entry_size(&key, entry);
+                    self.resident += entry.size;
+                    self.live += spill.len as u64;
+                    spilled += 1;
+                }
+            }
+        }
+
+        if self.end >= COMPACT_MIN_SIZE && self.live * 2 < self.end {
+            self.compact(data)?;
+        }
+        Ok(spilled)
+    }
+
+    // Copy the records still referenced to a new log and point the stubs at it
+    fn compact(&mut self, data: &mut Keyspace) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let out = open_log(&tmp_path)?;
+        let mut offsets = Vec::new();
+        let mut end = 0;
+        for spill in data.values().filter_map(|entry| entry.spilled) {
+            out.write_all_at(&self.read_raw(spill)?, end)?;
+            offsets.push(end);
+            end += spill.len as u64;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        // The map wasn't modified in between, so it iterates in the same order
+        let stubs = data.values_mut().filter_map(|entry| entry.spilled.as_mut());
+        for (spill, offset) in stubs.zip(offsets) {
+            spill.offset = offset;
+        }
+        self.file = Some(Arc::new(out));
+        self.end = end;
+        Ok(())
+    }
+
+    pub fn stats(&self, data: &Keyspace) -> TierStats {
+        let stubs = data.values().filter_map(|entry| entry.spilled);
+        TierStats {
+            memory_limit: self.options.memory_limit,
+            memory_hits: self.memory_hits,
+            disk_hits: self.disk_hits,
+            misses: self.misses,
+            spilled: self.spilled,
+            keys_on_disk: stubs.clone().count(),
+            log_bytes: self.end,
+            live_bytes: stubs.map(|spill| spill.len as u64).sum(),
+        }
+    }
+}
+
+// Bytes of the key and the value in memory, estimated from a few elements of
+// aggregates
+fn entry_size(key: &str, entry: &Entry) -> usize {
+    malloc_size(key.len()) + entry.value.memory_usage_with_samples(SIZE_SAMPLES)
+}
+
+fn open_log(path: &PathBuf) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StringValue;
+
+    #[test]
+    fn test_spill_and_page_in() {
+        let path = std::env::temp_dir().join(format!("tier-test-{}.log", std::process::id()));
+        let mut tier = DiskTier::new(
+            TierOptions {
+                memory_limit: 1,
+                min_value_size: 0,
+                filename: String::new(),
+            },
+            path.clone(),
+        );
+        let limits = EncodingLimits::default();
+        let mut data = Keyspace::new();
+        let value = Value::String(StringValue::new(vec![b'x'; 100]));
+        tier.touch("cold", None);
+        data.insert("cold".into(), Entry::new(value.clone()));
+
+        assert_eq!(tier.spill_cold(&mut data).unwrap(), 1);
+        assert_eq!(data["cold"].value, Value::Nil);
+        assert_eq!(tier.stats(&data).keys_on_disk, 1);
+
+        let entry = tier.resident(&mut data, &limits, "cold").unwrap().unwrap();
+        assert_eq!(entry.value, value);
+        assert!(
+            tier.resident(&mut data, &limits, "missing")
+                .unwrap()
+                .is_none()
+        );
+        let stats = tier.stats(&data);
+        assert_eq!(
+            (stats.memory_hits, stats.disk_hits, stats.misses),
+            (0, 1, 1)
+        );
+        assert_eq!(stats.keys_on_disk, 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_sizes_follow_touched_keys() {
+        let path = std::env::temp_dir().join(format!("tier-size-{}.log", std::process::id()));
+        let mut tier = DiskTier::new(
+            TierOptions {
+                memory_limit: 1000,
+                min_value_size: 0,
+                filename: String::new(),
+            },
+            path.clone(),
+        );
+        let mut data = Keyspace::new();
+        for key in ["a", "b"] {
+            tier.touch(key, None);
+            let value = Value::String(StringValue::new(vec![b'x'; 300]));
+            data.insert(key.into(), Entry::new(value));
+        }
+        assert_eq!(tier.spill_cold(&mut data).unwrap(), 0);
+        let sizes = data["a"].size + data["b"].size;
+        assert_eq!(tier.resident, sizes);
+
+        // Untouched changes go unnoticed until the key is touched
+        let big = || Value::String(StringValue::new(vec![b'x'; 800]));
+        data.get_mut("a").unwrap().value = big();
+        assert_eq!(tier.spill_cold(&mut data).unwrap(), 0);
+        tier.touch("b", data.get("b"));
+        data.get_mut("b").unwrap().value = big();
+        assert_eq!(tier.spill_cold(&mut data).unwrap(), 1);
+        assert!(tier.resident <= 1000);
+        assert!(tier.live > 0);
+
+        // Removed keys no longer count
+        for key in ["a", "b"] {
+            tier.touch(key, data.get(key));
+            data.remove(key);
+        }
+        tier.spill_cold(&mut data).unwrap();
+        assert_eq!((tier.resident, tier.live), (0, 0));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_failure_keeps_key() {
+        let path = std::env::temp_dir().join(format!("tier-fail-{}.log", std::process::id()));
+        let mut tier = DiskTier::new(
+            TierOptions {
+                memory_limit: 1,
+                min_value_size: 0,
+                filename: String::new(),
+            },
+            path.clone(),
+        );
+        let limits = EncodingLimits::default();
+        let mut data = Keyspace::new();
+        let value = Value::String(StringValue::new(vec![b'x'; 100]));
+        tier.touch("cold", None);
+        data.insert("cold".into(), Entry::new(value));
+        assert_eq!(tier.spill_cold(&mut data).unwrap(), 1);
+
+        // The log lost the value: the read fails and the key stays spilled
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        assert!(tier.resident(&mut data, &limits, "cold").is_err());
+        assert!(data["cold"].spilled.is_some());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,231 +1,1030 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
+
+use bytes::Bytes;
 
 use super::{
-    HashEncoding, HashValue, ListEncoding, ListValue, OrderedFloat, SetEncoding, SetValue,
-    SortedSetEncoding, SortedSetValue, StringEncoding, StringValue, Value,
+    EMBSTR_SIZE_LIMIT, EmbStr, EncodingLimits, HashData, HashEncoding, HashValue, ListData,
+    ListEncoding, ListValue, SHARED_INTEGERS, SetData, SetEncoding, SetValue, SortedSetData,
+    SortedSetEncoding, SortedSetValue, StringData, StringEncoding, StringValue, Value,
+    intset::IntSet,
+    listpack::{Listpack, parse_canonical_int},
+    quicklist::{Quicklist, fits_in_node},
+    skiplist::SkipList,
 };
 
 impl Value {
     // Convert to string representation
     pub fn as_string(&self) -> Option<String> {
         match self {
-            Value::String(s) => String::from_utf8(s.data.clone()).ok(),
+            Value::String(s) => String::from_utf8(s.as_bytes().into_owned()).ok(),
             _ => None,
         }
     }
 
-    // Get memory usage estimate
+    // Get memory usage estimate: heap bytes owned by the value, not counting the
+    // inline `Value` itself (that lives in the keyspace table slot).
     pub fn memory_usage(&self) -> usize {
+        self.memory_usage_with_samples(0)
+    }
+
+    // Same as `memory_usage`, but for aggregates only `samples` nested elements are
+    // measured and the average is extrapolated to the whole value. 0 means all.
+    pub fn memory_usage_with_samples(&self, samples: usize) -> usize {
         match self {
-            Value::String(s) => s.data.len() + std::mem::size_of::<StringValue>(),
-            Value::List(l) => {
-                l.elements.iter().map(|e| e.len()).sum::<usize>() + std::mem::size_of::<ListValue>()
-            }
-            Value::Set(s) => {
-                s.members.iter().map(|m| m.len()).sum::<usize>() + std::mem::size_of::<SetValue>()
-            }
-            Value::SortedSet(zs) => {
-                zs.members.iter().map(|(_, m)| m.len()).sum::<usize>()
-                    + std::mem::size_of::<SortedSetValue>()
-            }
-            Value::Hash(h) => {
-                h.fields
-                    .iter()
-                    .map(|(k, v)| k.len() + v.len())
-                    .sum::<usize>()
-                    + std::mem::size_of::<HashValue>()
-            }
+            Value::String(s) => s.heap_size(),
+            Value::List(l) => match &l.data {
+                ListData::Listpack(lp) => malloc_size(lp.capacity()),
+                ListData::Quicklist(ql) => ql.allocated(),
+            },
+            Value::Set(s) => match &s.data {
+                SetData::IntSet(is) => malloc_size(is.capacity()),
+                SetData::Listpack(lp) => malloc_size(lp.capacity()),
+                SetData::HashTable(members) => {
+                    hash_table_size(members.capacity(), size_of::<Vec<u8>>())
+                        + sampled(
+                            members.iter().map(|m| malloc_size(m.capacity())),
+                            members.len(),
+                            samples,
+                        )
+                }
+            },
+            Value::SortedSet(zs) => match &zs.data {
+                SortedSetData::Listpack(lp) => malloc_size(lp.capacity()),
+                SortedSetData::SkipList { zsl, dict } => {
+                    zsl.allocated()
+                        + hash_table_size(dict.capacity(), size_of::<(Vec<u8>, f64)>())
+                        // every member is stored twice: in the list and in the dict
+                        + sampled(
+                            zsl.iter().map(|(m, _)| 2 * malloc_size(m.len())),
+                            zsl.len(),
+                            samples,
+                        )
+                }
+            },
+            Value::Hash(h) => match &h.data {
+                HashData::Listpack(lp) => malloc_size(lp.capacity()),
+                HashData::HashTable(fields) => {
+                    hash_table_size(fields.capacity(), size_of::<(Vec<u8>, Vec<u8>)>())
+                        + sampled(
+                            fields.iter().map(|(k, v)| {
+                                malloc_size(k.capacity()) + malloc_size(v.capacity())
+                            }),
+                            fields.len(),
+                            samples,
+                        )
+                }
+            },
             Value::Nil => 0,
         }
     }
 
+    // Allocations freed when the value is dropped: one per quicklist node or hash
+    // table element, compact encodings are a single buffer
+    pub fn free_effort(&self) -> usize {
+        match self {
+            Value::List(l) => match &l.data {
+                ListData::Quicklist(ql) => ql.node_count(),
+                ListData::Listpack(_) => 1,
+            },
+            Value::Set(s) => match &s.data {
+                SetData::HashTable(members) => members.len(),
+                _ => 1,
+            },
+            Value::SortedSet(zs) => match &zs.data {
+                SortedSetData::SkipList { zsl, .. } => zsl.len(),
+                SortedSetData::Listpack(_) => 1,
+            },
+            Value::Hash(h) => match &h.data {
+                HashData::HashTable(fields) => fields.len(),
+                HashData::Listpack(_) => 1,
+            },
+            Value::String(_) | Value::Nil => 1,
+        }
+    }
+
+    // Name of the internal encoding, as reported by OBJECT ENCODING
+    pub fn encoding_name(&self) -> &'static str {
+        match self {
+            Value::String(s) => s.encoding().as_str(),
+            Value::List(l) => l.encoding().as_str(),
+            Value::Set(s) => s.encoding().as_str(),
+            Value::SortedSet(zs) => zs.encoding().as_str(),
+            Value::Hash(h) => h.encoding().as_str(),
+            Value::Nil => "none",
+        }
+    }
+
     // Check if value is empty
     pub fn is_empty(&self) -> bool {
         match self {
-            Value::String(s) => s.data.is_empty(),
-            Value::List(l) => l.elements.is_empty(),
-            Value::Set(s) => s.members.is_empty(),
-            Value::SortedSet(zs) => zs.members.is_empty(),
-            Value::Hash(h) => h.fields.is_empty(),
+            Value::String(s) => s.len() == 0,
+            Value::List(l) => l.len() == 0,
+            Value::Set(s) => s.len() == 0,
+            Value::SortedSet(zs) => zs.len() == 0,
+            Value::Hash(h) => h.len() == 0,
             Value::Nil => true,
         }
     }
 }
 
+// Size of the block the allocator hands out for a `size` byte request: an 8 byte
+// chunk header, rounded up to 16 byte alignment, with a 32 byte minimum chunk.
+pub fn malloc_size(size: usize) -> usize {
+    if size == 0 {
+        return 0;
+    }
+    ((size + 8).max(32) + 15) & !15
+}
+
+// Size of a hash table allocation with room for `capacity` slots of `slot` bytes:
+// the slot array, one control byte per bucket and a trailing probe group.
+pub fn hash_table_size(capacity: usize, slot: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = if capacity < 4 {
+        4
+    } else if capacity < 8 {
+        8
+    } else {
+        (capacity * 8 / 7).next_power_of_two()
+    };
+    malloc_size(buckets * slot + buckets + 16)
+}
+
+fn sampled<I: Iterator<Item = usize>>(sizes: I, len: usize, samples: usize) -> usize {
+    if samples == 0 || len <= samples {
+        return sizes.sum();
+    }
+    sizes.take(samples).sum::<usize>() * len / samples
+}
+
+impl StringEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StringEncoding::Raw => "raw",
+            StringEncoding::Int => "int",
+            StringEncoding::Embstr => "embstr",
+        }
+    }
+}
+
+impl ListEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListEncoding::Listpack => "listpack",
+            ListEncoding::Quicklist => "quicklist",
+        }
+    }
+}
+
+impl SetEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SetEncoding::HashTable => "hashtable",
+            SetEncoding::IntSet => "intset",
+            SetEncoding::Listpack => "listpack",
+        }
+    }
+}
+
+impl SortedSetEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortedSetEncoding::Listpack => "listpack",
+            SortedSetEncoding::SkipList => "skiplist",
+        }
+    }
+}
+
+impl HashEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashEncoding::Listpack => "listpack",
+            HashEncoding::HashTable => "hashtable",
+        }
+    }
+}
+
 impl StringValue {
+    // Pick the most compact encoding: canonical integers are stored as i64, short
+    // strings inline, and everything else in a heap buffer
     pub fn new<T: Into<Vec<u8>>>(data: T) -> Self {
         let data = data.into();
-        let encoding = if data.len() <= 39 {
-            StringEncoding::Embstr
+        if let Some(v) = parse_canonical_int(&data) {
+            return Self::from_int(v);
+        }
+
+        let data = if data.len() <= EMBSTR_SIZE_LIMIT {
+            let mut buf = [0u8; EMBSTR_SIZE_LIMIT];
+            buf[..data.len()].copy_from_slice(&data);
+            StringData::Embstr(EmbStr {
+                len: data.len() as u8,
+                buf,
+            })
         } else {
-            StringEncoding::Raw
+            StringData::Raw(data)
         };
-
-        Self { data, encoding }
+        Self { data }
     }
 
     pub fn from_int(value: i64) -> Self {
         Self {
-            data: value.to_string().into_bytes(),
-            encoding: StringEncoding::Int,
+            data: StringData::Int(value),
+        }
+    }
+
+    pub fn encoding(&self) -> StringEncoding {
+        match self.data {
+            StringData::Int(_) => StringEncoding::Int,
+            StringData::Embstr(_) => StringEncoding::Embstr,
+            StringData::Raw(_) => StringEncoding::Raw,
         }
     }
 
-    pub fn as_str(&self) -> Result<&str, std::str::Utf8Error> {
-        std::str::from_utf8(&self.data)
+    // Whether the value is one of the pooled small integers
+    pub fn is_shared(&self) -> bool {
+        matches!(self.data, StringData::Int(v) if (0..SHARED_INTEGERS).contains(&v))
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.data {
+            StringData::Int(v) => int_len(*v),
+            StringData::Embstr(e) => e.len as usize,
+            StringData::Raw(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        match &self.data {
+            StringData::Int(v) => match shared_integer(*v) {
+                Some(rendered) => Cow::Borrowed(rendered.as_ref()),
+                None => Cow::Owned(v.to_string().into_bytes()),
+            },
+            StringData::Embstr(e) => Cow::Borrowed(&e.buf[..e.len as usize]),
+            StringData::Raw(data) => Cow::Borrowed(data),
+        }
+    }
+
+    // Bytes for a reply frame. Pooled integers share their buffer instead of being
+    // formatted again.
+    pub fn to_bytes(&self) -> Bytes {
+        match &self.data {
+            StringData::Int(v) => match shared_integer(*v) {
+                Some(rendered) => rendered.clone(),
+                None => Bytes::from(v.to_string()),
+            },
+            _ => Bytes::copy_from_slice(&self.as_bytes()),
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.data {
+            StringData::Raw(data) => data,
+            _ => self.as_bytes().into_owned(),
+        }
     }
 
     pub fn as_int(&self) -> Option<i64> {
-        self.as_str().ok()?.parse().ok()
+        match &self.data {
+            StringData::Int(v) => Some(*v),
+            _ => std::str::from_utf8(&self.as_bytes()).ok()?.parse().ok(),
+        }
     }
 
     pub fn as_float(&self) -> Option<f64> {
-        self.as_str().ok()?.parse().ok()
+        match &self.data {
+            StringData::Int(v) => Some(*v as f64),
+            _ => std::str::from_utf8(&self.as_bytes()).ok()?.parse().ok(),
+        }
+    }
+
+    // Heap bytes owned by the string; integers and embedded strings live inline
+    fn heap_size(&self) -> usize {
+        match &self.data {
+            StringData::Raw(data) => malloc_size(data.capacity()),
+            _ => 0,
+        }
+    }
+}
+
+// Decimal renderings of the shared integers, built on first use. Replies clone
+// these buffers instead of formatting the number again.
+static SHARED_INTEGER_POOL: LazyLock<Vec<Bytes>> = LazyLock::new(|| {
+    (0..SHARED_INTEGERS)
+        .map(|v| Bytes::from(v.to_string()))
+        .collect()
+});
+
+fn shared_integer(v: i64) -> Option<&'static Bytes> {
+    if (0..SHARED_INTEGERS).contains(&v) {
+        Some(&SHARED_INTEGER_POOL[v as usize])
+    } else {
+        None
+    }
+}
+
+fn int_len(v: i64) -> usize {
+    let digits = v.unsigned_abs().checked_ilog10().unwrap_or(0) as usize + 1;
+    if v < 0 { digits + 1 } else { digits }
+}
+
+impl Default for ListValue {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl ListValue {
     pub fn new() -> Self {
         Self {
-            elements: Vec::new(),
-            encoding: ListEncoding::Quicklist,
+            data: ListData::Listpack(Listpack::new()),
         }
     }
 
-    pub fn push_left<T: Into<Vec<u8>>>(&mut self, value: T) {
-        self.elements.insert(0, value.into());
+    pub fn encoding(&self) -> ListEncoding {
+        match self.data {
+            ListData::Listpack(_) => ListEncoding::Listpack,
+            ListData::Quicklist(_) => ListEncoding::Quicklist,
+        }
     }
 
-    pub fn push_right<T: Into<Vec<u8>>>(&mut self, value: T) {
-        self.elements.push(value.into());
+    pub fn push_left<T: Into<Vec<u8>>>(&mut self, value: T, limits: &EncodingLimits) {
+        let value = value.into();
+        self.grow(&value, limits);
+        match &mut self.data {
+            ListData::Listpack(lp) => lp.push_front(&value),
+            ListData::Quicklist(ql) => ql.push_front(&value),
+        }
+    }
+
+    pub fn push_right<T: Into<Vec<u8>>>(&mut self, value: T, limits: &EncodingLimits) {
+        let value = value.into();
+        self.grow(&value, limits);
+        match &mut self.data {
+            ListData::Listpack(lp) => lp.push_back(&value),
+            ListData::Quicklist(ql) => ql.push_back(&value),
+        }
     }
 
     pub fn pop_left(&mut self) -> Option<Vec<u8>> {
-        if !self.elements.is_empty() {
-            Some(self.elements.remove(0))
-        } else {
-            None
+        match &mut self.data {
+            ListData::Listpack(lp) => lp.pop_front(),
+            ListData::Quicklist(ql) => ql.pop_front(),
         }
     }
 
     pub fn pop_right(&mut self) -> Option<Vec<u8>> {
-        self.elements.pop()
+        match &mut self.data {
+            ListData::Listpack(lp) => lp.pop_back(),
+            ListData::Quicklist(ql) => ql.pop_back(),
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.elements.len()
+        match &self.data {
+            ListData::Listpack(lp) => lp.len(),
+            ListData::Quicklist(ql) => ql.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    pub fn get(&self, index: i64) -> Option<&Vec<u8>> {
-        let len = self.elements.len() as i64;
+    pub fn get(&self, index: i64) -> Option<Vec<u8>> {
+        let len = self.len() as i64;
         let actual_index = if index < 0 { len + index } else { index };
 
         if actual_index >= 0 && actual_index < len {
-            self.elements.get(actual_index as usize)
+            match &self.data {
+                ListData::Listpack(lp) => lp.seek(actual_index).map(|off| lp.get(off).to_vec()),
+                ListData::Quicklist(ql) => ql.get(actual_index as usize).map(|e| e.to_vec()),
+            }
         } else {
             None
         }
     }
+
+    // Elements in [start, stop)
+    pub fn range(&self, start: usize, stop: usize) -> Vec<Vec<u8>> {
+        let count = stop.saturating_sub(start);
+        match &self.data {
+            ListData::Listpack(lp) => lp
+                .iter()
+                .skip(start)
+                .take(count)
+                .map(|e| e.to_vec())
+                .collect(),
+            ListData::Quicklist(ql) => ql
+                .iter_from(start)
+                .take(count)
+                .map(|e| e.to_vec())
+                .collect(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match &self.data {
+            ListData::Listpack(lp) => Box::new(lp.iter().map(|e| e.as_bytes())),
+            ListData::Quicklist(ql) => Box::new(ql.iter().map(|e| e.as_bytes())),
+        }
+    }
+
+    // Convert to a quicklist once the listpack would outgrow a single node
+    fn grow(&mut self, value: &[u8], limits: &EncodingLimits) {
+        let fill = limits.list_max_listpack_size;
+        if let ListData::Listpack(lp) = &mut self.data
+            && !fits_in_node(lp, value, fill)
+        {
+            let lp = std::mem::take(lp);
+            self.data = ListData::Quicklist(Quicklist::from_listpack(lp, fill));
+        }
+    }
+}
+
+impl Default for SetValue {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SetValue {
     pub fn new() -> Self {
         Self {
-            members: HashSet::new(),
-            encoding: SetEncoding::HashTable,
+            data: SetData::IntSet(IntSet::new()),
+        }
+    }
+
+    pub fn encoding(&self) -> SetEncoding {
+        match self.data {
+            SetData::IntSet(_) => SetEncoding::IntSet,
+            SetData::Listpack(_) => SetEncoding::Listpack,
+            SetData::HashTable(_) => SetEncoding::HashTable,
         }
     }
 
-    pub fn add<T: Into<Vec<u8>>>(&mut self, member: T) -> bool {
-        self.members.insert(member.into())
+    pub fn add<T: Into<Vec<u8>>>(&mut self, member: T, limits: &EncodingLimits) -> bool {
+        let member = member.into();
+        if self.contains(&member) {
+            return false;
+        }
+
+        let int_member = parse_canonical_int(&member);
+        let len = self.len();
+        match &self.data {
+            SetData::IntSet(_) if int_member.is_some() => {
+                if len + 1 > limits.set_max_intset_entries {
+                    self.convert_to_hashtable();
+                }
+            }
+            SetData::IntSet(_) => {
+                if len + 1 > limits.set_max_listpack_entries
+                    || member.len() > limits.set_max_listpack_value
+                {
+                    self.convert_to_hashtable();
+                } else {
+                    self.convert_to_listpack();
+                }
+            }
+            SetData::Listpack(_) => {
+                if len + 1 > limits.set_max_listpack_entries
+                    || member.len() > limits.set_max_listpack_value
+                {
+                    self.convert_to_hashtable();
+                }
+            }
+            SetData::HashTable(_) => {}
+        }
+
+        match &mut self.data {
+            SetData::IntSet(is) => is.add(int_member.unwrap()),
+            SetData::Listpack(lp) => {
+                lp.push_back(&member);
+                true
+            }
+            SetData::HashTable(members) => members.insert(member),
+        }
     }
 
     pub fn remove(&mut self, member: &[u8]) -> bool {
-        self.members.remove(member)
+        match &mut self.data {
+            SetData::IntSet(is) => parse_canonical_int(member).is_some_and(|v| is.remove(v)),
+            SetData::Listpack(lp) => match lp.find(member, 1) {
+                Some(off) => {
+                    lp.delete(off);
+                    true
+                }
+                None => false,
+            },
+            SetData::HashTable(members) => members.remove(member),
+        }
     }
 
     pub fn contains(&self, member: &[u8]) -> bool {
-        self.members.contains(member)
+        match &self.data {
+            SetData::IntSet(is) => parse_canonical_int(member).is_some_and(|v| is.contains(v)),
+            SetData::Listpack(lp) => lp.find(member, 1).is_some(),
+            SetData::HashTable(members) => members.contains(member),
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.members.len()
+        match &self.data {
+            SetData::IntSet(is) => is.len(),
+            SetData::Listpack(lp) => lp.len(),
+            SetData::HashTable(members) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match &self.data {
+            SetData::IntSet(is) => Box::new(is.iter().map(|v| Cow::Owned(v.to_string().into()))),
+            SetData::Listpack(lp) => Box::new(lp.iter().map(|e| e.as_bytes())),
+            SetData::HashTable(members) => {
+                Box::new(members.iter().map(|m| Cow::Borrowed(m.as_slice())))
+            }
+        }
+    }
+
+    pub fn members(&self) -> Vec<Vec<u8>> {
+        self.iter().map(|m| m.into_owned()).collect()
+    }
+
+    fn convert_to_listpack(&mut self) {
+        let mut lp = Listpack::new();
+        for member in self.iter() {
+            lp.push_back(&member);
+        }
+        self.data = SetData::Listpack(lp);
+    }
+
+    fn convert_to_hashtable(&mut self) {
+        let members: HashSet<Vec<u8>> = self.iter().map(|m| m.into_owned()).collect();
+        self.data = SetData::HashTable(members);
+    }
+}
+
+impl Default for SortedSetValue {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl SortedSetValue {
     pub fn new() -> Self {
         Self {
-            members: BTreeMap::new(),
-            member_scores: HashMap::new(),
-            encoding: SortedSetEncoding::SkipList,
+            data: SortedSetData::Listpack(Listpack::new()),
         }
     }
 
-    pub fn add(&mut self, score: f64, member: Vec<u8>) -> bool {
-        let ordered_score = OrderedFloat(score);
+    pub fn encoding(&self) -> SortedSetEncoding {
+        match self.data {
+            SortedSetData::Listpack(_) => SortedSetEncoding::Listpack,
+            SortedSetData::SkipList { .. } => SortedSetEncoding::SkipList,
+        }
+    }
 
-        // Remove existing member if it exists
-        if let Some(old_score) = self.member_scores.remove(&member) {
-            self.members.remove(&old_score);
+    // Add a member or update its score. Returns true if the member is new. The
+    // score is never NaN: ZADD and CacheStore::zadd reject it, and so does
+    // SnapshotValue::into_value for everything loaded.
+    pub fn add(&mut self, score: f64, member: Vec<u8>, limits: &EncodingLimits) -> bool {
+        if let SortedSetData::Listpack(lp) = &self.data {
+            let adding = lp.find(&member, 2).is_none();
+            if (adding && lp.len() / 2 + 1 > limits.zset_max_listpack_entries)
+                || member.len() > limits.zset_max_listpack_value
+            {
+                self.convert_to_skiplist();
+            }
         }
 
-        self.members.insert(ordered_score, member.clone());
-        self.member_scores.insert(member, ordered_score);
-        true
+        match &mut self.data {
+            SortedSetData::Listpack(lp) => {
+                let existed = match lp.find(&member, 2) {
+                    Some(off) => {
+                        lp.delete(off); // member
+                        lp.delete(off); // score
+                        true
+                    }
+                    None => false,
+                };
+
+                // Keep pairs ordered by score, then member
+                let mut off = lp.first();
+                while let Some(o) = off {
+                    let score_off = lp.next(o).unwrap();
+                    let s = listpack_score(lp, score_off);
+                    if s > score || (s == score && lp.get(o).to_vec() > member) {
+                        break;
+                    }
+                    off = lp.next(score_off);
+                }
+                let at = off.unwrap_or(lp.end());
+                lp.insert(at, &member);
+                let score_at = lp.next(at).unwrap_or(lp.end());
+                lp.insert(score_at, &score_to_bytes(score));
+                !existed
+            }
+            SortedSetData::SkipList { zsl, dict } => match dict.get_mut(&member) {
+                Some(old_score) => {
+                    if *old_score != score {
+                        zsl.remove(*old_score, &member);
+                        zsl.insert(score, member);
+                        *old_score = score;
+                    }
+                    false
+                }
+                None => {
+                    dict.insert(member.clone(), score);
+                    zsl.insert(score, member);
+                    true
+                }
+            },
+        }
     }
 
     pub fn remove(&mut self, member: &[u8]) -> bool {
-        if let Some(score) = self.member_scores.remove(member) {
-            self.members.remove(&score);
-            true
-        } else {
-            false
+        match &mut self.data {
+            SortedSetData::Listpack(lp) => match lp.find(member, 2) {
+                Some(off) => {
+                    lp.delete(off);
+                    lp.delete(off);
+                    true
+                }
+                None => false,
+            },
+            SortedSetData::SkipList { zsl, dict } => match dict.remove(member) {
+                Some(score) => zsl.remove(score, member),
+                None => false,
+            },
         }
     }
 
     pub fn score(&self, member: &[u8]) -> Option<f64> {
-        self.member_scores.get(member).map(|s| s.0)
+        match &self.data {
+            SortedSetData::Listpack(lp) => lp
+                .find(member, 2)
+                .map(|off| listpack_score(lp, lp.next(off).unwrap())),
+            SortedSetData::SkipList { dict, .. } => dict.get(member).copied(),
+        }
+    }
+
+    // 0-based position of the member in score order
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        match &self.data {
+            SortedSetData::Listpack(_) => self.iter().position(|(m, _)| m.as_ref() == member),
+            SortedSetData::SkipList { zsl, dict } => {
+                dict.get(member).and_then(|score| zsl.rank(*score, member))
+            }
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.members.len()
+        match &self.data {
+            SortedSetData::Listpack(lp) => lp.len() / 2,
+            SortedSetData::SkipList { zsl, .. } => zsl.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Members in score order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (Cow<'_, [u8]>, f64)> + '_> {
+        match &self.data {
+            SortedSetData::Listpack(lp) => {
+                let mut entries = lp.iter();
+                Box::new(std::iter::from_fn(move || {
+                    let member = entries.next()?.as_bytes();
+                    let score = entries.next()?.as_bytes();
+                    Some((member, parse_score(&score)))
+                }))
+            }
+            SortedSetData::SkipList { zsl, .. } => Box::new(
+                zsl.iter()
+                    .map(|(member, score)| (Cow::Borrowed(member), score)),
+            ),
+        }
+    }
+
+    // Members with rank in [start, stop)
+    pub fn range(&self, start: usize, stop: usize) -> Vec<(Vec<u8>, f64)> {
+        let count = stop.saturating_sub(start);
+        match &self.data {
+            SortedSetData::Listpack(_) => self
+                .iter()
+                .skip(start)
+                .take(count)
+                .map(|(member, score)| (member.into_owned(), score))
+                .collect(),
+            SortedSetData::SkipList { zsl, .. } => zsl
+                .iter_from(start)
+                .take(count)
+                .map(|(member, score)| (member.to_vec(), score))
+                .collect(),
+        }
+    }
+
+    fn convert_to_skiplist(&mut self) {
+        let mut zsl = SkipList::new();
+        let mut dict = HashMap::new();
+        for (member, score) in self.iter() {
+            dict.insert(member.to_vec(), score);
+            zsl.insert(score, member.into_owned());
+        }
+        self.data = SortedSetData::SkipList { zsl, dict };
+    }
+}
+
+fn listpack_score(lp: &Listpack, off: usize) -> f64 {
+    parse_score(&lp.get(off).as_bytes())
+}
+
+fn parse_score(bytes: &[u8]) -> f64 {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0.0)
+}
+
+// Integral scores are stored as integers so the listpack can pack them
+fn score_to_bytes(score: f64) -> Vec<u8> {
+    if score.fract() == 0.0 && score.abs() < (1u64 << 53) as f64 {
+        (score as i64).to_string().into_bytes()
+    } else {
+        score.to_string().into_bytes()
+    }
+}
+
+type FieldIter<'a> = Box<dyn Iterator<Item = (Cow<'a, [u8]>, Cow<'a, [u8]>)> + 'a>;
+
+impl Default for HashValue {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl HashValue {
     pub fn new() -> Self {
         Self {
-            fields: HashMap::new(),
-            encoding: HashEncoding::HashTable,
+            data: HashData::Listpack(Listpack::new()),
+        }
+    }
+
+    pub fn encoding(&self) -> HashEncoding {
+        match self.data {
+            HashData::Listpack(_) => HashEncoding::Listpack,
+            HashData::HashTable(_) => HashEncoding::HashTable,
         }
     }
 
-    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, field: K, value: V) {
-        self.fields.insert(field.into(), value.into());
+    // Set a field, returns true if the field is new
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &mut self,
+        field: K,
+        value: V,
+        limits: &EncodingLimits,
+    ) -> bool {
+        let (field, value) = (field.into(), value.into());
+        if let HashData::Listpack(lp) = &self.data {
+            let adding = lp.find(&field, 2).is_none();
+            if (adding && lp.len() / 2 + 1 > limits.hash_max_listpack_entries)
+                || field.len() > limits.hash_max_listpack_value
+                || value.len() > limits.hash_max_listpack_value
+            {
+                let fields = self
+                    .iter()
+                    .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                    .collect();
+                self.data = HashData::HashTable(fields);
+            }
+        }
+
+        match &mut self.data {
+            HashData::Listpack(lp) => match lp.find(&field, 2) {
+                Some(off) => {
+                    let value_off = lp.next(off).unwrap();
+                    lp.replace(value_off, &value);
+                    false
+                }
+                None => {
+                    lp.push_back(&field);
+                    lp.push_back(&value);
+                    true
+                }
+            },
+            HashData::HashTable(fields) => fields.insert(field, value).is_none(),
+        }
     }
 
-    pub fn get(&self, field: &[u8]) -> Option<&Vec<u8>> {
-        self.fields.get(field)
+    pub fn get(&self, field: &[u8]) -> Option<Vec<u8>> {
+        match &self.data {
+            HashData::Listpack(lp) => lp
+                .find(field, 2)
+                .map(|off| lp.get(lp.next(off).unwrap()).to_vec()),
+            HashData::HashTable(fields) => fields.get(field).cloned(),
+        }
     }
 
     pub fn remove(&mut self, field: &[u8]) -> bool {
-        self.fields.remove(field).is_some()
+        match &mut self.data {
+            HashData::Listpack(lp) => match lp.find(field, 2) {
+                Some(off) => {
+                    lp.delete(off);
+                    lp.delete(off);
+                    true
+                }
+                None => false,
+            },
+            HashData::HashTable(fields) => fields.remove(field).is_some(),
+        }
     }
 
     pub fn contains_field(&self, field: &[u8]) -> bool {
-        self.fields.contains_key(field)
+        match &self.data {
+            HashData::Listpack(lp) => lp.find(field, 2).is_some(),
+            HashData::HashTable(fields) => fields.contains_key(field),
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.fields.len()
+        match &self.data {
+            HashData::Listpack(lp) => lp.len() / 2,
+            HashData::HashTable(fields) => fields.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> FieldIter<'_> {
+        match &self.data {
+            HashData::Listpack(lp) => {
+                let mut entries = lp.iter();
+                Box::new(std::iter::from_fn(move || {
+                    let field = entries.next()?.as_bytes();
+                    let value = entries.next()?.as_bytes();
+                    Some((field, value))
+                }))
+            }
+            HashData::HashTable(fields) => Box::new(
+                fields
+                    .iter()
+                    .map(|(k, v)| (Cow::Borrowed(k.as_slice()), Cow::Borrowed(v.as_slice()))),
+            ),
+        }
+    }
+
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.iter().map(|(k, _)| k.into_owned()).collect()
+    }
+
+    pub fn values(&self) -> Vec<Vec<u8>> {
+        self.iter().map(|(_, v)| v.into_owned()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_limits() -> EncodingLimits {
+        EncodingLimits {
+            hash_max_listpack_entries: 2,
+            set_max_intset_entries: 2,
+            set_max_listpack_entries: 2,
+            zset_max_listpack_entries: 2,
+            list_max_listpack_size: 2,
+            ..EncodingLimits::default()
+        }
+    }
+
+    #[test]
+    fn test_string_encodings() {
+        let int = StringValue::new("-1234");
+        assert_eq!(int.encoding(), StringEncoding::Int);
+        assert_eq!(int.as_bytes().as_ref(), b"-1234");
+        assert_eq!(int.len(), 5);
+        assert!(!int.is_shared());
+        assert!(StringValue::new("42").is_shared());
+
+        // Not canonical, so kept as text
+        assert_eq!(StringValue::new("007").encoding(), StringEncoding::Embstr);
+
+        let emb = StringValue::new("x".repeat(EMBSTR_SIZE_LIMIT));
+        assert_eq!(emb.encoding(), StringEncoding::Embstr);
+        assert_eq!(Value::String(emb).memory_usage(), 0);
+
+        let raw = StringValue::new("x".repeat(EMBSTR_SIZE_LIMIT + 1));
+        assert_eq!(raw.encoding(), StringEncoding::Raw);
+        assert_eq!(raw.into_vec().len(), EMBSTR_SIZE_LIMIT + 1);
+    }
+
+    #[test]
+    fn test_hash_converts_past_limits() {
+        let limits = small_limits();
+        let mut hash = HashValue::new();
+        assert!(hash.set("a", "1", &limits));
+        assert!(!hash.set("a", "2", &limits));
+        assert!(hash.set("b", "3", &limits));
+        assert_eq!(hash.encoding(), HashEncoding::Listpack);
+        assert_eq!(hash.get(b"a"), Some(b"2".to_vec()));
+
+        assert!(hash.set("c", "4", &limits));
+        assert_eq!(hash.encoding(), HashEncoding::HashTable);
+        assert_eq!(hash.len(), 3);
+        assert_eq!(hash.get(b"a"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_set_encodings() {
+        let limits = small_limits();
+        let mut set = SetValue::new();
+        assert!(set.add("1", &limits));
+        assert!(set.add("2", &limits));
+        assert_eq!(set.encoding(), SetEncoding::IntSet);
+        assert!(set.add("3", &limits));
+        assert_eq!(set.encoding(), SetEncoding::HashTable);
+
+        let mut set = SetValue::new();
+        set.add("1", &limits);
+        set.add("one", &limits);
+        assert_eq!(set.encoding(), SetEncoding::Listpack);
+        assert!(set.contains(b"1"));
+        assert!(!set.contains(b"01"));
+        assert!(set.remove(b"one"));
+        assert_eq!(set.members(), vec![b"1".to_vec()]);
     }
 
-    pub fn keys(&self) -> Vec<&Vec<u8>> {
-        self.fields.keys().collect()
+    #[test]
+    fn test_sorted_set_listpack_order() {
+        let limits = EncodingLimits::default();
+        let mut zset = SortedSetValue::new();
+        zset.add(2.0, b"b".to_vec(), &limits);
+        zset.add(1.5, b"a".to_vec(), &limits);
+        zset.add(2.0, b"a2".to_vec(), &limits);
+        assert!(!zset.add(3.0, b"a".to_vec(), &limits));
+
+        assert_eq!(
+            zset.range(0, 3),
+            vec![
+                (b"a2".to_vec(), 2.0),
+                (b"b".to_vec(), 2.0),
+                (b"a".to_vec(), 3.0)
+            ]
+        );
+        assert_eq!(zset.score(b"a"), Some(3.0));
+        assert_eq!(zset.encoding(), SortedSetEncoding::Listpack);
+    }
+
+    #[test]
+    fn test_sorted_set_skiplist_keeps_equal_scores() {
+        let limits = small_limits();
+        let mut zset = SortedSetValue::new();
+        for member in ["d", "a", "c", "b"] {
+            zset.add(1.0, member.as_bytes().to_vec(), &limits);
+        }
+        assert_eq!(zset.encoding(), SortedSetEncoding::SkipList);
+        assert_eq!(zset.len(), 4);
+        assert_eq!(zset.rank(b"c"), Some(2));
+
+        assert!(!zset.add(0.5, b"c".to_vec(), &limits));
+        assert_eq!(zset.rank(b"c"), Some(0));
+        assert_eq!(
+            zset.range(1, 3),
+            vec![(b"a".to_vec(), 1.0), (b"b".to_vec(), 1.0)]
+        );
+        assert!(zset.remove(b"a"));
+        assert_eq!(zset.rank(b"d"), Some(2));
     }
 
-    pub fn values(&self) -> Vec<&Vec<u8>> {
-        self.fields.values().collect()
+    #[test]
+    fn test_list_converts_past_limits() {
+        let limits = small_limits();
+        let mut list = ListValue::new();
+        list.push_right("b", &limits);
+        list.push_left("a", &limits);
+        assert_eq!(list.encoding(), ListEncoding::Listpack);
+        list.push_right("c", &limits);
+        assert_eq!(list.encoding(), ListEncoding::Quicklist);
+        assert_eq!(
+            list.range(0, 3),
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(list.get(-1), Some(b"c".to_vec()));
     }
 }
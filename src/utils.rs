@@ -0,0 +1,151 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Current unix time in milliseconds
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Lowercase hex, two digits per byte
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// `bytes` random bytes as hex, e.g. for replication ids
+pub fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0; bytes];
+    getrandom::getrandom(&mut buf).expect("no source of randomness");
+    to_hex(&buf)
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Redis style glob matching: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*`: pattern position after it, string position
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                // Collapse consecutive stars
+                while pattern.get(p) == Some(&b'*') {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                backtrack = Some((p, s));
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                true
+            }
+            Some(b'[') => match match_class(pattern, p + 1, string[s]) {
+                Some((matched, end)) => {
+                    p = end;
+                    matched
+                }
+                None => false,
+            },
+            Some(b'\\') if p + 1 < pattern.len() => {
+                p += 2;
+                pattern[p - 1] == string[s]
+            }
+            Some(c) => {
+                p += 1;
+                *c == string[s]
+            }
+            None => false,
+        };
+
+        if matched {
+            s += 1;
+        } else if let Some((star_p, star_s)) = backtrack {
+            // Let the last star swallow one more byte and retry
+            p = star_p;
+            s = star_s + 1;
+            backtrack = Some((star_p, star_s + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// Match `c` against the class starting at `start` (just past the `[`). Returns
+// whether it matched and the pattern position after the closing `]`.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut p = start;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        match pattern.get(p)? {
+            b']' => break,
+            b'\\' => {
+                p += 1;
+                matched |= *pattern.get(p)? == c;
+            }
+            &lo if pattern.get(p + 1) == Some(&b'-')
+                && pattern.get(p + 2).is_some_and(|hi| *hi != b']') =>
+            {
+                let hi = pattern[p + 2];
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                matched |= (lo..=hi).contains(&c);
+                p += 2;
+            }
+            &other => matched |= other == c,
+        }
+        p += 1;
+    }
+    Some((matched != negate, p + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "anything", true),
+            ("__keyspace@0__:*", "__keyspace@0__:user:1", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("*b*c", "abxbc", true),
+            ("a*", "b", false),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), string.as_bytes()),
+                *expected,
+                "{} ~ {}",
+                pattern,
+                string
+            );
+        }
+    }
+}